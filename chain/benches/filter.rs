@@ -0,0 +1,171 @@
+//! Compact filter matching benchmarks.
+//!
+//! Measures how long it takes to match a wallet's scripts against a day's worth of
+//! mainnet-sized BIP 158 filters, ie. 144 filters of a few thousand elements each, with
+//! two algorithms:
+//!
+//! * `merge`: the matcher of the `bitcoin` crate, which hashes and sorts the query
+//!   for every filter, and merges it with the filter as it is decoded.
+//! * `intersection`: decodes every filter into a set, and looks up each hashed query
+//!   element in it.
+//!
+//! Filters and scripts are random, so matches are rare, and every filter is decoded in
+//! full, as is the case in practice.
+use std::collections::HashSet;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{Rng, SeedableRng};
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::consensus::encode::{Decodable, VarInt};
+use bitcoin::util::bip158::{BlockFilter, BlockFilterWriter};
+use bitcoin_hashes::{siphash24, Hash};
+
+use nakamoto_common::block::BlockHash;
+use nakamoto_test::BITCOIN_HEADERS;
+
+/// Number of filters to match against, ie. a day of blocks.
+const FILTERS: usize = 144;
+/// Number of elements in each filter. Typical of recent mainnet blocks.
+const FILTER_SIZE: usize = 2500;
+/// Golomb-Rice coding parameter of basic filters.
+const P: u8 = 19;
+/// Inverse false-positive rate of basic filters.
+const M: u64 = 784_931;
+
+/// Generate a random script, the size of a P2WPKH output script.
+fn script(rng: &mut impl Rng) -> Vec<u8> {
+    (0..22).map(|_| rng.gen()).collect()
+}
+
+/// Generate random filters for the first blocks of the main chain.
+fn filters(rng: &mut impl Rng) -> Vec<(BlockHash, BlockFilter)> {
+    BITCOIN_HEADERS
+        .iter()
+        .take(FILTERS)
+        .map(|header| {
+            let block = Block {
+                header: *header,
+                txdata: vec![],
+            };
+            let mut content = Vec::new();
+            let mut writer = BlockFilterWriter::new(&mut content, &block);
+
+            for _ in 0..FILTER_SIZE {
+                writer.add_element(&script(rng));
+            }
+            writer.finish().unwrap();
+
+            (block.block_hash(), BlockFilter::new(&content))
+        })
+        .collect()
+}
+
+/// Decode the hashed elements of a filter. Returns the number of elements in the filter,
+/// and the set of elements.
+fn decode(filter: &BlockFilter) -> (u64, HashSet<u64>) {
+    let mut reader = &filter.content[..];
+    let n = VarInt::consensus_decode(&mut reader).unwrap().0;
+    let mut bits = BitReader::new(reader);
+    let mut elements = HashSet::with_capacity(n as usize);
+    let mut value = 0;
+
+    for _ in 0..n {
+        let mut quotient = 0;
+        while bits.read(1) == 1 {
+            quotient += 1;
+        }
+        value += (quotient << P) + bits.read(P);
+        elements.insert(value);
+    }
+    (n, elements)
+}
+
+/// Hash a query element, as it would be hashed in the filter of the given block.
+fn hash(block_hash: &BlockHash, n: u64, element: &[u8]) -> u64 {
+    let key = block_hash.as_inner();
+    let mut k0 = [0; 8];
+    let mut k1 = [0; 8];
+
+    k0.copy_from_slice(&key[0..8]);
+    k1.copy_from_slice(&key[8..16]);
+
+    let hash = siphash24::Hash::hash_to_u64_with_keys(
+        u64::from_le_bytes(k0),
+        u64::from_le_bytes(k1),
+        element,
+    );
+    ((hash as u128 * (n * M) as u128) >> 64) as u64
+}
+
+/// Reads bits from a byte slice, most significant bit first.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn read(&mut self, bits: u8) -> u64 {
+        let mut value = 0;
+
+        for _ in 0..bits {
+            let byte = self.bytes[self.offset / 8];
+            let bit = (byte >> (7 - self.offset % 8)) & 1;
+
+            value = (value << 1) | bit as u64;
+            self.offset += 1;
+        }
+        value
+    }
+}
+
+fn matching(c: &mut Criterion) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let filters = filters(&mut rng);
+
+    let mut group = c.benchmark_group("match");
+    group.throughput(Throughput::Elements(FILTERS as u64));
+    group.sample_size(10);
+
+    for count in &[1, 100, 10_000] {
+        let scripts = (0..*count).map(|_| script(&mut rng)).collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("merge", count), &scripts, |b, scripts| {
+            b.iter(|| {
+                filters
+                    .iter()
+                    .filter(|(block_hash, filter)| {
+                        let mut query = scripts.iter().map(|s| s.as_slice());
+                        filter.match_any(block_hash, &mut query).unwrap()
+                    })
+                    .count()
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("intersection", count),
+            &scripts,
+            |b, scripts| {
+                b.iter(|| {
+                    filters
+                        .iter()
+                        .filter(|(block_hash, filter)| {
+                            let (n, elements) = decode(filter);
+
+                            scripts
+                                .iter()
+                                .any(|s| elements.contains(&hash(block_hash, n, s)))
+                        })
+                        .count()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, matching);
+criterion_main!(benches);
@@ -0,0 +1,76 @@
+//! Header import benchmarks.
+//!
+//! Measures how many headers per second can be imported into a block cache, with each
+//! store backend, both in batches, as during initial sync, and one at a time, as when
+//! following the tip.
+use std::net;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use nonempty::NonEmpty;
+
+use bitcoin::consensus::params::Params;
+
+use nakamoto_chain::block::cache::BlockCache;
+use nakamoto_chain::block::store::{self, Store};
+use nakamoto_common::block::time::{AdjustedTime, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::BlockHeader;
+use nakamoto_test::BITCOIN_HEADERS;
+
+fn cache<S: Store<Header = BlockHeader>>(store: S) -> BlockCache<S> {
+    BlockCache::from(store, Params::new(bitcoin::Network::Bitcoin), &[]).unwrap()
+}
+
+fn import(c: &mut Criterion) {
+    let genesis = BITCOIN_HEADERS.head;
+    let headers = BITCOIN_HEADERS.tail.clone();
+    let time = LocalTime::from_block_time(headers.last().unwrap().time);
+    let clock = AdjustedTime::<net::SocketAddr>::new(time);
+
+    let mut group = c.benchmark_group("import");
+    group.throughput(Throughput::Elements(headers.len() as u64));
+
+    group.bench_function("memory", |b| {
+        b.iter_batched(
+            || cache(store::Memory::new(NonEmpty::new(genesis))),
+            |mut cache| {
+                cache
+                    .import_blocks(headers.iter().cloned(), &clock)
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("memory/one-by-one", |b| {
+        b.iter_batched(
+            || cache(store::Memory::new(NonEmpty::new(genesis))),
+            |mut cache| {
+                for header in headers.iter() {
+                    cache.extend_tip(*header, &clock).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("file", |b| {
+        b.iter_batched(
+            || {
+                let dir = tempfile::tempdir().unwrap();
+                let store = store::File::create(dir.path().join("headers.db"), genesis).unwrap();
+
+                // Keep the directory around until the iteration is over.
+                (dir, cache(store))
+            },
+            |(_dir, mut cache)| {
+                cache
+                    .import_blocks(headers.iter().cloned(), &clock)
+                    .unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, import);
+criterion_main!(benches);
@@ -9,6 +9,7 @@ pub mod test;
 
 use std::cmp::Ordering;
 use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem;
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::params::Params;
@@ -17,6 +18,8 @@ use bitcoin::network::constants::Network;
 
 use nonempty::NonEmpty;
 
+use crate::block::pow;
+
 use nakamoto_common::block::tree::{self, BlockTree, Branch, Error, ImportResult};
 use nakamoto_common::block::{
     self,
@@ -169,22 +172,23 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
     fn import_block(
         &mut self,
         header: BlockHeader,
+        hash: BlockHash,
         clock: &impl Clock,
     ) -> Result<ImportResult, Error> {
-        let hash = header.block_hash();
         let tip = self.chain.last();
         let best = tip.hash;
 
+        if self.is_known(&hash) {
+            return Err(Error::DuplicateBlock(hash));
+        }
+
         // Block extends the active chain.
         if header.prev_blockhash == best {
             let height = tip.height + 1;
 
-            self.validate(&tip, &header, clock)?;
+            self.validate(&tip, &header, &hash, clock)?;
             self.extend_chain(height, hash, header);
             self.store.put(std::iter::once(header))?;
-        } else if self.headers.contains_key(&hash) || self.orphans.contains_key(&hash) {
-            // FIXME: This shouldn't be an error.
-            return Err(Error::DuplicateBlock(hash));
         } else {
             if let Some(height) = self.headers.get(&header.prev_blockhash) {
                 // Don't accept any forks from the main chain, prior to the last checkpoint.
@@ -198,7 +202,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             //
             // We do this because it's cheap to verify and prevents flooding attacks.
             let target = header.target();
-            match header.validate_pow(&target) {
+            match pow::validate(&header, &hash, &target) {
                 Ok(_) => {
                     let limit = self.params.pow_limit;
                     if target > limit {
@@ -270,7 +274,6 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         let (hash, _) = self.tip();
         if hash != best {
-            // TODO: Test the reverted blocks.
             Ok(ImportResult::TipChanged(
                 hash,
                 self.height(),
@@ -341,22 +344,26 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         };
 
         for header in candidate.headers.iter() {
-            self.validate(&tip, header, clock)?;
+            let hash = header.block_hash();
+
+            self.validate(&tip, header, &hash, clock)?;
 
             tip = CachedBlock {
                 height: tip.height + 1,
-                hash: header.block_hash(),
+                hash,
                 header: *header,
             };
         }
         Ok(())
     }
 
-    /// Validate a block header as a potential new tip. This performs full header validation.
+    /// Validate a block header as a potential new tip, given its hash. This performs full
+    /// header validation.
     fn validate(
         &self,
         tip: &CachedBlock,
         header: &BlockHeader,
+        hash: &BlockHash,
         clock: &impl Clock,
     ) -> Result<(), Error> {
         assert_eq!(tip.hash, header.prev_blockhash);
@@ -375,7 +382,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         let target = BlockHeader::u256_from_compact_target(compact_target);
 
-        match header.validate_pow(&target) {
+        match pow::validate(header, hash, &target) {
             Err(bitcoin::util::Error::BlockBadProofOfWork) => {
                 return Err(Error::InvalidBlockPoW);
             }
@@ -390,10 +397,8 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         let height = tip.height + 1;
 
         if let Some(checkpoint) = self.checkpoints.get(&height) {
-            let hash = header.block_hash();
-
-            if &hash != checkpoint {
-                return Err(Error::InvalidBlockHash(hash, height));
+            if hash != checkpoint {
+                return Err(Error::InvalidBlockHash(*hash, height));
             }
         }
 
@@ -496,15 +501,27 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         context: &C,
     ) -> Result<ImportResult, Error> {
         let mut result = None;
-
-        for (i, header) in chain.enumerate() {
-            match self.import_block(header, context) {
+        let chain = chain.collect::<Vec<_>>();
+        // Hash all headers up-front, possibly in parallel, since this is the most
+        // expensive part of the import.
+        let hashes = pow::hashes(&chain);
+
+        for (i, (header, hash)) in chain.into_iter().zip(hashes).enumerate() {
+            // Skip headers we already have. This is common, since several peers may send
+            // us the same headers.
+            if self.is_known(&hash) {
+                continue;
+            }
+            match self.import_block(header, hash, context) {
                 Ok(r) => result = Some(r),
-                Err(Error::DuplicateBlock(hash)) => log::trace!("Duplicate block {}", hash),
                 Err(Error::BlockMissing(hash)) => log::trace!("Missing block {}", hash),
                 Err(err) => return Err(Error::BlockImportAborted(err.into(), i, self.height())),
             }
         }
+        // Imported headers are appended to the store one by one, so we write them out
+        // together, once the whole batch is imported.
+        self.store.flush()?;
+
         Ok(result.unwrap_or(ImportResult::TipUnchanged))
     }
 
@@ -520,9 +537,10 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         if header.prev_blockhash == tip.hash {
             let height = tip.height + 1;
 
-            self.validate(&tip, &header, clock)?;
+            self.validate(&tip, &header, &hash, clock)?;
             self.extend_chain(height, hash, header);
             self.store.put(std::iter::once(header))?;
+            self.store.flush()?;
 
             Ok(ImportResult::TipChanged(hash, height, vec![]))
         } else {
@@ -642,4 +660,41 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         }
         hashes
     }
+
+    /// Approximate memory used by the cache. Accounts for the cached blocks of the active
+    /// chain and their index, and for orphan and stale headers.
+    fn memory_usage(&self) -> (usize, usize) {
+        let headers = self.chain.len() * mem::size_of::<CachedBlock>()
+            + self.headers.len() * mem::size_of::<(BlockHash, Height)>();
+        let orphans = self.orphans.len() * mem::size_of::<(BlockHash, BlockHeader)>();
+
+        (headers, orphans)
+    }
+
+    /// Discard orphan and stale headers, by increasing timestamp, until they fit in the
+    /// given number of bytes.
+    fn prune_orphans(&mut self, max: usize) {
+        let excess = self
+            .orphans
+            .len()
+            .saturating_sub(max / mem::size_of::<(BlockHash, BlockHeader)>());
+
+        if excess == 0 {
+            return;
+        }
+        let mut orphans = self
+            .orphans
+            .iter()
+            .map(|(hash, header)| (header.time, *hash))
+            .collect::<Vec<_>>();
+        orphans.sort_unstable();
+
+        for (_, hash) in orphans.into_iter().take(excess) {
+            self.orphans.remove(&hash);
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.store.sync().map_err(Error::from)
+    }
 }
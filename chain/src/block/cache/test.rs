@@ -2,10 +2,13 @@ use super::BlockCache;
 
 use nakamoto_common::block::time::{AdjustedTime, Clock, LocalTime};
 use nakamoto_common::block::tree::{BlockTree, Error, ImportResult};
-use nakamoto_common::block::{BlockTime, Height, Target};
+use nakamoto_common::block::{pow_limit_bits, BlockTime, Height, Target};
 
 use nakamoto_test::block;
 use nakamoto_test::block::cache::model;
+use nakamoto_test::block::gen;
+use nakamoto_test::block::reorg;
+use nakamoto_test::block::tree;
 
 use crate::block::store::{self, Store};
 
@@ -512,6 +515,120 @@ fn test_bitcoin_difficulty() {
     }
 }
 
+// Test that our difficulty validation rejects variants of the bitcoin main chain
+// retargets, where either the difficulty or the retarget period was tampered with.
+#[test]
+fn test_bitcoin_difficulty_mutated() {
+    use crate::tests;
+
+    let network = bitcoin::Network::Bitcoin;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+
+    let mut cache = HeightCache::new(genesis);
+
+    for (height, prev_time, prev_bits, time, bits) in tests::TARGETS.iter().cloned() {
+        let prev_target = BlockHeader::u256_from_compact_target(prev_bits);
+        let target = cache.next_difficulty_target(height - 1, prev_time, prev_target, &params);
+
+        assert_eq!(target, bits, "block {} is accepted", height);
+
+        // A retarget period that is a day shorter yields a higher difficulty, unless the
+        // difficulty is clamped to its minimum, as with the early blocks.
+        let shortened = BlockHeader::u256_from_compact_target(cache.next_difficulty_target(
+            height - 1,
+            prev_time - 60 * 60 * 24,
+            prev_target,
+            &params,
+        ));
+        let target = BlockHeader::u256_from_compact_target(bits);
+
+        if bits == pow_limit_bits(&network) {
+            assert!(shortened <= target);
+        } else {
+            assert!(shortened < target, "block {} with a shorter period", height);
+        }
+
+        // A block that isn't on a retarget boundary keeps the previous difficulty.
+        assert_eq!(
+            cache.next_difficulty_target(height, prev_time, prev_target, &params),
+            prev_bits
+        );
+
+        cache.import(
+            height,
+            BlockHeader {
+                version: 1,
+                time,
+                bits,
+                merkle_root: Default::default(),
+                prev_blockhash: Default::default(),
+                nonce: 0,
+            },
+        );
+    }
+}
+
+// Test that headers from the bitcoin main chain are accepted when imported, while
+// mutated variants of them are rejected, and leave the chain untouched.
+#[test]
+fn test_bitcoin_headers_mutated() {
+    let params = Params::new(bitcoin::Network::Bitcoin);
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let headers = &*nakamoto_test::BITCOIN_HEADERS;
+    let (prefix, suffix) = headers.tail.split_at(headers.tail.len() / 2);
+    let store = store::Memory::new(NonEmpty::from((headers.head, prefix.to_vec())));
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let rejected = |result: Result<ImportResult, Error>| match result {
+        Err(Error::BlockImportAborted(err, 0, _)) => Some(*err),
+        _ => None,
+    };
+
+    for header in suffix {
+        let (tip, _) = cache.tip();
+        let height = cache.height();
+
+        // A block claiming a different target than required.
+        let mutated = BlockHeader {
+            bits: header.bits + 1,
+            ..*header
+        };
+        assert!(matches!(
+            rejected(cache.import_blocks(iter::once(mutated), &ctx)),
+            Some(Error::InvalidBlockTarget(..))
+        ));
+
+        // Blocks whose hash no longer meets their target, since the hash commits to all
+        // fields, including a timestamp that is no longer past the median time.
+        let mutated = [
+            BlockHeader {
+                nonce: header.nonce.wrapping_add(1),
+                ..*header
+            },
+            BlockHeader {
+                merkle_root: TxMerkleNode::default(),
+                ..*header
+            },
+            BlockHeader {
+                time: cache.median_time_past(height + 1),
+                ..*header
+            },
+        ];
+        for mutated in mutated.iter() {
+            assert!(matches!(
+                rejected(cache.import_blocks(iter::once(*mutated), &ctx)),
+                Some(Error::InvalidBlockPoW)
+            ));
+        }
+        assert_eq!(cache.tip().0, tip, "rejected blocks aren't imported");
+
+        assert_eq!(
+            cache.import_blocks(iter::once(*header), &ctx).unwrap(),
+            ImportResult::TipChanged(header.block_hash(), height + 1, vec![])
+        );
+    }
+}
+
 // Test that we're correctly loading headers from the header store.
 #[test]
 fn test_from_store() {
@@ -1183,6 +1300,48 @@ fn test_cache_import_duplicate() {
     });
 }
 
+#[test]
+fn test_cache_import_blocks_duplicate() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+    let g = &mut rand::thread_rng();
+
+    // a0 <- a1 <- a2 <- a3 *
+    //     \
+    //      <- b1
+    let a0 = Tree::new(genesis);
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let a3 = a2.next(g);
+    let b1 = a0.next(g);
+
+    cache
+        .import_blocks(vec![a1.block(), a2.block(), b1.block()].into_iter(), &ctx)
+        .unwrap();
+    assert_eq!(cache.height(), 2);
+
+    // Importing known headers, whether active or not, is a no-op.
+    assert_eq!(
+        cache
+            .import_blocks(vec![a1.block(), a2.block(), b1.block()].into_iter(), &ctx)
+            .unwrap(),
+        ImportResult::TipUnchanged
+    );
+    assert_eq!(cache.height(), 2);
+
+    // Known headers are skipped, while new ones are imported.
+    assert_eq!(
+        cache
+            .import_blocks(vec![a1.block(), a2.block(), a3.block()].into_iter(), &ctx)
+            .unwrap(),
+        ImportResult::TipChanged(a3.hash, 3, vec![])
+    );
+}
+
 #[test]
 #[allow(unused_variables)]
 fn test_cache_import_unordered() {
@@ -1394,3 +1553,100 @@ fn test_cache_locate_headers() {
         "If the stop height is equal to the start height, we don't expect anything"
     );
 }
+
+/// Create an empty block cache for the generated header trees.
+fn regtest_cache() -> BlockCache<store::Memory<BlockHeader>> {
+    let genesis = tree::genesis();
+    let params = Params::new(tree::NETWORK);
+
+    BlockCache::from(store::Memory::new(NonEmpty::new(genesis)), params, &[]).unwrap()
+}
+
+#[quickcheck]
+fn prop_cache_tip_selection(headers: tree::Headers) -> bool {
+    tree::prop_tip_selection(regtest_cache(), &headers)
+}
+
+#[quickcheck]
+fn prop_cache_reorg(reorg: tree::Reorg) -> bool {
+    tree::prop_reorg(regtest_cache(), &reorg)
+}
+
+#[quickcheck]
+fn prop_cache_import_idempotent(headers: tree::Headers) -> bool {
+    tree::prop_import_idempotent(regtest_cache(), &headers)
+}
+
+#[quickcheck]
+fn prop_cache_import_duplicates(headers: tree::Headers) -> bool {
+    tree::prop_import_duplicates(regtest_cache(), &headers)
+}
+
+#[quickcheck]
+fn prop_cache_invalid_rejected(invalid: tree::Invalid) -> bool {
+    tree::prop_invalid_rejected(regtest_cache(), &invalid)
+}
+
+#[test]
+fn test_cache_import_generated() {
+    let mut rng = gen::rng(1);
+    let mut cache = regtest_cache();
+    let clock = gen::clock();
+    let genesis = gen::genesis();
+
+    let chain = gen::chain(&genesis, 32, &mut rng);
+    let tip = chain.last().unwrap().block_hash();
+
+    assert!(matches!(
+        cache.import_blocks(chain.iter().cloned(), &clock),
+        Ok(ImportResult::TipChanged(hash, 32, _)) if hash == tip
+    ));
+
+    // A longer fork replaces the blocks of the chain past the fork point.
+    let fork = gen::fork(&genesis, &chain, 16, 20, &mut rng);
+    let tip = fork.last().unwrap().block_hash();
+
+    match cache.import_blocks(fork.iter().cloned(), &clock) {
+        Ok(ImportResult::TipChanged(hash, height, reverted)) => {
+            assert_eq!(hash, tip);
+            assert_eq!(height, 36);
+            assert_eq!(reverted.len(), 16);
+        }
+        result => panic!("unexpected import result: {:?}", result),
+    }
+    assert_eq!(cache.height(), 36);
+}
+
+#[test]
+fn test_cache_reorg_scenarios() {
+    for seed in 0..4 {
+        let mut rng = gen::rng(seed);
+        let base = gen::chain(&gen::genesis(), 8, &mut rng);
+
+        for scenario in reorg::scenarios(&base, &mut rng) {
+            if let Err(err) = reorg::run(&mut regtest_cache(), &scenario) {
+                panic!("seed {}: {}", seed, err);
+            }
+        }
+    }
+}
+
+// Requires `bitcoind` to be installed. See [`nakamoto_test::bitcoind`].
+// Run with `cargo test -p nakamoto-chain -- --ignored`.
+#[test]
+#[ignore]
+fn test_cache_diff_bitcoind() {
+    use nakamoto_test::bitcoind::Bitcoind;
+    use nakamoto_test::block::diff;
+    use std::time::SystemTime;
+
+    let bitcoind = Bitcoind::spawn().unwrap();
+    let clock = AdjustedTime::<net::SocketAddr>::new(LocalTime::now());
+    let cases = diff::cases(16, SystemTime::now(), &mut gen::rng(1));
+
+    let divergences = diff::run(&mut regtest_cache(), &cases, &clock, |header| {
+        bitcoind.submit_header(header).unwrap()
+    });
+    assert!(divergences.is_empty(), "{:#?}", divergences);
+    assert_eq!(bitcoind.height().unwrap(), 0, "headers aren't blocks");
+}
@@ -0,0 +1,91 @@
+//! Proof-of-work verification.
+//!
+//! Hashing headers dominates the CPU time of header sync, especially on slower devices.
+//! Header batches are therefore hashed up-front, before being imported one by one, and
+//! the precomputed hashes are checked against the targets required by the chain during
+//! import. With the `parallel` feature, large batches are hashed across rayon's worker
+//! pool, whose size can be set with the `RAYON_NUM_THREADS` environment variable.
+use std::convert::TryInto;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::Hash;
+use bitcoin::util::uint::Uint256;
+
+/// Minimum number of headers for a batch to be hashed in parallel. Smaller batches, eg.
+/// block announcements, aren't worth the synchronization overhead.
+pub const PARALLEL_THRESHOLD: usize = 64;
+
+/// Hash the given headers, in order.
+#[cfg(feature = "parallel")]
+pub fn hashes(headers: &[BlockHeader]) -> Vec<BlockHash> {
+    use rayon::prelude::*;
+
+    if headers.len() < PARALLEL_THRESHOLD {
+        return headers.iter().map(BlockHeader::block_hash).collect();
+    }
+    headers.par_iter().map(BlockHeader::block_hash).collect()
+}
+
+/// Hash the given headers, in order.
+#[cfg(not(feature = "parallel"))]
+pub fn hashes(headers: &[BlockHeader]) -> Vec<BlockHash> {
+    headers.iter().map(BlockHeader::block_hash).collect()
+}
+
+/// Validate the proof-of-work of a header against the required target, given the header's
+/// hash. This is equivalent to [`BlockHeader::validate_pow`], without hashing the header.
+pub fn validate(
+    header: &BlockHeader,
+    hash: &BlockHash,
+    required: &Uint256,
+) -> Result<(), bitcoin::util::Error> {
+    let target = header.target();
+    if target != *required {
+        return Err(bitcoin::util::Error::BlockBadTarget);
+    }
+
+    let mut words = [0u64; 4];
+    for (word, bytes) in words.iter_mut().zip(hash.into_inner().chunks(8)) {
+        *word = u64::from_le_bytes(bytes.try_into().unwrap());
+    }
+
+    if Uint256(words) <= target {
+        Ok(())
+    } else {
+        Err(bitcoin::util::Error::BlockBadProofOfWork)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nakamoto_test::BITCOIN_HEADERS;
+
+    #[test]
+    fn test_validate() {
+        let headers = BITCOIN_HEADERS.iter().cloned().collect::<Vec<_>>();
+        let hashes = hashes(&headers);
+
+        for (header, hash) in headers.iter().zip(&hashes) {
+            assert_eq!(*hash, header.block_hash());
+            assert!(validate(header, hash, &header.target()).is_ok());
+
+            let mut invalid = *header;
+            invalid.nonce = invalid.nonce.wrapping_add(1);
+
+            assert_eq!(
+                validate(&invalid, &invalid.block_hash(), &invalid.target()).is_ok(),
+                invalid.validate_pow(&invalid.target()).is_ok()
+            );
+            assert!(matches!(
+                validate(
+                    header,
+                    hash,
+                    &(header.target() + Uint256::from_u64(1).unwrap())
+                ),
+                Err(bitcoin::util::Error::BlockBadTarget)
+            ));
+        }
+    }
+}
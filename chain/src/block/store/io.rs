@@ -1,29 +1,29 @@
 //! Persistent storage backend for blocks.
+//!
+//! Headers are stored back to back in a single file, in their consensus encoding, so that
+//! the store takes up close to 80 bytes per header. Since the common case is extending the
+//! tip, appended headers are buffered in memory, and written to the file in one go when the
+//! store is flushed, or when the buffer is full. Random-access writes are only needed to
+//! roll back the chain, eg. on reorgs. Headers that were never flushed are lost on crash,
+//! and have to be fetched again from peers.
+//!
+//! Flushed headers are synchronized to disk according to the store's [`SyncPolicy`].
 use std::fs;
 use std::io::{self, Read, Seek, Write};
 use std::iter;
 use std::marker::PhantomData;
 use std::mem;
 use std::path::Path;
+use std::time::Instant;
 
 use bitcoin::consensus::encode::{Decodable, Encodable};
 
-use nakamoto_common::block::store::{Error, Store};
+use nakamoto_common::block::store::{Error, Store, SyncPolicy};
 use nakamoto_common::block::Height;
 
-/// Append a block to the end of the stream.
-fn put<H: Sized + Encodable, S: Seek + Write, I: Iterator<Item = H>>(
-    mut stream: S,
-    headers: I,
-) -> Result<Height, Error> {
-    let mut pos = stream.seek(io::SeekFrom::End(0))?;
-    let size = std::mem::size_of::<H>();
-
-    for header in headers {
-        pos += header.consensus_encode(&mut stream)? as u64;
-    }
-    Ok(pos / size as u64)
-}
+/// Size of the append buffer, in bytes, after which appended headers are written to the
+/// file, even if the store wasn't flushed. This is enough for a full `headers` message.
+const BUFFER_SIZE: usize = 2000 * 80;
 
 /// Get a block from the stream.
 fn get<H: Decodable, S: Seek + Read>(mut stream: S, ix: u64) -> Result<H, Error> {
@@ -73,6 +73,64 @@ impl<H: Decodable> Iterator for Iter<H> {
 pub struct File<H> {
     file: fs::File,
     genesis: H,
+    /// Encoded headers appended to the store, that weren't yet written to the file.
+    buffer: Vec<u8>,
+    /// When flushed changes are synchronized to disk.
+    sync_policy: SyncPolicy,
+    /// Whether the file was modified since it was last synchronized.
+    unsynced: bool,
+    /// When the file was last synchronized.
+    last_sync: Option<Instant>,
+}
+
+impl<H> File<H> {
+    /// Get the number of complete headers in the file, excluding the genesis and buffered
+    /// headers.
+    fn file_len(&self) -> Result<u64, Error> {
+        let len = self.file.metadata()?.len();
+
+        Ok(len / mem::size_of::<H>() as u64)
+    }
+
+    /// Write the buffered headers to the end of the file.
+    fn write_buffer(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            // Nb. The file is opened in append mode.
+            self.file.write_all(&self.buffer)?;
+            self.buffer.clear();
+            self.unsynced = true;
+        }
+        Ok(())
+    }
+
+    /// Synchronize the file to disk, if it was modified since the last synchronization.
+    fn sync_file(&mut self) -> Result<(), Error> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+            self.last_sync = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Set the policy used to synchronize flushed changes to disk.
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+}
+
+impl<H> Drop for File<H> {
+    fn drop(&mut self) {
+        if let Err(err) = self.write_buffer() {
+            log::error!("Error writing buffered headers to disk: {}", err);
+        }
+        if self.sync_policy != SyncPolicy::Never {
+            if let Err(err) = self.sync_file() {
+                log::error!("Error synchronizing headers to disk: {}", err);
+            }
+        }
+    }
 }
 
 impl<H> File<H> {
@@ -83,7 +141,14 @@ impl<H> File<H> {
             .read(true)
             .append(true)
             .open(path)
-            .map(|file| Self { file, genesis })
+            .map(|file| Self {
+                file,
+                genesis,
+                buffer: Vec::with_capacity(BUFFER_SIZE),
+                sync_policy: SyncPolicy::default(),
+                unsynced: false,
+                last_sync: None,
+            })
     }
 
     /// Create a new file store at the given path, with the provided genesis header.
@@ -94,7 +159,14 @@ impl<H> File<H> {
             .append(true)
             .open(path)?;
 
-        Ok(Self { file, genesis })
+        Ok(Self {
+            file,
+            genesis,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            sync_policy: SyncPolicy::default(),
+            unsynced: false,
+            last_sync: None,
+        })
     }
 }
 
@@ -106,15 +178,36 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
         self.genesis
     }
 
-    /// Append a block to the end of the file.
+    /// Append a block to the end of the file. Blocks are buffered until the store is
+    /// flushed, or the buffer is full.
     fn put<I: Iterator<Item = Self::Header>>(&mut self, headers: I) -> Result<Height, Error> {
-        self::put(&mut self.file, headers)
+        for header in headers {
+            header.consensus_encode(&mut self.buffer)?;
+        }
+        let height = self.height()?;
+
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(height)
     }
 
     /// Get the block at the given height. Returns `io::ErrorKind::UnexpectedEof` if
     /// the height is not found.
     fn get(&self, height: Height) -> Result<H, Error> {
         if let Some(ix) = height.checked_sub(1) {
+            let len = self.file_len()?;
+
+            if ix >= len {
+                let size = mem::size_of::<H>();
+                let start = (ix - len) as usize * size;
+                let bytes = self
+                    .buffer
+                    .get(start..start + size)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+                return H::consensus_decode(bytes).map_err(Error::from);
+            }
             // Clone so this function doesn't have to take a `&mut self`.
             let mut file = self.file.try_clone()?;
             get(&mut file, ix)
@@ -125,28 +218,79 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 
     /// Rollback the chain to the given height. Behavior is undefined if the given
     /// height is not contained in the store.
+    ///
+    /// If the rolled back headers are all buffered, the file isn't touched.
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
         let size = mem::size_of::<H>();
+        let len = self.file_len()?;
+
+        if height >= len {
+            self.buffer.truncate((height - len) as usize * size);
 
-        self.file
-            .set_len((height) * size as u64)
-            .map_err(Error::from)
+            return Ok(());
+        }
+        self.buffer.clear();
+        self.file.set_len((height) * size as u64)?;
+        self.unsynced = true;
+
+        Ok(())
+    }
+
+    /// Write buffered blocks to the file, and synchronize them to disk according to the
+    /// store's sync policy.
+    fn flush(&mut self) -> Result<(), Error> {
+        self.write_buffer()?;
+
+        match self.sync_policy {
+            SyncPolicy::Batch => self.sync_file(),
+            SyncPolicy::Periodic(interval) => match self.last_sync {
+                Some(time) if time.elapsed() < interval => Ok(()),
+                _ => self.sync_file(),
+            },
+            SyncPolicy::Never => Ok(()),
+        }
     }
 
-    /// Flush changes to disk.
+    /// Flush changes to disk, regardless of the sync policy.
     fn sync(&mut self) -> Result<(), Error> {
-        self.file.sync_data().map_err(Error::from)
+        self.write_buffer()?;
+        self.sync_file()
     }
 
     /// Iterate over all headers in the store.
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, H), Error>>> {
+        let size = mem::size_of::<H>();
+        let len = match self.file_len() {
+            Ok(len) => len,
+            Err(err) => return Box::new(iter::once(Err(err))),
+        };
+        // Buffered headers are decoded up-front, since the buffer may change while
+        // iterating.
+        let buffered = self
+            .buffer
+            .chunks(size)
+            .zip(len + 1..)
+            .map(|(bytes, height)| {
+                H::consensus_decode(bytes)
+                    .map(|header| (height, header))
+                    .map_err(Error::from)
+            })
+            .collect::<Vec<_>>();
+
         // Clone so this function doesn't have to take a `&mut self`.
         match self.file.try_clone() {
-            Ok(file) => Box::new(iter::once(Ok((0, self.genesis))).chain(Iter {
-                height: 1,
-                file,
-                _phantom: PhantomData,
-            })),
+            Ok(file) => Box::new(
+                iter::once(Ok((0, self.genesis)))
+                    .chain(
+                        Iter {
+                            height: 1,
+                            file,
+                            _phantom: PhantomData,
+                        }
+                        .take(len as usize),
+                    )
+                    .chain(buffered),
+            ),
             Err(err) => Box::new(iter::once(Err(Error::Io(err)))),
         }
     }
@@ -162,7 +306,7 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
         if len as usize % size != 0 {
             return Err(Error::Corruption);
         }
-        Ok(len as usize / size + 1)
+        Ok((len as usize + self.buffer.len()) / size + 1)
     }
 
     /// Return the block height of the store.
@@ -196,7 +340,7 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
 mod test {
     use std::{io, iter};
 
-    use super::{Error, File, Height, Store};
+    use super::{Error, File, Height, Store, SyncPolicy};
     use crate::block::BlockHeader;
 
     const HEADER_SIZE: usize = 80;
@@ -311,6 +455,92 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_buffered() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("headers.db");
+        let genesis = store("headers.db").genesis;
+        let mut store = File::open(&path, genesis).unwrap();
+        let headers = (0..8)
+            .map(|i| BlockHeader {
+                nonce: i,
+                ..genesis
+            })
+            .collect::<Vec<_>>();
+
+        store.put(headers[..4].iter().cloned()).unwrap();
+        store.flush().unwrap();
+        store.put(headers[4..].iter().cloned()).unwrap();
+
+        assert_eq!(store.height().unwrap(), 8);
+        assert_eq!(
+            store.file_len().unwrap(),
+            4,
+            "headers are buffered until flushed"
+        );
+        assert_eq!(store.get(3).unwrap(), headers[2]);
+        assert_eq!(store.get(6).unwrap(), headers[5]);
+        assert!(store.get(9).is_err());
+        assert_eq!(
+            store.iter().map(|r| r.unwrap().1).collect::<Vec<_>>(),
+            std::iter::once(genesis)
+                .chain(headers.iter().cloned())
+                .collect::<Vec<_>>()
+        );
+
+        // Rolling back buffered headers doesn't touch the file.
+        store.rollback(6).unwrap();
+        assert_eq!(store.height().unwrap(), 6);
+        assert_eq!(store.file_len().unwrap(), 4);
+
+        // Rolling back further truncates the file.
+        store.rollback(2).unwrap();
+        assert_eq!(store.height().unwrap(), 2);
+        assert_eq!(store.file_len().unwrap(), 2);
+
+        store.put(headers[4..].iter().cloned()).unwrap();
+        drop(store);
+
+        // Buffered headers are written out when the store is dropped.
+        let store = File::open(&path, genesis).unwrap();
+        assert_eq!(store.height().unwrap(), 6);
+        assert_eq!(store.get(3).unwrap(), headers[4]);
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let genesis = store("headers.db").genesis;
+        let header = BlockHeader {
+            nonce: 1,
+            ..genesis
+        };
+
+        let mut file = store("headers.db").with_sync_policy(SyncPolicy::Batch);
+        file.put(iter::once(header)).unwrap();
+        file.flush().unwrap();
+        assert!(!file.unsynced, "flushed batches are synchronized");
+
+        let mut file = store("headers.db").with_sync_policy(SyncPolicy::Never);
+        file.put(iter::once(header)).unwrap();
+        file.flush().unwrap();
+        assert!(file.unsynced);
+        file.sync().unwrap();
+        assert!(!file.unsynced, "explicit syncs ignore the policy");
+
+        let interval = std::time::Duration::from_secs(60);
+        let mut file = store("headers.db").with_sync_policy(SyncPolicy::Periodic(interval));
+        file.put(iter::once(header)).unwrap();
+        file.flush().unwrap();
+        assert!(!file.unsynced, "the first flush is synchronized");
+        file.put(iter::once(header)).unwrap();
+        file.flush().unwrap();
+        assert!(
+            file.unsynced,
+            "flushes within the interval aren't synchronized"
+        );
+        assert_eq!(file.file_len().unwrap(), 2);
+    }
+
     #[test]
     fn test_iter() {
         let mut store = store("headers.db");
@@ -366,6 +596,7 @@ mod test {
             },
         ];
         store.put(headers.iter().cloned()).unwrap();
+        store.flush().unwrap();
         store.check().unwrap();
 
         assert_eq!(store.len().unwrap(), 3);
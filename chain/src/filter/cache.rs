@@ -109,7 +109,7 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
         self.headers
             .iter()
             .skip(range.start as usize)
-            .take(range.end as usize - range.start as usize)
+            .take((range.end as usize).saturating_sub(range.start as usize))
             .map(|h| (h.hash, h.header))
             .collect()
     }
@@ -123,7 +123,11 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
             .map(|(hash, header)| StoredHeader { hash, header });
 
         self.headers.tail.extend(iter.clone());
-        self.header_store.put(iter).map_err(Error::from)
+
+        let height = self.header_store.put(iter)?;
+        self.header_store.flush()?;
+
+        Ok(height)
     }
 
     fn tip(&self) -> (&FilterHash, &FilterHeader) {
@@ -144,4 +148,8 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
 
         Ok(())
     }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.header_store.sync().map_err(Error::from)
+    }
 }
@@ -0,0 +1,347 @@
+//! Client builder.
+//!
+//! Builds a client [`Config`], applying network-specific defaults, and validates it
+//! before anything is started, eg.
+//!
+//! ```no_run
+//! use std::net;
+//! use nakamoto_client::builder::ClientBuilder;
+//! use nakamoto_client::client::Network;
+//!
+//! let client = ClientBuilder::new(Network::Testnet)
+//!     .home("/var/lib/nakamoto")
+//!     .connect_only(vec![([127, 0, 0, 1], 18333).into()])
+//!     .build::<nakamoto_net_poll::Reactor<net::TcpStream>>()
+//!     .unwrap();
+//! ```
+use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time;
+
+use thiserror::Error;
+
+use nakamoto_chain::block::store::SyncPolicy;
+use nakamoto_p2p::bitcoin::network::constants::ServiceFlags;
+use nakamoto_p2p::protocol::interceptor::Interceptor;
+use nakamoto_p2p::protocol::{addrmgr, connmgr, memory};
+
+use crate::client::{Client, Config, Network, Reactor};
+
+/// An invalid client configuration.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The client must only connect to the given peers, but none were given.
+    #[error("the client is set to connect to specific peers only, but none were given")]
+    NoPeers,
+    /// More outbound peers offering compact filters are required than outbound peers.
+    #[error("{filter} filter peers are required, but only {target} outbound peers are targeted")]
+    FilterPeers {
+        /// Required filter peers.
+        filter: usize,
+        /// Target outbound peers.
+        target: usize,
+    },
+    /// The home directory is not set, eg. because `HOME` isn't set.
+    #[error("the client home directory is not set")]
+    NoHome,
+    /// The command timeout is zero.
+    #[error("the command timeout must not be zero")]
+    Timeout,
+    /// The application name or version given for the user agent is invalid.
+    #[error("invalid user agent {0:?}, expected `name:version`")]
+    UserAgent(String),
+    /// A file to load from doesn't exist.
+    #[error("file {0:?} not found")]
+    FileNotFound(PathBuf),
+}
+
+/// Builds a validated client configuration.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    config: Config,
+}
+
+impl From<Config> for ClientBuilder {
+    fn from(config: Config) -> Self {
+        Self { config }
+    }
+}
+
+impl ClientBuilder {
+    /// Create a builder for the given network, with that network's defaults.
+    ///
+    /// Since regtest has no DNS seeds, regtest clients connect to a local node on the
+    /// network's default port, unless other peers are given.
+    pub fn new(network: Network) -> Self {
+        let mut config = Config {
+            network,
+            ..Config::default()
+        };
+        if let Network::Regtest = network {
+            config.connect = vec![([127, 0, 0, 1], network.port()).into()];
+            config.connect_only = true;
+        }
+        Self { config }
+    }
+
+    /// Set the directory under which runtime data is stored.
+    pub fn home<P: AsRef<Path>>(mut self, home: P) -> Self {
+        self.config.home = home.as_ref().to_path_buf();
+        self
+    }
+
+    /// Set the addresses to listen on for inbound connections.
+    pub fn listen(mut self, addrs: Vec<net::SocketAddr>) -> Self {
+        self.config.listen = addrs;
+        self
+    }
+
+    /// Connect to the given peers, in addition to the ones discovered.
+    pub fn connect(mut self, peers: Vec<net::SocketAddr>) -> Self {
+        self.config.connect = peers;
+        self.config.connect_only = false;
+        self
+    }
+
+    /// Connect to the given peers only, eg. to one's own full node. Address discovery
+    /// and inbound connections are disabled.
+    pub fn connect_only(mut self, peers: Vec<net::SocketAddr>) -> Self {
+        self.config.connect = peers;
+        self.config.connect_only = true;
+        self
+    }
+
+    /// Trust the given peer addresses, eg. one's own full node. Whitelisted peers are
+    /// never banned or evicted, are exempt from rate limits, and are preferred for syncing
+    /// over other peers at the same height.
+    pub fn whitelist(mut self, addrs: Vec<net::IpAddr>) -> Self {
+        self.config.whitelist = addrs;
+        self
+    }
+
+    /// Set the timeout of client commands.
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Set the target number of outbound peers.
+    pub fn target_outbound_peers(mut self, n: usize) -> Self {
+        self.config.target_outbound_peers = n;
+        self
+    }
+
+    /// Set the maximum number of inbound peers.
+    pub fn max_inbound_peers(mut self, n: usize) -> Self {
+        self.config.max_inbound_peers = n;
+        self
+    }
+
+    /// Set the target number of outbound block-relay-only peers.
+    pub fn block_relay_peers(mut self, n: usize) -> Self {
+        self.config.block_relay_peers = n;
+        self
+    }
+
+    /// Set the minimum number of outbound peers offering compact filters. Set to zero if
+    /// compact filters aren't needed.
+    pub fn filter_peers(mut self, n: usize) -> Self {
+        self.config.filter_peers = n;
+        self
+    }
+
+    /// Periodically rotate outbound peers, for privacy.
+    pub fn peer_rotation(mut self, rotation: connmgr::Rotation) -> Self {
+        self.config.peer_rotation = Some(rotation);
+        self
+    }
+
+    /// Set whether and what to advertise as our address to peers.
+    pub fn advertise(mut self, advertise: addrmgr::Advertise) -> Self {
+        self.config.advertise = advertise;
+        self
+    }
+
+    /// Seed the address book from a Bitcoin Core `peers.dat` file, if it is empty.
+    pub fn import_peers<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config.import_peers = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Diversify outbound peers using the given AS map file.
+    pub fn asmap<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config.asmap = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Record protocol outputs to the given file.
+    pub fn journal<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config.journal = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Record protocol inputs to the given file, for replay.
+    pub fn record<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.config.recording = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Pass all peer messages through the given interceptor, which may modify or drop them.
+    pub fn interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.config.interceptor = Some(interceptor);
+        self
+    }
+
+    /// Cap the memory used by the client's caches.
+    pub fn memory_limits(mut self, limits: memory::Limits) -> Self {
+        self.config.memory_limits = limits;
+        self
+    }
+
+    /// Set when the header stores are synchronized to disk. Relaxing the default
+    /// speeds up initial sync on slow disks, at the cost of durability.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.config.sync_policy = policy;
+        self
+    }
+
+    /// Set the services offered by the client.
+    pub fn services(mut self, services: ServiceFlags) -> Self {
+        self.config.services = services;
+        self
+    }
+
+    /// Append the given application name and version to the user agent advertised to
+    /// peers, as described in BIP 14, eg. `/nakamoto:0.1.0/wallet:1.0/`.
+    pub fn user_agent(mut self, name: &str, version: &str) -> Self {
+        self.config.user_agent = Some(format!("{}:{}", name, version));
+        self
+    }
+
+    /// Seed the protocol's random number generator, so that peer selection and other
+    /// random choices can be reproduced.
+    pub fn rng_seed(mut self, seed: u64) -> Self {
+        self.config.rng_seed = Some(seed);
+        self
+    }
+
+    /// Set the client name, used for logging.
+    pub fn name(mut self, name: &'static str) -> Self {
+        self.config.name = name;
+        self
+    }
+
+    /// Validate the configuration, and return it.
+    pub fn config(self) -> Result<Config, Error> {
+        let cfg = self.config;
+
+        if cfg.connect_only && cfg.connect.is_empty() {
+            return Err(Error::NoPeers);
+        }
+        if !cfg.connect_only && cfg.filter_peers > cfg.target_outbound_peers {
+            return Err(Error::FilterPeers {
+                filter: cfg.filter_peers,
+                target: cfg.target_outbound_peers,
+            });
+        }
+        if cfg.home.as_os_str().is_empty() {
+            return Err(Error::NoHome);
+        }
+        if cfg.timeout == time::Duration::from_secs(0) {
+            return Err(Error::Timeout);
+        }
+        if let Some(app) = &cfg.user_agent {
+            let mut parts = app.split(':');
+            let valid = |s: Option<&str>| {
+                s.map_or(false, |s| {
+                    !s.is_empty() && !s.contains(|c| matches!(c, '/' | ':' | '(' | ')'))
+                })
+            };
+            if !valid(parts.next()) || !valid(parts.next()) || parts.next().is_some() {
+                return Err(Error::UserAgent(app.clone()));
+            }
+        }
+        for path in cfg.import_peers.iter().chain(cfg.asmap.iter()) {
+            if !path.exists() {
+                return Err(Error::FileNotFound(path.clone()));
+            }
+        }
+        Ok(cfg)
+    }
+
+    /// Validate the configuration, and create the client.
+    pub fn build<R: Reactor>(self) -> Result<Client<R>, crate::error::Error> {
+        Client::new(self.config()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_network_defaults() {
+        let cfg = ClientBuilder::new(Network::Regtest)
+            .home("/tmp")
+            .config()
+            .unwrap();
+
+        assert!(cfg.connect_only);
+        assert_eq!(cfg.connect, vec![([127, 0, 0, 1], 18334).into()]);
+
+        let cfg = ClientBuilder::new(Network::Testnet)
+            .home("/tmp")
+            .config()
+            .unwrap();
+
+        assert!(!cfg.connect_only);
+        assert!(cfg.connect.is_empty());
+    }
+
+    #[test]
+    fn test_validation() {
+        let builder = ClientBuilder::new(Network::Mainnet).home("/tmp");
+
+        assert!(matches!(
+            builder.clone().connect_only(vec![]).config(),
+            Err(Error::NoPeers)
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .target_outbound_peers(2)
+                .filter_peers(3)
+                .config(),
+            Err(Error::FilterPeers {
+                filter: 3,
+                target: 2
+            })
+        ));
+        assert!(matches!(
+            builder.clone().home("").config(),
+            Err(Error::NoHome)
+        ));
+        assert!(matches!(
+            builder
+                .clone()
+                .timeout(time::Duration::from_secs(0))
+                .config(),
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(
+            builder.clone().asmap("/nonexistent/asmap").config(),
+            Err(Error::FileNotFound(_))
+        ));
+        assert!(matches!(
+            builder.clone().user_agent("my/wallet", "1.0").config(),
+            Err(Error::UserAgent(_))
+        ));
+        assert!(matches!(
+            builder.clone().user_agent("wallet", "").config(),
+            Err(Error::UserAgent(_))
+        ));
+        assert!(builder.clone().user_agent("wallet", "1.0").config().is_ok());
+        assert!(builder.config().is_ok());
+    }
+}
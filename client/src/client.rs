@@ -6,7 +6,7 @@ use std::fs;
 use std::io;
 use std::net;
 use std::ops::Range;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{self, SystemTime};
 
@@ -17,26 +17,33 @@ use nakamoto_chain::block::store;
 use nakamoto_chain::filter;
 use nakamoto_chain::filter::cache::FilterCache;
 
-use nakamoto_common::block::filter::{BlockFilter, Filters};
+use nakamoto_common::block::filter::{BlockFilter, FilterHash, FilterHeader, Filters};
 use nakamoto_common::block::store::{Genesis as _, Store as _};
-use nakamoto_common::block::time::AdjustedTime;
+use nakamoto_common::block::time::{AdjustedTime, LocalDuration, TimeOffset};
 use nakamoto_common::block::tree::{self, BlockTree, ImportResult};
 use nakamoto_common::block::{Block, BlockHash, BlockHeader, Height, Transaction};
-use nakamoto_common::p2p::peer::{Source, Store as _};
+use nakamoto_common::p2p::netgroup::AsMap;
+use nakamoto_common::p2p::peer::{Ban, Source, Store as _};
 
 pub use nakamoto_common::network::Network;
 
 use nakamoto_p2p as p2p;
 use nakamoto_p2p::bitcoin::network::constants::ServiceFlags;
 use nakamoto_p2p::bitcoin::network::message::NetworkMessage;
+use nakamoto_p2p::bitcoin::Script;
+use nakamoto_p2p::protocol::interceptor::Interceptor;
+use nakamoto_p2p::protocol::state::SharedChainState;
 use nakamoto_p2p::protocol::Command;
+use nakamoto_p2p::protocol::DisconnectReason;
 use nakamoto_p2p::protocol::Link;
-use nakamoto_p2p::protocol::{connmgr, peermgr, spvmgr, syncmgr};
+use nakamoto_p2p::protocol::Whitelist;
+use nakamoto_p2p::protocol::{addrmgr, connmgr, memory, peermgr, spvmgr, stats, syncmgr};
 
 pub use nakamoto_p2p::event::Event;
 pub use nakamoto_p2p::reactor::Reactor;
 
 use crate::error::Error;
+use crate::event::{ClientEvent, ClientListener, Publisher, SyncState};
 use crate::handle;
 use crate::peer;
 
@@ -49,18 +56,58 @@ pub struct Config {
     pub network: Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Only connect to the peers in `connect`, eg. to connect to one's own full node.
+    /// Address discovery and inbound connections are disabled in this mode.
+    pub connect_only: bool,
+    /// Trusted peer addresses, eg. of one's own full node. Whitelisted peers are never
+    /// banned or evicted, are exempt from rate limits, and are preferred for syncing
+    /// over other peers at the same height.
+    pub whitelist: Vec<net::IpAddr>,
     /// Target number of outbound peers to connect to.
     pub target_outbound_peers: usize,
     /// Maximum number of inbound peers supported.
     pub max_inbound_peers: usize,
+    /// Target number of outbound block-relay-only peers to connect to.
+    pub block_relay_peers: usize,
+    /// Minimum number of outbound peers offering compact filters to connect to.
+    /// Set to zero if compact filters aren't needed.
+    pub filter_peers: usize,
+    /// Periodically rotate outbound peers, for privacy. Disabled if `None`.
+    pub peer_rotation: Option<connmgr::Rotation>,
+    /// Whether and what to advertise as our address to peers.
+    pub advertise: addrmgr::Advertise,
     /// Timeout duration for client commands.
     pub timeout: time::Duration,
     /// Client home path, where runtime data is stored, eg. block headers and filters.
     pub home: PathBuf,
+    /// Bitcoin Core `peers.dat` file to seed the address book from, if it is empty.
+    pub import_peers: Option<PathBuf>,
+    /// File mapping IP ranges to AS numbers, with one `<prefix> <asn>` entry per line,
+    /// eg. `203.0.113.0/24 AS64496`. Used to diversify outbound peers across network
+    /// operators. If not set, peers are diversified by address range.
+    pub asmap: Option<PathBuf>,
+    /// File to record protocol outputs to, as JSON lines, for post-mortem analysis.
+    /// Disabled if `None`.
+    pub journal: Option<PathBuf>,
+    /// File to record protocol inputs to, as JSON lines, so that a run can be replayed
+    /// with the same RNG seed. Disabled if `None`.
+    pub recording: Option<PathBuf>,
+    /// Interceptor of peer messages, eg. to enforce a custom relay policy.
+    pub interceptor: Option<Arc<dyn Interceptor>>,
+    /// Memory caps of the client's caches. Unbounded by default.
+    pub memory_limits: memory::Limits,
+    /// When the header and filter header stores are synchronized to disk.
+    pub sync_policy: store::SyncPolicy,
     /// Client name. Used for logging only.
     pub name: &'static str,
+    /// Application name and version, appended to our user agent as described in BIP 14,
+    /// eg. `wallet:1.0`.
+    pub user_agent: Option<String>,
     /// Services offered by this node.
     pub services: ServiceFlags,
+    /// Seed of the protocol's random number generator. If set, peer selection and other
+    /// random choices are reproducible. Otherwise, a random seed is used, and logged.
+    pub rng_seed: Option<u64>,
 }
 
 impl Config {
@@ -78,33 +125,104 @@ impl Config {
 
         Ok(())
     }
+
+    /// Create the protocol's random number generator.
+    fn rng(&self) -> fastrand::Rng {
+        let seed = self.rng_seed.unwrap_or_else(|| fastrand::u64(..));
+        log::info!("Random seed is {}", seed);
+
+        fastrand::Rng::with_seed(seed)
+    }
+
+    /// Get the user agent advertised to peers.
+    fn user_agent(&self) -> String {
+        match &self.user_agent {
+            Some(app) => format!("{}{}/", p2p::protocol::USER_AGENT, app),
+            None => p2p::protocol::USER_AGENT.to_owned(),
+        }
+    }
 }
 
 impl From<Config> for p2p::protocol::Config {
     fn from(cfg: Config) -> Self {
+        let user_agent = cfg.user_agent();
+        let asmap = cfg.asmap.as_deref().and_then(self::load_asmap);
+
         Self {
             network: cfg.network,
+            params: cfg.network.params(),
+            user_agent,
             target: cfg.name,
             connect: cfg.connect,
+            connect_only: cfg.connect_only,
+            whitelist: Whitelist::new(cfg.whitelist),
             target_outbound_peers: cfg.target_outbound_peers,
             max_inbound_peers: cfg.max_inbound_peers,
+            block_relay_peers: cfg.block_relay_peers,
+            filter_peers: cfg.filter_peers,
+            asmap,
+            peer_rotation: cfg.peer_rotation,
+            // We don't accept inbound connections in connect-only mode.
+            advertise: if cfg.connect_only {
+                addrmgr::Advertise::Never
+            } else {
+                cfg.advertise
+            },
+            services: cfg.services,
+            journal: cfg.journal,
+            recording: cfg.recording,
+            interceptor: cfg.interceptor,
+            memory_limits: cfg.memory_limits,
             ..Self::default()
         }
     }
 }
 
+/// Load an AS map from a file. Errors are logged, and no map is returned.
+fn load_asmap(path: &Path) -> Option<AsMap> {
+    let result = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| s.parse::<AsMap>().map_err(|e| e.to_string()));
+
+    match result {
+        Ok(asmap) => {
+            log::info!("AS map loaded from {:?}", path);
+            Some(asmap)
+        }
+        Err(err) => {
+            log::warn!("Error loading AS map from {:?}: {}", path, err);
+            None
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             listen: vec![([0, 0, 0, 0], 0).into()],
             network: Network::default(),
             connect: Vec::new(),
+            connect_only: false,
+            whitelist: Vec::new(),
             timeout: time::Duration::from_secs(60),
             home: PathBuf::from(env::var("HOME").unwrap_or_default()),
+            import_peers: None,
+            asmap: None,
             target_outbound_peers: p2p::protocol::connmgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: p2p::protocol::connmgr::MAX_INBOUND_PEERS,
+            block_relay_peers: p2p::protocol::connmgr::BLOCK_RELAY_PEERS,
+            filter_peers: p2p::protocol::connmgr::FILTER_PEERS,
+            peer_rotation: None,
+            advertise: addrmgr::Advertise::Never,
             services: ServiceFlags::NONE,
+            journal: None,
+            recording: None,
+            interceptor: None,
+            memory_limits: memory::Limits::default(),
+            sync_policy: store::SyncPolicy::default(),
             name: "self",
+            user_agent: None,
+            rng_seed: None,
         }
     }
 }
@@ -124,15 +242,13 @@ impl BlockSubscribers {
         self.subs.entry(hash).or_default().push(channel);
     }
 
-    fn input(&self, block: Block, height: Height) {
-        let hash = block.block_hash();
-
-        for (h, subs) in self.subs.iter() {
-            if *h == hash {
-                for sub in subs {
-                    // TODO: Can we avoid the extra clone here? Eg. if there's only one sub.
-                    sub.send((block.clone(), height)).unwrap();
-                }
+    /// Send a block to its subscribers. Each subscription is for a single delivery, and is
+    /// removed once the block is received.
+    fn input(&mut self, block: Block, height: Height) {
+        if let Some(subs) = self.subs.remove(&block.block_hash()) {
+            for sub in subs {
+                // TODO: Can we avoid the extra clone here? Eg. if there's only one sub.
+                sub.send((block.clone(), height)).ok();
             }
         }
     }
@@ -157,14 +273,29 @@ impl FilterSubscribers {
         self.subs.entry(range).or_default().push(channel);
     }
 
-    fn input(&self, filter: BlockFilter, block_hash: BlockHash, height: Height) {
-        for (range, subs) in self.subs.iter() {
+    fn unsubscribe(
+        &mut self,
+        range: &Range<Height>,
+        channel: &chan::Sender<(BlockFilter, BlockHash, Height)>,
+    ) {
+        if let Some(subs) = self.subs.get_mut(range) {
+            subs.retain(|sub| !sub.same_channel(channel));
+
+            if subs.is_empty() {
+                self.subs.remove(range);
+            }
+        }
+    }
+
+    /// Send a filter to the subscribers of ranges containing its height. Subscribers that
+    /// have gone away are removed.
+    fn input(&mut self, filter: BlockFilter, block_hash: BlockHash, height: Height) {
+        for (range, subs) in self.subs.iter_mut() {
             if range.contains(&height) {
-                for sub in subs {
-                    sub.send((filter.clone(), block_hash, height)).unwrap();
-                }
+                subs.retain(|sub| sub.send((filter.clone(), block_hash, height)).is_ok());
             }
         }
+        self.subs.retain(|_, subs| !subs.is_empty());
     }
 }
 
@@ -179,6 +310,13 @@ pub struct Client<R> {
 
     blocks: Arc<Mutex<BlockSubscribers>>,
     filters: Arc<Mutex<FilterSubscribers>>,
+    publisher: Arc<Mutex<Publisher>>,
+    /// Chain state published by the protocol.
+    chain_state: SharedChainState,
+
+    /// Dropped when the client stops, which lets handles know that it has stopped.
+    _stopped: chan::Sender<()>,
+    stopped: chan::Receiver<()>,
 }
 
 impl<R: Reactor> Client<R> {
@@ -189,6 +327,9 @@ impl<R: Reactor> Client<R> {
         let reactor = R::new(subscriber, commands)?;
         let blocks = Arc::new(Mutex::new(BlockSubscribers::new()));
         let filters = Arc::new(Mutex::new(FilterSubscribers::new()));
+        let publisher = Arc::new(Mutex::new(Publisher::default()));
+        let chain_state = SharedChainState::default();
+        let (_stopped, stopped) = chan::bounded(0);
 
         Ok(Self {
             events,
@@ -197,6 +338,10 @@ impl<R: Reactor> Client<R> {
             config,
             blocks,
             filters,
+            publisher,
+            chain_state,
+            _stopped,
+            stopped,
         })
     }
 
@@ -210,34 +355,27 @@ impl<R: Reactor> Client<R> {
     }
 
     /// Start the client process. This function is meant to be run in its own thread.
-    pub fn run(mut self) -> Result<(), Error> {
-        let home = self.config.home.join(".nakamoto");
-        let dir = home.join(self.config.network.as_str());
-        let listen = self.config.listen.clone();
+    pub fn run(self) -> Result<(), Error> {
+        let dir = self.dir();
 
         fs::create_dir_all(&dir)?;
 
         let genesis = self.config.network.genesis();
         let params = self.config.network.params();
 
-        log::info!("Initializing client ({:?})..", self.config.network);
-        log::info!(
-            "Genesis block hash is {}",
-            self.config.network.genesis_hash()
-        );
-
         let path = dir.join("headers.db");
         let store = match store::File::create(&path, genesis) {
             Err(store::Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists => {
                 log::info!("Found existing store {:?}", path);
                 store::File::open(path, genesis)?
             }
-            Err(err) => panic!(err.to_string()),
+            Err(err) => return Err(err.into()),
             Ok(store) => {
                 log::info!("Initializing new block store {:?}", path);
                 store
             }
-        };
+        }
+        .with_sync_policy(self.config.sync_policy);
         if store.check().is_err() {
             log::warn!("Corruption detected in header store, healing..");
             store.heal()?; // Rollback store to the last valid header.
@@ -245,11 +383,8 @@ impl<R: Reactor> Client<R> {
         log::info!("Store height = {}", store.height()?);
         log::info!("Loading block headers from store..");
 
-        let local_time = SystemTime::now().into();
         let checkpoints = self.config.network.checkpoints().collect::<Vec<_>>();
-        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
         let cache = BlockCache::from(store, params, &checkpoints)?;
-        let rng = fastrand::Rng::new();
 
         log::info!("Initializing block filters..");
 
@@ -260,12 +395,13 @@ impl<R: Reactor> Client<R> {
                 log::info!("Found existing store {:?}", cfheaders_path);
                 store::File::open(cfheaders_path, cfheaders_genesis)?
             }
-            Err(err) => panic!(err.to_string()), // TODO
+            Err(err) => return Err(err.into()),
             Ok(store) => {
                 log::info!("Initializing new filter header store {:?}", cfheaders_path);
                 store
             }
-        };
+        }
+        .with_sync_policy(self.config.sync_policy);
         if cfheaders_store.check().is_err() {
             log::warn!("Corruption detected in filter store, healing..");
             cfheaders_store.heal()?; // Rollback store to the last valid header.
@@ -285,10 +421,7 @@ impl<R: Reactor> Client<R> {
         let mut peers = match peer::Cache::create(&peers_path) {
             Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
                 log::info!("Found existing peer cache {:?}", peers_path);
-                let cache = peer::Cache::open(&peers_path).map_err(Error::PeerStore)?;
-                log::info!("{} peer(s) found..", cache.len());
-
-                cache
+                peer::Cache::open(&peers_path).map_err(Error::PeerStore)?
             }
             Err(err) => {
                 return Err(Error::PeerStore(err));
@@ -301,6 +434,33 @@ impl<R: Reactor> Client<R> {
 
         log::trace!("{:#?}", peers);
 
+        if let (true, Some(path)) = (peers.is_empty(), &self.config.import_peers) {
+            log::info!(
+                "Address book is empty. Importing addresses from {:?}..",
+                path
+            );
+
+            match peer::peers_dat::read(path, self.config.network) {
+                Ok(entries) => {
+                    let (ip, overlay): (Vec<_>, Vec<_>) =
+                        entries.into_iter().partition(|e| e.addr.is_ip());
+                    let count = peer::peers_dat::import(&mut peers, ip);
+                    let overlay = overlay
+                        .into_iter()
+                        .filter(|e| peers.insert_overlay(e.to_known_address()))
+                        .count();
+                    peers.flush()?;
+
+                    log::info!(
+                        "{} address(es) imported to address book, {} overlay address(es) kept",
+                        count,
+                        overlay
+                    );
+                }
+                Err(err) => log::warn!("Error importing addresses from {:?}: {}", path, err),
+            }
+        }
+
         if self.config.connect.is_empty() && peers.is_empty() {
             log::info!("Address book is empty. Trying DNS seeds..");
             peers.seed(
@@ -316,62 +476,57 @@ impl<R: Reactor> Client<R> {
             log::info!("{} seeds added to address book", peers.len());
         }
 
-        let cfg = p2p::protocol::Config {
-            network: self.config.network,
-            params: self.config.network.params(),
-            target: self.config.name,
-            connect: self.config.connect,
-            target_outbound_peers: self.config.target_outbound_peers,
-            max_inbound_peers: self.config.max_inbound_peers,
-            services: self.config.services,
-            ..p2p::protocol::Config::default()
-        };
-        let builder = p2p::protocol::Builder {
-            cache,
-            clock,
-            filters,
-            peers,
-            rng,
-            cfg,
-        };
-
-        self.reactor.run(builder, &listen, {
-            let blocks = self.blocks;
-            let filters = self.filters;
-
-            move |event| Self::process_event(event, blocks.clone(), filters.clone())
-        })?;
-
-        Ok(())
+        self.run_with(cache, filters, peers)
     }
 
     /// Start the client process, supplying the block cache. This function is meant to be run in
     /// its own thread.
+    ///
+    /// Anchors and bans are loaded from, and saved to the client's home directory.
     pub fn run_with<T: BlockTree, F: Filters, P: peer::Store>(
         mut self,
         cache: T,
         filters: F,
         peers: P,
     ) -> Result<(), Error> {
+        let dir = self.dir();
+
+        fs::create_dir_all(&dir)?;
+
+        let anchors = peer::Anchors::open(dir.join("anchors.json")).map_err(Error::PeerStore)?;
+        let bans = peer::BanList::open(dir.join("bans.json")).map_err(Error::PeerStore)?;
+        let connect_only = self.config.connect_only;
+        // Don't listen for inbound connections if we're not going to accept them.
+        let listen = if connect_only {
+            vec![]
+        } else {
+            self.config.listen.clone()
+        };
+        let rng = self.config.rng();
         let cfg = p2p::protocol::Config {
-            services: self.config.services,
-            ..p2p::protocol::Config::from(
-                self.config.name,
-                self.config.network,
-                self.config.connect,
-            )
+            anchors: if connect_only {
+                vec![]
+            } else {
+                anchors.addrs().to_vec()
+            },
+            bans: bans.iter().map(|(ip, ban)| (*ip, ban.clone())).collect(),
+            chain_state: self.chain_state.clone(),
+            ..self.config.clone().into()
         };
 
         log::info!("Initializing client ({:?})..", cfg.network);
         log::info!("Genesis block hash is {}", cfg.network.genesis_hash());
         log::info!("Chain height is {}", cache.height());
+        log::info!("{} peer(s) found..", peers.len());
+
+        if connect_only {
+            log::info!("Connecting only to {} peer(s)..", cfg.connect.len());
+        } else {
+            log::info!("{} anchor(s) found..", cfg.anchors.len());
+        }
 
         let local_time = SystemTime::now().into();
         let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
-        let rng = fastrand::Rng::new();
-
-        log::info!("{} peer(s) found..", peers.len());
-
         let builder = p2p::protocol::Builder {
             cache,
             clock,
@@ -381,14 +536,32 @@ impl<R: Reactor> Client<R> {
             cfg,
         };
 
-        self.reactor.run(builder, &self.config.listen, {
+        let waker = self.reactor.waker();
+        let result = self.reactor.run(builder, &listen, {
             let blocks = self.blocks;
             let filters = self.filters;
+            let publisher = self.publisher;
+            let commands = self.handle;
+            let anchors = Mutex::new(anchors);
+            let bans = Mutex::new(bans);
 
-            move |event| Self::process_event(event, blocks.clone(), filters.clone())
-        })?;
+            move |event| {
+                Self::update_anchors(&event, &anchors);
+                Self::update_bans(&event, &bans);
 
-        Ok(())
+                let published = publisher.lock().unwrap().publish(&event);
+
+                Self::fetch_matched_blocks(&published, &commands, &waker);
+                Self::process_event(event, blocks.clone(), filters.clone())
+            }
+        });
+
+        // Only let handles know that we've stopped once the reactor, along with the
+        // protocol and its stores, is gone, so that nothing is still being written to disk.
+        drop(self.reactor);
+        drop(self._stopped);
+
+        result.map_err(Error::from)
     }
 
     /// Create a new handle to communicate with the client.
@@ -400,11 +573,97 @@ impl<R: Reactor> Client<R> {
             timeout: self.config.timeout,
             blocks: self.blocks.clone(),
             filters: self.filters.clone(),
+            publisher: self.publisher.clone(),
+            chain_state: self.chain_state.clone(),
+            stopped: self.stopped.clone(),
         }
     }
 
+    /// Directory where the client's runtime data is stored, for the configured network.
+    fn dir(&self) -> PathBuf {
+        self.config
+            .home
+            .join(".nakamoto")
+            .join(self.config.network.as_str())
+    }
+
+    /// Register a listener, to be called back from the event loop with client events.
+    /// This is an alternative to subscribing to events via a [`Handle`].
+    pub fn add_listener<L: ClientListener + 'static>(&self, listener: L) {
+        self.publisher.lock().unwrap().listen(Box::new(listener));
+    }
+
     ////////////////////////////////////////////////////////////////////////////
 
+    /// Keep track of our block-relay peers, so that we can reconnect to them on restart.
+    fn update_anchors(event: &Event, anchors: &Mutex<peer::Anchors>) {
+        let result = match event {
+            Event::ConnManager(connmgr::Event::BlockRelayNegotiated(addr)) => {
+                anchors.lock().unwrap().insert(*addr)
+            }
+            // Anchors are kept across ordinary disconnects, eg. timeouts or rotations,
+            // so that they can be reconnected to on the next startup. Only peers we
+            // no longer trust are dropped.
+            Event::ConnManager(connmgr::Event::Disconnected(
+                addr,
+                DisconnectReason::PeerMisbehaving(_),
+            )) => anchors.lock().unwrap().remove(addr),
+            Event::ConnManager(connmgr::Event::Banned(ip, _)) => {
+                let mut anchors = anchors.lock().unwrap();
+                let banned = anchors
+                    .addrs()
+                    .iter()
+                    .filter(|a| a.ip() == *ip)
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                banned.iter().try_for_each(|addr| anchors.remove(addr))
+            }
+            _ => Ok(()),
+        };
+
+        if let Err(err) = result {
+            log::error!("Error saving anchors: {}", err);
+        }
+    }
+
+    /// Keep the ban list on disk in sync with the protocol.
+    fn update_bans(event: &Event, bans: &Mutex<peer::BanList>) {
+        let result = match event {
+            Event::ConnManager(connmgr::Event::Banned(ip, ban)) => {
+                bans.lock().unwrap().insert(*ip, ban.clone())
+            }
+            Event::ConnManager(connmgr::Event::Unbanned(ip)) => bans.lock().unwrap().remove(ip),
+            _ => Ok(()),
+        };
+
+        if let Err(err) = result {
+            log::error!("Error saving bans: {}", err);
+        }
+    }
+
+    /// Fetch the blocks whose filters matched the watched scripts. Each block is requested
+    /// once, on behalf of all subscribers: the transactions it contains that pay to watched
+    /// scripts are published when it is received.
+    fn fetch_matched_blocks(
+        events: &[ClientEvent],
+        commands: &chan::Sender<Command>,
+        waker: &R::Waker,
+    ) {
+        let mut requested = false;
+
+        for e in events {
+            if let ClientEvent::FilterMatched { block_hash, .. } = e {
+                requested |= commands.send(Command::GetBlock(*block_hash)).is_ok();
+            }
+        }
+        if requested {
+            if let Err(err) = R::wake(waker) {
+                log::error!("Error waking up the event loop: {}", err);
+            }
+        }
+    }
+
     fn process_event(
         event: Event,
         blocks: Arc<Mutex<BlockSubscribers>>,
@@ -428,6 +687,10 @@ impl<R: Reactor> Client<R> {
 }
 
 /// An instance of [`handle::Handle`] for [`Client`].
+///
+/// Handles can be cloned and sent to other threads, to communicate with the same client.
+/// Note that clones share the same protocol event feed: each event is received by only one
+/// of them. Client events are broadcast, see [`handle::Handle::subscribe`].
 pub struct Handle<R: Reactor> {
     commands: chan::Sender<Command>,
     events: chan::Receiver<Event>,
@@ -436,6 +699,26 @@ pub struct Handle<R: Reactor> {
 
     blocks: Arc<Mutex<BlockSubscribers>>,
     filters: Arc<Mutex<FilterSubscribers>>,
+    publisher: Arc<Mutex<Publisher>>,
+    chain_state: SharedChainState,
+    /// Disconnected once the client has stopped.
+    stopped: chan::Receiver<()>,
+}
+
+impl<R: Reactor> Clone for Handle<R> {
+    fn clone(&self) -> Self {
+        Self {
+            commands: self.commands.clone(),
+            events: self.events.clone(),
+            waker: self.waker.clone(),
+            timeout: self.timeout,
+            blocks: self.blocks.clone(),
+            filters: self.filters.clone(),
+            publisher: self.publisher.clone(),
+            chain_state: self.chain_state.clone(),
+            stopped: self.stopped.clone(),
+        }
+    }
 }
 
 impl<R: Reactor> Handle<R> {
@@ -451,16 +734,95 @@ impl<R: Reactor> Handle<R> {
 
         Ok(())
     }
+
+    /// Receive events until the given predicate is fulfilled, or the timeout elapses.
+    fn recv_until<E, F, T>(&self, events: &chan::Receiver<E>, mut f: F) -> Result<T, handle::Error>
+    where
+        F: FnMut(E) -> Option<T>,
+    {
+        let start = time::Instant::now();
+
+        loop {
+            if let Some(timeout) = self.timeout.checked_sub(start.elapsed()) {
+                match events.recv_timeout(timeout) {
+                    Ok(event) => {
+                        if let Some(t) = f(event) {
+                            return Ok(t);
+                        }
+                    }
+                    Err(chan::RecvTimeoutError::Disconnected) => {
+                        return Err(handle::Error::Disconnected);
+                    }
+                    Err(chan::RecvTimeoutError::Timeout) => {
+                        // Keep trying until our timeout reaches zero.
+                        continue;
+                    }
+                }
+            } else {
+                return Err(handle::Error::Timeout);
+            }
+        }
+    }
 }
 
 impl<R: Reactor> handle::Handle for Handle<R> {
     fn get_tip(&self) -> Result<(Height, BlockHeader), handle::Error> {
+        // Avoid a round-trip through the event loop once the chain state is published.
+        if let Some(state) = self.chain_state.get() {
+            return Ok(state.tip());
+        }
         let (transmit, receive) = chan::bounded::<(Height, BlockHeader)>(1);
         self.command(Command::GetTip(transmit))?;
 
         Ok(receive.recv()?)
     }
 
+    fn get_header(&self, hash: &BlockHash) -> Result<Option<(Height, BlockHeader)>, handle::Error> {
+        // Once the chain state is published, the active chain can be read directly.
+        if self.chain_state.get().is_some() {
+            return Ok(self.chain_state.get_header(hash));
+        }
+        let (transmit, receive) = chan::bounded::<Option<(Height, BlockHeader)>>(1);
+        self.command(Command::GetHeader(*hash, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_header_by_height(&self, height: Height) -> Result<Option<BlockHeader>, handle::Error> {
+        if self.chain_state.get().is_some() {
+            return Ok(self.chain_state.get_header_by_height(height));
+        }
+        let (transmit, receive) = chan::bounded::<Option<BlockHeader>>(1);
+        self.command(Command::GetHeaderByHeight(height, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_filter_header(
+        &self,
+        height: Height,
+    ) -> Result<Option<(FilterHash, FilterHeader)>, handle::Error> {
+        let (transmit, receive) = chan::bounded::<Option<(FilterHash, FilterHeader)>>(1);
+        self.command(Command::GetFilterHeader(height, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_filter_height(&self) -> Result<Height, handle::Error> {
+        let (transmit, receive) = chan::bounded::<Height>(1);
+        self.command(Command::GetFilterHeight(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn subscribe(&self) -> chan::Receiver<ClientEvent> {
+        self.publisher.lock().unwrap().subscribe()
+    }
+
+    fn watch(&self, scripts: Vec<Script>) {
+        self.publisher.lock().unwrap().watch(scripts);
+    }
+
     fn get_block(
         &self,
         hash: &BlockHash,
@@ -481,13 +843,23 @@ impl<R: Reactor> handle::Handle for Handle<R> {
             !range.is_empty(),
             "client::Handle::get_filters: range cannot be empty"
         );
+        let (transmit, receive) = chan::bounded(1);
+
+        // Subscribe before sending the command, so that no filter is missed, and
+        // unsubscribe if the filters can't be requested.
         self.filters
             .lock()
             .unwrap()
-            .subscribe(range.clone(), channel);
-        self.command(Command::GetFilters(range))?;
+            .subscribe(range.clone(), channel.clone());
 
-        Ok(())
+        let result = self
+            .command(Command::GetFilters(range.clone(), transmit))
+            .and_then(|()| Ok(receive.recv()??));
+
+        if result.is_err() {
+            self.filters.lock().unwrap().unsubscribe(&range, &channel);
+        }
+        result
     }
 
     fn broadcast(&self, msg: NetworkMessage) -> Result<(), handle::Error> {
@@ -516,7 +888,7 @@ impl<R: Reactor> handle::Handle for Handle<R> {
     fn disconnect(&self, addr: net::SocketAddr) -> Result<(), handle::Error> {
         self.command(Command::Disconnect(addr))?;
         self.wait(|e| match e {
-            Event::ConnManager(connmgr::Event::Disconnected(a))
+            Event::ConnManager(connmgr::Event::Disconnected(a, _))
                 if a == addr || (addr.ip().is_unspecified() && a.port() == addr.port()) =>
             {
                 Some(())
@@ -525,6 +897,82 @@ impl<R: Reactor> handle::Handle for Handle<R> {
         })
     }
 
+    fn add_node(&self, addr: net::SocketAddr) -> Result<(), handle::Error> {
+        self.command(Command::AddNode(addr))
+    }
+
+    fn remove_node(&self, addr: net::SocketAddr) -> Result<(), handle::Error> {
+        self.command(Command::RemoveNode(addr))
+    }
+
+    fn ban(
+        &self,
+        ip: net::IpAddr,
+        duration: LocalDuration,
+        reason: &str,
+    ) -> Result<(), handle::Error> {
+        self.command(Command::Ban(ip, duration, reason.to_owned()))
+    }
+
+    fn unban(&self, ip: net::IpAddr) -> Result<(), handle::Error> {
+        self.command(Command::Unban(ip))
+    }
+
+    fn peers(&self) -> Result<Vec<peermgr::PeerInfo>, handle::Error> {
+        let (transmit, receive) = chan::bounded::<Vec<peermgr::PeerInfo>>(1);
+        self.command(Command::GetPeers(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn bans(&self) -> Result<Vec<(net::IpAddr, Ban)>, handle::Error> {
+        let (transmit, receive) = chan::bounded::<Vec<(net::IpAddr, Ban)>>(1);
+        self.command(Command::GetBans(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn export_addresses(
+        &self,
+        path: &Path,
+        format: peer::export::Format,
+    ) -> Result<usize, handle::Error> {
+        let (transmit, receive) = chan::bounded::<Vec<addrmgr::AddressInfo>>(1);
+        self.command(Command::ExportAddresses(transmit))?;
+
+        let entries = receive.recv()?;
+        let file = fs::File::create(path)?;
+
+        peer::export::write(io::BufWriter::new(file), &entries, format)?;
+
+        Ok(entries.len())
+    }
+
+    fn peer_stats(&self) -> Result<stats::Snapshot, handle::Error> {
+        let (transmit, receive) = chan::bounded::<stats::Snapshot>(1);
+        self.command(Command::GetPeerStats(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn reset_peer_stats(&self) -> Result<(), handle::Error> {
+        self.command(Command::ResetPeerStats)
+    }
+
+    fn memory_info(&self) -> Result<memory::MemoryInfo, handle::Error> {
+        let (transmit, receive) = chan::bounded::<memory::MemoryInfo>(1);
+        self.command(Command::GetMemoryInfo(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn time_offset(&self) -> Result<TimeOffset, handle::Error> {
+        let (transmit, receive) = chan::bounded::<TimeOffset>(1);
+        self.command(Command::GetTimeOffset(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
     fn import_headers(
         &self,
         headers: Vec<BlockHeader>,
@@ -547,66 +995,70 @@ impl<R: Reactor> handle::Handle for Handle<R> {
     where
         F: Fn(Event) -> Option<T>,
     {
-        let start = time::Instant::now();
-
-        loop {
-            if let Some(timeout) = self.timeout.checked_sub(start.elapsed()) {
-                match self.events.recv_timeout(timeout) {
-                    Ok(event) => {
-                        if let Some(t) = f(event) {
-                            return Ok(t);
-                        }
-                    }
-                    Err(chan::RecvTimeoutError::Disconnected) => {
-                        return Err(handle::Error::Disconnected);
-                    }
-                    Err(chan::RecvTimeoutError::Timeout) => {
-                        // Keep trying until our timeout reaches zero.
-                        continue;
-                    }
-                }
-            } else {
-                return Err(handle::Error::Timeout);
-            }
-        }
+        self.recv_until(&self.events, f)
     }
 
     fn wait_for_peers(&self, count: usize) -> Result<(), handle::Error> {
         use std::collections::HashSet;
 
-        self.wait(|e| {
-            let mut negotiated = HashSet::new();
-
-            match e {
-                Event::PeerManager(peermgr::Event::PeerNegotiated { addr }) => {
-                    negotiated.insert(addr);
+        // Subscribe before querying the negotiated peers, so that no peer is missed.
+        let events = self.subscribe();
+        let mut negotiated = self
+            .peers()?
+            .into_iter()
+            .map(|p| p.addr)
+            .collect::<HashSet<_>>();
 
-                    if negotiated.len() == count {
-                        Some(())
-                    } else {
-                        None
-                    }
+        if negotiated.len() >= count {
+            return Ok(());
+        }
+        self.recv_until(&events, |e| match e {
+            ClientEvent::PeerConnected { addr, .. } => {
+                negotiated.insert(addr);
+
+                if negotiated.len() >= count {
+                    Some(())
+                } else {
+                    None
                 }
-                _ => None,
             }
+            ClientEvent::PeerDisconnected { addr } => {
+                negotiated.remove(&addr);
+                None
+            }
+            _ => None,
         })
     }
 
     fn wait_for_ready(&self) -> Result<(), handle::Error> {
-        self.wait(|e| match e {
-            Event::SyncManager(syncmgr::Event::Synced(_, _)) => Some(()),
+        let (events, state) = {
+            let mut publisher = self.publisher.lock().unwrap();
+            (publisher.subscribe(), publisher.sync_state().cloned())
+        };
+
+        if let Some(SyncState::Synced { .. }) = state {
+            return Ok(());
+        }
+        self.recv_until(&events, |e| match e {
+            ClientEvent::SyncStateChanged(SyncState::Synced { .. }) => Some(()),
             _ => None,
         })
     }
 
     fn wait_for_height(&self, h: Height) -> Result<BlockHash, handle::Error> {
-        // TODO: Should return immediately if we are already at that height.
-        self.wait(|e| match e {
-            Event::SyncManager(syncmgr::Event::HeadersImported(ImportResult::TipChanged(
-                hash,
-                height,
-                _,
-            ))) if height == h => Some(hash),
+        // Subscribe before looking up the active chain, so that no tip change is missed.
+        let events = self.subscribe();
+
+        if let Some(header) = self.get_header_by_height(h)? {
+            return Ok(header.block_hash());
+        }
+        self.recv_until(&events, |e| match e {
+            ClientEvent::TipChanged { hash, height, .. } if height == h => Some(hash),
+            ClientEvent::TipChanged { height, .. } if height > h => self
+                .get_header_by_height(h)
+                .ok()
+                .flatten()
+                .map(|b| b.block_hash()),
             _ => None,
         })
     }
@@ -618,6 +1070,11 @@ impl<R: Reactor> handle::Handle for Handle<R> {
     fn shutdown(self) -> Result<(), handle::Error> {
         self.command(Command::Shutdown)?;
 
-        Ok(())
+        // Nothing is ever sent on this channel: it disconnects when the client is dropped,
+        // which happens once its `run` function has returned.
+        match self.stopped.recv_timeout(self.timeout) {
+            Err(chan::RecvTimeoutError::Timeout) => Err(handle::Error::Timeout),
+            _ => Ok(()),
+        }
     }
 }
@@ -0,0 +1,427 @@
+//! Client configuration files.
+//!
+//! Client settings can be loaded from a TOML file, eg.
+//!
+//! ```toml
+//! network = "testnet"
+//! home = "/var/lib/nakamoto"
+//! connect = ["127.0.0.1:18333"]
+//! timeout = 30
+//! fsync = "batch" # Or "never", or an interval in seconds.
+//!
+//! [connections]
+//! target_outbound = 8
+//! max_inbound = 16
+//! ```
+//!
+//! Settings that aren't specified keep their default value. Only the subset of TOML
+//! needed for client settings is supported: tables, and keys with string, integer,
+//! boolean or single-line array values.
+use std::env;
+use std::fs;
+use std::io;
+use std::net;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use thiserror::Error;
+
+use nakamoto_chain::block::store::SyncPolicy;
+
+use crate::client::{Config, Network};
+
+/// Prefix of environment variables overriding configuration settings.
+pub const ENV_PREFIX: &str = "NAKAMOTO_";
+
+/// Settings that take a list of values. When set from the environment, values are
+/// comma-separated.
+const LIST_KEYS: &[&str] = &["listen", "connect"];
+
+/// All supported settings.
+const KEYS: &[&str] = &[
+    "network",
+    "home",
+    "listen",
+    "connect",
+    "connect_only",
+    "timeout",
+    "import_peers",
+    "asmap",
+    "journal",
+    "recording",
+    "rng_seed",
+    "fsync",
+    "connections.target_outbound",
+    "connections.max_inbound",
+    "connections.block_relay",
+    "connections.filter",
+];
+
+/// An error loading a configuration file.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The file could not be parsed.
+    #[error("syntax error on line {0}")]
+    Syntax(usize),
+    /// The setting is not known.
+    #[error("unknown setting `{0}`")]
+    UnknownSetting(String),
+    /// The value of a setting is invalid.
+    #[error("invalid value for setting `{0}`")]
+    InvalidValue(String),
+}
+
+/// A configuration value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match self {
+            Self::Integer(n) if *n >= 0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    fn as_socket_addrs(&self) -> Option<Vec<net::SocketAddr>> {
+        match self {
+            Self::Array(items) => items
+                .iter()
+                .map(|v| v.as_str().and_then(|s| s.parse().ok()))
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// Load a configuration file. Settings can be overridden by environment variables,
+    /// see [`Config::apply_env`].
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = fs::read_to_string(path)?;
+        let mut cfg = Self::from_toml(&s)?;
+
+        cfg.apply_env(env::vars())?;
+
+        Ok(cfg)
+    }
+
+    /// Parse a configuration from TOML. Settings that aren't specified are set to their
+    /// default.
+    pub fn from_toml(s: &str) -> Result<Self, Error> {
+        let mut cfg = Self::default();
+
+        for (key, val) in self::parse(s)? {
+            cfg.set(&key, val)?;
+        }
+        Ok(cfg)
+    }
+
+    /// Override settings from environment variables. The variable of a setting is its key
+    /// in uppercase, with dots replaced by underscores and prefixed with [`ENV_PREFIX`],
+    /// eg. `NAKAMOTO_CONNECTIONS_MAX_INBOUND`. Lists are comma-separated.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), Error> {
+        for (var, raw) in vars {
+            let name = match var.strip_prefix(ENV_PREFIX) {
+                Some(name) => name.to_lowercase(),
+                None => continue,
+            };
+            let key = match KEYS.iter().find(|k| k.replace('.', "_") == name) {
+                Some(key) => *key,
+                None => return Err(Error::UnknownSetting(var)),
+            };
+            let val = if LIST_KEYS.contains(&key) {
+                Value::Array(
+                    raw.split(',')
+                        .map(|s| s.trim())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| Value::String(s.to_owned()))
+                        .collect(),
+                )
+            } else {
+                match self::value(&raw) {
+                    Some((val @ Value::Integer(_), rest))
+                    | Some((val @ Value::Boolean(_), rest))
+                        if rest.trim().is_empty() =>
+                    {
+                        val
+                    }
+                    _ => Value::String(raw),
+                }
+            };
+            self.set(key, val)?;
+        }
+        Ok(())
+    }
+
+    /// Set a single setting.
+    fn set(&mut self, key: &str, val: Value) -> Result<(), Error> {
+        let invalid = || Error::InvalidValue(key.to_owned());
+
+        match key {
+            "network" => {
+                self.network = match val.as_str() {
+                    Some("mainnet") => Network::Mainnet,
+                    Some("testnet") => Network::Testnet,
+                    Some("regtest") => Network::Regtest,
+                    _ => return Err(invalid()),
+                }
+            }
+            "home" => self.home = PathBuf::from(val.as_str().ok_or_else(invalid)?),
+            "listen" => self.listen = val.as_socket_addrs().ok_or_else(invalid)?,
+            "connect" => self.connect = val.as_socket_addrs().ok_or_else(invalid)?,
+            "connect_only" => self.connect_only = val.as_bool().ok_or_else(invalid)?,
+            "timeout" => {
+                self.timeout = time::Duration::from_secs(val.as_usize().ok_or_else(invalid)? as u64)
+            }
+            "import_peers" => {
+                self.import_peers = Some(PathBuf::from(val.as_str().ok_or_else(invalid)?))
+            }
+            "asmap" => self.asmap = Some(PathBuf::from(val.as_str().ok_or_else(invalid)?)),
+            "journal" => self.journal = Some(PathBuf::from(val.as_str().ok_or_else(invalid)?)),
+            "recording" => self.recording = Some(PathBuf::from(val.as_str().ok_or_else(invalid)?)),
+            "rng_seed" => self.rng_seed = Some(val.as_usize().ok_or_else(invalid)? as u64),
+            "fsync" => {
+                self.sync_policy = match (val.as_str(), val.as_usize()) {
+                    (Some("batch"), _) => SyncPolicy::Batch,
+                    (Some("never"), _) => SyncPolicy::Never,
+                    (_, Some(secs)) => SyncPolicy::Periodic(time::Duration::from_secs(secs as u64)),
+                    _ => return Err(invalid()),
+                }
+            }
+            "connections.target_outbound" => {
+                self.target_outbound_peers = val.as_usize().ok_or_else(invalid)?
+            }
+            "connections.max_inbound" => {
+                self.max_inbound_peers = val.as_usize().ok_or_else(invalid)?
+            }
+            "connections.block_relay" => {
+                self.block_relay_peers = val.as_usize().ok_or_else(invalid)?
+            }
+            "connections.filter" => self.filter_peers = val.as_usize().ok_or_else(invalid)?,
+            _ => return Err(Error::UnknownSetting(key.to_owned())),
+        }
+        Ok(())
+    }
+}
+
+/// Parse a TOML document into a list of settings. Keys inside a table are prefixed with
+/// the table name, eg. `"connections.max_inbound"`.
+fn parse(s: &str) -> Result<Vec<(String, Value)>, Error> {
+    let is_key = |k: &str| {
+        !k.is_empty()
+            && k.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    let mut table = None;
+    let mut settings = Vec::new();
+
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        let syntax = || Error::Syntax(i + 1);
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix('[') {
+            let end = rest.find(']').ok_or_else(syntax)?;
+            let (name, rest) = (rest[..end].trim(), rest[end + 1..].trim());
+
+            if !is_key(name) || !(rest.is_empty() || rest.starts_with('#')) {
+                return Err(syntax());
+            }
+            table = Some(name.to_owned());
+
+            continue;
+        }
+        let eq = line.find('=').ok_or_else(syntax)?;
+        let key = line[..eq].trim();
+
+        if !is_key(key) {
+            return Err(syntax());
+        }
+        let (val, rest) = self::value(&line[eq + 1..]).ok_or_else(syntax)?;
+        let rest = rest.trim();
+
+        if !(rest.is_empty() || rest.starts_with('#')) {
+            return Err(syntax());
+        }
+        let key = match &table {
+            Some(table) => format!("{}.{}", table, key),
+            None => key.to_owned(),
+        };
+        settings.push((key, val));
+    }
+    Ok(settings)
+}
+
+/// Parse a TOML value at the start of the input. Returns the value and the rest of the
+/// input.
+fn value(s: &str) -> Option<(Value, &str)> {
+    let s = s.trim_start();
+
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut string = String::new();
+        let mut chars = rest.char_indices();
+
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::String(string), &rest[i + 1..])),
+                '\\' => match chars.next()?.1 {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    '"' => string.push('"'),
+                    '\\' => string.push('\\'),
+                    _ => return None,
+                },
+                c => string.push(c),
+            }
+        }
+        None
+    } else if let Some(rest) = s.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+
+        Some((Value::String(rest[..end].to_owned()), &rest[end + 1..]))
+    } else if let Some(mut rest) = s.strip_prefix('[') {
+        let mut items = Vec::new();
+
+        loop {
+            rest = rest.trim_start();
+
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Some((Value::Array(items), rest));
+            }
+            let (item, r) = self::value(rest)?;
+            let r = r.trim_start();
+
+            items.push(item);
+            rest = match r.strip_prefix(',') {
+                Some(r) => r,
+                None if r.starts_with(']') => r,
+                None => return None,
+            };
+        }
+    } else {
+        let end = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+'))
+            .unwrap_or_else(|| s.len());
+        let (word, rest) = s.split_at(end);
+        let val = match word {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            _ => Value::Integer(word.replace('_', "").parse().ok()?),
+        };
+        Some((val, rest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_toml() {
+        let cfg = Config::from_toml(
+            r#"
+            # Connect to our own node.
+            network = "testnet"
+            home = '/var/lib/nakamoto'
+            connect = ["127.0.0.1:18333", "[::1]:18333"] # Local peers.
+            connect_only = true
+            timeout = 1_000
+            rng_seed = 42
+            fsync = 30
+
+            [connections]
+            max_inbound = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(cfg.network, Network::Testnet);
+        assert_eq!(cfg.home, PathBuf::from("/var/lib/nakamoto"));
+        assert_eq!(
+            cfg.connect,
+            vec![
+                net::SocketAddr::from(([127, 0, 0, 1], 18333)),
+                "[::1]:18333".parse().unwrap()
+            ]
+        );
+        assert!(cfg.connect_only);
+        assert_eq!(cfg.timeout, time::Duration::from_secs(1000));
+        assert_eq!(cfg.rng_seed, Some(42));
+        assert_eq!(
+            cfg.sync_policy,
+            SyncPolicy::Periodic(time::Duration::from_secs(30))
+        );
+        assert_eq!(cfg.max_inbound_peers, 0);
+        assert_eq!(
+            cfg.target_outbound_peers,
+            Config::default().target_outbound_peers
+        );
+
+        assert!(matches!(
+            Config::from_toml("network = \"testnet\"\nnetwork = testnet"),
+            Err(Error::Syntax(2))
+        ));
+        assert!(matches!(
+            Config::from_toml("[connections]\nmax = 1"),
+            Err(Error::UnknownSetting(key)) if key == "connections.max"
+        ));
+        assert!(matches!(
+            Config::from_toml("timeout = \"1\""),
+            Err(Error::InvalidValue(key)) if key == "timeout"
+        ));
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut cfg = Config::from_toml("network = \"testnet\"").unwrap();
+        let vars = vec![
+            ("HOME".to_owned(), "/home/satoshi".to_owned()),
+            ("NAKAMOTO_NETWORK".to_owned(), "regtest".to_owned()),
+            (
+                "NAKAMOTO_LISTEN".to_owned(),
+                "0.0.0.0:8333, [::]:8333".to_owned(),
+            ),
+            ("NAKAMOTO_CONNECTIONS_FILTER".to_owned(), "4".to_owned()),
+            ("NAKAMOTO_FSYNC".to_owned(), "never".to_owned()),
+        ];
+        cfg.apply_env(vars).unwrap();
+
+        assert_eq!(cfg.network, Network::Regtest);
+        assert_eq!(cfg.listen.len(), 2);
+        assert_eq!(cfg.filter_peers, 4);
+        assert_eq!(cfg.sync_policy, SyncPolicy::Never);
+
+        assert!(matches!(
+            cfg.apply_env(vec![("NAKAMOTO_FOO".to_owned(), "1".to_owned())]),
+            Err(Error::UnknownSetting(var)) if var == "NAKAMOTO_FOO"
+        ));
+    }
+}
@@ -34,6 +34,9 @@ pub enum Error {
     /// An error coming from the peer store.
     #[error("error loading peers: {0}")]
     PeerStore(io::Error),
+    /// The client configuration is invalid.
+    #[error("invalid configuration: {0}")]
+    Config(#[from] crate::builder::Error),
     /// A communication channel error.
     #[error("command channel disconnected")]
     Channel,
@@ -0,0 +1,402 @@
+//! Client events.
+//!
+//! Protocol events are low-level and tied to the internals of the protocol. Client events
+//! are a smaller set of structured events that applications can subscribe to, via
+//! [`crate::handle::Handle::subscribe`].
+use std::collections::HashSet;
+use std::{fmt, net};
+
+use crossbeam_channel as chan;
+
+use nakamoto_common::block::time::TimeOffset;
+use nakamoto_common::block::tree::ImportResult;
+use nakamoto_common::block::{BlockHash, Height, Transaction};
+use nakamoto_p2p::bitcoin::Script;
+use nakamoto_p2p::event::Event;
+use nakamoto_p2p::protocol::{connmgr, peermgr, spvmgr, syncmgr, Link};
+
+/// Block header sync state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncState {
+    /// Syncing headers with a peer.
+    Syncing {
+        /// The peer we're syncing with.
+        peer: net::SocketAddr,
+    },
+    /// Synced up to the given block.
+    Synced {
+        /// Hash of the best block.
+        hash: BlockHash,
+        /// Height of the best block.
+        height: Height,
+    },
+    /// Our tip hasn't changed in a while, and may be stale.
+    Stale,
+}
+
+/// An event emitted by the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// A peer connected, and completed the handshake.
+    PeerConnected {
+        /// Peer address.
+        addr: net::SocketAddr,
+        /// Connection direction.
+        link: Link,
+    },
+    /// A peer disconnected.
+    PeerDisconnected {
+        /// Peer address.
+        addr: net::SocketAddr,
+    },
+    /// Block headers were imported.
+    HeadersImported(ImportResult),
+    /// The tip of the active chain changed.
+    TipChanged {
+        /// Hash of the new tip.
+        hash: BlockHash,
+        /// Height of the new tip.
+        height: Height,
+        /// Blocks that are no longer part of the active chain, in case of a re-org.
+        reverted: Vec<BlockHash>,
+    },
+    /// A compact block filter matched one of the watched scripts.
+    FilterMatched {
+        /// Hash of the matching block.
+        block_hash: BlockHash,
+        /// Height of the matching block.
+        height: Height,
+    },
+    /// A transaction paying to one of the watched scripts was found in a received block.
+    TransactionMatched {
+        /// The matching transaction.
+        transaction: Transaction,
+        /// Hash of the block containing the transaction.
+        block_hash: BlockHash,
+        /// Height of the block containing the transaction.
+        height: Height,
+    },
+    /// The header sync state changed.
+    SyncStateChanged(SyncState),
+    /// Our peers' clocks disagree with ours by more than the maximum clock skew. The local
+    /// clock is likely wrong, and should be checked, since block header timestamps are
+    /// validated against it.
+    ClockSkewDetected {
+        /// Median offset of our peers' clocks from ours, in seconds.
+        offset: TimeOffset,
+    },
+}
+
+/// Receives client events through callbacks, for embedders that would rather not use
+/// channels. See [`crate::client::Client::add_listener`].
+///
+/// Callbacks are invoked from the client's event loop, and should return quickly. They
+/// must not call back into the client handle, as this may deadlock. All methods do
+/// nothing by default.
+pub trait ClientListener: Send {
+    /// A peer connected, and completed the handshake.
+    fn on_peer_connected(&mut self, _addr: net::SocketAddr, _link: Link) {}
+    /// A peer disconnected.
+    fn on_peer_disconnected(&mut self, _addr: net::SocketAddr) {}
+    /// The tip of the active chain changed. Blocks that are no longer part of the active
+    /// chain are passed in case of a re-org.
+    fn on_tip_changed(&mut self, _hash: &BlockHash, _height: Height, _reverted: &[BlockHash]) {}
+    /// The header sync state changed.
+    fn on_sync_state_changed(&mut self, _state: &SyncState) {}
+    /// A compact block filter matched one of the watched scripts.
+    fn on_filter_matched(&mut self, _block_hash: &BlockHash, _height: Height) {}
+    /// A transaction paying to one of the watched scripts was found in a received block.
+    fn on_tx_matched(&mut self, _tx: &Transaction, _block_hash: &BlockHash, _height: Height) {}
+    /// Our peers' clocks disagree with ours by the given offset, in seconds.
+    fn on_clock_skew_detected(&mut self, _offset: TimeOffset) {}
+}
+
+impl ClientEvent {
+    /// Invoke the listener callback corresponding to this event, if any.
+    fn notify(&self, listener: &mut dyn ClientListener) {
+        match self {
+            Self::PeerConnected { addr, link } => listener.on_peer_connected(*addr, *link),
+            Self::PeerDisconnected { addr } => listener.on_peer_disconnected(*addr),
+            Self::HeadersImported(_) => {}
+            Self::TipChanged {
+                hash,
+                height,
+                reverted,
+            } => listener.on_tip_changed(hash, *height, reverted),
+            Self::FilterMatched { block_hash, height } => {
+                listener.on_filter_matched(block_hash, *height)
+            }
+            Self::TransactionMatched {
+                transaction,
+                block_hash,
+                height,
+            } => listener.on_tx_matched(transaction, block_hash, *height),
+            Self::SyncStateChanged(state) => listener.on_sync_state_changed(state),
+            Self::ClockSkewDetected { offset } => listener.on_clock_skew_detected(*offset),
+        }
+    }
+}
+
+/// Publishes client events to subscribers and listeners.
+#[derive(Default)]
+pub(crate) struct Publisher {
+    subscribers: Vec<chan::Sender<ClientEvent>>,
+    listeners: Vec<Box<dyn ClientListener>>,
+    watch: HashSet<Script>,
+    sync_state: Option<SyncState>,
+}
+
+impl fmt::Debug for Publisher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Publisher")
+            .field("subscribers", &self.subscribers)
+            .field("listeners", &self.listeners.len())
+            .field("watch", &self.watch)
+            .field("sync_state", &self.sync_state)
+            .finish()
+    }
+}
+
+impl Publisher {
+    /// Add a new subscriber.
+    pub fn subscribe(&mut self) -> chan::Receiver<ClientEvent> {
+        let (sender, receiver) = chan::unbounded();
+        self.subscribers.push(sender);
+
+        receiver
+    }
+
+    /// Add a new listener.
+    pub fn listen(&mut self, listener: Box<dyn ClientListener>) {
+        self.listeners.push(listener);
+    }
+
+    /// Watch scripts for filter matches.
+    pub fn watch(&mut self, scripts: impl IntoIterator<Item = Script>) {
+        self.watch.extend(scripts);
+    }
+
+    /// The last published header sync state, if any.
+    pub fn sync_state(&self) -> Option<&SyncState> {
+        self.sync_state.as_ref()
+    }
+
+    /// Publish the client events derived from a protocol event, and return them.
+    /// Subscribers that have gone away are removed.
+    pub fn publish(&mut self, event: &Event) -> Vec<ClientEvent> {
+        let events = self.events(event);
+
+        for e in events.iter() {
+            if let ClientEvent::SyncStateChanged(state) = e {
+                self.sync_state = Some(state.clone());
+            }
+            for listener in self.listeners.iter_mut() {
+                e.notify(listener.as_mut());
+            }
+            self.subscribers.retain(|s| s.send(e.clone()).is_ok());
+        }
+        events
+    }
+
+    /// Derive client events from a protocol event.
+    fn events(&self, event: &Event) -> Vec<ClientEvent> {
+        match event {
+            Event::PeerManager(peermgr::Event::PeerNegotiated { addr, link, .. }) => {
+                vec![ClientEvent::PeerConnected {
+                    addr: *addr,
+                    link: *link,
+                }]
+            }
+            Event::ConnManager(connmgr::Event::Disconnected(addr, _)) => {
+                vec![ClientEvent::PeerDisconnected { addr: *addr }]
+            }
+            Event::SyncManager(syncmgr::Event::HeadersImported(result)) => {
+                let mut events = vec![ClientEvent::HeadersImported(result.clone())];
+
+                if let ImportResult::TipChanged(hash, height, reverted) = result {
+                    events.push(ClientEvent::TipChanged {
+                        hash: *hash,
+                        height: *height,
+                        reverted: reverted.clone(),
+                    });
+                }
+                events
+            }
+            Event::SyncManager(syncmgr::Event::Syncing(peer)) => {
+                vec![ClientEvent::SyncStateChanged(SyncState::Syncing {
+                    peer: *peer,
+                })]
+            }
+            Event::SyncManager(syncmgr::Event::Synced(hash, height)) => {
+                vec![ClientEvent::SyncStateChanged(SyncState::Synced {
+                    hash: *hash,
+                    height: *height,
+                })]
+            }
+            Event::SyncManager(syncmgr::Event::StaleTipDetected(_)) => {
+                vec![ClientEvent::SyncStateChanged(SyncState::Stale)]
+            }
+            Event::SpvManager(spvmgr::Event::FilterReceived {
+                filter,
+                block_hash,
+                height,
+                ..
+            }) if !self.watch.is_empty() => {
+                let mut query = self.watch.iter().map(|s| s.as_bytes());
+
+                match filter.match_any(block_hash, &mut query) {
+                    Ok(true) => vec![ClientEvent::FilterMatched {
+                        block_hash: *block_hash,
+                        height: *height,
+                    }],
+                    _ => vec![],
+                }
+            }
+            Event::SyncManager(syncmgr::Event::BlockReceived(_, block, height))
+                if !self.watch.is_empty() =>
+            {
+                let block_hash = block.block_hash();
+
+                block
+                    .txdata
+                    .iter()
+                    .filter(|tx| {
+                        tx.output
+                            .iter()
+                            .any(|o| self.watch.contains(&o.script_pubkey))
+                    })
+                    .map(|tx| ClientEvent::TransactionMatched {
+                        transaction: tx.clone(),
+                        block_hash,
+                        height: *height,
+                    })
+                    .collect()
+            }
+            Event::ClockSkewDetected(offset) => {
+                vec![ClientEvent::ClockSkewDetected { offset: *offset }]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use nakamoto_common::block::time::LocalTime;
+    use nakamoto_common::network::Network;
+    use nakamoto_p2p::bitcoin::network::constants::ServiceFlags;
+    use nakamoto_p2p::protocol::DisconnectReason;
+
+    #[test]
+    fn test_publish() {
+        let mut publisher = Publisher::default();
+        let addr: net::SocketAddr = ([88, 13, 16, 1], 8333).into();
+
+        let alice = publisher.subscribe();
+        let bob = publisher.subscribe();
+
+        publisher.publish(&Event::PeerManager(peermgr::Event::PeerNegotiated {
+            addr,
+            link: Link::Outbound,
+            services: ServiceFlags::NETWORK,
+        }));
+        publisher.publish(&Event::SyncManager(syncmgr::Event::StaleTipDetected(
+            LocalTime::from_secs(1),
+        )));
+
+        assert_eq!(publisher.sync_state(), Some(&SyncState::Stale));
+
+        for sub in &[&alice, &bob] {
+            assert_eq!(
+                sub.try_iter().collect::<Vec<_>>(),
+                vec![
+                    ClientEvent::PeerConnected {
+                        addr,
+                        link: Link::Outbound
+                    },
+                    ClientEvent::SyncStateChanged(SyncState::Stale),
+                ]
+            );
+        }
+
+        // Subscribers that went away are removed.
+        drop(bob);
+        publisher.publish(&Event::ConnManager(connmgr::Event::Disconnected(
+            addr,
+            DisconnectReason::Command,
+        )));
+
+        assert_eq!(publisher.subscribers.len(), 1);
+        assert_eq!(alice.try_recv(), Ok(ClientEvent::PeerDisconnected { addr }));
+    }
+
+    #[test]
+    fn test_listener() {
+        #[derive(Default)]
+        struct Listener {
+            tips: Arc<Mutex<Vec<Height>>>,
+            peers: Arc<Mutex<Vec<net::SocketAddr>>>,
+        }
+
+        impl ClientListener for Listener {
+            fn on_peer_connected(&mut self, addr: net::SocketAddr, _link: Link) {
+                self.peers.lock().unwrap().push(addr);
+            }
+
+            fn on_tip_changed(&mut self, _hash: &BlockHash, height: Height, _: &[BlockHash]) {
+                self.tips.lock().unwrap().push(height);
+            }
+        }
+
+        let mut publisher = Publisher::default();
+        let listener = Listener::default();
+        let tips = listener.tips.clone();
+        let peers = listener.peers.clone();
+        let addr: net::SocketAddr = ([88, 13, 16, 1], 8333).into();
+        let hash = Network::Mainnet.genesis_hash();
+
+        publisher.listen(Box::new(listener));
+        publisher.publish(&Event::PeerManager(peermgr::Event::PeerNegotiated {
+            addr,
+            link: Link::Inbound,
+            services: ServiceFlags::NETWORK,
+        }));
+        publisher.publish(&Event::SyncManager(syncmgr::Event::HeadersImported(
+            ImportResult::TipChanged(hash, 1, vec![]),
+        )));
+        publisher.publish(&Event::ConnManager(connmgr::Event::Disconnected(
+            addr,
+            DisconnectReason::Command,
+        )));
+
+        assert_eq!(*peers.lock().unwrap(), vec![addr]);
+        assert_eq!(*tips.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_transaction_matched() {
+        let mut publisher = Publisher::default();
+        let addr: net::SocketAddr = ([88, 13, 16, 1], 8333).into();
+        let block = Network::Mainnet.genesis_block();
+        let coinbase = block.txdata[0].clone();
+        let events = publisher.subscribe();
+        let received = Event::SyncManager(syncmgr::Event::BlockReceived(addr, block.clone(), 0));
+
+        publisher.publish(&received);
+        assert!(events.try_recv().is_err(), "no scripts are watched");
+
+        publisher.watch(vec![coinbase.output[0].script_pubkey.clone()]);
+
+        let matched = ClientEvent::TransactionMatched {
+            transaction: coinbase,
+            block_hash: block.block_hash(),
+            height: 0,
+        };
+        // Published events are returned, so that the client can act on them.
+        assert_eq!(publisher.publish(&received), vec![matched.clone()]);
+        assert_eq!(events.try_recv(), Ok(matched));
+    }
+}
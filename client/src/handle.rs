@@ -2,14 +2,24 @@
 //! protocol instance.
 use std::net;
 use std::ops::Range;
+use std::path::Path;
 
 use crossbeam_channel as chan;
 use thiserror::Error;
 
-use nakamoto_common::block::filter::BlockFilter;
+use nakamoto_common::block::filter::{BlockFilter, FilterHash, FilterHeader};
+use nakamoto_common::block::time::{LocalDuration, TimeOffset};
 use nakamoto_common::block::tree::ImportResult;
 use nakamoto_common::block::{self, Block, BlockHash, BlockHeader, Height, Transaction};
-use nakamoto_p2p::{bitcoin::network::message::NetworkMessage, event::Event, protocol::Link};
+use nakamoto_common::p2p::peer::Ban;
+use nakamoto_p2p::bitcoin::Script;
+use nakamoto_p2p::protocol::peermgr::PeerInfo;
+use nakamoto_p2p::protocol::spvmgr::GetFiltersError;
+use nakamoto_p2p::protocol::{memory, stats, Link};
+use nakamoto_p2p::{bitcoin::network::message::NetworkMessage, event::Event};
+
+use crate::event::ClientEvent;
+use crate::peer::export;
 
 /// An error resulting from a handle method.
 #[derive(Error, Debug)]
@@ -23,6 +33,9 @@ pub enum Error {
     /// An I/O error occured.
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    /// Compact filters could not be requested.
+    #[error(transparent)]
+    GetFilters(#[from] GetFiltersError),
 }
 
 impl From<chan::RecvError> for Error {
@@ -41,6 +54,17 @@ impl<T> From<chan::SendError<T>> for Error {
 pub trait Handle {
     /// Get the tip of the chain.
     fn get_tip(&self) -> Result<(Height, BlockHeader), Error>;
+    /// Get a block header by hash, along with its height.
+    fn get_header(&self, hash: &BlockHash) -> Result<Option<(Height, BlockHeader)>, Error>;
+    /// Get the block header at the given height in the active chain.
+    fn get_header_by_height(&self, height: Height) -> Result<Option<BlockHeader>, Error>;
+    /// Get the stored compact filter header at the given height, along with its filter hash.
+    fn get_filter_header(
+        &self,
+        height: Height,
+    ) -> Result<Option<(FilterHash, FilterHeader)>, Error>;
+    /// Get the height of the last stored compact filter header.
+    fn get_filter_height(&self) -> Result<Height, Error>;
     /// Get a full block from the network.
     fn get_block(
         &self,
@@ -62,6 +86,32 @@ pub trait Handle {
     fn connect(&self, addr: net::SocketAddr) -> Result<Link, Error>;
     /// Disconnect from the designated peer address.
     fn disconnect(&self, addr: net::SocketAddr) -> Result<(), Error>;
+    /// Connect to the designated peer address, and re-dial it whenever it disconnects,
+    /// similar to `addnode <addr> add` in Bitcoin Core.
+    fn add_node(&self, addr: net::SocketAddr) -> Result<(), Error>;
+    /// Stop re-dialing a peer added with [`Handle::add_node`], and disconnect from it.
+    fn remove_node(&self, addr: net::SocketAddr) -> Result<(), Error>;
+    /// Ban a peer address for the given duration. Connected peers with that address
+    /// are disconnected.
+    fn ban(&self, ip: net::IpAddr, duration: LocalDuration, reason: &str) -> Result<(), Error>;
+    /// Lift a ban on a peer address.
+    fn unban(&self, ip: net::IpAddr) -> Result<(), Error>;
+    /// Get information about connected peers that completed the handshake.
+    fn peers(&self) -> Result<Vec<PeerInfo>, Error>;
+    /// Get the list of banned peer addresses.
+    fn bans(&self) -> Result<Vec<(net::IpAddr, Ban)>, Error>;
+    /// Export the address book to a file, with selection scores, buckets and timestamps.
+    /// Returns the number of addresses exported.
+    fn export_addresses(&self, path: &Path, format: export::Format) -> Result<usize, Error>;
+    /// Get traffic statistics of connected peers, as well as totals across all peers.
+    fn peer_stats(&self) -> Result<stats::Snapshot, Error>;
+    /// Reset all peer traffic statistics.
+    fn reset_peer_stats(&self) -> Result<(), Error>;
+    /// Get the approximate memory used by the client's caches, in bytes.
+    fn memory_info(&self) -> Result<memory::MemoryInfo, Error>;
+    /// Get the network-adjusted time offset, in seconds. This is the median offset of
+    /// our connected peers' clocks from our own, and zero until enough peers are connected.
+    fn time_offset(&self) -> Result<TimeOffset, Error>;
     /// Submit a transaction to the network.
     fn submit_transaction(&self, tx: Transaction) -> Result<(), Error>;
     /// Import block headers into the node.
@@ -70,17 +120,30 @@ pub trait Handle {
         &self,
         headers: Vec<BlockHeader>,
     ) -> Result<Result<ImportResult, block::tree::Error>, Error>;
-    /// Wait for the given predicate to be fulfilled.
+    /// Wait for the given predicate to be fulfilled by a protocol event. Events are read
+    /// from the feed returned by [`Handle::events`], which is shared with other handles.
     fn wait<F: Fn(Event) -> Option<T>, T>(&self, f: F) -> Result<T, Error>;
-    /// Wait for a given number of peers to be connected.
+    /// Wait for a given number of peers to be connected. Returns immediately if there are
+    /// already that many peers.
     fn wait_for_peers(&self, count: usize) -> Result<(), Error>;
     /// Wait for the node to be ready and in sync with the blockchain.
     fn wait_for_ready(&self) -> Result<(), Error>;
     /// Wait for the node's active chain to reach a certain height. The hash at that height
-    /// is returned.
+    /// is returned, immediately if the height was already reached.
     fn wait_for_height(&self, h: Height) -> Result<BlockHash, Error>;
-    /// Listen on events.
+    /// Listen on protocol events. The feed is shared between a handle and its clones,
+    /// so each event is received by only one of them.
     fn events(&self) -> &chan::Receiver<Event>;
-    /// Shutdown the node process.
+    /// Subscribe to client events. Each subscriber receives all events emitted after it
+    /// subscribed.
+    fn subscribe(&self) -> chan::Receiver<ClientEvent>;
+    /// Watch scripts, so that [`ClientEvent::FilterMatched`] is emitted when a received
+    /// compact filter matches any of them. The matching blocks are then fetched, and
+    /// [`ClientEvent::TransactionMatched`] is emitted for their transactions paying to the
+    /// watched scripts.
+    fn watch(&self, scripts: Vec<Script>);
+    /// Shutdown the node process. Peers are disconnected and the address book is saved,
+    /// after which the client's `run` function returns. Blocks until the client has
+    /// stopped, or the handle's timeout has elapsed.
     fn shutdown(self) -> Result<(), Error>;
 }
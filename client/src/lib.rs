@@ -1,8 +1,12 @@
 //! Nakamoto's client library.
 #![deny(missing_docs, unsafe_code)]
+pub mod builder;
 pub mod client;
+pub mod config;
 pub mod error;
+pub mod event;
 pub mod handle;
+pub mod multi;
 pub mod peer;
 
 pub use client::*;
@@ -0,0 +1,138 @@
+//! Multiple clients in one process.
+//!
+//! Runs independent clients, eg. one per network, for backends that serve several
+//! networks at once. Each client runs on its own thread, with its own data directory and
+//! address book, under `<home>/.nakamoto/<network>`. Clients are named after their network
+//! in the logs, unless they were given a name.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::thread;
+
+use thiserror::Error;
+
+use crate::builder::{self, ClientBuilder};
+use crate::client::{Client, Handle, Network, Reactor};
+use crate::error;
+use crate::handle::Handle as _;
+
+/// An error starting clients.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// Two clients would share the same data directory.
+    #[error("more than one {network:?} client uses the data directory under {home:?}")]
+    Conflict {
+        /// Client network.
+        network: Network,
+        /// Client home directory.
+        home: PathBuf,
+    },
+    /// A client configuration is invalid.
+    #[error(transparent)]
+    Config(#[from] builder::Error),
+    /// A client failed to start.
+    #[error(transparent)]
+    Client(#[from] error::Error),
+}
+
+/// Independent clients, running in the background.
+pub struct Clients<R: Reactor> {
+    handles: HashMap<(Network, PathBuf), Handle<R>>,
+    threads: Vec<thread::JoinHandle<Result<(), error::Error>>>,
+}
+
+impl<R: Reactor + Send + 'static> Clients<R> {
+    /// Validate the given client configurations, and start the clients. No client is
+    /// started if any configuration is invalid, or if two clients would share the same
+    /// data directory.
+    pub fn spawn(builders: Vec<ClientBuilder>) -> Result<Self, Error> {
+        let mut configs = HashMap::new();
+
+        for builder in builders {
+            let mut cfg = builder.config()?;
+            let key = (cfg.network, cfg.home.clone());
+
+            if configs.contains_key(&key) {
+                return Err(Error::Conflict {
+                    network: cfg.network,
+                    home: cfg.home,
+                });
+            }
+            if cfg.name == "self" {
+                cfg.name = cfg.network.as_str();
+            }
+            configs.insert(key, cfg);
+        }
+
+        let mut clients = Vec::with_capacity(configs.len());
+        for (key, cfg) in configs {
+            clients.push((key, Client::<R>::new(cfg)?));
+        }
+
+        let mut handles = HashMap::new();
+        let mut threads = Vec::new();
+
+        for (key, client) in clients {
+            handles.insert(key, client.handle());
+            threads.push(thread::spawn(move || client.run()));
+        }
+        Ok(Self { handles, threads })
+    }
+
+    /// Get a handle to the client of the given network. If there is more than one client
+    /// for the network, any one of them is returned.
+    pub fn handle(&self, network: Network) -> Option<&Handle<R>> {
+        self.handles
+            .iter()
+            .find(|((n, _), _)| *n == network)
+            .map(|(_, h)| h)
+    }
+
+    /// Get handles to all clients, along with their network and home directory.
+    pub fn handles(&self) -> impl Iterator<Item = (&Network, &PathBuf, &Handle<R>)> {
+        self.handles.iter().map(|((n, home), h)| (n, home, h))
+    }
+
+    /// Shut down all clients, and wait for them to stop. Returns the first error
+    /// encountered, if any, after all clients were asked to shut down.
+    pub fn shutdown(self) -> Result<(), error::Error> {
+        let mut result = Ok(());
+
+        for (_, handle) in self.handles {
+            if let Err(err) = handle.shutdown() {
+                result = result.and(Err(err.into()));
+            }
+        }
+        for thread in self.threads {
+            match thread.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => result = result.and(Err(err)),
+                Err(_) => log::error!("Client thread panicked"),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conflict() {
+        type Reactor = nakamoto_net_poll::Reactor<std::net::TcpStream>;
+
+        let result = Clients::<Reactor>::spawn(vec![
+            ClientBuilder::new(Network::Mainnet).home("/tmp/a"),
+            ClientBuilder::new(Network::Testnet).home("/tmp/a"),
+            ClientBuilder::new(Network::Mainnet).home("/tmp/a"),
+        ]);
+
+        assert!(matches!(
+            result,
+            Err(Error::Conflict {
+                network: Network::Mainnet,
+                ..
+            })
+        ));
+    }
+}
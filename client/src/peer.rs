@@ -1,14 +1,25 @@
 //! Client-related peer functionality.
+pub mod export;
+pub mod peers_dat;
+
 use std::collections::HashMap;
 use std::path::Path;
 use std::{fs, io, net};
 
 pub use nakamoto_common::p2p::peer::*;
 
+use nakamoto_common::p2p::addr::PeerAddr;
+use nakamoto_p2p::protocol::connmgr::MAX_ANCHORS;
+
 /// A file-backed implementation of [`Store`].
+///
+/// Besides IP addresses, the cache also keeps addresses on overlay networks such as
+/// Tor and I2P, along with their connection history. Since these can't be dialed over
+/// TCP, they are not part of the [`Store`] used by the address manager.
 #[derive(Debug)]
 pub struct Cache {
     addrs: HashMap<net::IpAddr, KnownAddress>,
+    overlay: HashMap<PeerAddr, KnownAddress>,
     file: fs::File,
 }
 
@@ -32,6 +43,7 @@ impl Cache {
         Ok(Self {
             file,
             addrs: HashMap::new(),
+            overlay: HashMap::new(),
         })
     }
 
@@ -43,6 +55,7 @@ impl Cache {
 
         let mut s = String::new();
         let mut addrs = HashMap::new();
+        let mut overlay = HashMap::new();
 
         file.read_to_string(&mut s)?;
 
@@ -55,17 +68,43 @@ impl Cache {
                     for (k, v) in ary.into_iter() {
                         let ka = KnownAddress::from_json(v)
                             .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
-                        let ip = net::IpAddr::from_str(k.as_str())
-                            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
 
-                        addrs.insert(ip, ka);
+                        // Overlay network addresses are keyed by their full address,
+                        // eg. `"<base32>.onion:8333"`.
+                        if let Ok(ip) = net::IpAddr::from_str(k.as_str()) {
+                            addrs.insert(ip, ka);
+                        } else if !ka.addr.is_ip() {
+                            overlay.insert(ka.addr, ka);
+                        } else {
+                            return Err(io::ErrorKind::InvalidData.into());
+                        }
                     }
                 }
                 _ => return Err(io::ErrorKind::InvalidData.into()),
             }
         }
 
-        Ok(Self { file, addrs })
+        Ok(Self {
+            file,
+            addrs,
+            overlay,
+        })
+    }
+
+    /// Insert an address on an overlay network, eg. Tor. Returns `false` if the address
+    /// is an IP address, or if it was already known.
+    pub fn insert_overlay(&mut self, ka: KnownAddress) -> bool {
+        if ka.addr.is_ip() || self.overlay.contains_key(&ka.addr) {
+            return false;
+        }
+        self.overlay.insert(ka.addr, ka);
+
+        true
+    }
+
+    /// Iterate over the known overlay network addresses.
+    pub fn overlay(&self) -> impl Iterator<Item = &KnownAddress> {
+        self.overlay.values()
     }
 }
 
@@ -95,7 +134,8 @@ impl Store for Cache {
     }
 
     fn clear(&mut self) {
-        self.addrs.clear()
+        self.addrs.clear();
+        self.overlay.clear();
     }
 
     fn len(&self) -> usize {
@@ -104,12 +144,17 @@ impl Store for Cache {
 
     fn flush<'a>(&mut self) -> io::Result<()> {
         use io::{Seek, Write};
-        use microserde::json::Value;
+        use microserde::json::{Object, Value};
 
-        let peers: microserde::json::Object = self
+        let peers: Object = self
             .addrs
             .iter()
             .map(|(ip, ka)| (ip.to_string(), ka.to_json()))
+            .chain(
+                self.overlay
+                    .iter()
+                    .map(|(addr, ka)| (addr.to_string(), ka.to_json())),
+            )
             .collect();
         let s = microserde::json::to_string(&Value::Object(peers));
 
@@ -123,10 +168,189 @@ impl Store for Cache {
     }
 }
 
+/// File-backed list of anchor peers.
+///
+/// Anchors are the block-relay peers we were connected to during our last session.
+/// On startup, we connect to these first.
+#[derive(Debug)]
+pub struct Anchors {
+    addrs: Vec<net::SocketAddr>,
+    file: fs::File,
+}
+
+impl Anchors {
+    /// Open an anchors file, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        use io::Read;
+        use microserde::json::Value;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let mut s = String::new();
+        let mut addrs = Vec::new();
+
+        file.read_to_string(&mut s)?;
+
+        if !s.is_empty() {
+            let val: Value = microserde::json::from_str(&s)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+            match val {
+                Value::Array(ary) => {
+                    for v in ary.into_iter() {
+                        match v {
+                            Value::String(addr) => addrs.push(
+                                addr.parse()
+                                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?,
+                            ),
+                            _ => return Err(io::ErrorKind::InvalidData.into()),
+                        }
+                    }
+                }
+                _ => return Err(io::ErrorKind::InvalidData.into()),
+            }
+        }
+
+        Ok(Self { addrs, file })
+    }
+
+    /// Get the anchor addresses, most recent first.
+    pub fn addrs(&self) -> &[net::SocketAddr] {
+        &self.addrs
+    }
+
+    /// Record a new anchor. Only the most recent anchors are kept.
+    pub fn insert(&mut self, addr: net::SocketAddr) -> io::Result<()> {
+        if self.addrs.contains(&addr) {
+            return Ok(());
+        }
+        self.addrs.insert(0, addr);
+        self.addrs.truncate(MAX_ANCHORS);
+        self.flush()
+    }
+
+    /// Remove an anchor, eg. because the peer disconnected.
+    pub fn remove(&mut self, addr: &net::SocketAddr) -> io::Result<()> {
+        if let Some(ix) = self.addrs.iter().position(|a| a == addr) {
+            self.addrs.remove(ix);
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        use io::{Seek, Write};
+        use microserde::json::Value;
+
+        let addrs = self
+            .addrs
+            .iter()
+            .map(|a| Value::String(a.to_string()))
+            .collect();
+        let s = microserde::json::to_string(&Value::Array(addrs));
+
+        self.file.set_len(0)?;
+        self.file.seek(io::SeekFrom::Start(0))?;
+        self.file.write_all(s.as_bytes())?;
+        self.file.write_all(&[b'\n'])?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+/// File-backed list of banned peer addresses.
+#[derive(Debug)]
+pub struct BanList {
+    bans: HashMap<net::IpAddr, Ban>,
+    file: fs::File,
+}
+
+impl BanList {
+    /// Open a ban list file, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        use io::Read;
+        use microserde::json::Value;
+        use std::str::FromStr;
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        let mut s = String::new();
+        let mut bans = HashMap::new();
+
+        file.read_to_string(&mut s)?;
+
+        if !s.is_empty() {
+            let val: Value = microserde::json::from_str(&s)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+            match val {
+                Value::Object(obj) => {
+                    for (k, v) in obj.into_iter() {
+                        let ban = Ban::from_json(v)
+                            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                        let ip = net::IpAddr::from_str(k.as_str())
+                            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+                        bans.insert(ip, ban);
+                    }
+                }
+                _ => return Err(io::ErrorKind::InvalidData.into()),
+            }
+        }
+
+        Ok(Self { bans, file })
+    }
+
+    /// Iterate over the banned addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (&net::IpAddr, &Ban)> {
+        self.bans.iter()
+    }
+
+    /// Ban an address, replacing any existing ban.
+    pub fn insert(&mut self, ip: net::IpAddr, ban: Ban) -> io::Result<()> {
+        self.bans.insert(ip, ban);
+        self.flush()
+    }
+
+    /// Lift a ban.
+    pub fn remove(&mut self, ip: &net::IpAddr) -> io::Result<()> {
+        if self.bans.remove(ip).is_some() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        use io::{Seek, Write};
+        use microserde::json::Value;
+
+        let bans: microserde::json::Object = self
+            .bans
+            .iter()
+            .map(|(ip, ban)| (ip.to_string(), ban.to_json()))
+            .collect();
+        let s = microserde::json::to_string(&Value::Object(bans));
+
+        self.file.set_len(0)?;
+        self.file.seek(io::SeekFrom::Start(0))?;
+        self.file.write_all(s.as_bytes())?;
+        self.file.write_all(&[b'\n'])?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use bitcoin::network::address::Address;
     use bitcoin::network::constants::ServiceFlags;
     use nakamoto_common::block::time::LocalTime;
 
@@ -155,10 +379,14 @@ mod test {
                 let sockaddr = net::SocketAddr::from((ip, 8333));
                 let services = ServiceFlags::NETWORK;
                 let ka = KnownAddress {
-                    addr: Address::new(&sockaddr, services),
+                    addr: sockaddr.into(),
+                    services,
                     source: Source::Dns,
                     last_success: Some(LocalTime::from_secs(i as u64)),
                     last_attempt: None,
+                    last_failure: None,
+                    attempts: 0,
+                    latency: None,
                 };
                 cache.insert(ip, ka);
             }
@@ -182,4 +410,95 @@ mod test {
             assert_eq!(actual, expected);
         }
     }
+
+    #[test]
+    fn test_save_and_load_overlay() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("cache");
+        let onion: PeerAddr = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333"
+            .parse()
+            .unwrap();
+
+        let mut expected = KnownAddress::new(onion, ServiceFlags::NETWORK, Source::Imported);
+        expected.last_success = Some(LocalTime::from_secs(42));
+
+        {
+            let mut cache = Cache::create(&path).unwrap();
+
+            assert!(cache.insert_overlay(expected.clone()));
+            assert!(!cache.insert_overlay(expected.clone()));
+            assert!(!cache.insert_overlay(KnownAddress::new(
+                PeerAddr::Ip(([88, 13, 16, 1], 8333).into()),
+                ServiceFlags::NETWORK,
+                Source::Imported,
+            )));
+            cache.flush().unwrap();
+        }
+
+        {
+            let cache = Cache::open(&path).unwrap();
+            let actual = cache.overlay().cloned().collect::<Vec<_>>();
+
+            assert!(cache.is_empty());
+            assert_eq!(actual, vec![expected]);
+        }
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("bans");
+        let alice = net::IpAddr::from([88, 13, 16, 1]);
+        let bob = net::IpAddr::from([88, 13, 16, 2]);
+        let ban = Ban::new("misbehaving", LocalTime::from_secs(1024));
+
+        {
+            let mut bans = BanList::open(&path).unwrap();
+
+            bans.insert(alice, ban.clone()).unwrap();
+            bans.insert(bob, ban.clone()).unwrap();
+            bans.remove(&bob).unwrap();
+        }
+
+        {
+            let bans = BanList::open(&path).unwrap();
+            let actual = bans
+                .iter()
+                .map(|(ip, ban)| (*ip, ban.clone()))
+                .collect::<Vec<_>>();
+
+            assert_eq!(actual, vec![(alice, ban)]);
+        }
+    }
+
+    #[test]
+    fn test_anchors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("anchors");
+        let addrs: Vec<net::SocketAddr> = (1..=MAX_ANCHORS as u8 + 1)
+            .map(|i| ([88, 13, 16, i], 8333).into())
+            .collect();
+
+        {
+            let mut anchors = Anchors::open(&path).unwrap();
+            assert!(anchors.addrs().is_empty());
+
+            for addr in &addrs {
+                anchors.insert(*addr).unwrap();
+            }
+            assert_eq!(anchors.addrs().len(), MAX_ANCHORS);
+            assert_eq!(anchors.addrs()[0], *addrs.last().unwrap());
+
+            let latest = anchors.addrs()[0];
+            anchors.remove(&latest).unwrap();
+        }
+
+        {
+            let anchors = Anchors::open(&path).unwrap();
+            assert_eq!(
+                anchors.addrs(),
+                &addrs[addrs.len() - MAX_ANCHORS..addrs.len() - 1]
+            );
+        }
+    }
 }
@@ -0,0 +1,187 @@
+//! Export of the address book, for debugging.
+//!
+//! Each address is written along with its selection score, address range bucket and
+//! connection timestamps, so that operators can inspect why some peers are picked over
+//! others.
+use std::io;
+use std::str::FromStr;
+
+use microserde::json::{Number, Object, Value};
+use thiserror::Error;
+
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_p2p::protocol::addrmgr::AddressInfo;
+
+/// Column names of the CSV format.
+const CSV_HEADER: &str = "address,services,source,score,bucket,connected,\
+                          last_success,last_attempt,last_failure,attempts,latency_ms";
+
+/// An error parsing an export format.
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown export format `{0}`, expected `json` or `csv`")]
+pub struct UnknownFormat(String);
+
+/// Address book export format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON array of objects.
+    Json,
+    /// Comma-separated values, with a header row.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// Write address book entries in the given format.
+pub fn write<W: io::Write>(mut w: W, entries: &[AddressInfo], format: Format) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            let ary = entries.iter().map(self::to_json).collect();
+            let s = microserde::json::to_string(&Value::Array(ary));
+
+            writeln!(w, "{}", s)
+        }
+        Format::Csv => {
+            let time =
+                |t: Option<LocalTime>| t.map_or(String::new(), |t| t.block_time().to_string());
+
+            writeln!(w, "{}", CSV_HEADER)?;
+
+            for e in entries {
+                let ka = &e.known;
+
+                writeln!(
+                    w,
+                    "{},{},{},{},{},{},{},{},{},{},{}",
+                    net_addr(e),
+                    ka.services.as_u64(),
+                    ka.source,
+                    e.score,
+                    e.bucket,
+                    e.connected,
+                    time(ka.last_success),
+                    time(ka.last_attempt),
+                    time(ka.last_failure),
+                    ka.attempts,
+                    ka.latency
+                        .map_or(String::new(), |l| l.as_millis().to_string()),
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Get the address of an entry, as a string.
+fn net_addr(e: &AddressInfo) -> String {
+    e.known.addr.to_string()
+}
+
+/// Convert an entry to a JSON value.
+fn to_json(e: &AddressInfo) -> Value {
+    let ka = &e.known;
+    let time = |t: Option<LocalTime>| match t {
+        Some(t) => Value::Number(Number::U64(t.block_time() as u64)),
+        None => Value::Null,
+    };
+    let mut obj = Object::new();
+
+    obj.insert("address".to_owned(), Value::String(net_addr(e)));
+    obj.insert(
+        "services".to_owned(),
+        Value::Number(Number::U64(ka.services.as_u64())),
+    );
+    obj.insert("source".to_owned(), Value::String(ka.source.to_string()));
+    obj.insert("score".to_owned(), Value::Number(Number::F64(e.score)));
+    obj.insert(
+        "bucket".to_owned(),
+        Value::Number(Number::U64(e.bucket as u64)),
+    );
+    obj.insert("connected".to_owned(), Value::Bool(e.connected));
+    obj.insert("last_success".to_owned(), time(ka.last_success));
+    obj.insert("last_attempt".to_owned(), time(ka.last_attempt));
+    obj.insert("last_failure".to_owned(), time(ka.last_failure));
+    obj.insert(
+        "attempts".to_owned(),
+        Value::Number(Number::U64(ka.attempts as u64)),
+    );
+    obj.insert(
+        "latency_ms".to_owned(),
+        match ka.latency {
+            Some(l) => Value::Number(Number::U64(l.as_millis() as u64)),
+            None => Value::Null,
+        },
+    );
+
+    Value::Object(obj)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net;
+
+    use nakamoto_common::block::time::LocalDuration;
+    use nakamoto_common::p2p::peer::{KnownAddress, Source};
+    use nakamoto_p2p::bitcoin::network::constants::ServiceFlags;
+
+    #[test]
+    fn test_export() {
+        let addr: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+        let mut known = KnownAddress::new(addr.into(), ServiceFlags::NETWORK, Source::Dns);
+
+        known.last_success = Some(LocalTime::from_block_time(1_600_000_000));
+        known.attempts = 1;
+        known.latency = Some(LocalDuration::from_millis(120));
+
+        let entries = vec![AddressInfo {
+            ip: addr.ip(),
+            known,
+            score: 2.,
+            bucket: 7,
+            connected: true,
+        }];
+
+        let mut csv = Vec::new();
+        write(&mut csv, &entries, Format::Csv).unwrap();
+
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            format!(
+                "{}\n88.13.16.59:8333,1,DNS,2,7,true,1600000000,,,1,120\n",
+                CSV_HEADER
+            )
+        );
+
+        let mut json = Vec::new();
+        write(&mut json, &entries, Format::Json).unwrap();
+
+        let val: Value = microserde::json::from_str(std::str::from_utf8(&json).unwrap()).unwrap();
+        match val {
+            Value::Array(ary) => match &ary[..] {
+                [Value::Object(obj)] => {
+                    assert!(matches!(
+                        obj.get("address"),
+                        Some(Value::String(a)) if a == "88.13.16.59:8333"
+                    ));
+                    assert!(matches!(obj.get("last_attempt"), Some(Value::Null)));
+                }
+                _ => panic!("expected a single object"),
+            },
+            _ => panic!("expected an array"),
+        }
+
+        assert_eq!("CSV".parse::<Format>(), Ok(Format::Csv));
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
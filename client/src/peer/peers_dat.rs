@@ -0,0 +1,405 @@
+//! Import of peer addresses from Bitcoin Core's `peers.dat` address database.
+//!
+//! The file consists of the network magic, followed by the serialized address manager,
+//! and a double-SHA256 checksum of everything that precedes it. We only read the
+//! address entries, and ignore Bitcoin Core's bucketing information.
+use std::path::Path;
+use std::{fs, io};
+
+use thiserror::Error;
+
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_common::block::BlockTime;
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::addr::{PeerAddr, BIP155_IPV4, BIP155_IPV6};
+use nakamoto_common::p2p::peer::{KnownAddress, Source, Store};
+
+use nakamoto_p2p::bitcoin::hashes::{sha256d, Hash};
+use nakamoto_p2p::bitcoin::network::constants::ServiceFlags;
+use nakamoto_p2p::protocol::addrmgr;
+
+/// Highest address manager format version we know how to read.
+const MAX_FORMAT: u8 = 4;
+/// First format version using BIP155 (`addrv2`) address encoding.
+const FORMAT_BIP155: u8 = 3;
+/// Offset added to the lowest compatible format version, when stored.
+const INCOMPATIBILITY_BASE: u8 = 32;
+/// Flag set in an address's disk version when it uses BIP155 encoding.
+const DISK_VERSION_ADDRV2: u32 = 1 << 29;
+/// Maximum number of entries we expect in a valid file.
+const MAX_ENTRIES: i32 = 1024 * 64 + 256 * 64;
+/// Maximum size of a BIP155 network address.
+const MAX_ADDRV2_SIZE: u64 = 512;
+
+/// An error reading a `peers.dat` file.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The file is for a different network.
+    #[error("network magic mismatch: {0:#x}")]
+    Magic(u32),
+    /// The file format is too recent to be read.
+    #[error("unsupported format version {0}")]
+    UnsupportedFormat(u8),
+    /// The checksum doesn't match the file contents.
+    #[error("checksum mismatch")]
+    Checksum,
+    /// The file is corrupted.
+    #[error("invalid data: {0}")]
+    Invalid(&'static str),
+}
+
+/// An address entry read from a `peers.dat` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Peer address. IP, Tor v3 and I2P addresses are supported.
+    pub addr: PeerAddr,
+    /// Services advertised by the peer.
+    pub services: ServiceFlags,
+    /// Last time the address was seen on the network.
+    pub time: BlockTime,
+    /// Last time Bitcoin Core successfully connected to this address.
+    pub last_success: Option<LocalTime>,
+    /// Whether Bitcoin Core has successfully connected to this address before,
+    /// ie. whether it was in the "tried" table.
+    pub tried: bool,
+}
+
+impl Entry {
+    /// Convert the entry into a known address, as stored in our address book.
+    pub fn to_known_address(&self) -> KnownAddress {
+        let mut ka = KnownAddress::new(self.addr, self.services, Source::Imported);
+        ka.last_success = self.last_success;
+        ka
+    }
+}
+
+/// Read the address entries from a `peers.dat` file for the given network.
+pub fn read<P: AsRef<Path>>(path: P, network: Network) -> Result<Vec<Entry>, Error> {
+    let bytes = fs::read(path)?;
+
+    self::parse(&bytes, network)
+}
+
+/// Parse the address entries from the contents of a `peers.dat` file. Entries for
+/// networks other than IPv4, IPv6, Tor v3 and I2P are skipped.
+pub fn parse(bytes: &[u8], network: Network) -> Result<Vec<Entry>, Error> {
+    if bytes.len() < 32 {
+        return Err(Error::Invalid("file too short"));
+    }
+    let (data, checksum) = bytes.split_at(bytes.len() - 32);
+
+    if sha256d::Hash::hash(data)[..] != checksum[..] {
+        return Err(Error::Checksum);
+    }
+    let mut r = Reader::new(data);
+
+    let magic = r.u32()?;
+    if magic != network.magic() {
+        return Err(Error::Magic(magic));
+    }
+    let format = r.u8()?;
+    let compat = r.u8()?.saturating_sub(INCOMPATIBILITY_BASE);
+    if compat > MAX_FORMAT {
+        return Err(Error::UnsupportedFormat(format));
+    }
+    // The secret key used by Bitcoin Core to pick buckets. We don't need it.
+    r.bytes(32)?;
+
+    let new = r.i32()?;
+    let tried = r.i32()?;
+    // The number of buckets, which we don't need either.
+    r.i32()?;
+
+    if new < 0 || tried < 0 || new + tried > MAX_ENTRIES {
+        return Err(Error::Invalid("invalid entry count"));
+    }
+    let bip155 = format >= FORMAT_BIP155;
+    let mut entries = Vec::new();
+
+    for i in 0..new + tried {
+        if let Some(mut entry) = self::entry(&mut r, bip155)? {
+            entry.tried = i >= new;
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Import address entries into a peer store. Non-IP, non-routable and already known
+/// addresses are skipped. Returns the number of addresses imported.
+pub fn import<S: Store>(store: &mut S, entries: impl IntoIterator<Item = Entry>) -> usize {
+    let mut imported = 0;
+
+    for entry in entries {
+        let addr = match entry.addr.socket_addr() {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let ip = addr.ip();
+
+        if !addrmgr::is_routable(&ip) || addrmgr::is_local(&ip) {
+            continue;
+        }
+        if store.insert(ip, entry.to_known_address()) {
+            imported += 1;
+        }
+    }
+    imported
+}
+
+/// Read an address entry. Returns `None` if the address is on an unsupported network.
+fn entry(r: &mut Reader, bip155: bool) -> Result<Option<Entry>, Error> {
+    let version = r.u32()?;
+    let addrv2 = version & DISK_VERSION_ADDRV2 != 0;
+    let time = r.u32()?;
+    let services = if addrv2 { r.compact_size()? } else { r.u64()? };
+    let netaddr = self::netaddr(r, addrv2)?;
+    let port = r.u16_be()?;
+
+    // The source of the address, which we don't keep.
+    self::netaddr(r, bip155)?;
+
+    let last_success = r.i64()?;
+    // The number of failed attempts since the last success.
+    r.i32()?;
+
+    let addr =
+        netaddr.and_then(|(network, bytes)| PeerAddr::from_bip155(network, &bytes, port).ok());
+
+    Ok(addr.map(|addr| Entry {
+        addr,
+        services: ServiceFlags::from(services),
+        time,
+        last_success: if last_success > 0 {
+            Some(LocalTime::from_block_time(last_success as BlockTime))
+        } else {
+            None
+        },
+        tried: false,
+    }))
+}
+
+/// Read a network address, as its BIP155 network identifier and address bytes. Returns
+/// `None` if the address can't be represented in BIP155, eg. Tor v2 addresses.
+fn netaddr(r: &mut Reader, addrv2: bool) -> Result<Option<(u8, Vec<u8>)>, Error> {
+    if addrv2 {
+        let network = r.u8()?;
+        let len = r.compact_size()?;
+
+        if len > MAX_ADDRV2_SIZE {
+            return Err(Error::Invalid("network address too large"));
+        }
+        let bytes = r.bytes(len as usize)?;
+
+        Ok(Some((network, bytes.to_vec())))
+    } else {
+        let octets = r.bytes(16)?;
+
+        // IPv4 addresses are mapped into IPv6, ie. `::ffff:a.b.c.d`.
+        if octets[..10] == [0; 10] && octets[10..12] == [0xff, 0xff] {
+            return Ok(Some((BIP155_IPV4, octets[12..].to_vec())));
+        }
+        // Tor v2 and internal addresses are encoded in the `fd00::/8` range.
+        if octets[0] == 0xfd {
+            return Ok(None);
+        }
+        Ok(Some((BIP155_IPV6, octets.to_vec())))
+    }
+}
+
+/// A cursor over a byte slice, decoding Bitcoin Core's serialization format.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < n {
+            return Err(Error::Invalid("unexpected end of file"));
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut buf = [0; N];
+        buf.copy_from_slice(self.bytes(N)?);
+
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16_be(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn compact_size(&mut self) -> Result<u64, Error> {
+        match self.u8()? {
+            0xff => self.u64(),
+            0xfe => Ok(u32::from_le_bytes(self.array()?) as u64),
+            0xfd => Ok(u16::from_le_bytes(self.array()?) as u64),
+            n => Ok(n as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net;
+
+    /// Serialize a `peers.dat` file in the BIP155 format.
+    fn serialize(network: Network, new: &[Entry], tried: &[Entry]) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        buf.extend(&network.magic().to_le_bytes());
+        buf.push(FORMAT_BIP155);
+        buf.push(INCOMPATIBILITY_BASE + FORMAT_BIP155);
+        buf.extend(&[0xaa; 32]);
+        buf.extend(&(new.len() as i32).to_le_bytes());
+        buf.extend(&(tried.len() as i32).to_le_bytes());
+        buf.extend(&(1024 ^ (1 << 30) as i32).to_le_bytes());
+
+        for entry in new.iter().chain(tried) {
+            let ip = entry.addr.to_bip155();
+
+            buf.extend(&(DISK_VERSION_ADDRV2 | 220000).to_le_bytes());
+            buf.extend(&entry.time.to_le_bytes());
+            buf.push(entry.services.as_u64() as u8);
+            buf.push(ip.0);
+            buf.push(ip.1.len() as u8);
+            buf.extend(&ip.1);
+            buf.extend(&entry.addr.port().to_be_bytes());
+            // Source address.
+            buf.extend(&[BIP155_IPV4, 4, 1, 1, 1, 1]);
+            buf.extend(
+                &entry
+                    .last_success
+                    .map_or(0, |t| t.block_time() as i64)
+                    .to_le_bytes(),
+            );
+            buf.extend(&0i32.to_le_bytes());
+        }
+        // Bucket data, ignored.
+        buf.extend(&[0; 16]);
+
+        let checksum = sha256d::Hash::hash(&buf).into_inner();
+        buf.extend(&checksum);
+        buf
+    }
+
+    #[test]
+    fn test_parse() {
+        let network = Network::Mainnet;
+        let new = vec![
+            Entry {
+                addr: PeerAddr::Ip(([88, 13, 16, 59], 8333).into()),
+                services: ServiceFlags::NETWORK,
+                time: 1_600_000_000,
+                last_success: None,
+                tried: false,
+            },
+            Entry {
+                addr: "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333"
+                    .parse()
+                    .unwrap(),
+                services: ServiceFlags::NETWORK,
+                time: 1_600_000_000,
+                last_success: None,
+                tried: false,
+            },
+        ];
+        let tried = vec![Entry {
+            addr: "[2001:db8::1]:8333".parse().unwrap(),
+            services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+            time: 1_600_000_100,
+            last_success: Some(LocalTime::from_block_time(1_600_000_050)),
+            tried: true,
+        }];
+        let bytes = serialize(network, &new, &tried);
+        let entries = parse(&bytes, network).unwrap();
+
+        assert_eq!(entries, [new, tried].concat());
+
+        // Files for other networks are rejected.
+        assert!(matches!(
+            parse(&bytes, Network::Testnet),
+            Err(Error::Magic(_))
+        ));
+
+        // Corrupted files are rejected.
+        let mut corrupted = bytes.clone();
+        corrupted[40] ^= 1;
+        assert!(matches!(parse(&corrupted, network), Err(Error::Checksum)));
+    }
+
+    #[test]
+    fn test_import() {
+        let mut store: HashMap<net::IpAddr, KnownAddress> = HashMap::new();
+        let entries = vec![
+            Entry {
+                addr: PeerAddr::Ip(([88, 13, 16, 59], 8333).into()),
+                services: ServiceFlags::NETWORK,
+                time: 0,
+                last_success: None,
+                tried: false,
+            },
+            Entry {
+                addr: PeerAddr::Ip(([192, 168, 1, 2], 8333).into()),
+                services: ServiceFlags::NETWORK,
+                time: 0,
+                last_success: None,
+                tried: false,
+            },
+            Entry {
+                addr: "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333"
+                    .parse()
+                    .unwrap(),
+                services: ServiceFlags::NETWORK,
+                time: 0,
+                last_success: None,
+                tried: false,
+            },
+        ];
+
+        assert_eq!(import(&mut store, entries.clone()), 1);
+        assert_eq!(
+            import(&mut store, entries),
+            0,
+            "known addresses are skipped"
+        );
+        assert_eq!(
+            store[&net::IpAddr::from([88, 13, 16, 59])].source,
+            Source::Imported
+        );
+    }
+}
@@ -1,16 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net;
 use std::thread;
+use std::time;
 
 use nakamoto_chain::block::cache::BlockCache;
 use nakamoto_chain::block::store;
 use nakamoto_chain::filter::cache::FilterCache;
 use nakamoto_common::block::Height;
 use nakamoto_p2p::protocol::syncmgr;
+use nakamoto_test::bitcoind::Bitcoind;
 use nakamoto_test::{logger, BITCOIN_HEADERS};
 
-use crate::client::{self, Client, Config, Event};
+use crate::client::{self, Client, Config, Event, Network};
 use crate::error;
+use crate::event::ClientEvent;
 use crate::handle::Handle as _;
 
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream>;
@@ -27,10 +30,13 @@ fn network(
 > {
     let mut handles = Vec::new();
 
-    for cfg in cfgs.iter().cloned() {
+    for mut cfg in cfgs.iter().cloned() {
         let checkpoints = cfg.network.checkpoints().collect::<Vec<_>>();
         let genesis = cfg.network.genesis();
         let params = cfg.network.params();
+        // Each node keeps its anchors and bans in its own home directory.
+        let home = tempfile::tempdir()?;
+        cfg.home = home.path().to_owned();
 
         let node = Client::new(cfg)?;
         let handle = node.handle();
@@ -40,6 +46,7 @@ fn network(
             let checkpoints = checkpoints.clone();
 
             move || {
+                let _home = home;
                 let store = store::Memory::new((genesis, vec![]).into());
                 let cache = BlockCache::from(store, params, &checkpoints).unwrap();
                 let filters = FilterCache::from(store::Memory::default()).unwrap();
@@ -68,6 +75,43 @@ fn network(
     Ok(handles)
 }
 
+#[test]
+fn test_protocol_config() {
+    use nakamoto_p2p::protocol::{self, addrmgr, memory};
+
+    let ip: net::IpAddr = [88, 13, 16, 59].into();
+    let limits = memory::Limits {
+        orphans: Some(1024),
+        addresses: None,
+    };
+    let cfg = Config {
+        network: Network::Regtest,
+        whitelist: vec![ip],
+        user_agent: Some("wallet:1.0".to_owned()),
+        advertise: addrmgr::Advertise::Discovered(8333),
+        memory_limits: limits,
+        ..Config::default()
+    };
+    let protocol: protocol::Config = cfg.clone().into();
+
+    assert!(protocol.whitelist.contains_addr(&ip));
+    assert_eq!(
+        protocol.user_agent,
+        format!("{}wallet:1.0/", protocol::USER_AGENT)
+    );
+    assert_eq!(protocol.advertise, addrmgr::Advertise::Discovered(8333));
+    assert_eq!(protocol.memory_limits, limits);
+    assert_eq!(protocol.params.network, Network::Regtest.params().network);
+
+    // Our address isn't advertised when we don't accept inbound connections.
+    let protocol: protocol::Config = Config {
+        connect_only: true,
+        ..cfg
+    }
+    .into();
+    assert_eq!(protocol.advertise, addrmgr::Advertise::Never);
+}
+
 #[test]
 fn test_full_sync() {
     logger::init(log::Level::Debug);
@@ -97,3 +141,152 @@ fn test_full_sync() {
         thread.join().unwrap();
     }
 }
+
+#[test]
+fn test_wait_for_height_clones() {
+    let nodes = network(&[Config::default()]).unwrap();
+    let (handle, _, t) = nodes.into_iter().next().unwrap();
+    let headers = BITCOIN_HEADERS.tail.clone();
+    let height = headers.len() as Height;
+    let hash = headers.last().unwrap().block_hash();
+
+    // Clones waiting on the same event all receive it.
+    let waiters = (0..3)
+        .map(|_| {
+            let handle = handle.clone();
+            thread::spawn(move || handle.wait_for_height(height).unwrap())
+        })
+        .collect::<Vec<_>>();
+
+    handle
+        .import_headers(headers)
+        .expect("command is successful")
+        .expect("chain is valid");
+
+    for waiter in waiters {
+        assert_eq!(waiter.join().unwrap(), hash);
+    }
+    // Once the height is reached, waiting for it returns immediately.
+    assert_eq!(handle.wait_for_height(height).unwrap(), hash);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+/// Spawn a client connected only to the given `bitcoind`.
+fn regtest(bitcoind: &Bitcoind) -> (client::Handle<Reactor>, thread::JoinHandle<()>) {
+    let home = tempfile::tempdir().unwrap();
+    let cfg = Config {
+        network: Network::Regtest,
+        connect: vec![bitcoind.p2p_addr],
+        connect_only: true,
+        listen: vec![],
+        home: home.path().to_owned(),
+        ..Config::default()
+    };
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let node = Client::new(cfg).unwrap();
+    let handle = node.handle();
+
+    let t = thread::spawn(move || {
+        let _home = home;
+        let store = store::Memory::new((genesis, vec![]).into());
+        let cache = BlockCache::from(store, params, &[]).unwrap();
+        let filters = FilterCache::from(store::Memory::default()).unwrap();
+        let peers = HashMap::new();
+
+        node.run_with(cache, filters, peers).unwrap();
+    });
+    (handle, t)
+}
+
+/// Check the given condition until it holds, or a timeout is reached.
+fn eventually<F: FnMut() -> bool>(mut f: F) -> bool {
+    let deadline = time::Instant::now() + time::Duration::from_secs(30);
+
+    while time::Instant::now() < deadline {
+        if f() {
+            return true;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    false
+}
+
+// The following tests require `bitcoind` to be installed. See [`nakamoto_test::bitcoind`].
+// Run them with `cargo test -p nakamoto-client -- --ignored`.
+
+#[test]
+#[ignore]
+fn test_bitcoind_sync() {
+    let bitcoind = Bitcoind::spawn().unwrap();
+    let blocks = bitcoind.generate(10).unwrap();
+    let (handle, t) = regtest(&bitcoind);
+
+    assert_eq!(handle.wait_for_height(10).unwrap(), blocks[9]);
+
+    // Blocks mined once we're synced are announced to us.
+    let blocks = bitcoind.generate(1).unwrap();
+    assert_eq!(handle.wait_for_height(11).unwrap(), blocks[0]);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+#[ignore]
+fn test_bitcoind_reorg() {
+    let bitcoind = Bitcoind::spawn().unwrap();
+    let blocks = bitcoind.generate(5).unwrap();
+    let (handle, t) = regtest(&bitcoind);
+
+    assert_eq!(handle.wait_for_height(5).unwrap(), blocks[4]);
+
+    let events = handle.subscribe();
+
+    // Replace the last three blocks with a longer fork.
+    bitcoind.invalidate(&blocks[2]).unwrap();
+    let fork = bitcoind.generate(4).unwrap();
+
+    assert_eq!(bitcoind.height().unwrap(), 6);
+
+    let reverted = loop {
+        match events.recv_timeout(time::Duration::from_secs(30)).unwrap() {
+            ClientEvent::TipChanged { reverted, .. } if !reverted.is_empty() => break reverted,
+            _ => {}
+        }
+    };
+
+    assert_eq!(
+        reverted.into_iter().collect::<HashSet<_>>(),
+        blocks[2..].iter().cloned().collect::<HashSet<_>>()
+    );
+    assert!(eventually(
+        || handle.get_tip().unwrap().1.block_hash() == fork[3]
+    ));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+#[ignore]
+fn test_bitcoind_transaction() {
+    let bitcoind = Bitcoind::spawn().unwrap();
+    // Coinbase outputs can only be spent after 100 blocks.
+    bitcoind.generate(101).unwrap();
+
+    let (handle, t) = regtest(&bitcoind);
+    handle.wait_for_height(101).unwrap();
+
+    let tx = bitcoind.transaction().unwrap();
+    let txid = tx.txid();
+
+    handle.submit_transaction(tx).unwrap();
+
+    assert!(eventually(|| bitcoind.mempool().unwrap().contains(&txid)));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -2,6 +2,7 @@
 #![warn(missing_docs)]
 
 use std::io;
+use std::mem;
 use std::ops::Range;
 
 use thiserror::Error;
@@ -165,4 +166,14 @@ pub trait Filters {
     }
     /// Rollback chain by the given number of headers.
     fn rollback(&mut self, n: usize) -> Result<(), Error>;
+    /// Approximate memory used by the filter header chain, in bytes.
+    fn memory_usage(&self) -> usize {
+        (self.height() as usize + 1) * mem::size_of::<(FilterHash, FilterHeader)>()
+    }
+    /// Write any buffered headers to the underlying store, and synchronize the store to
+    /// disk, eg. before shutting down. Caches that aren't persisted don't need to
+    /// implement this.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
@@ -22,6 +22,27 @@ pub enum Error {
     Corruption,
 }
 
+/// When a store synchronizes its changes to disk, ie. calls `fsync`. Only affects
+/// durability on crash or power loss: changes are always visible to readers once flushed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Synchronize after every flushed batch of headers. This is the safest option, but
+    /// can slow down initial sync considerably, especially on spinning disks.
+    Batch,
+    /// Synchronize flushed changes at most once per interval. Changes made since the last
+    /// synchronization may be lost on crash, and have to be fetched again from peers.
+    Periodic(std::time::Duration),
+    /// Never synchronize explicitly, and leave it to the operating system. This is the
+    /// fastest option, and is intended for tests and regtest.
+    Never,
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        Self::Batch
+    }
+}
+
 /// Represents an object (such as a header), that has a genesis.
 pub trait Genesis {
     /// Create a genesis header.
@@ -50,6 +71,12 @@ pub trait Store {
     fn rollback(&mut self, height: Height) -> Result<(), Error>;
     /// Synchronize the changes to disk.
     fn sync(&mut self) -> Result<(), Error>;
+    /// Write buffered changes to the underlying storage, and synchronize them to disk
+    /// if the store's [`SyncPolicy`] requires it. Stores that don't buffer writes don't
+    /// need to implement this.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
     /// Iterate over all headers in the store.
     fn iter(&self) -> Box<dyn Iterator<Item = Result<(Height, Self::Header), Error>>>;
     /// Return the number of headers in the store.
@@ -1,5 +1,5 @@
 //! Block time and other time-related types.
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -8,6 +8,10 @@ use super::{BlockTime, Height};
 /// Maximum time adjustment between network and local time (70 minutes).
 pub const MAX_TIME_ADJUSTMENT: TimeOffset = 70 * 60;
 
+/// Network time offset beyond which our local clock is considered to be wrong
+/// (10 minutes). This is the threshold at which Bitcoin Core warns about clock skew.
+pub const MAX_CLOCK_SKEW: TimeOffset = 10 * 60;
+
 /// Maximum a block timestamp can exceed the network-adjusted time before
 /// it is considered invalid (2 hours).
 pub const MAX_FUTURE_BLOCK_TIME: BlockTime = 60 * 60 * 2;
@@ -65,6 +69,11 @@ impl LocalTime {
         }
     }
 
+    /// Construct a local time from milliseconds since Epoch.
+    pub const fn from_millis(millis: u128) -> Self {
+        Self { millis }
+    }
+
     /// Convert a block time into a local time.
     pub fn from_block_time(t: BlockTime) -> Self {
         Self::from_secs(t as u64)
@@ -78,6 +87,11 @@ impl LocalTime {
         (self.millis / 1000).try_into().unwrap()
     }
 
+    /// Return the local time as milliseconds since Epoch.
+    pub fn as_millis(&self) -> u128 {
+        self.millis
+    }
+
     /// Get the duration since the given time.
     ///
     /// # Panics
@@ -224,15 +238,18 @@ impl From<LocalDuration> for std::time::Duration {
 /// Since we store only time offsets for each peer, the network-adjusted time is
 /// the local time plus the median offset of all connected peers.
 ///
-/// Nb. Network time is never adjusted more than 70 minutes from local system time.
+/// Nb. Network time is never adjusted more than [`MAX_CLOCK_SKEW`] from local system time.
+/// If the median offset exceeds it, our own clock is likely wrong, and we stop adjusting
+/// altogether.
 #[derive(Debug, Clone)]
 pub struct AdjustedTime<K> {
-    /// Sample sources. Prevents us from getting two samples from the same source.
-    sources: HashSet<K>,
-    /// Time offset samples.
-    samples: Vec<TimeOffset>,
+    /// Time offset samples, by source. Prevents us from getting two samples from the
+    /// same source.
+    samples: HashMap<K, TimeOffset>,
     /// Current time offset, based on our samples.
     offset: TimeOffset,
+    /// Median time offset that exceeded the maximum clock skew, if any.
+    skew: Option<TimeOffset>,
     /// Last known local time.
     local_time: LocalTime,
 }
@@ -255,25 +272,23 @@ impl<K: Hash + Eq> Default for AdjustedTime<K> {
 
 impl<K: Hash + Eq> AdjustedTime<K> {
     /// Create a new network-adjusted time tracker.
-    /// Starts with a single sample of zero.
+    /// Starts with a single sample of zero, which is our own clock.
     pub fn new(local_time: LocalTime) -> Self {
-        let offset = 0;
-
-        let mut samples = Vec::with_capacity(MAX_TIME_SAMPLES);
-        samples.push(offset);
-
-        let sources = HashSet::with_capacity(MAX_TIME_SAMPLES);
+        let samples = HashMap::with_capacity(MAX_TIME_SAMPLES);
 
         Self {
-            sources,
             samples,
-            offset,
+            offset: 0,
+            skew: None,
             local_time,
         }
     }
 
     /// Add a time sample to influence the network-adjusted time.
-    pub fn record_offset(&mut self, source: K, sample: TimeOffset) {
+    ///
+    /// Returns the median offset if our clock was just found to be skewed. See
+    /// [`AdjustedTime::skew`].
+    pub fn record_offset(&mut self, source: K, sample: TimeOffset) -> Option<TimeOffset> {
         // Nb. This behavior is based on Bitcoin Core. An alternative is to truncate the
         // samples list, to never exceed `MAX_TIME_SAMPLES`, and allow new samples to be
         // added to the list, while the set of sample sources keeps growing. This has the
@@ -284,24 +299,58 @@ impl<K: Hash + Eq> AdjustedTime<K> {
         // reach `MAX_TIME_SAMPLES + 1`, since there is always an initial `0` sample with
         // no associated source.
         //
-        // Finally, we never remove sources. Even after peers disconnect. This is congruent
-        // with Bitcoin Core behavior. I'm not sure why that is.
-        if self.sources.len() == MAX_TIME_SAMPLES {
-            return;
+        // Unlike Bitcoin Core, samples are removed when their source disconnects, so that
+        // the offset always reflects the peers we're connected to.
+        if self.samples.len() == MAX_TIME_SAMPLES {
+            return None;
         }
-        if !self.sources.insert(source) {
-            return;
+        if self.samples.contains_key(&source) {
+            return None;
+        }
+        self.samples.insert(source, sample);
+        self.adjust()
+    }
+
+    /// Remove the time sample of the given source, eg. when it disconnects.
+    ///
+    /// Returns the median offset if our clock was just found to be skewed. See
+    /// [`AdjustedTime::skew`].
+    pub fn remove_offset(&mut self, source: &K) -> Option<TimeOffset> {
+        if self.samples.remove(source).is_some() {
+            self.adjust()
+        } else {
+            None
         }
-        self.samples.push(sample);
+    }
+
+    /// Get the median time offset that exceeded [`MAX_CLOCK_SKEW`], if any. Once this
+    /// happens, our time offset is reset to zero and no longer adjusted, since block
+    /// header timestamps are validated against the network-adjusted time.
+    pub fn skew(&self) -> Option<TimeOffset> {
+        self.skew
+    }
+
+    /// Get the sources we've recorded a time sample from.
+    pub fn sources(&self) -> impl Iterator<Item = &K> {
+        self.samples.keys()
+    }
 
-        let mut offsets = self.samples.clone();
+    /// Re-compute the time offset from our samples.
+    fn adjust(&mut self) -> Option<TimeOffset> {
+        if self.skew.is_some() {
+            return None;
+        }
+        let mut offsets = std::iter::once(0)
+            .chain(self.samples.values().cloned())
+            .collect::<Vec<_>>();
         let count = offsets.len();
 
         offsets.sort_unstable();
 
         // Don't adjust if less than 5 samples exist.
         if count < MIN_TIME_SAMPLES {
-            return;
+            self.offset = 0;
+            return None;
         }
 
         // Only adjust when a true median is found.
@@ -313,16 +362,25 @@ impl<K: Hash + Eq> AdjustedTime<K> {
             let median_offset: TimeOffset = offsets[count / 2];
 
             // Don't let other nodes change our time by more than a certain amount.
-            if median_offset.abs() <= MAX_TIME_ADJUSTMENT {
-                self.offset = median_offset;
-            } else {
-                // TODO: Check whether other nodes have times similar to ours, otherwise
-                // log a warning about our clock possibly being wrong.
+            // If most of them disagree with us by that much, it's our clock that is
+            // likely wrong.
+            if median_offset.abs() > MAX_CLOCK_SKEW {
                 self.offset = 0;
+                self.skew = Some(median_offset);
+
+                #[cfg(feature = "log")]
+                log::warn!(
+                    "Network time offset of {} seconds is too large, check your clock",
+                    median_offset
+                );
+                return self.skew;
             }
+            self.offset = median_offset;
+
             #[cfg(feature = "log")]
             log::debug!("Time offset adjusted to {} seconds", self.offset);
         };
+        None
     }
 
     /// Get the median network time offset.
@@ -377,7 +435,8 @@ mod tests {
         for i in 3.. {
             adjusted_time.record_offset(([127, 0, 0, i], 8333).into(), MAX_TIME_ADJUSTMENT + 1);
 
-            if adjusted_time.samples.len() >= MIN_TIME_SAMPLES {
+            // Including the initial sample.
+            if adjusted_time.samples.len() + 1 >= MIN_TIME_SAMPLES {
                 break;
             }
         }
@@ -425,6 +484,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_adjusted_time_remove() {
+        let mut adjusted_time: AdjustedTime<SocketAddr> = AdjustedTime::default();
+        let source = |i| SocketAddr::from(([127, 0, 0, i], 8333));
+
+        for i in 1..5 {
+            adjusted_time.record_offset(source(i), 96);
+        } // samples = [0, 96, 96, 96, 96]
+        assert_eq!(adjusted_time.offset(), 96);
+
+        adjusted_time.remove_offset(&source(1));
+        assert_eq!(
+            adjusted_time.offset(),
+            0,
+            "Not enough samples are left to adjust"
+        ); // samples = [0, 96, 96, 96]
+
+        adjusted_time.record_offset(source(1), -96);
+        adjusted_time.record_offset(source(5), -96);
+        adjusted_time.record_offset(source(6), -96);
+        assert_eq!(adjusted_time.offset(), 0); // samples = [-96, -96, -96, 0, 96, 96, 96]
+
+        adjusted_time.remove_offset(&source(2));
+        adjusted_time.remove_offset(&source(3));
+        assert_eq!(adjusted_time.offset(), -96); // samples = [-96, -96, -96, 0, 96]
+
+        adjusted_time.remove_offset(&source(2));
+        assert_eq!(
+            adjusted_time.offset(),
+            -96,
+            "Removing an unknown source has no effect"
+        );
+        assert_eq!(adjusted_time.sources().count(), 4);
+    }
+
+    #[test]
+    fn test_adjusted_time_skew() {
+        let mut adjusted_time: AdjustedTime<SocketAddr> = AdjustedTime::default();
+        let source = |i| SocketAddr::from(([127, 0, 0, i], 8333));
+
+        for i in 1..4 {
+            assert_eq!(
+                adjusted_time.record_offset(source(i), MAX_CLOCK_SKEW + 1),
+                None
+            );
+        }
+        assert_eq!(
+            adjusted_time.record_offset(source(4), MAX_CLOCK_SKEW + 1),
+            Some(MAX_CLOCK_SKEW + 1)
+        ); // samples = [0, 601, 601, 601, 601]
+        assert_eq!(adjusted_time.skew(), Some(MAX_CLOCK_SKEW + 1));
+        assert_eq!(adjusted_time.offset(), 0);
+
+        // No further adjustments are made.
+        for i in 5..9 {
+            assert_eq!(adjusted_time.record_offset(source(i), 42), None);
+        }
+        assert_eq!(adjusted_time.remove_offset(&source(1)), None);
+        assert_eq!(adjusted_time.offset(), 0);
+        assert_eq!(adjusted_time.skew(), Some(MAX_CLOCK_SKEW + 1));
+    }
+
     #[test]
     fn test_adjusted_time_max_samples() {
         let mut adjusted_time: AdjustedTime<SocketAddr> = AdjustedTime::default();
@@ -438,7 +559,8 @@ mod tests {
         for i in (MAX_TIME_SAMPLES / 2).. {
             adjusted_time.record_offset(([127, 0, 0, i as u8], 8333).into(), 1);
 
-            if adjusted_time.samples.len() == MAX_TIME_SAMPLES {
+            // Including the initial sample.
+            if adjusted_time.samples.len() + 1 == MAX_TIME_SAMPLES {
                 break;
             }
         }
@@ -450,7 +572,7 @@ mod tests {
         adjusted_time.record_offset(([127, 0, 0, 254], 8333).into(), 2);
         adjusted_time.record_offset(([127, 0, 0, 255], 8333).into(), 3);
         assert_eq!(
-            adjusted_time.sources.len(),
+            adjusted_time.samples.len(),
             MAX_TIME_SAMPLES,
             "Adding a sample after the maximum is reached, has no effect"
         );
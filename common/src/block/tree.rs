@@ -1,5 +1,7 @@
 //! Types and functions relating to block trees.
 #![warn(missing_docs)]
+use std::mem;
+
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::params::Params;
 use bitcoin::hash_types::BlockHash;
@@ -94,7 +96,8 @@ impl<'a, H: Header> Branch<'a, H> {
 
 /// A representation of all known blocks that keeps track of the longest chain.
 pub trait BlockTree {
-    /// Import a chain of block headers into the block tree.
+    /// Import a chain of block headers into the block tree. Headers that are already known
+    /// are skipped, so importing them again is a no-op.
     fn import_blocks<I: Iterator<Item = BlockHeader>, C: Clock>(
         &mut self,
         chain: I,
@@ -161,6 +164,21 @@ pub trait BlockTree {
     ) -> Vec<BlockHeader>;
     /// Get the locator hashes starting from the given height and going backwards.
     fn locator_hashes(&self, from: Height) -> Vec<BlockHash>;
+    /// Approximate memory used by the tree, in bytes, as a pair of the memory used by the
+    /// active chain, and by orphan and stale headers.
+    fn memory_usage(&self) -> (usize, usize) {
+        let headers = (self.height() as usize + 1) * mem::size_of::<BlockHeader>();
+        (headers, 0)
+    }
+    /// Discard orphan and stale headers, oldest first, until they use at most the given
+    /// number of bytes. Headers of the active chain are never discarded.
+    fn prune_orphans(&mut self, _max: usize) {}
+    /// Write any buffered headers to the underlying store, and synchronize the store to
+    /// disk, eg. before shutting down. Trees that aren't persisted don't need to
+    /// implement this.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
     /// Get the next difficulty given a block height, time and bits.
     fn next_difficulty_target(
         &self,
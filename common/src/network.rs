@@ -10,7 +10,7 @@ use bitcoin_hashes::sha256d;
 use crate::block::Height;
 
 /// Bitcoin peer network.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Network {
     /// Bitcoin Mainnet.
     Mainnet,
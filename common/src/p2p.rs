@@ -1,3 +1,5 @@
 //! P2P-related types
 
+pub mod addr;
+pub mod netgroup;
 pub mod peer;
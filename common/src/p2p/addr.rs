@@ -0,0 +1,465 @@
+//! Peer addresses.
+//!
+//! Besides IP addresses, peers may be reachable over overlay networks such as Tor and
+//! I2P, which have their own address formats. These addresses are encoded as described
+//! in BIP155 (`addrv2`), and displayed in the format used by their respective networks,
+//! eg. `"<base32>.onion:8333"`.
+use std::fmt;
+use std::net;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// BIP155 network identifier for IPv4.
+pub const BIP155_IPV4: u8 = 1;
+/// BIP155 network identifier for IPv6.
+pub const BIP155_IPV6: u8 = 2;
+/// BIP155 network identifier for Tor v3 onion services.
+pub const BIP155_TORV3: u8 = 4;
+/// BIP155 network identifier for I2P.
+pub const BIP155_I2P: u8 = 5;
+
+/// Tor v3 onion address version.
+const TORV3_VERSION: u8 = 3;
+/// Prefix of the data hashed to compute an onion address checksum.
+const TORV3_CHECKSUM: &[u8] = b".onion checksum";
+/// RFC 4648 base32 alphabet, in lowercase.
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// An error parsing or decoding a peer address.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The address could not be parsed.
+    #[error("invalid peer address")]
+    Invalid,
+    /// The onion address checksum doesn't match.
+    #[error("invalid onion address checksum")]
+    Checksum,
+    /// The network is unknown or not supported.
+    #[error("unsupported network {0}")]
+    UnsupportedNetwork(u8),
+}
+
+/// The address of a peer, on any of the supported networks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PeerAddr {
+    /// An IPv4 or IPv6 address.
+    Ip(net::SocketAddr),
+    /// A Tor v3 onion service, identified by its ed25519 public key.
+    Onion {
+        /// Public key of the onion service.
+        pubkey: [u8; 32],
+        /// Port.
+        port: u16,
+    },
+    /// An I2P destination, identified by the SHA-256 hash of the destination.
+    I2p {
+        /// Hash of the destination.
+        hash: [u8; 32],
+        /// Port. Always `0` with the I2P SAM 3.1 protocol.
+        port: u16,
+    },
+}
+
+impl PeerAddr {
+    /// Get the IP socket address, if this is an IP address.
+    pub fn socket_addr(&self) -> Option<net::SocketAddr> {
+        match self {
+            Self::Ip(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    /// Check whether this is an IP address.
+    pub fn is_ip(&self) -> bool {
+        matches!(self, Self::Ip(_))
+    }
+
+    /// Get the port.
+    pub fn port(&self) -> u16 {
+        match self {
+            Self::Ip(addr) => addr.port(),
+            Self::Onion { port, .. } | Self::I2p { port, .. } => *port,
+        }
+    }
+
+    /// Decode an address from its BIP155 network identifier and address bytes.
+    pub fn from_bip155(network: u8, bytes: &[u8], port: u16) -> Result<Self, Error> {
+        let array = |bytes: &[u8]| -> Result<[u8; 32], Error> {
+            let mut array = [0; 32];
+
+            if bytes.len() != array.len() {
+                return Err(Error::Invalid);
+            }
+            array.copy_from_slice(bytes);
+
+            Ok(array)
+        };
+
+        match network {
+            BIP155_IPV4 if bytes.len() == 4 => Ok(Self::Ip(net::SocketAddr::new(
+                net::IpAddr::from([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                port,
+            ))),
+            BIP155_IPV6 if bytes.len() == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(bytes);
+
+                Ok(Self::Ip(net::SocketAddr::new(
+                    net::IpAddr::from(octets),
+                    port,
+                )))
+            }
+            BIP155_TORV3 => Ok(Self::Onion {
+                pubkey: array(bytes)?,
+                port,
+            }),
+            BIP155_I2P => Ok(Self::I2p {
+                hash: array(bytes)?,
+                port,
+            }),
+            BIP155_IPV4 | BIP155_IPV6 => Err(Error::Invalid),
+            other => Err(Error::UnsupportedNetwork(other)),
+        }
+    }
+
+    /// Encode the address as its BIP155 network identifier and address bytes.
+    pub fn to_bip155(&self) -> (u8, Vec<u8>) {
+        match self {
+            Self::Ip(addr) => match addr.ip() {
+                net::IpAddr::V4(ip) => (BIP155_IPV4, ip.octets().to_vec()),
+                net::IpAddr::V6(ip) => (BIP155_IPV6, ip.octets().to_vec()),
+            },
+            Self::Onion { pubkey, .. } => (BIP155_TORV3, pubkey.to_vec()),
+            Self::I2p { hash, .. } => (BIP155_I2P, hash.to_vec()),
+        }
+    }
+}
+
+impl From<net::SocketAddr> for PeerAddr {
+    fn from(addr: net::SocketAddr) -> Self {
+        Self::Ip(addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Onion { pubkey, port } => {
+                let mut bytes = pubkey.to_vec();
+
+                bytes.extend(&onion_checksum(pubkey));
+                bytes.push(TORV3_VERSION);
+
+                write!(f, "{}.onion:{}", base32_encode(&bytes), port)
+            }
+            Self::I2p { hash, port } => write!(f, "{}.b32.i2p:{}", base32_encode(hash), port),
+        }
+    }
+}
+
+impl FromStr for PeerAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<net::SocketAddr>() {
+            return Ok(Self::Ip(addr));
+        }
+        let mut parts = s.rsplitn(2, ':');
+        let (port, host) = (parts.next(), parts.next());
+        let (host, port) = match (host, port) {
+            (Some(host), Some(port)) => (host, port.parse().map_err(|_| Error::Invalid)?),
+            _ => return Err(Error::Invalid),
+        };
+
+        if let Some(name) = host.strip_suffix(".onion") {
+            let bytes = base32_decode(name).ok_or(Error::Invalid)?;
+
+            if bytes.len() != 35 || bytes[34] != TORV3_VERSION {
+                return Err(Error::Invalid);
+            }
+            let mut pubkey = [0; 32];
+            pubkey.copy_from_slice(&bytes[..32]);
+
+            if bytes[32..34] != onion_checksum(&pubkey) {
+                return Err(Error::Checksum);
+            }
+            Ok(Self::Onion { pubkey, port })
+        } else if let Some(name) = host.strip_suffix(".b32.i2p") {
+            let bytes = base32_decode(name).ok_or(Error::Invalid)?;
+
+            if bytes.len() != 32 {
+                return Err(Error::Invalid);
+            }
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&bytes);
+
+            Ok(Self::I2p { hash, port })
+        } else {
+            Err(Error::Invalid)
+        }
+    }
+}
+
+/// Compute the checksum of a Tor v3 onion address.
+fn onion_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut data = TORV3_CHECKSUM.to_vec();
+
+    data.extend(pubkey);
+    data.push(TORV3_VERSION);
+
+    let hash = sha3_256(&data);
+
+    [hash[0], hash[1]]
+}
+
+/// Encode bytes as unpadded, lowercase RFC 4648 base32.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            s.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        s.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    s
+}
+
+/// Decode unpadded RFC 4648 base32, in either case.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in s.bytes() {
+        let c = c.to_ascii_lowercase();
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+
+        buffer = (buffer << 5) | value;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    // Leftover bits must be zero padding.
+    if buffer & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    Some(bytes)
+}
+
+/// Keccak round constants.
+const KECCAK_RC: [u64; 24] = [
+    0x0000000000000001,
+    0x0000000000008082,
+    0x800000000000808a,
+    0x8000000080008000,
+    0x000000000000808b,
+    0x0000000080000001,
+    0x8000000080008081,
+    0x8000000000008009,
+    0x000000000000008a,
+    0x0000000000000088,
+    0x0000000080008009,
+    0x000000008000000a,
+    0x000000008000808b,
+    0x800000000000008b,
+    0x8000000000008089,
+    0x8000000000008003,
+    0x8000000000008002,
+    0x8000000000000080,
+    0x000000000000800a,
+    0x800000008000000a,
+    0x8000000080008081,
+    0x8000000000008080,
+    0x0000000080000001,
+    0x8000000080008008,
+];
+/// Keccak rotation offsets, in the order of the `pi` step.
+const KECCAK_ROTC: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+/// Keccak lane permutation of the `pi` step.
+const KECCAK_PILN: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+/// The Keccak-f\[1600\] permutation.
+fn keccak_f(state: &mut [u64; 25]) {
+    for rc in KECCAK_RC.iter() {
+        // Theta.
+        let mut c = [0u64; 5];
+        for (x, c) in c.iter_mut().enumerate() {
+            *c = state[x] ^ state[x + 5] ^ state[x + 10] ^ state[x + 15] ^ state[x + 20];
+        }
+        for x in 0..5 {
+            let d = c[(x + 4) % 5] ^ c[(x + 1) % 5].rotate_left(1);
+
+            for y in (0..25).step_by(5) {
+                state[y + x] ^= d;
+            }
+        }
+        // Rho and pi.
+        let mut lane = state[1];
+        for (pi, rot) in KECCAK_PILN.iter().zip(KECCAK_ROTC.iter()) {
+            let next = state[*pi];
+
+            state[*pi] = lane.rotate_left(*rot);
+            lane = next;
+        }
+        // Chi.
+        for y in (0..25).step_by(5) {
+            let mut row = [0u64; 5];
+            row.copy_from_slice(&state[y..y + 5]);
+
+            for x in 0..5 {
+                state[y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+        // Iota.
+        state[0] ^= rc;
+    }
+}
+
+/// Compute the SHA3-256 hash of some data.
+fn sha3_256(data: &[u8]) -> [u8; 32] {
+    const RATE: usize = 136;
+
+    let mut state = [0u64; 25];
+    let mut absorb = |block: &[u8; RATE]| {
+        for (lane, chunk) in state.iter_mut().zip(block.chunks(8)) {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(chunk);
+
+            *lane ^= u64::from_le_bytes(bytes);
+        }
+        keccak_f(&mut state);
+    };
+
+    let mut chunks = data.chunks_exact(RATE);
+    for chunk in &mut chunks {
+        let mut block = [0; RATE];
+        block.copy_from_slice(chunk);
+
+        absorb(&block);
+    }
+    // Pad the last block with the SHA-3 domain separator.
+    let rest = chunks.remainder();
+    let mut block = [0; RATE];
+
+    block[..rest.len()].copy_from_slice(rest);
+    block[rest.len()] ^= 0x06;
+    block[RATE - 1] ^= 0x80;
+
+    absorb(&block);
+
+    let mut hash = [0; 32];
+    for (chunk, lane) in hash.chunks_mut(8).zip(state.iter()) {
+        chunk.copy_from_slice(&lane.to_le_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha3_256() {
+        let hex = |bytes: [u8; 32]| {
+            bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+
+        assert_eq!(
+            hex(sha3_256(b"")),
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a"
+        );
+        // Longer than a single block.
+        assert_eq!(
+            hex(sha3_256(&[b'a'; 200])),
+            "cce34485baf2bf2aca99b94833892a4f52896d3d153f7b840cc4f9fe695f1387"
+        );
+    }
+
+    #[test]
+    fn test_onion() {
+        let s = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333";
+        let addr = s.parse::<PeerAddr>().unwrap();
+
+        match addr {
+            PeerAddr::Onion { pubkey, port } => {
+                assert_eq!(pubkey[..4], [0xd1, 0xb3, 0x8b, 0x83]);
+                assert_eq!(port, 8333);
+            }
+            _ => panic!("expected an onion address"),
+        }
+        assert_eq!(addr.to_string(), s);
+        assert_eq!(
+            "3gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:8333"
+                .parse::<PeerAddr>(),
+            Err(Error::Checksum)
+        );
+
+        let (network, bytes) = addr.to_bip155();
+        assert_eq!(network, BIP155_TORV3);
+        assert_eq!(PeerAddr::from_bip155(network, &bytes, 8333), Ok(addr));
+    }
+
+    #[test]
+    fn test_i2p() {
+        let mut hash = [0; 32];
+        for (i, b) in hash.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let addr = PeerAddr::I2p { hash, port: 0 };
+        let s = "aaaqeayeaudaocajbifqydiob4ibceqtcqkrmfyydenbwha5dypq.b32.i2p:0";
+
+        assert_eq!(addr.to_string(), s);
+        assert_eq!(s.parse::<PeerAddr>(), Ok(addr));
+        assert_eq!(
+            s.to_uppercase()
+                .replace("B32.I2P", "b32.i2p")
+                .parse::<PeerAddr>(),
+            Ok(addr)
+        );
+
+        let (network, bytes) = addr.to_bip155();
+        assert_eq!(PeerAddr::from_bip155(network, &bytes, 0), Ok(addr));
+    }
+
+    #[test]
+    fn test_ip() {
+        let addr: PeerAddr = "[2001:db8::1]:8333".parse().unwrap();
+
+        assert!(addr.is_ip());
+        assert_eq!(addr.to_string(), "[2001:db8::1]:8333");
+
+        let (network, bytes) = addr.to_bip155();
+        assert_eq!(network, BIP155_IPV6);
+        assert_eq!(PeerAddr::from_bip155(network, &bytes, 8333), Ok(addr));
+        assert_eq!(
+            PeerAddr::from_bip155(BIP155_IPV4, &[1, 2, 3], 8333),
+            Err(Error::Invalid)
+        );
+        assert_eq!(
+            PeerAddr::from_bip155(6, &[0xfc; 16], 8333),
+            Err(Error::UnsupportedNetwork(6))
+        );
+        assert_eq!("example.com:8333".parse::<PeerAddr>(), Err(Error::Invalid));
+    }
+}
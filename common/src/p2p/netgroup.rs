@@ -0,0 +1,188 @@
+//! Network groups.
+//!
+//! Peers in the same network group are likely to be operated by the same entity, eg.
+//! a hosting provider. Limiting the number of connections to any one group makes it
+//! harder for a single entity to control all of our connections.
+use std::collections::{BTreeMap, HashMap};
+use std::net;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// The network group of an IP address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NetGroup {
+    /// Autonomous system number, when an [`AsMap`] is available.
+    Asn(u32),
+    /// IPv4 `/16` range.
+    Ipv4([u8; 2]),
+    /// IPv6 `/32` range.
+    Ipv6([u16; 2]),
+}
+
+impl NetGroup {
+    /// Get the network group of an IP address. If an AS map is given and the address
+    /// is mapped, the address is grouped by its AS number, otherwise by its range.
+    pub fn of(ip: &net::IpAddr, asmap: Option<&AsMap>) -> Self {
+        if let Some(asn) = asmap.and_then(|m| m.lookup(ip)) {
+            return Self::Asn(asn);
+        }
+        match ip {
+            net::IpAddr::V4(ip) => {
+                let [a, b, _, _] = ip.octets();
+
+                Self::Ipv4([a, b])
+            }
+            net::IpAddr::V6(ip) => {
+                let segments = ip.segments();
+
+                Self::Ipv6([segments[0], segments[1]])
+            }
+        }
+    }
+}
+
+/// An error parsing an AS map.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// An entry could not be parsed.
+    #[error("invalid AS map entry on line {0}")]
+    InvalidEntry(usize),
+}
+
+/// Maps IP address prefixes to autonomous system numbers (ASNs).
+///
+/// AS maps are parsed from text, with one entry per line, eg. `"203.0.113.0/24 AS64496"`.
+/// Empty lines and lines starting with `#` are ignored. When prefixes overlap, the
+/// longest matching prefix wins.
+#[derive(Debug, Clone, Default)]
+pub struct AsMap {
+    /// Prefixes by length, as IPv6 addresses. IPv4 addresses are stored as IPv4-mapped
+    /// IPv6 addresses.
+    prefixes: BTreeMap<u8, HashMap<u128, u32>>,
+}
+
+impl AsMap {
+    /// Create an empty AS map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map an address prefix to the given AS number.
+    pub fn insert(&mut self, ip: net::IpAddr, len: u8, asn: u32) {
+        let (bits, len) = self::bits(&ip, len);
+
+        self.prefixes
+            .entry(len)
+            .or_default()
+            .insert(mask(bits, len), asn);
+    }
+
+    /// Get the AS number of an IP address, if it's mapped.
+    pub fn lookup(&self, ip: &net::IpAddr) -> Option<u32> {
+        let (bits, _) = self::bits(ip, 0);
+
+        self.prefixes
+            .iter()
+            .rev()
+            .find_map(|(len, prefixes)| prefixes.get(&mask(bits, *len)).copied())
+    }
+
+    /// Check whether the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+}
+
+impl FromStr for AsMap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut asmap = AsMap::new();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = || -> Option<(net::IpAddr, u8, u32)> {
+                let mut fields = line.split_whitespace();
+                let mut prefix = fields.next()?.splitn(2, '/');
+                let (ip, len) = (prefix.next()?, prefix.next()?);
+                let asn = fields.next()?;
+                let asn = asn.strip_prefix("AS").unwrap_or(asn);
+
+                if fields.next().is_some() {
+                    return None;
+                }
+                let ip = ip.parse::<net::IpAddr>().ok()?;
+                let len = len.parse::<u8>().ok()?;
+                let max = if ip.is_ipv4() { 32 } else { 128 };
+
+                if len > max {
+                    return None;
+                }
+                Some((ip, len, asn.parse().ok()?))
+            };
+            let (ip, len, asn) = entry().ok_or(Error::InvalidEntry(i + 1))?;
+
+            asmap.insert(ip, len, asn);
+        }
+        Ok(asmap)
+    }
+}
+
+/// Get the bits of an IP address as an IPv6 address, along with the given prefix length
+/// adjusted to IPv6.
+fn bits(ip: &net::IpAddr, len: u8) -> (u128, u8) {
+    match ip {
+        net::IpAddr::V4(ip) => (u128::from(ip.to_ipv6_mapped()), len + 96),
+        net::IpAddr::V6(ip) => (u128::from(*ip), len),
+    }
+}
+
+/// Keep only the first `len` bits.
+fn mask(bits: u128, len: u8) -> u128 {
+    if len == 0 {
+        0
+    } else {
+        bits & (u128::MAX << (128 - len as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_netgroup() {
+        let a: net::IpAddr = [203, 0, 113, 7].into();
+        let b: net::IpAddr = [203, 0, 12, 1].into();
+        let c: net::IpAddr = [198, 51, 100, 1].into();
+
+        assert_eq!(NetGroup::of(&a, None), NetGroup::of(&b, None));
+        assert_ne!(NetGroup::of(&a, None), NetGroup::of(&c, None));
+
+        let asmap: AsMap = "
+            # Test mapping.
+            203.0.0.0/16 AS64496
+            203.0.113.0/24 AS64497
+            198.51.0.0/16 64496
+        "
+        .parse()
+        .unwrap();
+
+        assert_eq!(NetGroup::of(&a, Some(&asmap)), NetGroup::Asn(64497));
+        assert_eq!(NetGroup::of(&b, Some(&asmap)), NetGroup::Asn(64496));
+        assert_eq!(NetGroup::of(&c, Some(&asmap)), NetGroup::Asn(64496));
+        assert_eq!(
+            NetGroup::of(&[192, 0, 2, 1].into(), Some(&asmap)),
+            NetGroup::Ipv4([192, 0])
+        );
+        assert_eq!(
+            "203.0.0.0/33 AS1".parse::<AsMap>().unwrap_err(),
+            Error::InvalidEntry(1)
+        );
+    }
+}
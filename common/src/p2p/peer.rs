@@ -7,7 +7,8 @@ use microserde as serde;
 use bitcoin::network::address::Address;
 use bitcoin::network::constants::ServiceFlags;
 
-use crate::block::time::LocalTime;
+use crate::block::time::{LocalDuration, LocalTime};
+use crate::p2p::addr::PeerAddr;
 
 /// Peer store.
 ///
@@ -47,7 +48,7 @@ pub trait Store {
             for addr in seed.to_socket_addrs()? {
                 self.insert(
                     addr.ip(),
-                    KnownAddress::new(Address::new(&addr, ServiceFlags::NONE), source),
+                    KnownAddress::new(addr.into(), ServiceFlags::NONE, source),
                 );
             }
         }
@@ -111,6 +112,8 @@ pub enum Source {
     Peer(net::SocketAddr),
     /// An address that came from a DNS seed.
     Dns,
+    /// An address imported from another node's address book, eg. Bitcoin Core's `peers.dat`.
+    Imported,
 }
 
 impl std::fmt::Display for Source {
@@ -118,6 +121,7 @@ impl std::fmt::Display for Source {
         match self {
             Self::Peer(addr) => write!(f, "{}", addr),
             Self::Dns => write!(f, "DNS"),
+            Self::Imported => write!(f, "imported"),
         }
     }
 }
@@ -125,24 +129,65 @@ impl std::fmt::Display for Source {
 /// A known address.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KnownAddress {
-    /// Network address.
-    pub addr: Address,
+    /// Network address. May be an IP address, or an address on an overlay network.
+    pub addr: PeerAddr,
+    /// Services offered by the peer at this address.
+    pub services: ServiceFlags,
     /// Address of the peer who sent us this address.
     pub source: Source,
     /// Last time this address was used to successfully connect to a peer.
     pub last_success: Option<LocalTime>,
     /// Last time this address was tried.
     pub last_attempt: Option<LocalTime>,
+    /// Last time a connection attempt to this address failed.
+    pub last_failure: Option<LocalTime>,
+    /// Number of connection attempts made to this address.
+    pub attempts: u32,
+    /// Last observed round-trip latency of the peer at this address.
+    pub latency: Option<LocalDuration>,
 }
 
 impl KnownAddress {
     /// Create a new known address.
-    pub fn new(addr: Address, source: Source) -> Self {
+    pub fn new(addr: PeerAddr, services: ServiceFlags, source: Source) -> Self {
         Self {
             addr,
+            services,
             source,
             last_success: None,
             last_attempt: None,
+            last_failure: None,
+            attempts: 0,
+            latency: None,
+        }
+    }
+
+    /// Get the network address in the format used by `addr` messages. Returns `None` for
+    /// addresses that aren't IP addresses.
+    pub fn address(&self) -> Option<Address> {
+        self.addr
+            .socket_addr()
+            .map(|addr| Address::new(&addr, self.services))
+    }
+
+    /// Check whether a connection attempt to this address is still pending, ie. we've
+    /// tried this address, but don't yet know whether the attempt succeeded or failed.
+    pub fn is_pending(&self) -> bool {
+        match self.last_attempt {
+            Some(attempt) => {
+                self.last_success.map_or(true, |t| t < attempt)
+                    && self.last_failure.map_or(true, |t| t < attempt)
+            }
+            None => false,
+        }
+    }
+
+    /// Check whether the last connection attempt to this address failed.
+    pub fn is_failing(&self) -> bool {
+        match (self.last_failure, self.last_success) {
+            (Some(failure), Some(success)) => failure > success,
+            (Some(_), None) => true,
+            (None, _) => false,
         }
     }
 
@@ -150,10 +195,8 @@ impl KnownAddress {
     pub fn to_json(&self) -> serde::json::Value {
         use serde::json::{Number, Object, Value};
 
-        let ip = &self.addr.address;
-        let port = &self.addr.port;
-        let address = net::SocketAddr::from((*ip, *port)).to_string();
-        let services = self.addr.services.as_u64();
+        let address = self.addr.to_string();
+        let services = self.services.as_u64();
 
         let mut obj = Object::new();
 
@@ -173,10 +216,29 @@ impl KnownAddress {
                 None => Value::Null,
             },
         );
+        obj.insert(
+            "last_failure".to_owned(),
+            match self.last_failure {
+                Some(t) => Value::Number(Number::U64(t.block_time() as u64)),
+                None => Value::Null,
+            },
+        );
+        obj.insert(
+            "attempts".to_owned(),
+            Value::Number(Number::U64(self.attempts as u64)),
+        );
+        obj.insert(
+            "latency".to_owned(),
+            match self.latency {
+                Some(d) => Value::Number(Number::U64(d.as_millis() as u64)),
+                None => Value::Null,
+            },
+        );
         obj.insert(
             "source".to_owned(),
             match self.source {
                 Source::Dns => Value::String("dns".to_owned()),
+                Source::Imported => Value::String("imported".to_owned()),
                 Source::Peer(addr) => Value::String(addr.to_string()),
             },
         );
@@ -194,7 +256,17 @@ impl KnownAddress {
         };
 
         let addr = match obj.get("address") {
-            Some(Value::String(addr)) => addr.parse().unwrap(),
+            // Older stores have IPv4 addresses encoded as IPv4-mapped IPv6 addresses.
+            Some(Value::String(addr)) => match addr.parse().map_err(|_| serde::Error)? {
+                PeerAddr::Ip(net::SocketAddr::V6(addr)) => match addr.ip().segments() {
+                    [0, 0, 0, 0, 0, 0xffff, hi, lo] => PeerAddr::Ip(net::SocketAddr::from((
+                        net::Ipv4Addr::from(((hi as u32) << 16) | lo as u32),
+                        addr.port(),
+                    ))),
+                    _ => PeerAddr::Ip(addr.into()),
+                },
+                addr => addr,
+            },
             _ => return Err(serde::Error),
         };
         let services = match obj.get("services") {
@@ -211,10 +283,28 @@ impl KnownAddress {
             Some(Value::Number(Number::U64(n))) => Some(LocalTime::from_block_time(*n as u32)),
             _ => return Err(serde::Error),
         };
+        // The fields below were added later, and may be missing from older stores.
+        let last_failure = match obj.get("last_failure") {
+            None | Some(Value::Null) => None,
+            Some(Value::Number(Number::U64(n))) => Some(LocalTime::from_block_time(*n as u32)),
+            _ => return Err(serde::Error),
+        };
+        let attempts = match obj.get("attempts") {
+            None => 0,
+            Some(Value::Number(Number::U64(n))) => *n as u32,
+            _ => return Err(serde::Error),
+        };
+        let latency = match obj.get("latency") {
+            None | Some(Value::Null) => None,
+            Some(Value::Number(Number::U64(n))) => Some(LocalDuration::from_millis(*n as u128)),
+            _ => return Err(serde::Error),
+        };
         let source = match obj.get("source") {
             Some(Value::String(s)) => {
                 if s == "dns" {
                     Source::Dns
+                } else if s == "imported" {
+                    Source::Imported
                 } else {
                     match s.parse() {
                         Ok(addr) => Source::Peer(addr),
@@ -226,18 +316,92 @@ impl KnownAddress {
         };
 
         Ok(Self {
-            addr: Address::new(&addr, services),
+            addr,
+            services,
             source,
             last_success,
             last_attempt,
+            last_failure,
+            attempts,
+            latency,
         })
     }
 }
 
+/// A peer address ban.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    /// Reason for the ban.
+    pub reason: String,
+    /// Time at which the ban expires.
+    pub until: LocalTime,
+}
+
+impl Ban {
+    /// Create a new ban.
+    pub fn new(reason: impl Into<String>, until: LocalTime) -> Self {
+        Self {
+            reason: reason.into(),
+            until,
+        }
+    }
+
+    /// Check whether the ban has expired, given the current time.
+    pub fn is_expired(&self, now: LocalTime) -> bool {
+        now >= self.until
+    }
+
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> serde::json::Value {
+        use serde::json::{Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        obj.insert("reason".to_owned(), Value::String(self.reason.clone()));
+        obj.insert(
+            "until".to_owned(),
+            Value::Number(Number::U64(self.until.block_time() as u64)),
+        );
+
+        Value::Object(obj)
+    }
+
+    /// Convert from a JSON value.
+    pub fn from_json(v: serde::json::Value) -> Result<Self, serde::Error> {
+        use serde::json::{Number, Value};
+
+        let obj = match v {
+            Value::Object(obj) => obj,
+            _ => return Err(serde::Error),
+        };
+
+        let reason = match obj.get("reason") {
+            Some(Value::String(reason)) => reason.clone(),
+            _ => return Err(serde::Error),
+        };
+        let until = match obj.get("until") {
+            Some(Value::Number(Number::U64(n))) => LocalTime::from_block_time(*n as u32),
+            _ => return Err(serde::Error),
+        };
+
+        Ok(Self { reason, until })
+    }
+}
+
 /// Source of peer addresses.
 pub trait AddressSource {
     /// Sample a random peer address. Returns `None` if there are no addresses left.
-    fn sample(&self, services: ServiceFlags) -> Option<(&Address, Source)>;
+    fn sample(&self, services: ServiceFlags) -> Option<(&PeerAddr, Source)> {
+        self.sample_with(services, |_| true)
+    }
+
+    /// Sample a random peer address whose IP matches the given predicate. Returns `None`
+    /// if there are no such addresses left.
+    fn sample_with(
+        &self,
+        services: ServiceFlags,
+        predicate: impl Fn(&net::IpAddr) -> bool,
+    ) -> Option<(&PeerAddr, Source)>;
 }
 
 #[cfg(test)]
@@ -249,10 +413,14 @@ mod tests {
         let sockaddr = net::SocketAddr::from(([1, 2, 3, 4], 8333));
         let services = ServiceFlags::NETWORK;
         let ka = KnownAddress {
-            addr: Address::new(&sockaddr, services),
+            addr: sockaddr.into(),
+            services,
             source: Source::Peer(net::SocketAddr::from(([4, 5, 6, 7], 8333))),
             last_success: Some(LocalTime::from_secs(42)),
             last_attempt: None,
+            last_failure: Some(LocalTime::from_secs(36)),
+            attempts: 3,
+            latency: Some(LocalDuration::from_millis(120)),
         };
 
         let value = ka.to_json();
@@ -260,4 +428,35 @@ mod tests {
 
         assert_eq!(ka, deserialized);
     }
+
+    #[test]
+    fn test_known_address_ipv4_mapped() {
+        use serde::json::Value;
+
+        let mut value = KnownAddress::new(
+            net::SocketAddr::from(([1, 2, 3, 4], 8333)).into(),
+            ServiceFlags::NETWORK,
+            Source::Dns,
+        )
+        .to_json();
+
+        if let Value::Object(obj) = &mut value {
+            obj.insert(
+                "address".to_owned(),
+                Value::String("[::ffff:1.2.3.4]:8333".to_owned()),
+            );
+        }
+        let ka = KnownAddress::from_json(value).unwrap();
+
+        assert_eq!(ka.addr, PeerAddr::Ip(([1, 2, 3, 4], 8333).into()));
+    }
+
+    #[test]
+    fn test_ban() {
+        let ban = Ban::new("misbehaving", LocalTime::from_secs(1024));
+
+        assert!(!ban.is_expired(LocalTime::from_secs(1023)));
+        assert!(ban.is_expired(LocalTime::from_secs(1024)));
+        assert_eq!(Ban::from_json(ban.to_json()).unwrap(), ban);
+    }
 }
@@ -0,0 +1,285 @@
+//! Bitcoin peer-to-peer network crawler.
+//!
+//! Walks the network by asking peers for addresses with `getaddr`, starting from the DNS
+//! seeds or from the given peers, and records which peers were reachable, along with their
+//! services and user agents. Once done, a JSON report is written to standard output.
+#![deny(missing_docs, unsafe_code)]
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Write};
+use std::net::{self, ToSocketAddrs};
+use std::thread;
+use std::time::{self, SystemTime};
+
+use argh::FromArgs;
+use crossbeam_channel as chan;
+use microserde::json::{Number, Object, Value};
+use thiserror::Error;
+
+use nakamoto_common::network::Network;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::network::address::Address;
+use nakamoto_p2p::bitcoin::network::constants::ServiceFlags;
+use nakamoto_p2p::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use nakamoto_p2p::bitcoin::network::message_network::VersionMessage;
+use nakamoto_p2p::bitcoin::network::stream_reader::StreamReader;
+use nakamoto_p2p::protocol::{addrmgr, PROTOCOL_VERSION, USER_AGENT};
+
+#[derive(FromArgs)]
+/// Crawl the Bitcoin peer-to-peer network.
+pub struct Options {
+    /// start from these peers instead of the DNS seeds
+    #[argh(option)]
+    pub seed: Vec<net::SocketAddr>,
+
+    /// use the bitcoin test network (default: false)
+    #[argh(switch)]
+    pub testnet: bool,
+
+    /// maximum number of peers to visit (default: 1000)
+    #[argh(option, default = "1000")]
+    pub max_peers: usize,
+
+    /// number of peers to visit concurrently (default: 32)
+    #[argh(option, default = "32")]
+    pub concurrency: usize,
+
+    /// timeout in seconds when connecting to and waiting on a peer (default: 10)
+    #[argh(option, default = "10")]
+    pub timeout: u64,
+}
+
+/// An error encountered while visiting a peer.
+#[derive(Error, Debug)]
+pub enum Error {
+    /// An I/O error.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// An error decoding a message.
+    #[error(transparent)]
+    Encode(#[from] encode::Error),
+    /// The peer didn't complete the handshake in time.
+    #[error("handshake timed out")]
+    Timeout,
+}
+
+/// Information gathered from a reachable peer.
+#[derive(Debug)]
+pub struct Visit {
+    /// The peer's `version` message.
+    pub version: VersionMessage,
+    /// Addresses returned by the peer.
+    pub addrs: Vec<Address>,
+}
+
+/// Connect to a peer, perform the handshake and ask it for addresses.
+fn visit(
+    addr: &net::SocketAddr,
+    network: Network,
+    timeout: time::Duration,
+) -> Result<Visit, Error> {
+    let deadline = time::Instant::now() + timeout * 3;
+    let mut stream = net::TcpStream::connect_timeout(addr, timeout)?;
+
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut reader = StreamReader::new(stream.try_clone()?, None);
+    let mut send = |payload: NetworkMessage| -> Result<(), Error> {
+        let msg = RawNetworkMessage {
+            magic: network.magic(),
+            payload,
+        };
+        stream.write_all(&encode::serialize(&msg))?;
+
+        Ok(())
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    send(NetworkMessage::Version(VersionMessage {
+        version: PROTOCOL_VERSION,
+        services: ServiceFlags::NONE,
+        timestamp,
+        receiver: Address::new(addr, ServiceFlags::NONE),
+        sender: Address::new(&([0, 0, 0, 0], 0).into(), ServiceFlags::NONE),
+        nonce: fastrand::u64(..),
+        user_agent: USER_AGENT.to_owned(),
+        start_height: 0,
+        relay: false,
+    }))?;
+
+    let mut version = None;
+
+    while time::Instant::now() < deadline {
+        let msg: RawNetworkMessage = match reader.read_next() {
+            Ok(msg) => msg,
+            // If the handshake completed, report what we have so far.
+            Err(_) if version.is_some() => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        match msg.payload {
+            NetworkMessage::Version(msg) => {
+                version = Some(msg);
+                send(NetworkMessage::Verack)?;
+            }
+            NetworkMessage::Verack => {
+                send(NetworkMessage::GetAddr)?;
+            }
+            NetworkMessage::Ping(nonce) => {
+                send(NetworkMessage::Pong(nonce))?;
+            }
+            // Peers usually announce their own address right after the handshake. We
+            // wait for a larger message, which is the response to our `getaddr`.
+            NetworkMessage::Addr(addrs) if addrs.len() > 1 => {
+                if let Some(version) = version {
+                    return Ok(Visit {
+                        version,
+                        addrs: addrs.into_iter().map(|(_, a)| a).collect(),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    match version {
+        // The peer completed the handshake, but didn't send us any addresses.
+        Some(version) => Ok(Visit {
+            version,
+            addrs: Vec::new(),
+        }),
+        None => Err(Error::Timeout),
+    }
+}
+
+/// Convert a visit to a JSON value, for the report.
+fn to_json(addr: &net::SocketAddr, visit: &Visit) -> Value {
+    let mut obj = Object::new();
+
+    obj.insert("address".to_owned(), Value::String(addr.to_string()));
+    obj.insert(
+        "services".to_owned(),
+        Value::Number(Number::U64(visit.version.services.as_u64())),
+    );
+    obj.insert(
+        "userAgent".to_owned(),
+        Value::String(visit.version.user_agent.clone()),
+    );
+    obj.insert(
+        "version".to_owned(),
+        Value::Number(Number::U64(visit.version.version as u64)),
+    );
+    obj.insert(
+        "height".to_owned(),
+        Value::Number(Number::I64(visit.version.start_height as i64)),
+    );
+    obj.insert(
+        "addresses".to_owned(),
+        Value::Number(Number::U64(visit.addrs.len() as u64)),
+    );
+
+    Value::Object(obj)
+}
+
+fn main() {
+    let opts: Options = argh::from_env();
+    let network = if opts.testnet {
+        Network::Testnet
+    } else {
+        Network::Mainnet
+    };
+    let timeout = time::Duration::from_secs(opts.timeout);
+
+    let mut queue = VecDeque::new();
+    let mut seen = HashSet::new();
+
+    if opts.seed.is_empty() {
+        for seed in network.seeds() {
+            match (*seed, network.port()).to_socket_addrs() {
+                Ok(addrs) => queue.extend(addrs),
+                Err(err) => eprintln!("Error resolving seed {}: {}", seed, err),
+            }
+        }
+    } else {
+        queue.extend(opts.seed.iter().cloned());
+    }
+    queue.retain(|addr| seen.insert(*addr));
+
+    let (work_tx, work_rx) = chan::unbounded::<net::SocketAddr>();
+    let (result_tx, result_rx) = chan::unbounded();
+
+    for _ in 0..opts.concurrency.max(1) {
+        let work_rx = work_rx.clone();
+        let result_tx = result_tx.clone();
+
+        thread::spawn(move || {
+            for addr in work_rx.iter() {
+                let result = visit(&addr, network, timeout);
+
+                if result_tx.send((addr, result)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut pending = 0;
+    let mut visited = 0;
+    let mut reachable = Vec::new();
+
+    loop {
+        while pending < opts.concurrency.max(1) && visited + pending < opts.max_peers {
+            if let Some(addr) = queue.pop_front() {
+                work_tx.send(addr).expect("workers are running");
+                pending += 1;
+            } else {
+                break;
+            }
+        }
+        if pending == 0 {
+            break;
+        }
+        let (addr, result): (net::SocketAddr, Result<Visit, Error>) =
+            result_rx.recv().expect("workers are running");
+
+        pending -= 1;
+        visited += 1;
+
+        match result {
+            Ok(visit) => {
+                for addr in &visit.addrs {
+                    if let Ok(addr) = addr.socket_addr() {
+                        if addrmgr::is_routable(&addr.ip()) && seen.insert(addr) {
+                            queue.push_back(addr);
+                        }
+                    }
+                }
+                eprintln!(
+                    "{}: {} ({} address(es))",
+                    addr,
+                    visit.version.user_agent,
+                    visit.addrs.len()
+                );
+                reachable.push(to_json(&addr, &visit));
+            }
+            Err(err) => eprintln!("{}: {}", addr, err),
+        }
+    }
+
+    let mut report = Object::new();
+
+    report.insert(
+        "visited".to_owned(),
+        Value::Number(Number::U64(visited as u64)),
+    );
+    report.insert(
+        "discovered".to_owned(),
+        Value::Number(Number::U64(seen.len() as u64)),
+    );
+    report.insert("reachable".to_owned(), Value::Array(reachable));
+
+    println!("{}", microserde::json::to_string(&Value::Object(report)));
+}
@@ -0,0 +1,195 @@
+#ifndef NAKAMOTO_H
+#define NAKAMOTO_H
+
+/* This file is generated by cbindgen from `src/lib.rs`. Do not edit it by hand. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * Kind of a client event, which determines which event fields are set.
+ */
+typedef enum NakamotoEventKind {
+  /**
+   * A peer connected. `data` holds the peer address, as a UTF-8 string.
+   */
+  NAKAMOTO_EVENT_KIND_PEER_CONNECTED = 0,
+  /**
+   * A peer disconnected. `data` holds the peer address, as a UTF-8 string.
+   */
+  NAKAMOTO_EVENT_KIND_PEER_DISCONNECTED = 1,
+  /**
+   * The chain tip changed. `hash` and `height` are those of the new tip.
+   */
+  NAKAMOTO_EVENT_KIND_TIP_CHANGED = 2,
+  /**
+   * Headers are synced. `hash` and `height` are those of the best block.
+   */
+  NAKAMOTO_EVENT_KIND_SYNCED = 3,
+  /**
+   * A block filter matched a watched script. `hash` and `height` are those of the
+   * matching block, which is fetched automatically.
+   */
+  NAKAMOTO_EVENT_KIND_FILTER_MATCHED = 4,
+  /**
+   * A transaction of a watched script was found. `hash` and `height` are those of the
+   * block containing it, and `data` holds the transaction.
+   */
+  NAKAMOTO_EVENT_KIND_TRANSACTION_MATCHED = 5,
+} NakamotoEventKind;
+
+/**
+ * Bitcoin network to connect to.
+ */
+typedef enum NakamotoNetwork {
+  /**
+   * Bitcoin Mainnet.
+   */
+  NAKAMOTO_NETWORK_MAINNET = 0,
+  /**
+   * Bitcoin Testnet.
+   */
+  NAKAMOTO_NETWORK_TESTNET = 1,
+  /**
+   * Bitcoin regression test net.
+   */
+  NAKAMOTO_NETWORK_REGTEST = 2,
+} NakamotoNetwork;
+
+/**
+ * Status returned by fallible functions.
+ */
+typedef enum NakamotoStatus {
+  /**
+   * The call succeeded.
+   */
+  NAKAMOTO_STATUS_OK = 0,
+  /**
+   * An argument was null or invalid.
+   */
+  NAKAMOTO_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The client encountered an error. Details are logged.
+   */
+  NAKAMOTO_STATUS_CLIENT = 2,
+  /**
+   * The operation timed out.
+   */
+  NAKAMOTO_STATUS_TIMEOUT = 3,
+  /**
+   * The client is no longer running.
+   */
+  NAKAMOTO_STATUS_DISCONNECTED = 4,
+  /**
+   * The call panicked. Details are logged.
+   */
+  NAKAMOTO_STATUS_PANIC = 5,
+} NakamotoStatus;
+
+/**
+ * A light-client running in the background. Opaque to C.
+ */
+typedef struct NakamotoClient NakamotoClient;
+
+/**
+ * A client event. Events with data must be freed with [`nakamoto_event_free`].
+ */
+typedef struct NakamotoEvent {
+  /**
+   * Kind of event.
+   */
+  enum NakamotoEventKind kind;
+  /**
+   * Block height, if any, or zero.
+   */
+  uint64_t height;
+  /**
+   * Block hash, if any, or zeroes.
+   */
+  uint8_t hash[32];
+  /**
+   * Event data, if any, or null.
+   */
+  uint8_t *data;
+  /**
+   * Length of the event data.
+   */
+  uintptr_t len;
+} NakamotoEvent;
+
+/**
+ * Create a light-client for the given network, and start it in the background. Runtime
+ * data is stored under `home`, or under the `HOME` directory if it is null.
+ *
+ * Returns null if the client couldn't be created, or if creating it panicked. Details are
+ * logged.
+ *
+ * # Safety
+ *
+ * `home` must be null or a valid NUL-terminated string.
+ */
+struct NakamotoClient *nakamoto_client_new(enum NakamotoNetwork network, const char *home);
+
+/**
+ * Watch a script. Transactions paying to watched scripts are reported as events.
+ *
+ * # Safety
+ *
+ * `client` must be a client returned by [`nakamoto_client_new`], and `script` must point
+ * to `len` readable bytes.
+ */
+enum NakamotoStatus nakamoto_client_watch(struct NakamotoClient *client,
+                                          const uint8_t *script,
+                                          uintptr_t len);
+
+/**
+ * Wait up to `timeout_ms` milliseconds for the next event, and write it to `event`.
+ *
+ * Returns [`NakamotoStatus::Timeout`] if no event was received in time, in which case
+ * `event` is left untouched.
+ *
+ * # Safety
+ *
+ * `client` must be a client returned by [`nakamoto_client_new`], and `event` must point
+ * to a writable event. The event is overwritten without being freed: if it holds data
+ * from a previous call, it must first be freed with [`nakamoto_event_free`], or the data
+ * is leaked.
+ */
+enum NakamotoStatus nakamoto_client_poll_event(struct NakamotoClient *client,
+                                               uint64_t timeout_ms,
+                                               struct NakamotoEvent *event);
+
+/**
+ * Free the data of an event. The event itself is owned by the caller.
+ *
+ * # Safety
+ *
+ * `event` must be null, or an event written by [`nakamoto_client_poll_event`], whose data
+ * hasn't been modified.
+ */
+void nakamoto_event_free(struct NakamotoEvent *event);
+
+/**
+ * Broadcast a consensus-encoded transaction to the network.
+ *
+ * # Safety
+ *
+ * `client` must be a client returned by [`nakamoto_client_new`], and `tx` must point to
+ * `len` readable bytes.
+ */
+enum NakamotoStatus nakamoto_client_broadcast(struct NakamotoClient *client,
+                                              const uint8_t *tx,
+                                              uintptr_t len);
+
+/**
+ * Shut the client down, and free it. The client must not be used after this call,
+ * whatever the returned status.
+ *
+ * # Safety
+ *
+ * `client` must be a client returned by [`nakamoto_client_new`].
+ */
+enum NakamotoStatus nakamoto_client_shutdown(struct NakamotoClient *client);
+
+#endif /* NAKAMOTO_H */
@@ -0,0 +1,488 @@
+//! C bindings for the nakamoto light-client.
+//!
+//! Lets non-Rust applications embed the light-client, through a small and stable C API.
+//! The corresponding header is generated under `include/nakamoto.h` when the crate is
+//! built.
+//!
+//! A client is created with [`nakamoto_client_new`], which starts it in the background,
+//! and is stopped and freed with [`nakamoto_client_shutdown`]. In between, applications
+//! can watch scripts, poll for events and broadcast transactions. A client may be used
+//! from any thread, but not from more than one thread at a time.
+//!
+//! Block and transaction hashes are passed as 32 bytes, in internal byte order, ie. the
+//! reverse of their usual hex representation. Transactions are consensus-encoded.
+#![deny(missing_docs)]
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::{net, panic, ptr, slice, thread, time};
+
+use crossbeam_channel as chan;
+
+use nakamoto_client::builder::ClientBuilder;
+use nakamoto_client::client::Handle;
+use nakamoto_client::error::Error;
+use nakamoto_client::event::{ClientEvent, SyncState};
+use nakamoto_client::handle::{self, Handle as _};
+use nakamoto_common::block::{Block, Height};
+use nakamoto_common::network::Network;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::hashes::Hash;
+use nakamoto_p2p::bitcoin::{Script, Transaction};
+
+/// The network reactor we're going to use.
+type Reactor = nakamoto_net_poll::Reactor<net::TcpStream>;
+
+/// Status returned by fallible functions.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NakamotoStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// An argument was null or invalid.
+    InvalidArgument = 1,
+    /// The client encountered an error. Details are logged.
+    Client = 2,
+    /// The operation timed out.
+    Timeout = 3,
+    /// The client is no longer running.
+    Disconnected = 4,
+    /// The call panicked. Details are logged.
+    Panic = 5,
+}
+
+impl From<handle::Error> for NakamotoStatus {
+    fn from(err: handle::Error) -> Self {
+        match err {
+            handle::Error::Disconnected => Self::Disconnected,
+            handle::Error::Timeout => Self::Timeout,
+            handle::Error::Io(err) => {
+                log::error!("Client I/O error: {}", err);
+                Self::Client
+            }
+            handle::Error::GetFilters(err) => {
+                log::error!("Error requesting filters: {}", err);
+                Self::Client
+            }
+        }
+    }
+}
+
+/// Run the body of an entry point, returning `default` if it panics, since unwinding into
+/// C is undefined behavior.
+fn catch<T>(default: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => {
+            log::error!("Panic in nakamoto C bindings");
+            default
+        }
+    }
+}
+
+/// Bitcoin network to connect to.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NakamotoNetwork {
+    /// Bitcoin Mainnet.
+    Mainnet = 0,
+    /// Bitcoin Testnet.
+    Testnet = 1,
+    /// Bitcoin regression test net.
+    Regtest = 2,
+}
+
+impl From<NakamotoNetwork> for Network {
+    fn from(network: NakamotoNetwork) -> Self {
+        match network {
+            NakamotoNetwork::Mainnet => Self::Mainnet,
+            NakamotoNetwork::Testnet => Self::Testnet,
+            NakamotoNetwork::Regtest => Self::Regtest,
+        }
+    }
+}
+
+/// Kind of a client event, which determines which event fields are set.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NakamotoEventKind {
+    /// A peer connected. `data` holds the peer address, as a UTF-8 string.
+    PeerConnected = 0,
+    /// A peer disconnected. `data` holds the peer address, as a UTF-8 string.
+    PeerDisconnected = 1,
+    /// The chain tip changed. `hash` and `height` are those of the new tip.
+    TipChanged = 2,
+    /// Headers are synced. `hash` and `height` are those of the best block.
+    Synced = 3,
+    /// A block filter matched a watched script. `hash` and `height` are those of the
+    /// matching block, which is fetched automatically.
+    FilterMatched = 4,
+    /// A transaction of a watched script was found. `hash` and `height` are those of the
+    /// block containing it, and `data` holds the transaction.
+    TransactionMatched = 5,
+}
+
+/// A client event. Events with data must be freed with [`nakamoto_event_free`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct NakamotoEvent {
+    /// Kind of event.
+    pub kind: NakamotoEventKind,
+    /// Block height, if any, or zero.
+    pub height: u64,
+    /// Block hash, if any, or zeroes.
+    pub hash: [u8; 32],
+    /// Event data, if any, or null.
+    pub data: *mut u8,
+    /// Length of the event data.
+    pub len: usize,
+}
+
+impl NakamotoEvent {
+    /// Create an event without data.
+    fn new(kind: NakamotoEventKind, hash: [u8; 32], height: Height) -> Self {
+        Self {
+            kind,
+            height,
+            hash,
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// Create an event with the given data. Ownership of the data is passed to the caller,
+    /// until it is freed.
+    fn with_data(kind: NakamotoEventKind, hash: [u8; 32], height: Height, data: Vec<u8>) -> Self {
+        let len = data.len();
+        let data = Box::into_raw(data.into_boxed_slice());
+
+        Self {
+            kind,
+            height,
+            hash,
+            data: data as *mut u8,
+            len,
+        }
+    }
+
+    /// Convert a client event, if it's exposed through the C API.
+    fn from_client(event: &ClientEvent) -> Option<Self> {
+        use NakamotoEventKind::*;
+
+        match event {
+            ClientEvent::PeerConnected { addr, .. } => Some(Self::with_data(
+                PeerConnected,
+                [0; 32],
+                0,
+                addr.to_string().into_bytes(),
+            )),
+            ClientEvent::PeerDisconnected { addr } => Some(Self::with_data(
+                PeerDisconnected,
+                [0; 32],
+                0,
+                addr.to_string().into_bytes(),
+            )),
+            ClientEvent::TipChanged { hash, height, .. } => {
+                Some(Self::new(TipChanged, hash.into_inner(), *height))
+            }
+            ClientEvent::SyncStateChanged(SyncState::Synced { hash, height }) => {
+                Some(Self::new(Synced, hash.into_inner(), *height))
+            }
+            ClientEvent::FilterMatched { block_hash, height } => {
+                Some(Self::new(FilterMatched, block_hash.into_inner(), *height))
+            }
+            ClientEvent::TransactionMatched {
+                transaction,
+                block_hash,
+                height,
+            } => Some(Self::with_data(
+                TransactionMatched,
+                block_hash.into_inner(),
+                *height,
+                encode::serialize(transaction),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A light-client running in the background. Opaque to C.
+pub struct NakamotoClient {
+    handle: Handle<Reactor>,
+    events: chan::Receiver<ClientEvent>,
+    /// Blocks requested for matched filters. Matching transactions are emitted as events
+    /// once the block is received, so the blocks themselves are discarded.
+    blocks: (
+        chan::Sender<(Block, Height)>,
+        chan::Receiver<(Block, Height)>,
+    ),
+    thread: thread::JoinHandle<Result<(), Error>>,
+}
+
+/// Create a light-client for the given network, and start it in the background. Runtime
+/// data is stored under `home`, or under the `HOME` directory if it is null.
+///
+/// Returns null if the client couldn't be created, or if creating it panicked. Details are
+/// logged.
+///
+/// # Safety
+///
+/// `home` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn nakamoto_client_new(
+    network: NakamotoNetwork,
+    home: *const c_char,
+) -> *mut NakamotoClient {
+    self::catch(ptr::null_mut(), || {
+        // Don't listen for incoming connections.
+        let mut builder = ClientBuilder::new(network.into()).listen(vec![]);
+
+        if !home.is_null() {
+            match CStr::from_ptr(home).to_str() {
+                Ok(home) => builder = builder.home(home),
+                Err(_) => return ptr::null_mut(),
+            }
+        }
+        let client = match builder.build::<Reactor>() {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("Error creating client: {}", err);
+                return ptr::null_mut();
+            }
+        };
+        let handle = client.handle();
+        let events = handle.subscribe();
+        let thread = thread::spawn(|| client.run());
+
+        Box::into_raw(Box::new(NakamotoClient {
+            handle,
+            events,
+            blocks: chan::unbounded(),
+            thread,
+        }))
+    })
+}
+
+/// Watch a script. Transactions paying to watched scripts are reported as events.
+///
+/// # Safety
+///
+/// `client` must be a client returned by [`nakamoto_client_new`], and `script` must point
+/// to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nakamoto_client_watch(
+    client: *mut NakamotoClient,
+    script: *const u8,
+    len: usize,
+) -> NakamotoStatus {
+    self::catch(NakamotoStatus::Panic, || {
+        let client = match client.as_ref() {
+            Some(client) if !script.is_null() => client,
+            _ => return NakamotoStatus::InvalidArgument,
+        };
+        let script = Script::from(slice::from_raw_parts(script, len).to_vec());
+
+        client.handle.watch(vec![script]);
+
+        NakamotoStatus::Ok
+    })
+}
+
+/// Wait up to `timeout_ms` milliseconds for the next event, and write it to `event`.
+///
+/// Returns [`NakamotoStatus::Timeout`] if no event was received in time, in which case
+/// `event` is left untouched.
+///
+/// # Safety
+///
+/// `client` must be a client returned by [`nakamoto_client_new`], and `event` must point
+/// to a writable event. The event is overwritten without being freed: if it holds data
+/// from a previous call, it must first be freed with [`nakamoto_event_free`], or the data
+/// is leaked.
+#[no_mangle]
+pub unsafe extern "C" fn nakamoto_client_poll_event(
+    client: *mut NakamotoClient,
+    timeout_ms: u64,
+    event: *mut NakamotoEvent,
+) -> NakamotoStatus {
+    self::catch(NakamotoStatus::Panic, || {
+        let (client, event) = match (client.as_ref(), event.as_mut()) {
+            (Some(client), Some(event)) => (client, event),
+            _ => return NakamotoStatus::InvalidArgument,
+        };
+        let deadline = time::Instant::now() + time::Duration::from_millis(timeout_ms);
+
+        loop {
+            // Discard blocks we've received for previous filter matches.
+            client.blocks.1.try_iter().for_each(drop);
+
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+            let e = match client.events.recv_timeout(timeout) {
+                Ok(e) => e,
+                Err(chan::RecvTimeoutError::Timeout) => return NakamotoStatus::Timeout,
+                Err(chan::RecvTimeoutError::Disconnected) => return NakamotoStatus::Disconnected,
+            };
+            if let ClientEvent::FilterMatched { block_hash, .. } = &e {
+                if let Err(err) = client.handle.get_block(block_hash, client.blocks.0.clone()) {
+                    return err.into();
+                }
+            }
+            if let Some(e) = NakamotoEvent::from_client(&e) {
+                *event = e;
+
+                return NakamotoStatus::Ok;
+            }
+        }
+    })
+}
+
+/// Free the data of an event. The event itself is owned by the caller.
+///
+/// # Safety
+///
+/// `event` must be null, or an event written by [`nakamoto_client_poll_event`], whose data
+/// hasn't been modified.
+#[no_mangle]
+pub unsafe extern "C" fn nakamoto_event_free(event: *mut NakamotoEvent) {
+    self::catch((), || {
+        if let Some(event) = event.as_mut() {
+            if !event.data.is_null() {
+                drop(Box::from_raw(slice::from_raw_parts_mut(
+                    event.data, event.len,
+                )));
+            }
+            event.data = ptr::null_mut();
+            event.len = 0;
+        }
+    })
+}
+
+/// Broadcast a consensus-encoded transaction to the network.
+///
+/// # Safety
+///
+/// `client` must be a client returned by [`nakamoto_client_new`], and `tx` must point to
+/// `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nakamoto_client_broadcast(
+    client: *mut NakamotoClient,
+    tx: *const u8,
+    len: usize,
+) -> NakamotoStatus {
+    self::catch(NakamotoStatus::Panic, || {
+        let client = match client.as_ref() {
+            Some(client) if !tx.is_null() => client,
+            _ => return NakamotoStatus::InvalidArgument,
+        };
+        let tx: Transaction = match encode::deserialize(slice::from_raw_parts(tx, len)) {
+            Ok(tx) => tx,
+            Err(_) => return NakamotoStatus::InvalidArgument,
+        };
+
+        match client.handle.submit_transaction(tx) {
+            Ok(()) => NakamotoStatus::Ok,
+            Err(err) => err.into(),
+        }
+    })
+}
+
+/// Shut the client down, and free it. The client must not be used after this call,
+/// whatever the returned status.
+///
+/// # Safety
+///
+/// `client` must be a client returned by [`nakamoto_client_new`].
+#[no_mangle]
+pub unsafe extern "C" fn nakamoto_client_shutdown(client: *mut NakamotoClient) -> NakamotoStatus {
+    self::catch(NakamotoStatus::Panic, || {
+        if client.is_null() {
+            return NakamotoStatus::InvalidArgument;
+        }
+        let NakamotoClient { handle, thread, .. } = *Box::from_raw(client);
+
+        if let Err(err) = handle.shutdown() {
+            return err.into();
+        }
+        match thread.join() {
+            Ok(Ok(())) => NakamotoStatus::Ok,
+            Ok(Err(err)) => {
+                log::error!("Client error: {}", err);
+                NakamotoStatus::Client
+            }
+            Err(_) => NakamotoStatus::Panic,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nakamoto_common::block::BlockHash;
+    use nakamoto_p2p::bitcoin::blockdata::constants;
+    use nakamoto_p2p::bitcoin::hashes::hex::FromHex;
+
+    #[test]
+    fn test_null_arguments() {
+        let mut event = NakamotoEvent::new(NakamotoEventKind::TipChanged, [0; 32], 0);
+
+        unsafe {
+            assert_eq!(
+                nakamoto_client_watch(ptr::null_mut(), ptr::null(), 0),
+                NakamotoStatus::InvalidArgument
+            );
+            assert_eq!(
+                nakamoto_client_poll_event(ptr::null_mut(), 0, &mut event),
+                NakamotoStatus::InvalidArgument
+            );
+            assert_eq!(
+                nakamoto_client_broadcast(ptr::null_mut(), ptr::null(), 0),
+                NakamotoStatus::InvalidArgument
+            );
+            assert_eq!(
+                nakamoto_client_shutdown(ptr::null_mut()),
+                NakamotoStatus::InvalidArgument
+            );
+            nakamoto_event_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        assert_eq!(
+            catch(NakamotoStatus::Panic, || NakamotoStatus::Ok),
+            NakamotoStatus::Ok
+        );
+        assert_eq!(
+            catch(NakamotoStatus::Panic, || panic!("boom")),
+            NakamotoStatus::Panic
+        );
+    }
+
+    #[test]
+    fn test_transaction_event() {
+        let genesis = constants::genesis_block(nakamoto_p2p::bitcoin::Network::Bitcoin);
+        let transaction = genesis.txdata[0].clone();
+        let block_hash =
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        let mut event = NakamotoEvent::from_client(&ClientEvent::TransactionMatched {
+            transaction: transaction.clone(),
+            block_hash,
+            height: 0,
+        })
+        .unwrap();
+
+        assert_eq!(event.kind, NakamotoEventKind::TransactionMatched);
+        assert_eq!(event.hash, block_hash.into_inner());
+        assert_eq!(event.hash[0], 0x6f, "hashes are in internal byte order");
+
+        let data = unsafe { slice::from_raw_parts(event.data, event.len) };
+        assert_eq!(
+            encode::deserialize::<Transaction>(data).unwrap(),
+            transaction
+        );
+
+        unsafe { nakamoto_event_free(&mut event) };
+        assert!(event.data.is_null());
+    }
+}
@@ -0,0 +1,83 @@
+//! Feeds arbitrary messages to the protocol, as if they were received from a peer
+//! that completed the handshake.
+#![no_main]
+use std::collections::HashMap;
+use std::net;
+
+use crossbeam_channel as chan;
+use libfuzzer_sys::fuzz_target;
+
+use bitcoin::consensus::encode;
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_network::VersionMessage;
+
+use nakamoto_common::block::filter::FilterHeader;
+use nakamoto_common::block::time::{AdjustedTime, LocalTime};
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::peer::KnownAddress;
+use nakamoto_p2p::protocol::{Builder, Config, Input, Link, PROTOCOL_VERSION};
+use nakamoto_test::block::cache::model;
+
+fuzz_target!(|data: &[u8]| {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_block_time(genesis.time);
+    let (tx, rx) = chan::unbounded();
+    let mut protocol = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::with_seed(0),
+        cfg: Config::default(),
+    }
+    .build(tx);
+
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let remote: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+    let raw = |payload| RawNetworkMessage {
+        magic: network.magic(),
+        payload,
+    };
+
+    protocol.initialize(time);
+    protocol.step(
+        Input::Connected {
+            addr: remote,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    protocol.step(
+        Input::received(
+            remote,
+            raw(NetworkMessage::Version(VersionMessage {
+                version: PROTOCOL_VERSION,
+                services,
+                timestamp: genesis.time as i64,
+                receiver: Address::new(&local_addr, ServiceFlags::NONE),
+                sender: Address::new(&remote, services),
+                nonce: 1,
+                user_agent: String::from("/fuzz:0.0.0/"),
+                start_height: 0,
+                relay: false,
+            })),
+        ),
+        time,
+    );
+    protocol.step(Input::received(remote, raw(NetworkMessage::Verack)), time);
+
+    // Decode as many messages as possible from the input, and feed them to the protocol.
+    // The magic is fixed, so that messages aren't trivially rejected.
+    let mut data = data;
+    while let Ok((mut msg, n)) = encode::deserialize_partial::<RawNetworkMessage>(data) {
+        msg.magic = network.magic();
+        protocol.step(Input::received(remote, msg), time);
+        data = &data[n..];
+    }
+    rx.try_iter().for_each(drop);
+});
@@ -0,0 +1,24 @@
+//! Feeds arbitrary bytes to the socket reader, as if they were received from a peer.
+#![no_main]
+use std::io;
+use std::net;
+
+use libfuzzer_sys::fuzz_target;
+
+use bitcoin::network::message::RawNetworkMessage;
+
+use nakamoto_common::block::time::LocalTime;
+use nakamoto_net_poll::socket::Socket;
+use nakamoto_p2p::protocol::Link;
+
+fuzz_target!(|data: &[u8]| {
+    let addr: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let stream = io::Cursor::new(data.to_vec());
+    let mut socket = Socket::<_, RawNetworkMessage>::from(stream, addr, Link::Inbound);
+
+    // Read messages until the input is exhausted, or the stream is unreadable, and
+    // decode them as the protocol would.
+    while let Ok(envelope) = socket.read(LocalTime::default()) {
+        envelope.decode().ok();
+    }
+});
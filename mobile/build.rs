@@ -0,0 +1,4 @@
+//! Generates the Rust scaffolding for the UniFFI interface definition.
+fn main() {
+    uniffi_build::generate_scaffolding("./src/nakamoto.udl").expect("scaffolding is generated");
+}
@@ -0,0 +1,261 @@
+//! Kotlin and Swift bindings for the nakamoto light-client, generated with UniFFI.
+//!
+//! The interface is defined in `src/nakamoto.udl`. Bindings are generated with
+//! `uniffi-bindgen`, eg.
+//!
+//! ```text
+//! uniffi-bindgen generate src/nakamoto.udl --language kotlin --config-path uniffi.toml
+//! uniffi-bindgen generate src/nakamoto.udl --language swift --config-path uniffi.toml
+//! ```
+//!
+//! The client runs in the background for as long as the [`LightClient`] object is alive,
+//! or until it is shut down. Events are delivered to an [`EventListener`] on a dedicated
+//! thread. Since mobile applications may be paused and resumed at any time, the listener
+//! can be replaced or cleared while the client runs, eg. when an activity or view
+//! controller goes away. Events emitted while no listener is set are dropped.
+#![deny(missing_docs)]
+use std::sync::{Arc, Mutex};
+use std::{fmt, net, thread};
+
+use crossbeam_channel as chan;
+use thiserror::Error;
+
+use nakamoto_client::builder::ClientBuilder;
+use nakamoto_client::client::Handle;
+use nakamoto_client::event::{ClientEvent, SyncState};
+use nakamoto_client::handle::{self, Handle as _};
+use nakamoto_common::network;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::{Script, Transaction};
+
+uniffi_macros::include_scaffolding!("nakamoto");
+
+/// The network reactor we're going to use.
+type Reactor = nakamoto_net_poll::Reactor<net::TcpStream>;
+
+/// An error returned to the bindings.
+#[derive(Error, Debug)]
+pub enum NakamotoError {
+    /// An argument was invalid.
+    #[error("invalid argument")]
+    InvalidArgument,
+    /// The client encountered an error. Details are logged.
+    #[error("client error")]
+    Client,
+    /// The operation timed out.
+    #[error("the operation timed out")]
+    Timeout,
+    /// The client is no longer running.
+    #[error("client disconnected")]
+    Disconnected,
+}
+
+impl From<handle::Error> for NakamotoError {
+    fn from(err: handle::Error) -> Self {
+        match err {
+            handle::Error::Disconnected => Self::Disconnected,
+            handle::Error::Timeout => Self::Timeout,
+            handle::Error::Io(err) => {
+                log::error!("Client I/O error: {}", err);
+                Self::Client
+            }
+            handle::Error::GetFilters(err) => {
+                log::error!("Error requesting filters: {}", err);
+                Self::Client
+            }
+        }
+    }
+}
+
+/// Bitcoin network to connect to.
+#[derive(Debug, Copy, Clone)]
+pub enum Network {
+    /// Bitcoin Mainnet.
+    Mainnet,
+    /// Bitcoin Testnet.
+    Testnet,
+    /// Bitcoin regression test net.
+    Regtest,
+}
+
+impl From<Network> for network::Network {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::Mainnet,
+            Network::Testnet => Self::Testnet,
+            Network::Regtest => Self::Regtest,
+        }
+    }
+}
+
+/// Receives client events. Implemented by the application, in Kotlin or Swift.
+///
+/// Block hashes are passed in their usual hex representation. Transactions are
+/// consensus-encoded.
+pub trait EventListener: Send + Sync + fmt::Debug {
+    /// A peer connected.
+    fn on_peer_connected(&self, addr: String);
+    /// A peer disconnected.
+    fn on_peer_disconnected(&self, addr: String);
+    /// The chain tip changed.
+    fn on_tip_changed(&self, hash: String, height: u64);
+    /// Headers are synced up to the given block.
+    fn on_synced(&self, hash: String, height: u64);
+    /// A transaction of a watched script was found.
+    fn on_transaction_matched(&self, transaction: Vec<u8>, block_hash: String, height: u64);
+}
+
+/// The current event listener, if any.
+type Listener = Arc<Mutex<Option<Box<dyn EventListener>>>>;
+
+/// A light-client running in the background.
+pub struct LightClient {
+    handle: Handle<Reactor>,
+    listener: Listener,
+    /// Stops the event dispatch thread when dropped.
+    stop: Mutex<Option<chan::Sender<()>>>,
+}
+
+impl LightClient {
+    /// Create a light-client for the given network, storing its runtime data under the
+    /// given directory, and start it in the background.
+    pub fn new(network: Network, home: String) -> Result<Self, NakamotoError> {
+        if home.is_empty() {
+            return Err(NakamotoError::InvalidArgument);
+        }
+        let client = ClientBuilder::new(network.into())
+            .listen(vec![]) // Don't listen for incoming connections.
+            .home(home)
+            .build::<Reactor>()
+            .map_err(|err| {
+                log::error!("Error creating client: {}", err);
+                NakamotoError::Client
+            })?;
+        let handle = client.handle();
+        let listener = Listener::default();
+        let (stop, stopped) = chan::bounded(0);
+
+        thread::spawn(move || {
+            if let Err(err) = client.run() {
+                log::error!("Client error: {}", err);
+            }
+        });
+        thread::spawn({
+            let handle = handle.clone();
+            let listener = listener.clone();
+
+            move || self::dispatch(handle, listener, stopped)
+        });
+
+        Ok(Self {
+            handle,
+            listener,
+            stop: Mutex::new(Some(stop)),
+        })
+    }
+
+    /// Set the event listener, replacing the current one.
+    pub fn set_listener(&self, listener: Box<dyn EventListener>) {
+        *self.listener.lock().unwrap() = Some(listener);
+    }
+
+    /// Clear the event listener, eg. when the application is paused.
+    pub fn clear_listener(&self) {
+        *self.listener.lock().unwrap() = None;
+    }
+
+    /// Watch a script. Transactions of watched scripts are reported to the listener.
+    pub fn watch(&self, script: Vec<u8>) {
+        self.handle.watch(vec![Script::from(script)]);
+    }
+
+    /// Get the height of the chain tip.
+    pub fn height(&self) -> Result<u64, NakamotoError> {
+        let (height, _) = self.handle.get_tip()?;
+
+        Ok(height)
+    }
+
+    /// Broadcast a consensus-encoded transaction to the network.
+    pub fn broadcast(&self, transaction: Vec<u8>) -> Result<(), NakamotoError> {
+        let tx: Transaction =
+            encode::deserialize(&transaction).map_err(|_| NakamotoError::InvalidArgument)?;
+
+        self.handle.submit_transaction(tx)?;
+
+        Ok(())
+    }
+
+    /// Shut the client down. Subsequent calls return [`NakamotoError::Disconnected`].
+    pub fn shutdown(&self) -> Result<(), NakamotoError> {
+        if self.stop.lock().unwrap().take().is_none() {
+            return Err(NakamotoError::Disconnected);
+        }
+        self.handle.clone().shutdown()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for LightClient {
+    fn drop(&mut self) {
+        if self.stop.lock().unwrap().is_some() {
+            self.shutdown().ok();
+        }
+    }
+}
+
+/// Dispatch client events to the current listener, until stopped.
+fn dispatch(handle: Handle<Reactor>, listener: Listener, stopped: chan::Receiver<()>) {
+    let events = handle.subscribe();
+    // Blocks requested for matched filters. Matching transactions are emitted by the
+    // client as events once the block is received, so the blocks themselves are
+    // discarded.
+    let (blocks, received) = chan::unbounded();
+
+    loop {
+        let event = chan::select! {
+            recv(events) -> event => match event {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+            recv(received) -> _ => continue,
+            recv(stopped) -> _ => break,
+        };
+
+        if let ClientEvent::FilterMatched { block_hash, .. } = &event {
+            if let Err(err) = handle.get_block(block_hash, blocks.clone()) {
+                log::warn!("Error requesting block {}: {}", block_hash, err);
+            }
+            continue;
+        }
+        let listener = listener.lock().unwrap();
+        let listener = match listener.as_ref() {
+            Some(listener) => listener,
+            None => continue,
+        };
+
+        match event {
+            ClientEvent::PeerConnected { addr, .. } => listener.on_peer_connected(addr.to_string()),
+            ClientEvent::PeerDisconnected { addr } => {
+                listener.on_peer_disconnected(addr.to_string())
+            }
+            ClientEvent::TipChanged { hash, height, .. } => {
+                listener.on_tip_changed(hash.to_string(), height)
+            }
+            ClientEvent::SyncStateChanged(SyncState::Synced { hash, height }) => {
+                listener.on_synced(hash.to_string(), height)
+            }
+            ClientEvent::TransactionMatched {
+                transaction,
+                block_hash,
+                height,
+            } => listener.on_transaction_matched(
+                encode::serialize(&transaction),
+                block_hash.to_string(),
+                height,
+            ),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Chaos mode, to continuously exercise the error handling and reconnection paths.
+//!
+//! When enabled with [`Reactor::with_chaos`](crate::Reactor::with_chaos), the reactor
+//! randomly delays writes, drops connections, and fails reads and writes with transient
+//! errors, ie. as if they would block. All decisions are drawn from an RNG seeded with
+//! [`Config::seed`], so that a schedule can be reproduced, given the same I/O events.
+//!
+//! Only available with the `chaos` feature. Not meant to be used outside of tests.
+use std::collections::HashSet;
+use std::net;
+
+use log::*;
+
+use crate::time::{LocalDuration, LocalTime, TimeoutManager};
+
+/// Chaos mode configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Seed of the chaos schedule.
+    pub seed: u64,
+    /// Probability that writes to a peer are delayed, between `0.0` and `1.0`.
+    pub delay: f64,
+    /// Maximum delay of delayed writes.
+    pub max_delay: LocalDuration,
+    /// Probability that a connection is dropped when it becomes readable.
+    pub disconnect: f64,
+    /// Probability that a read or write fails with a transient error.
+    pub error: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            delay: 0.1,
+            max_delay: LocalDuration::from_secs(1),
+            disconnect: 0.01,
+            error: 0.1,
+        }
+    }
+}
+
+/// Chaos schedule.
+pub(crate) struct Chaos {
+    cfg: Config,
+    rng: fastrand::Rng,
+    /// Peers whose writes are delayed, and until when.
+    delays: TimeoutManager<net::SocketAddr>,
+    delayed: HashSet<net::SocketAddr>,
+}
+
+impl Chaos {
+    /// Create a new chaos schedule.
+    pub fn new(cfg: Config) -> Self {
+        Self {
+            rng: fastrand::Rng::with_seed(cfg.seed),
+            cfg,
+            delays: TimeoutManager::new(),
+            delayed: HashSet::new(),
+        }
+    }
+
+    /// Check whether writes to the given peer should be held back. Writes stay delayed
+    /// until the peer is returned by [`Chaos::wake`], so that messages stay in order.
+    pub fn delay(&mut self, addr: &net::SocketAddr, local_time: LocalTime) -> bool {
+        if self.delayed.contains(addr) {
+            return true;
+        }
+        if self.rng.f64() < self.cfg.delay {
+            let max = self.cfg.max_delay.as_millis() as u64;
+            let delay = LocalDuration::from_millis(self.rng.u64(..=max) as u128);
+
+            debug!(
+                "{}: (chaos) Delaying writes by {}ms",
+                addr,
+                delay.as_millis()
+            );
+
+            self.delays.register(*addr, local_time + delay);
+            self.delayed.insert(*addr);
+
+            return true;
+        }
+        false
+    }
+
+    /// Check whether a connection should be dropped.
+    pub fn disconnect(&mut self) -> bool {
+        self.rng.f64() < self.cfg.disconnect
+    }
+
+    /// Check whether an I/O operation should fail with a transient error.
+    pub fn error(&mut self) -> bool {
+        self.rng.f64() < self.cfg.error
+    }
+
+    /// Time until the next delayed write is due, if any.
+    pub fn next(&self) -> Option<LocalDuration> {
+        self.delays.next()
+    }
+
+    /// Populate the given vector with the peers whose writes are no longer delayed.
+    pub fn wake(&mut self, local_time: LocalTime, woken: &mut Vec<net::SocketAddr>) {
+        self.delays.wake(local_time, woken);
+
+        for addr in woken.iter() {
+            self.delayed.remove(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule() {
+        let addr: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+        let time = LocalTime::from_secs(1_600_000_000);
+        let cfg = Config {
+            seed: 7,
+            delay: 0.5,
+            ..Config::default()
+        };
+
+        // The same seed yields the same schedule.
+        let schedule = |chaos: &mut Chaos| {
+            (0..64)
+                .map(|_| (chaos.disconnect(), chaos.error()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            schedule(&mut Chaos::new(cfg.clone())),
+            schedule(&mut Chaos::new(cfg.clone()))
+        );
+
+        // Delayed writes stay delayed until they're due.
+        let mut chaos = Chaos::new(cfg);
+        let mut woken = Vec::new();
+
+        while !chaos.delay(&addr, time) {}
+
+        assert!(chaos.delay(&addr, time));
+        chaos.wake(time + LocalDuration::from_secs(1), &mut woken);
+        assert_eq!(woken, vec![addr]);
+
+        chaos.wake(time + LocalDuration::from_secs(2), &mut woken);
+        assert!(woken.is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut chaos = Chaos::new(Config {
+            delay: 0.,
+            disconnect: 0.,
+            error: 0.,
+            ..Config::default()
+        });
+        let addr: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+        for _ in 0..64 {
+            assert!(!chaos.delay(&addr, LocalTime::default()));
+            assert!(!chaos.disconnect());
+            assert!(!chaos.error());
+        }
+        assert!(chaos.next().is_none());
+    }
+}
@@ -7,16 +7,17 @@
 //! To illustrate the above, lets trace the behavior of the system when a `ping`
 //! message is received via a peer connection to the client:
 //!
-//! 1. The `Reactor` reads from the socket and decodes a `NetworkMessage::Ping`
-//!    message.
+//! 1. The `Reactor` reads a complete `ping` message from the socket, without decoding
+//!    it.
 //! 2. The `Reactor` wraps this message into a protocol input `Input::Received(addr,
-//!    NetworkMessage::Ping)`, where `addr` is the remote address of the socket on
-//!    which it received this message.
+//!    envelope)`, where `addr` is the remote address of the socket on which it received
+//!    this message, and `envelope` holds the message command and its encoded bytes.
 //! 3. The `Reactor` calls `Protocol::step(input, time)`, where `input` is the above
 //!    input, and `time` is the current local time.
-//! 4. The `Protocol` forwards this message to the `PingManager`, which constructs
-//!    a new output `Out::Message(addr, NetworkMessage::Pong)`, and forwards it
-//!    upstream, to the reactor.
+//! 4. The `Protocol` decodes the message, since `ping` is a command it handles, and
+//!    forwards it to the `PingManager`, which constructs a new output
+//!    `Out::Message(addr, NetworkMessage::Pong)`, and forwards it upstream, to the
+//!    reactor.
 //! 5. The `Reactor` processes the output, encodes the raw message and writes it to
 //!    the socket corresponding to the `addr` address, effectively sending a `pong`
 //!    message back to the original sender.
@@ -25,6 +26,9 @@
 //! reactor and protocol interplay to handle network events.
 //!
 
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod pipe;
 #[cfg(unix)]
 pub mod reactor;
 pub mod socket;
@@ -0,0 +1,170 @@
+//! In-memory duplex transport.
+//!
+//! Connects two [`Socket`](crate::socket::Socket) instances without using the network, so
+//! that message framing and encoding can be exercised in tests. Each end of a [`pipe`]
+//! behaves like a non-blocking stream: reads fail with [`io::ErrorKind::WouldBlock`] when
+//! there is nothing to read, and return `0` once the other end was dropped.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// One direction of a pipe.
+#[derive(Debug, Default)]
+struct Buffer {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One end of an in-memory duplex pipe. Created with [`pipe`].
+#[derive(Debug)]
+pub struct Pipe {
+    /// Bytes written by the other end.
+    incoming: Arc<Mutex<Buffer>>,
+    /// Bytes written by this end.
+    outgoing: Arc<Mutex<Buffer>>,
+    /// Maximum number of bytes returned by a single read.
+    read_size: usize,
+}
+
+/// Create a pair of connected pipe ends. What is written to one end can be read from
+/// the other.
+pub fn pipe() -> (Pipe, Pipe) {
+    let a = Arc::new(Mutex::new(Buffer::default()));
+    let b = Arc::new(Mutex::new(Buffer::default()));
+
+    (
+        Pipe {
+            incoming: a.clone(),
+            outgoing: b.clone(),
+            read_size: usize::MAX,
+        },
+        Pipe {
+            incoming: b,
+            outgoing: a,
+            read_size: usize::MAX,
+        },
+    )
+}
+
+impl Pipe {
+    /// Limit the number of bytes returned by a single read, to simulate messages
+    /// arriving in fragments.
+    pub fn set_read_size(&mut self, read_size: usize) {
+        assert!(read_size > 0, "read size must be positive");
+
+        self.read_size = read_size;
+    }
+
+    /// Number of bytes written by the other end that weren't read yet.
+    pub fn pending(&self) -> usize {
+        self.incoming.lock().unwrap().bytes.len()
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+
+        if incoming.bytes.is_empty() {
+            if incoming.closed {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.read_size).min(incoming.bytes.len());
+
+        for (b, byte) in buf.iter_mut().zip(incoming.bytes.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.lock().unwrap();
+
+        if outgoing.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        outgoing.bytes.extend(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        // Signal end-of-file to the other end, and fail its writes.
+        self.outgoing.lock().unwrap().closed = true;
+        self.incoming.lock().unwrap().closed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::consensus::encode;
+    use bitcoin::network::constants::Network;
+    use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+
+    use nakamoto_common::block::time::LocalTime;
+    use nakamoto_p2p::protocol::Link;
+
+    use crate::socket::Socket;
+
+    #[test]
+    fn test_sockets() {
+        let time = LocalTime::from_secs(1_000_000);
+        let (a, mut b) = pipe();
+        b.set_read_size(7);
+
+        let mut alice = Socket::<_, RawNetworkMessage>::from(
+            a,
+            ([88, 13, 16, 59], 8333).into(),
+            Link::Outbound,
+        );
+        let mut bob =
+            Socket::<_, RawNetworkMessage>::from(b, ([44, 1, 8, 2], 8333).into(), Link::Inbound);
+
+        let msgs = vec![
+            NetworkMessage::Ping(42),
+            NetworkMessage::GetAddr,
+            NetworkMessage::Pong(42),
+        ]
+        .into_iter()
+        .map(|payload| RawNetworkMessage {
+            magic: Network::Bitcoin.magic(),
+            payload,
+        })
+        .collect::<Vec<_>>();
+
+        for msg in &msgs {
+            alice.write(msg).unwrap();
+        }
+        for msg in &msgs {
+            assert_eq!(&bob.read(time).unwrap().decode().unwrap(), msg);
+        }
+        assert!(matches!(
+            bob.read(time),
+            Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock
+        ));
+
+        // Replies travel in the other direction.
+        bob.write(&msgs[0]).unwrap();
+        assert_eq!(alice.read(time).unwrap().decode().unwrap(), msgs[0]);
+
+        // Dropping one end is seen as a disconnect by the other.
+        drop(alice);
+        assert!(matches!(
+            bob.read(time),
+            Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert!(bob.write(&msgs[0]).is_err());
+    }
+}
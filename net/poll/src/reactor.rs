@@ -12,12 +12,15 @@ use nakamoto_common::p2p::peer;
 use nakamoto_p2p;
 use nakamoto_p2p::error::Error;
 use nakamoto_p2p::event::Event;
+use nakamoto_p2p::journal::Journal;
 use nakamoto_p2p::protocol::{self, Command, DisconnectReason, Input, Link, Out};
+use nakamoto_p2p::replay::Recorder;
 
 use log::*;
 
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::net;
@@ -26,6 +29,8 @@ use std::sync::Arc;
 use std::time;
 use std::time::SystemTime;
 
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, Chaos};
 use crate::fallible;
 use crate::socket::Socket;
 use crate::time::TimeoutManager;
@@ -36,6 +41,9 @@ const READ_TIMEOUT: time::Duration = time::Duration::from_secs(6);
 const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(3);
 /// Maximum amount of time to wait for i/o.
 const WAIT_TIMEOUT: LocalDuration = LocalDuration::from_mins(60);
+/// Maximum number of messages read from a peer per iteration of the event loop. This
+/// prevents a peer with a lot of data to send from starving the other peers.
+const READ_BUDGET: usize = 64;
 
 #[must_use]
 #[derive(Debug, PartialEq, Eq)]
@@ -56,11 +64,17 @@ pub struct Reactor<R: Write + Read> {
     peers: HashMap<net::SocketAddr, Socket<R, RawNetworkMessage>>,
     connecting: HashSet<net::SocketAddr>,
     inputs: VecDeque<Input>,
+    /// Peers that ran out of read budget, and may have more messages to read.
+    backlog: HashSet<net::SocketAddr>,
     subscriber: chan::Sender<Event>,
     commands: chan::Receiver<Command>,
     sources: popol::Sources<Source>,
     waker: Arc<popol::Waker>,
     timeouts: TimeoutManager<()>,
+    journal: Option<Journal<io::LineWriter<fs::File>>>,
+    recorder: Option<Recorder<io::LineWriter<fs::File>>>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
 }
 
 /// The `R` parameter represents the underlying stream type, eg. `net::TcpStream`.
@@ -75,10 +89,18 @@ impl<R: Write + Read + AsRawFd> Reactor<R> {
     /// Unregister a peer from the reactor.
     fn unregister_peer(&mut self, addr: net::SocketAddr, reason: DisconnectReason) {
         self.connecting.remove(&addr);
+        self.backlog.remove(&addr);
         self.inputs.push_back(Input::Disconnected(addr, reason));
         self.sources.unregister(&Source::Peer(addr));
         self.peers.remove(&addr);
     }
+
+    /// Enable chaos mode, with the given configuration. See [`crate::chaos`].
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, cfg: chaos::Config) -> Self {
+        self.chaos = Some(Chaos::new(cfg));
+        self
+    }
 }
 
 impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
@@ -102,10 +124,15 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
             connecting,
             sources,
             inputs,
+            backlog: HashSet::new(),
             subscriber,
             commands,
             waker,
             timeouts,
+            journal: None,
+            recorder: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
         })
     }
 
@@ -131,12 +158,30 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
             Some(listener)
         };
 
+        if let Some(path) = &builder.cfg.journal {
+            info!("Recording protocol outputs to {:?}", path);
+
+            self.journal = Some(Journal::open(path)?);
+        }
+        if let Some(path) = &builder.cfg.recording {
+            info!("Recording protocol inputs to {:?}", path);
+
+            self.recorder = Some(Recorder::create(path)?);
+        }
+
         info!("Initializing protocol..");
 
         let (tx, rx) = chan::unbounded();
         let mut protocol = builder.build(tx);
         let local_time = SystemTime::now().into();
 
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.initialize(local_time) {
+                error!("Error writing to recording, disabling it: {}", err);
+
+                self.recorder = None;
+            }
+        }
         protocol.initialize(local_time);
 
         if let Control::Shutdown = self.process(&rx, local_time, &callback)? {
@@ -144,8 +189,11 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
         }
 
         // Drain input events in case some were added during the processing of outputs.
-        while let Some(event) = self.inputs.pop_front() {
-            protocol.step(event, local_time);
+        while !self.inputs.is_empty() {
+            let batch = self.inputs.drain(..).collect::<Vec<_>>();
+
+            self.record(local_time, &batch);
+            protocol.step_batch(batch, local_time);
 
             if let Control::Shutdown = self.process(&rx, local_time, &callback)? {
                 return Ok(());
@@ -164,9 +212,20 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
                 self.timeouts.len()
             );
 
-            let timeout = self.timeouts.next().unwrap_or(WAIT_TIMEOUT).into();
-            let result = self.sources.wait_timeout(&mut events, timeout); // Blocking.
+            // Don't block if there are messages left to read from the last iteration.
+            let timeout = if self.backlog.is_empty() {
+                self.timeouts.next().unwrap_or(WAIT_TIMEOUT)
+            } else {
+                LocalDuration::from_secs(0)
+            };
+            #[cfg(feature = "chaos")]
+            let timeout = match self.chaos.as_ref().and_then(|c| c.next()) {
+                Some(delay) => delay.min(timeout),
+                None => timeout,
+            };
+            let result = self.sources.wait_timeout(&mut events, timeout.into()); // Blocking.
             let local_time = SystemTime::now().into();
+            let mut backlog = std::mem::take(&mut self.backlog);
 
             match result {
                 Ok(()) => {
@@ -188,10 +247,11 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
                                 }
 
                                 if ev.writable {
-                                    self.handle_writable(&addr, source)?;
+                                    self.handle_writable(&addr, source, local_time)?;
                                 }
                                 if ev.readable {
-                                    self.handle_readable(&addr);
+                                    backlog.remove(addr);
+                                    self.handle_readable(&addr, local_time);
                                 }
                             }
                             Source::Listener => loop {
@@ -206,6 +266,12 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
                                             break;
                                         }
                                     };
+                                    // Keep the existing connection if there is one,
+                                    // since peers are identified by address.
+                                    if self.peers.contains_key(&addr) {
+                                        debug!("{}: Dropping duplicate connection", addr);
+                                        continue;
+                                    }
                                     conn.set_nonblocking(true)?;
 
                                     let local_addr = conn.local_addr()?;
@@ -239,8 +305,40 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
                 Err(err) => return Err(err.into()),
             }
 
-            while let Some(event) = self.inputs.pop_front() {
-                protocol.step(event, local_time);
+            // Read the remaining messages of peers that ran out of budget in the last
+            // iteration, and weren't readable in this one.
+            for addr in backlog {
+                if self.peers.contains_key(&addr) {
+                    self.handle_readable(&addr, local_time);
+                }
+            }
+
+            #[cfg(feature = "chaos")]
+            self.drain_delayed(local_time);
+
+            // Disconnect peers that are sending us data too slowly.
+            let stalled = self
+                .peers
+                .iter()
+                .filter(|(_, socket)| socket.is_stalled(local_time))
+                .map(|(addr, _)| *addr)
+                .collect::<Vec<_>>();
+
+            for addr in stalled {
+                if let Some(socket) = self.peers.get(&addr) {
+                    socket.disconnect().ok();
+                }
+                self.unregister_peer(
+                    addr,
+                    DisconnectReason::PeerMisbehaving("peer is sending data too slowly"),
+                );
+            }
+
+            while !self.inputs.is_empty() {
+                let batch = self.inputs.drain(..).collect::<Vec<_>>();
+
+                self.record(local_time, &batch);
+                protocol.step_batch(batch, local_time);
 
                 if let Control::Shutdown = self.process(&rx, local_time, &callback)? {
                     return Ok(());
@@ -263,6 +361,23 @@ impl nakamoto_p2p::reactor::Reactor for Reactor<net::TcpStream> {
 }
 
 impl Reactor<net::TcpStream> {
+    /// Record a batch of protocol inputs, if recording is enabled.
+    fn record(&mut self, local_time: LocalTime, batch: &[Input]) {
+        if let Some(recorder) = &mut self.recorder {
+            let result = recorder.batch(local_time, batch.len()).and_then(|()| {
+                batch
+                    .iter()
+                    .try_for_each(|input| recorder.record(local_time, input))
+            });
+
+            if let Err(err) = result {
+                error!("Error writing to recording, disabling it: {}", err);
+
+                self.recorder = None;
+            }
+        }
+    }
+
     /// Process protocol state machine outputs.
     fn process<C: Fn(Event)>(
         &mut self,
@@ -273,6 +388,13 @@ impl Reactor<net::TcpStream> {
         // Note that there may be messages destined for a peer that has since been
         // disconnected.
         for out in outputs.try_iter() {
+            if let Some(journal) = &mut self.journal {
+                if let Err(err) = journal.record(local_time, &out) {
+                    error!("Error writing to journal, disabling it: {}", err);
+
+                    self.journal = None;
+                }
+            }
             match out {
                 Out::Message(addr, msg) => {
                     if let Some(peer) = self.peers.get_mut(&addr) {
@@ -290,6 +412,10 @@ impl Reactor<net::TcpStream> {
 
                         peer.queue(msg);
 
+                        #[cfg(feature = "chaos")]
+                        if self::hold_writes(&mut self.chaos, &addr, src, local_time) {
+                            continue;
+                        }
                         if let Err(err) = peer.drain(&mut self.inputs, src) {
                             error!("{}: Write error: {}", addr, err.to_string());
 
@@ -340,56 +466,107 @@ impl Reactor<net::TcpStream> {
                     trace!("Event: {:?}", event);
 
                     callback(event.clone());
-                    self.subscriber.try_send(event).unwrap(); // FIXME
+                    // Nb. The subscriber may have gone away, in which case there is no one
+                    // left to notify.
+                    self.subscriber.try_send(event).ok();
                 }
                 Out::Shutdown => {
                     info!("Shutdown received");
 
+                    // Close any remaining connections, eg. ones that are still being
+                    // established.
+                    for (_, peer) in self.peers.drain() {
+                        peer.disconnect().ok();
+                    }
+
                     return Ok(Control::Shutdown);
                 }
+                Out::Fatal(err) => {
+                    error!("Shutting down due to fatal error: {}", err);
+
+                    for (_, peer) in self.peers.drain() {
+                        peer.disconnect().ok();
+                    }
+                    return Err(err.into());
+                }
             }
         }
         Ok(Control::Continue)
     }
 
-    fn handle_readable(&mut self, addr: &net::SocketAddr) {
+    fn handle_readable(&mut self, addr: &net::SocketAddr, local_time: LocalTime) {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("reactor", peer = %addr).entered();
         let socket = self.peers.get_mut(&addr).unwrap();
 
         trace!("{}: Socket is readable", addr);
 
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &mut self.chaos {
+            if chaos.disconnect() {
+                debug!("{}: (chaos) Dropping connection", addr);
+
+                // The read below fails, as if the peer had closed the connection.
+                socket.disconnect().ok();
+            } else if chaos.error() {
+                // Since `poll` is level-triggered, we'll be notified again.
+                return;
+            }
+        }
+
         // Nb. Normally, since `poll`, which `popol` is based on, is
         // level-triggered, we would be notified again if there was
         // still data to be read on the socket. However, since our
-        // socket abstraction actually returns *decoded messages*, this
+        // socket abstraction actually returns *complete messages*, this
         // doesn't apply. Thus, we have to loop to not miss messages.
-        loop {
-            match socket.read() {
-                Ok(msg) => {
-                    self.inputs.push_back(Input::Received(*addr, msg));
+        //
+        // To be fair to other peers, we only read up to a budget of messages. If it runs
+        // out, the peer is added to the backlog, and read from in the next iteration.
+        for _ in 0..READ_BUDGET {
+            match socket.read(local_time) {
+                Ok(envelope) => {
+                    self.inputs.push_back(Input::Received(*addr, envelope));
                 }
                 Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {
-                    break;
+                    return;
                 }
                 Err(err) => {
-                    match err {
+                    let reason = match err {
                         encode::Error::Io(ref err)
                             if err.kind() == io::ErrorKind::UnexpectedEof =>
                         {
-                            trace!("{}: Remote peer closed the connection", addr)
+                            trace!("{}: Remote peer closed the connection", addr);
+
+                            DisconnectReason::PeerDisconnected
                         }
-                        _ => trace!("{}: Read error: {}", addr, err.to_string()),
-                    }
+                        _ => {
+                            trace!("{}: Read error: {}", addr, err.to_string());
+
+                            DisconnectReason::ConnectionError(err.to_string())
+                        }
+                    };
 
                     socket.disconnect().ok();
-                    self.unregister_peer(*addr, DisconnectReason::ConnectionError(err.to_string()));
+                    self.unregister_peer(*addr, reason);
 
-                    break;
+                    return;
                 }
             }
         }
+        trace!("{}: Read budget exhausted", addr);
+
+        self.backlog.insert(*addr);
     }
 
-    fn handle_writable(&mut self, addr: &net::SocketAddr, source: &Source) -> io::Result<()> {
+    fn handle_writable(
+        &mut self,
+        addr: &net::SocketAddr,
+        source: &Source,
+        #[allow(unused_variables)] local_time: LocalTime,
+    ) -> io::Result<()> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("reactor", peer = %addr).entered();
+
         trace!("{}: Socket is writable", addr);
 
         let src = self.sources.get_mut(source).unwrap();
@@ -405,6 +582,10 @@ impl Reactor<net::TcpStream> {
             });
         }
 
+        #[cfg(feature = "chaos")]
+        if self::hold_writes(&mut self.chaos, addr, src, local_time) {
+            return Ok(());
+        }
         if let Err(err) = socket.drain(&mut self.inputs, src) {
             error!("{}: Write error: {}", addr, err.to_string());
 
@@ -413,6 +594,60 @@ impl Reactor<net::TcpStream> {
         }
         Ok(())
     }
+
+    /// Write the messages queued for peers whose writes were delayed by chaos mode.
+    #[cfg(feature = "chaos")]
+    fn drain_delayed(&mut self, local_time: LocalTime) {
+        let mut woken = Vec::new();
+
+        if let Some(chaos) = &mut self.chaos {
+            chaos.wake(local_time, &mut woken);
+        }
+        for addr in woken {
+            let (socket, src) = match (
+                self.peers.get_mut(&addr),
+                self.sources.get_mut(&Source::Peer(addr)),
+            ) {
+                (Some(socket), Some(src)) => (socket, src),
+                _ => continue,
+            };
+            if let Err(err) = socket.drain(&mut self.inputs, src) {
+                error!("{}: Write error: {}", addr, err.to_string());
+
+                socket.disconnect().ok();
+                self.unregister_peer(addr, DisconnectReason::ConnectionError(err.to_string()));
+            }
+        }
+    }
+}
+
+/// Check whether chaos mode holds back writes to the given peer. Writes failing with a
+/// transient error are retried once the socket is writable.
+#[cfg(feature = "chaos")]
+fn hold_writes(
+    chaos: &mut Option<Chaos>,
+    addr: &net::SocketAddr,
+    source: &mut popol::Source,
+    local_time: LocalTime,
+) -> bool {
+    let chaos = match chaos {
+        Some(chaos) => chaos,
+        None => return false,
+    };
+    if chaos.delay(addr, local_time) {
+        // Writes are resumed by `Reactor::drain_delayed`.
+        source.unset(popol::interest::WRITE);
+
+        return true;
+    }
+    if chaos.error() {
+        debug!("{}: (chaos) Write would block", addr);
+
+        source.set(popol::interest::WRITE);
+
+        return true;
+    }
+    false
 }
 
 /// Connect to a peer given a remote address.
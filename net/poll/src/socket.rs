@@ -6,16 +6,25 @@ use std::net;
 
 use bitcoin::consensus::encode::Decodable;
 use bitcoin::consensus::encode::{self, Encodable};
-use bitcoin::network::stream_reader::StreamReader;
+use bitcoin::network::message::RawNetworkMessage;
 
 use log::*;
 
-use nakamoto_p2p::protocol::{Input, Link};
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_p2p::protocol::{Envelope, Input, Link};
 
 use crate::fallible;
 
 /// Maximum peer-to-peer message size.
 const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+/// Size of a message header: magic, command, payload length and checksum.
+const MESSAGE_HEADER_SIZE: usize = Envelope::HEADER_SIZE;
+/// Size of the buffer used to read from the underlying stream.
+const READ_BUFFER_SIZE: usize = 1024 * 64;
+/// Time given to a peer to send an incomplete message, before its receive rate is checked.
+const MESSAGE_GRACE_PERIOD: LocalDuration = LocalDuration::from_secs(30);
+/// Minimum rate at which an incomplete message must be received, in bytes per second.
+const MIN_RECEIVE_RATE: u128 = 1024;
 
 /// Peer-to-peer socket abstraction.
 #[derive(Debug)]
@@ -23,7 +32,16 @@ pub struct Socket<R: Read + Write, M> {
     pub address: net::SocketAddr,
     pub link: Link,
 
-    raw: StreamReader<R>,
+    stream: R,
+    /// Receive buffer. Bytes before `offset` belong to messages that were already read,
+    /// and are discarded before the next read from the stream.
+    buffer: Vec<u8>,
+    /// Offset of the first byte of the receive buffer that wasn't yet read as a message.
+    offset: usize,
+    /// Send buffer, reused for encoding outgoing messages.
+    send_buffer: Vec<u8>,
+    /// Time at which we started receiving the incomplete message, if any.
+    receiving_since: Option<LocalTime>,
     queue: VecDeque<M>,
 }
 
@@ -33,66 +51,125 @@ impl<M> Socket<net::TcpStream, M> {
     }
 
     pub fn local_address(&self) -> io::Result<net::SocketAddr> {
-        self.raw.stream.local_addr()
+        self.stream.local_addr()
     }
 }
 
 impl<M: Encodable + Decodable + Debug> Socket<net::TcpStream, M> {
     pub fn disconnect(&self) -> io::Result<()> {
-        self.raw.stream.shutdown(net::Shutdown::Both)
+        self.stream.shutdown(net::Shutdown::Both)
     }
 }
 
 impl<R: Read + Write, M: Encodable + Decodable + Debug> Socket<R, M> {
     /// Create a new socket from a `io::Read` and an address pair.
-    pub fn from(r: R, address: net::SocketAddr, link: Link) -> Self {
-        let raw = StreamReader::new(r, Some(MAX_MESSAGE_SIZE));
+    pub fn from(stream: R, address: net::SocketAddr, link: Link) -> Self {
         let queue = VecDeque::new();
 
         Self {
-            raw,
+            stream,
+            buffer: Vec::with_capacity(READ_BUFFER_SIZE),
+            offset: 0,
+            send_buffer: Vec::new(),
+            receiving_since: None,
             link,
             address,
             queue,
         }
     }
 
-    pub fn read(&mut self) -> Result<M, encode::Error> {
+    /// Read the next message from the socket.
+    ///
+    /// Returns an error if the peer announces a message larger than [`MAX_MESSAGE_SIZE`].
+    /// Since we only read from the stream when the buffered bytes don't form a complete
+    /// message, this bounds the number of bytes buffered per peer.
+    ///
+    /// Only the message header is parsed. The message is returned undecoded, so that
+    /// the protocol only decodes the messages it handles. Received bytes are only moved
+    /// when the buffer is compacted, before the next read from the stream, rather than
+    /// after every message.
+    pub fn read(&mut self, local_time: LocalTime) -> Result<Envelope, encode::Error> {
         fallible! { encode::Error::Io(io::ErrorKind::Other.into()) };
 
-        match self.raw.read_next::<M>() {
-            Ok(msg) => {
-                trace!("{}: (read) {:#?}", self.address, msg);
+        loop {
+            if let Some(size) = self::message_size(&self.buffer[self.offset..])? {
+                let bytes = self.buffer[self.offset..self.offset + size].to_vec();
+                let envelope = Envelope::from_bytes(bytes)?;
 
-                Ok(msg)
+                self.offset += size;
+                self.receiving_since = if self.offset == self.buffer.len() {
+                    self.buffer.clear();
+                    self.offset = 0;
+
+                    None
+                } else {
+                    Some(local_time)
+                };
+                trace!("{}: (read) {:?}", self.address, envelope.command);
+
+                return Ok(envelope);
+            }
+            // Discard the bytes of messages already read, and read into the spare capacity
+            // of the buffer.
+            self.buffer.drain(..self.offset);
+            self.offset = 0;
+
+            let len = self.buffer.len();
+            self.buffer.resize(len + READ_BUFFER_SIZE, 0);
+
+            let result = self.stream.read(&mut self.buffer[len..]);
+            let n = *result.as_ref().unwrap_or(&0);
+
+            self.buffer.truncate(len + n);
+
+            if result? == 0 {
+                return Err(encode::Error::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            if len == 0 {
+                self.receiving_since = Some(local_time);
             }
-            Err(err) => Err(err),
         }
     }
 
+    /// Check whether the peer is sending its current message too slowly, eg. to hold
+    /// the connection hostage.
+    pub fn is_stalled(&self, local_time: LocalTime) -> bool {
+        if let Some(since) = self.receiving_since {
+            if local_time <= since + MESSAGE_GRACE_PERIOD {
+                return false;
+            }
+            let elapsed = (local_time - since).as_millis();
+            let rate = (self.buffer.len() - self.offset) as u128 * 1000 / elapsed;
+
+            return rate < MIN_RECEIVE_RATE;
+        }
+        false
+    }
+
     pub fn write(&mut self, msg: &M) -> Result<usize, encode::Error> {
         fallible! { encode::Error::Io(io::ErrorKind::Other.into()) };
 
-        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        self.send_buffer.clear();
 
-        match msg.consensus_encode(&mut buf[..]) {
-            Ok(len) => {
-                trace!("{}: (write) {:#?}", self.address, msg);
+        let len = msg.consensus_encode(&mut self.send_buffer)?;
+        if len > MESSAGE_HEADER_SIZE + MAX_MESSAGE_SIZE {
+            return Err(encode::Error::OversizedVectorAllocation {
+                requested: len,
+                max: MESSAGE_HEADER_SIZE + MAX_MESSAGE_SIZE,
+            });
+        }
+        trace!("{}: (write) {:#?}", self.address, msg);
 
-                // TODO: Is it possible to get a `WriteZero` here, given
-                // the non-blocking socket?
-                self.raw.stream.write_all(&buf[..len])?;
-                self.raw.stream.flush()?;
+        // TODO: Is it possible to get a `WriteZero` here, given
+        // the non-blocking socket?
+        self.stream.write_all(&self.send_buffer)?;
+        self.stream.flush()?;
 
-                Ok(len)
-            }
-            Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::WriteZero => {
-                unreachable!();
-            }
-            Err(err) => Err(err),
-        }
+        Ok(len)
     }
+}
 
+impl<R: Read + Write> Socket<R, RawNetworkMessage> {
     pub fn drain(
         &mut self,
         inputs: &mut VecDeque<Input>,
@@ -101,7 +178,7 @@ impl<R: Read + Write, M: Encodable + Decodable + Debug> Socket<R, M> {
         while let Some(msg) = self.queue.pop_front() {
             match self.write(&msg) {
                 Ok(n) => {
-                    inputs.push_back(Input::Sent(self.address, n));
+                    inputs.push_back(Input::Sent(self.address, msg.cmd(), n));
                 }
                 Err(encode::Error::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => {
                     source.set(popol::interest::WRITE);
@@ -123,3 +200,118 @@ impl<R: Read + Write, M: Encodable + Decodable + Debug> Socket<R, M> {
         Ok(())
     }
 }
+
+/// Size of the complete message at the start of the given bytes. Returns `None` if the
+/// message isn't complete, and an error if it announces a payload larger than
+/// [`MAX_MESSAGE_SIZE`].
+fn message_size(bytes: &[u8]) -> Result<Option<usize>, encode::Error> {
+    if bytes.len() < MESSAGE_HEADER_SIZE {
+        return Ok(None);
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&bytes[16..20]);
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(encode::Error::OversizedVectorAllocation {
+            requested: len,
+            max: MAX_MESSAGE_SIZE,
+        });
+    }
+    let size = MESSAGE_HEADER_SIZE + len;
+    if bytes.len() < size {
+        return Ok(None);
+    }
+    Ok(Some(size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin::network::message::NetworkMessage;
+
+    #[test]
+    fn test_read() {
+        let addr = ([88, 13, 16, 59], 8333).into();
+        let time = LocalTime::from_secs(1_000_000);
+        let ping = RawNetworkMessage {
+            magic: Network::Bitcoin.magic(),
+            payload: NetworkMessage::Ping(42),
+        };
+        let mut bytes = serialize(&ping);
+        bytes.extend(serialize(&ping));
+
+        let mut socket = Socket::<_, RawNetworkMessage>::from(
+            io::Cursor::new(bytes[..40].to_vec()),
+            addr,
+            Link::Inbound,
+        );
+        assert_eq!(socket.read(time).unwrap(), Envelope::from(ping.clone()));
+        assert!(
+            socket.read(time).is_err(),
+            "the second message is incomplete"
+        );
+        assert!(!socket.is_stalled(time + MESSAGE_GRACE_PERIOD));
+        assert!(
+            socket.is_stalled(time + LocalDuration::from_mins(1)),
+            "eight bytes in a minute is too slow"
+        );
+
+        // Messages are not decoded, and are framed using the payload length in the header.
+        let mut corrupted = serialize(&ping);
+        corrupted[20] ^= 0xff; // Bad checksum.
+        let mut unknown = serialize(&ping);
+        unknown[4..8].copy_from_slice(b"pang"); // Unknown command.
+
+        let mut stream = corrupted.clone();
+        stream.extend(unknown);
+        stream.extend(serialize(&ping));
+
+        let mut socket =
+            Socket::<_, RawNetworkMessage>::from(io::Cursor::new(stream), addr, Link::Inbound);
+
+        let envelope = socket.read(time).unwrap();
+        assert_eq!(envelope.command, "ping");
+        assert_eq!(envelope.as_bytes(), &corrupted[..]);
+        assert!(envelope.decode().is_err());
+
+        let envelope = socket.read(time).unwrap();
+        assert_eq!(envelope.command, "pang");
+        assert!(!envelope.is_handled());
+
+        assert_eq!(socket.read(time).unwrap().decode().unwrap(), ping);
+        assert!(socket.buffer.is_empty());
+
+        // Messages announcing an oversized payload are rejected before they're received.
+        bytes[16..20].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+
+        let mut socket =
+            Socket::<_, RawNetworkMessage>::from(io::Cursor::new(bytes), addr, Link::Inbound);
+        assert!(matches!(
+            socket.read(time),
+            Err(encode::Error::OversizedVectorAllocation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_message_size() {
+        let ping = RawNetworkMessage {
+            magic: Network::Bitcoin.magic(),
+            payload: NetworkMessage::Ping(42),
+        };
+        let bytes = serialize(&ping);
+
+        assert_eq!(message_size(&bytes[..MESSAGE_HEADER_SIZE]).unwrap(), None);
+        assert_eq!(message_size(&bytes[..bytes.len() - 1]).unwrap(), None);
+        assert_eq!(message_size(&bytes).unwrap(), Some(bytes.len()));
+
+        let envelope = Envelope::from_bytes(bytes.clone()).unwrap();
+        assert_eq!(envelope.magic, ping.magic);
+        assert_eq!(envelope.command, "ping");
+        assert_eq!(envelope.size(), bytes.len());
+        assert_eq!(envelope.decode().unwrap(), ping);
+    }
+}
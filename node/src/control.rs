@@ -0,0 +1,270 @@
+//! Control socket. Lets scripts drive a running daemon over a Unix domain socket.
+//!
+//! Commands are JSON objects sent one per line, eg. `{"cmd":"status"}`. Each command
+//! receives a single-line JSON reply, which is either the command result or an object
+//! of the form `{"error":"<message>"}`.
+//!
+//! The following commands are supported:
+//!
+//! * `status`: the chain tip, sync state, number of connected peers and height of the
+//!   last stored filter header. The client is considered synced once its tip is at least
+//!   as high as the best height reported by its peers.
+//! * `peers`: information about connected peers.
+//! * `broadcast`: submit a hex-encoded transaction to the network, eg.
+//!   `{"cmd":"broadcast","tx":"0100..."}`.
+//! * `rescan`: re-request compact filters from the given height up to the tip, eg.
+//!   `{"cmd":"rescan","from":600000}`. Filters are checked against the scripts watched by
+//!   the client, and matches are reported to the pubsub and Electrum subscribers. The
+//!   reply holds the rescanned range, and is sent once the filters are requested.
+//!
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use crossbeam_channel as chan;
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_client::handle::Handle;
+use nakamoto_common::block::Height;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::hashes::hex::FromHex;
+use nakamoto_p2p::bitcoin::Transaction;
+use nakamoto_p2p::protocol::Link;
+
+use crate::server;
+
+/// Listen for control connections on the given socket path. A socket left over by a
+/// previous instance is replaced, unless another process is still listening on it. The
+/// socket is only accessible to the current user. Each connection is served on its own
+/// thread, up to [`server::MAX_CONNECTIONS`] at a time.
+pub fn listen<H>(path: &Path, handle: H) -> io::Result<thread::JoinHandle<()>>
+where
+    H: Handle + Clone + Send + 'static,
+{
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a socket", path),
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{:?} is in use by another process", path),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+    log::info!("Listening for control connections on {:?}..", path);
+
+    Ok(thread::spawn(move || {
+        let connections = server::Connections::default();
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let slot = match connections.acquire() {
+                        Some(slot) => slot,
+                        None => {
+                            log::warn!("Too many control connections, closing new connection");
+                            continue;
+                        }
+                    };
+                    let handle = handle.clone();
+
+                    thread::spawn(move || {
+                        if let Err(err) = self::serve(stream, handle) {
+                            log::debug!("Control connection closed: {}", err);
+                        }
+                        drop(slot);
+                    });
+                }
+                Err(err) => log::warn!("Error accepting control connection: {}", err),
+            }
+        }
+    }))
+}
+
+/// Serve commands on a control connection, until it is closed, or stays idle for longer
+/// than [`server::TIMEOUT`].
+fn serve<H: Handle>(stream: UnixStream, handle: H) -> io::Result<()> {
+    stream.set_read_timeout(Some(server::TIMEOUT))?;
+    stream.set_write_timeout(Some(server::TIMEOUT))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = io::BufReader::new(stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        if server::read_line(&mut reader, &mut line)? == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match self::execute(line.trim(), &handle) {
+            Ok(value) => value,
+            Err(err) => {
+                let mut obj = Object::new();
+                obj.insert("error".to_owned(), Value::String(err));
+
+                Value::Object(obj)
+            }
+        };
+        writeln!(writer, "{}", json::to_string(&reply))?;
+    }
+    Ok(())
+}
+
+/// Execute a single command.
+fn execute<H: Handle>(line: &str, handle: &H) -> Result<Value, String> {
+    let cmd = match json::from_str::<Value>(line) {
+        Ok(Value::Object(obj)) => obj,
+        _ => return Err("invalid command: expected a JSON object".to_owned()),
+    };
+    let name = match cmd.get("cmd") {
+        Some(Value::String(name)) => name.as_str(),
+        _ => return Err("invalid command: missing `cmd` field".to_owned()),
+    };
+    let number = |n: u64| Value::Number(Number::U64(n));
+
+    match name {
+        "status" => {
+            let (height, tip) = handle.get_tip().map_err(|e| e.to_string())?;
+            let peers = handle.peers().map_err(|e| e.to_string())?;
+            let filter_height = handle.get_filter_height().map_err(|e| e.to_string())?;
+            let synced = peers
+                .iter()
+                .map(|p| p.height)
+                .max()
+                .map_or(false, |best| height >= best);
+            let mut obj = Object::new();
+
+            obj.insert("height".to_owned(), number(height));
+            obj.insert(
+                "tip".to_owned(),
+                Value::String(tip.block_hash().to_string()),
+            );
+            obj.insert("synced".to_owned(), Value::Bool(synced));
+            obj.insert("peers".to_owned(), number(peers.len() as u64));
+            obj.insert("filter_height".to_owned(), number(filter_height));
+
+            Ok(Value::Object(obj))
+        }
+        "peers" => {
+            let peers = handle.peers().map_err(|e| e.to_string())?;
+            let peers = peers
+                .into_iter()
+                .map(|p| {
+                    let mut obj = Object::new();
+                    let link = match p.link {
+                        Link::Inbound => "inbound",
+                        Link::Outbound => "outbound",
+                    };
+
+                    obj.insert("addr".to_owned(), Value::String(p.addr.to_string()));
+                    obj.insert("link".to_owned(), Value::String(link.to_owned()));
+                    obj.insert("user_agent".to_owned(), Value::String(p.user_agent));
+                    obj.insert("services".to_owned(), number(p.services.as_u64()));
+                    obj.insert("height".to_owned(), number(p.height));
+                    obj.insert(
+                        "latency_ms".to_owned(),
+                        match p.latency {
+                            Some(l) => number(l.as_millis() as u64),
+                            None => Value::Null,
+                        },
+                    );
+                    Value::Object(obj)
+                })
+                .collect();
+
+            Ok(Value::Array(peers))
+        }
+        "broadcast" => {
+            let hex = match cmd.get("tx") {
+                Some(Value::String(hex)) => hex,
+                _ => return Err("invalid command: missing `tx` field".to_owned()),
+            };
+            let bytes = Vec::<u8>::from_hex(hex).map_err(|e| format!("invalid hex: {}", e))?;
+            let tx: Transaction =
+                encode::deserialize(&bytes).map_err(|e| format!("invalid transaction: {}", e))?;
+            let txid = tx.txid();
+
+            handle.submit_transaction(tx).map_err(|e| e.to_string())?;
+
+            let mut obj = Object::new();
+            obj.insert("txid".to_owned(), Value::String(txid.to_string()));
+
+            Ok(Value::Object(obj))
+        }
+        "rescan" => {
+            let (height, _) = handle.get_tip().map_err(|e| e.to_string())?;
+            let range = self::rescan_range(&cmd, height)?;
+            // Filter matches are derived by the client from the received filters, so we
+            // don't need them here: the subscription is dropped when the channel is.
+            let (filters, _) = chan::unbounded();
+
+            handle
+                .get_filters(range.clone(), filters)
+                .map_err(|e| e.to_string())?;
+
+            let mut obj = Object::new();
+            obj.insert("from".to_owned(), number(range.start));
+            obj.insert("to".to_owned(), number(range.end));
+
+            Ok(Value::Object(obj))
+        }
+        other => Err(format!("unknown command `{}`", other)),
+    }
+}
+
+/// Get the range of filters to rescan, from the `from` field of a `rescan` command, up to
+/// the given tip height.
+fn rescan_range(cmd: &Object, tip: Height) -> Result<Range<Height>, String> {
+    let from = match cmd.get("from") {
+        Some(Value::Number(Number::U64(from))) => *from,
+        _ => return Err("invalid command: missing `from` field".to_owned()),
+    };
+    if from >= tip {
+        return Err(format!(
+            "invalid command: `from` must be below the tip height {}",
+            tip
+        ));
+    }
+    Ok(from..tip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rescan_range() {
+        let cmd = |line: &str| match json::from_str::<Value>(line).unwrap() {
+            Value::Object(obj) => obj,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(
+            rescan_range(&cmd(r#"{"cmd":"rescan","from":100}"#), 150),
+            Ok(100..150)
+        );
+        assert_eq!(
+            rescan_range(&cmd(r#"{"cmd":"rescan","from":0}"#), 1),
+            Ok(0..1)
+        );
+        assert!(rescan_range(&cmd(r#"{"cmd":"rescan","from":150}"#), 150).is_err());
+        assert!(rescan_range(&cmd(r#"{"cmd":"rescan"}"#), 150).is_err());
+        assert!(rescan_range(&cmd(r#"{"cmd":"rescan","from":"100"}"#), 150).is_err());
+    }
+}
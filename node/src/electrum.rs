@@ -0,0 +1,454 @@
+//! Electrum server frontend. Lets Electrum-based wallets use the daemon as their backend.
+//!
+//! A minimal subset of the Electrum protocol is supported, over newline-delimited JSON-RPC:
+//!
+//! * `server.version`, `server.ping`
+//! * `blockchain.headers.subscribe`, `blockchain.block.header`
+//! * `blockchain.scripthash.subscribe`, `blockchain.scripthash.unsubscribe`,
+//!   `blockchain.scripthash.get_history`
+//! * `blockchain.transaction.get`, `blockchain.transaction.broadcast`
+//!
+//! Electrum clients identify scripts by their hash, while compact filters are matched
+//! against the scripts themselves. Only scripts given to [`listen`] can therefore be
+//! subscribed to. Their history is built from the transactions matched while the daemon
+//! is running.
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_client::event::ClientEvent;
+use nakamoto_client::handle::Handle;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::hashes::hex::{FromHex, ToHex};
+use nakamoto_p2p::bitcoin::hashes::{sha256, Hash};
+use nakamoto_p2p::bitcoin::{BlockHeader, Script, Transaction, Txid};
+
+use crate::server;
+
+/// Electrum protocol version implemented.
+pub const PROTOCOL_VERSION: &str = "1.4";
+
+/// Time after which an idle session is closed. Electrum clients keep their sessions open,
+/// and ping the server periodically.
+const SESSION_TIMEOUT: time::Duration = time::Duration::from_secs(10 * 60);
+
+/// Generic request error code.
+const BAD_REQUEST: i64 = 1;
+/// The method does not exist.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// A script hash, as used by the Electrum protocol: the hex-encoded SHA-256 hash of the
+/// script, in reverse byte order.
+pub type ScriptHash = String;
+
+/// Get the Electrum script hash of a script.
+pub fn script_hash(script: &Script) -> ScriptHash {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+    hash.reverse();
+    hash.to_hex()
+}
+
+/// A client session.
+#[derive(Debug)]
+struct Session {
+    stream: net::TcpStream,
+    /// Whether the client subscribed to new headers.
+    headers: bool,
+    /// Script hashes the client subscribed to.
+    scripts: HashSet<ScriptHash>,
+}
+
+/// State shared between sessions.
+#[derive(Debug, Default)]
+struct State {
+    sessions: HashMap<usize, Session>,
+    /// Watched scripts, by script hash.
+    scripts: HashMap<ScriptHash, Script>,
+    /// Transactions matching each watched script.
+    history: HashMap<ScriptHash, Vec<(Txid, u64)>>,
+    /// Matched transactions.
+    transactions: HashMap<Txid, Transaction>,
+}
+
+impl State {
+    /// Send a message to a session. The session is removed if it can't be written to.
+    fn send(&mut self, id: usize, msg: &Value) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            if let Err(err) = writeln!(session.stream, "{}", json::to_string(msg)) {
+                log::debug!("Electrum session {} closed: {}", id, err);
+                self.sessions.remove(&id);
+            }
+        }
+    }
+
+    /// Send a notification to the sessions matching the predicate.
+    fn notify(&mut self, method: &str, params: Vec<Value>, predicate: impl Fn(&Session) -> bool) {
+        let mut obj = Object::new();
+
+        obj.insert("jsonrpc".to_owned(), Value::String("2.0".to_owned()));
+        obj.insert("method".to_owned(), Value::String(method.to_owned()));
+        obj.insert("params".to_owned(), Value::Array(params));
+
+        let msg = Value::Object(obj);
+        let ids = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| predicate(s))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in ids {
+            self.send(id, &msg);
+        }
+    }
+
+    /// Get the status of a script hash, which changes whenever its history changes.
+    fn status(&self, hash: &str) -> Value {
+        match self.history.get(hash) {
+            Some(history) if !history.is_empty() => {
+                let s = history
+                    .iter()
+                    .map(|(txid, height)| format!("{}:{}:", txid, height))
+                    .collect::<String>();
+
+                Value::String(sha256::Hash::hash(s.as_bytes()).to_string())
+            }
+            _ => Value::Null,
+        }
+    }
+
+    /// Record a matched transaction. Returns the script hashes whose history changed.
+    fn record(&mut self, tx: Transaction, height: u64) -> Vec<ScriptHash> {
+        let txid = tx.txid();
+        let mut changed = Vec::new();
+
+        for output in &tx.output {
+            let hash = self::script_hash(&output.script_pubkey);
+
+            if !self.scripts.contains_key(&hash) {
+                continue;
+            }
+            let history = self.history.entry(hash.clone()).or_default();
+
+            if !history.iter().any(|(t, _)| *t == txid) {
+                history.push((txid, height));
+                changed.push(hash);
+            }
+        }
+        self.transactions.insert(txid, tx);
+
+        changed
+    }
+}
+
+/// Serve Electrum clients on the given address. The given scripts are watched, and can be
+/// subscribed to by clients. Each session is served on its own thread, up to
+/// [`server::MAX_CONNECTIONS`] at a time.
+pub fn listen<H>(
+    addr: net::SocketAddr,
+    handle: H,
+    scripts: Vec<Script>,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    H: Handle + Clone + Send + 'static,
+{
+    let listener = net::TcpListener::bind(addr)?;
+    let state = Arc::new(Mutex::new(State {
+        scripts: scripts
+            .iter()
+            .map(|s| (self::script_hash(s), s.clone()))
+            .collect(),
+        ..State::default()
+    }));
+    let events = handle.subscribe();
+
+    handle.watch(scripts);
+
+    log::info!("Listening for Electrum clients on {}..", addr);
+
+    thread::spawn({
+        let state = state.clone();
+        let handle = handle.clone();
+
+        move || {
+            let connections = server::Connections::default();
+
+            for (id, stream) in listener.incoming().enumerate() {
+                let result = stream.and_then(|s| {
+                    s.set_read_timeout(Some(SESSION_TIMEOUT))?;
+                    s.set_write_timeout(Some(server::TIMEOUT))?;
+                    s.try_clone().map(|w| (s, w))
+                });
+
+                match result {
+                    Ok((stream, writer)) => {
+                        let slot = match connections.acquire() {
+                            Some(slot) => slot,
+                            None => {
+                                log::warn!("Too many Electrum sessions, closing new connection");
+                                continue;
+                            }
+                        };
+                        let session = Session {
+                            stream: writer,
+                            headers: false,
+                            scripts: HashSet::new(),
+                        };
+                        let state = state.clone();
+                        let handle = handle.clone();
+
+                        state.lock().unwrap().sessions.insert(id, session);
+                        thread::spawn(move || {
+                            if let Err(err) = self::serve(id, stream, &handle, &state) {
+                                log::debug!("Electrum session {} closed: {}", id, err);
+                            }
+                            state.lock().unwrap().sessions.remove(&id);
+                            drop(slot);
+                        });
+                    }
+                    Err(err) => log::warn!("Error accepting Electrum connection: {}", err),
+                }
+            }
+        }
+    });
+
+    Ok(thread::spawn(move || {
+        // Blocks matching the watched scripts are fetched by the client, which emits the
+        // matching transactions as events once the block is received.
+        for event in events {
+            match event {
+                ClientEvent::TipChanged { hash, height, .. } => match handle.get_header(&hash) {
+                    Ok(Some((_, header))) => {
+                        let params = vec![self::header_json(&header, height)];
+
+                        state
+                            .lock()
+                            .unwrap()
+                            .notify("blockchain.headers.subscribe", params, |s| s.headers);
+                    }
+                    Ok(None) => {}
+                    Err(err) => log::warn!("Error getting header {}: {}", hash, err),
+                },
+                ClientEvent::TransactionMatched {
+                    transaction,
+                    height,
+                    ..
+                } => {
+                    let mut state = state.lock().unwrap();
+
+                    for hash in state.record(transaction, height) {
+                        let params = vec![Value::String(hash.clone()), state.status(&hash)];
+
+                        state.notify("blockchain.scripthash.subscribe", params, |s| {
+                            s.scripts.contains(&hash)
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }))
+}
+
+/// Serve requests of a session, until it is closed.
+fn serve<H: Handle>(
+    id: usize,
+    stream: net::TcpStream,
+    handle: &H,
+    state: &Mutex<State>,
+) -> io::Result<()> {
+    let mut reader = io::BufReader::new(stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+
+        if server::read_line(&mut reader, &mut line)? == 0 {
+            break;
+        }
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+        let mut request = match json::from_str::<Value>(line) {
+            Ok(Value::Object(obj)) => obj,
+            _ => Object::new(),
+        };
+        let params = match request.remove("params") {
+            Some(Value::Array(params)) => params,
+            _ => vec![],
+        };
+        let result = match request.get("method") {
+            Some(Value::String(method)) => self::call(id, method, &params, handle, state),
+            _ => Err((BAD_REQUEST, "invalid request".to_owned())),
+        };
+        let mut reply = Object::new();
+
+        reply.insert("jsonrpc".to_owned(), Value::String("2.0".to_owned()));
+        reply.insert("id".to_owned(), request.remove("id").unwrap_or(Value::Null));
+        match result {
+            Ok(result) => {
+                reply.insert("result".to_owned(), result);
+            }
+            Err((code, message)) => {
+                let mut error = Object::new();
+
+                error.insert("code".to_owned(), Value::Number(Number::I64(code)));
+                error.insert("message".to_owned(), Value::String(message));
+                reply.insert("error".to_owned(), Value::Object(error));
+            }
+        }
+        state.lock().unwrap().send(id, &Value::Object(reply));
+    }
+    Ok(())
+}
+
+/// Call a method on behalf of a session.
+fn call<H: Handle>(
+    id: usize,
+    method: &str,
+    params: &[Value],
+    handle: &H,
+    state: &Mutex<State>,
+) -> Result<Value, (i64, String)> {
+    let bad_request = |msg: &str| (BAD_REQUEST, msg.to_owned());
+    let internal = |err: nakamoto_client::handle::Error| (BAD_REQUEST, err.to_string());
+    let string_param = |i: usize| match params.get(i) {
+        Some(Value::String(s)) => Ok(s.as_str()),
+        _ => Err(bad_request("invalid parameters")),
+    };
+
+    match method {
+        "server.version" => Ok(Value::Array(vec![
+            Value::String(format!("nakamoto {}", env!("CARGO_PKG_VERSION"))),
+            Value::String(PROTOCOL_VERSION.to_owned()),
+        ])),
+        "server.ping" => Ok(Value::Null),
+        "blockchain.headers.subscribe" => {
+            let (height, header) = handle.get_tip().map_err(internal)?;
+
+            if let Some(session) = state.lock().unwrap().sessions.get_mut(&id) {
+                session.headers = true;
+            }
+            Ok(self::header_json(&header, height))
+        }
+        "blockchain.block.header" => {
+            let height = match params.get(0) {
+                Some(Value::Number(Number::U64(h))) => *h,
+                _ => return Err(bad_request("invalid height")),
+            };
+            match handle.get_header_by_height(height).map_err(internal)? {
+                Some(header) => Ok(Value::String(encode::serialize(&header).to_hex())),
+                None => Err(bad_request("height out of range")),
+            }
+        }
+        "blockchain.scripthash.subscribe" => {
+            let hash = string_param(0)?;
+            let mut state = state.lock().unwrap();
+
+            if !state.scripts.contains_key(hash) {
+                return Err(bad_request("script hash is not watched by this server"));
+            }
+            if let Some(session) = state.sessions.get_mut(&id) {
+                session.scripts.insert(hash.to_owned());
+            }
+            Ok(state.status(hash))
+        }
+        "blockchain.scripthash.unsubscribe" => {
+            let hash = string_param(0)?;
+            let removed = state
+                .lock()
+                .unwrap()
+                .sessions
+                .get_mut(&id)
+                .map_or(false, |s| s.scripts.remove(hash));
+
+            Ok(Value::Bool(removed))
+        }
+        "blockchain.scripthash.get_history" => {
+            let hash = string_param(0)?;
+            let state = state.lock().unwrap();
+
+            if !state.scripts.contains_key(hash) {
+                return Err(bad_request("script hash is not watched by this server"));
+            }
+            let history = state
+                .history
+                .get(hash)
+                .map(|history| {
+                    history
+                        .iter()
+                        .map(|(txid, height)| {
+                            let mut obj = Object::new();
+
+                            obj.insert("tx_hash".to_owned(), Value::String(txid.to_string()));
+                            obj.insert("height".to_owned(), Value::Number(Number::U64(*height)));
+
+                            Value::Object(obj)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            Ok(Value::Array(history))
+        }
+        "blockchain.transaction.get" => {
+            let txid = Txid::from_hex(string_param(0)?).map_err(|_| bad_request("invalid txid"))?;
+
+            match state.lock().unwrap().transactions.get(&txid) {
+                Some(tx) => Ok(Value::String(encode::serialize(tx).to_hex())),
+                None => Err(bad_request("unknown transaction")),
+            }
+        }
+        "blockchain.transaction.broadcast" => {
+            let tx: Transaction = Vec::<u8>::from_hex(string_param(0)?)
+                .ok()
+                .and_then(|bytes| encode::deserialize(&bytes).ok())
+                .ok_or_else(|| bad_request("invalid transaction"))?;
+            let txid = tx.txid();
+
+            handle.submit_transaction(tx).map_err(internal)?;
+
+            Ok(Value::String(txid.to_string()))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+    }
+}
+
+/// Convert a block header to the JSON value used in header notifications.
+fn header_json(header: &BlockHeader, height: u64) -> Value {
+    let mut obj = Object::new();
+
+    obj.insert(
+        "hex".to_owned(),
+        Value::String(encode::serialize(header).to_hex()),
+    );
+    obj.insert("height".to_owned(), Value::Number(Number::U64(height)));
+
+    Value::Object(obj)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_script_hash() {
+        // P2PKH script of address `1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa`, from the Electrum
+        // protocol documentation.
+        let script = Script::from(
+            Vec::<u8>::from_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap(),
+        );
+
+        assert_eq!(
+            script_hash(&script),
+            "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161"
+        );
+    }
+}
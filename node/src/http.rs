@@ -0,0 +1,89 @@
+//! Minimal HTTP/1.1 support for the daemon's HTTP interfaces. Connections serve a single
+//! request, and are closed after the response.
+use std::io::{self, BufRead, Read, Write};
+
+use crate::server;
+
+/// Maximum size of a request body.
+pub const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+/// Maximum number of headers in a request.
+pub const MAX_HEADERS: usize = 64;
+
+/// An HTTP request.
+#[derive(Debug)]
+pub struct Request {
+    /// Request method, eg. `GET`.
+    pub method: String,
+    /// Request path, including the query string.
+    pub path: String,
+    /// Request body.
+    pub body: Vec<u8>,
+}
+
+/// Read an HTTP request. Returns an error of kind [`io::ErrorKind::InvalidData`] if the
+/// request is malformed, or if its lines, headers or body exceed their limits.
+pub fn read_request<R: BufRead>(r: &mut R) -> io::Result<Request> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut line = String::new();
+
+    server::read_line(r, &mut line)?;
+
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => return Err(invalid("malformed request line")),
+    };
+
+    let mut length = 0;
+    let mut headers = 0;
+    loop {
+        line.clear();
+
+        if server::read_line(r, &mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim();
+
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+
+        if headers > MAX_HEADERS {
+            return Err(invalid("too many headers"));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| invalid("invalid content length"))?;
+            }
+        }
+    }
+    if length > MAX_BODY_SIZE {
+        return Err(invalid("request body too large"));
+    }
+    let mut body = vec![0; length];
+    r.read_exact(&mut body)?;
+
+    Ok(Request { method, path, body })
+}
+
+/// Write an HTTP response.
+pub fn respond<W: Write>(
+    w: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        w,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+    )?;
+    w.write_all(body)?;
+    w.flush()
+}
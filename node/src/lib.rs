@@ -2,37 +2,108 @@
 #![deny(missing_docs, unsafe_code)]
 
 use std::net;
+use std::path::PathBuf;
 use std::time;
 
+pub use nakamoto_client::builder::ClientBuilder;
 pub use nakamoto_client::client::{Client, Config, Network};
 pub use nakamoto_client::error::Error;
+pub use nakamoto_p2p::bitcoin::Script;
 
+#[cfg(unix)]
+pub mod control;
+pub mod electrum;
+mod http;
 pub mod logger;
+pub mod pubsub;
+pub mod rest;
+pub mod rpc;
+mod server;
+#[cfg(unix)]
+pub mod signals;
+#[cfg(unix)]
+pub mod status;
+pub mod watch;
+
+/// Optional interfaces exposed by the daemon, for other processes to interact with it.
+#[derive(Debug, Default, Clone)]
+pub struct Interfaces {
+    /// Unix socket path to accept control commands on. See [`control`].
+    pub control: Option<PathBuf>,
+    /// Address to serve JSON-RPC requests on. See [`rpc`].
+    pub rpc: Option<net::SocketAddr>,
+    /// Address to serve REST requests on. See [`rest`].
+    pub rest: Option<net::SocketAddr>,
+    /// Address to publish notifications on. See [`pubsub`].
+    pub pubsub: Option<net::SocketAddr>,
+    /// Address to serve Electrum clients on. See [`electrum`].
+    pub electrum: Option<net::SocketAddr>,
+    /// Scripts that Electrum clients can subscribe to.
+    pub electrum_scripts: Vec<Script>,
+}
 
 /// The network reactor we're going to use.
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream>;
 
 /// Run the light-client. Takes an initial list of peers to connect to, a list of listen addresses
-/// and the Bitcoin network to connect to.
+/// and the Bitcoin network to connect to. If peers are specified, we connect to those peers only.
 pub fn run(
     connect: &[net::SocketAddr],
     listen: &[net::SocketAddr],
     network: Network,
 ) -> Result<(), Error> {
-    let mut cfg = Config {
+    let cfg = Config {
         network,
-        listen: if listen.is_empty() {
-            vec![([0, 0, 0, 0], 0).into()]
-        } else {
-            listen.to_vec()
-        },
-        connect: connect.to_vec(),
+        listen: vec![([0, 0, 0, 0], 0).into()],
         timeout: time::Duration::from_secs(30),
         ..Config::default()
     };
+
+    run_with(cfg, connect, listen, Interfaces::default())
+}
+
+/// Run the light-client with the given configuration, eg. loaded from a configuration file.
+/// Peers and listen addresses that are specified override the ones in the configuration.
+/// The given interfaces are started before the client runs.
+pub fn run_with(
+    mut cfg: Config,
+    connect: &[net::SocketAddr],
+    listen: &[net::SocketAddr],
+    interfaces: Interfaces,
+) -> Result<(), Error> {
+    if !listen.is_empty() {
+        cfg.listen = listen.to_vec();
+    }
     if !connect.is_empty() {
-        cfg.target_outbound_peers = connect.len();
+        cfg.connect = connect.to_vec();
+        cfg.connect_only = true;
     }
 
-    Client::<Reactor>::new(cfg)?.run()
+    let client = ClientBuilder::from(cfg).build::<Reactor>()?;
+
+    #[cfg(unix)]
+    signals::install(client.handle())?;
+
+    if let Some(path) = interfaces.control {
+        #[cfg(unix)]
+        control::listen(&path, client.handle())?;
+        #[cfg(not(unix))]
+        log::warn!(
+            "Control socket {:?} is not supported on this platform",
+            path
+        );
+    }
+    if let Some(addr) = interfaces.rpc {
+        rpc::listen(addr, client.handle())?;
+    }
+    if let Some(addr) = interfaces.rest {
+        rest::listen(addr, client.handle())?;
+    }
+    if let Some(addr) = interfaces.pubsub {
+        pubsub::listen(addr, client.handle())?;
+    }
+    if let Some(addr) = interfaces.electrum {
+        electrum::listen(addr, client.handle(), interfaces.electrum_scripts)?;
+    }
+    client.run()
 }
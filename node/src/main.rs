@@ -1,9 +1,13 @@
 use std::net;
+use std::path::PathBuf;
+use std::time;
 
 use argh::FromArgs;
 
-use nakamoto_client::client::Network;
-use nakamoto_node::logger;
+use nakamoto_client::client::{Config, Network};
+use nakamoto_common::block::Height;
+use nakamoto_node::{logger, Script};
+use nakamoto_p2p::bitcoin::hashes::hex::FromHex;
 
 #[derive(FromArgs)]
 /// A Bitcoin light client.
@@ -20,9 +24,80 @@ pub struct Options {
     #[argh(switch)]
     pub testnet: bool,
 
+    /// load settings from this configuration file
+    #[argh(option)]
+    pub config: Option<PathBuf>,
+
+    /// accept control commands on this unix socket
+    #[argh(option)]
+    pub control: Option<PathBuf>,
+
+    /// serve JSON-RPC requests on this address, eg. 127.0.0.1:8332
+    #[argh(option)]
+    pub rpc: Option<net::SocketAddr>,
+
+    /// serve headers and filter headers over REST on this address
+    #[argh(option)]
+    pub rest: Option<net::SocketAddr>,
+
+    /// publish tip and transaction notifications on this address
+    #[argh(option)]
+    pub pubsub: Option<net::SocketAddr>,
+
+    /// serve electrum clients on this address
+    #[argh(option)]
+    pub electrum: Option<net::SocketAddr>,
+
+    /// hex-encoded script that electrum clients can subscribe to
+    #[argh(option, from_str_fn(parse_script))]
+    pub electrum_script: Vec<Script>,
+
     /// log level (default: info)
     #[argh(option, default = "log::Level::Info")]
     pub log: log::Level,
+
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Watch(WatchOptions),
+    Status(StatusOptions),
+}
+
+#[derive(FromArgs)]
+/// Rescan the chain for transactions of an address, and watch for new ones.
+#[argh(subcommand, name = "watch")]
+pub struct WatchOptions {
+    /// address, or `addr(..)` or `raw(..)` descriptor to watch
+    #[argh(positional, from_str_fn(nakamoto_node::watch::parse))]
+    pub target: Script,
+
+    /// height to start rescanning from (default: 0)
+    #[argh(option, default = "0")]
+    pub from: Height,
+}
+
+#[derive(FromArgs)]
+/// Print the status of a running daemon, using its control socket.
+#[argh(subcommand, name = "status")]
+pub struct StatusOptions {
+    /// control socket of the daemon (default: the `--control` socket)
+    #[argh(option)]
+    pub socket: Option<PathBuf>,
+
+    /// print the status as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// Parse a hex-encoded script.
+fn parse_script(s: &str) -> Result<Script, String> {
+    Vec::<u8>::from_hex(s)
+        .map(Script::from)
+        .map_err(|e| format!("invalid script: {}", e))
 }
 
 impl Options {
@@ -34,15 +109,76 @@ impl Options {
 fn main() {
     let opts = Options::from_env();
 
+    if let Some(Command::Status(status)) = &opts.command {
+        let socket = match status.socket.as_ref().or_else(|| opts.control.as_ref()) {
+            Some(socket) => socket,
+            None => {
+                eprintln!("error: a control socket must be specified, with `--socket`");
+                std::process::exit(1);
+            }
+        };
+        #[cfg(unix)]
+        {
+            if let Err(err) = nakamoto_node::status::run(socket, status.json) {
+                eprintln!("error: querying daemon on {:?}: {}", socket, err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        #[cfg(not(unix))]
+        {
+            eprintln!("error: control sockets are not supported on this platform");
+            std::process::exit(1);
+        }
+    }
+
     logger::init(opts.log).expect("initializing logger for the first time");
 
-    let network = if opts.testnet {
-        Network::Testnet
-    } else {
-        Network::Mainnet
+    let cfg = match &opts.config {
+        Some(path) => {
+            let mut cfg = match Config::load(path) {
+                Ok(cfg) => cfg,
+                Err(err) => {
+                    log::error!("Error loading configuration from {:?}: {}", path, err);
+                    std::process::exit(1);
+                }
+            };
+            if opts.testnet {
+                cfg.network = Network::Testnet;
+            }
+            cfg
+        }
+        None => {
+            let network = if opts.testnet {
+                Network::Testnet
+            } else {
+                Network::Mainnet
+            };
+            Config {
+                network,
+                listen: vec![([0, 0, 0, 0], 0).into()],
+                timeout: time::Duration::from_secs(30),
+                ..Config::default()
+            }
+        }
+    };
+    let interfaces = nakamoto_node::Interfaces {
+        control: opts.control.clone(),
+        rpc: opts.rpc,
+        rest: opts.rest,
+        pubsub: opts.pubsub,
+        electrum: opts.electrum,
+        electrum_scripts: opts.electrum_script.clone(),
+    };
+    let result = match opts.command {
+        Some(Command::Watch(watch)) => {
+            nakamoto_node::watch::run(cfg, &opts.connect, watch.target, watch.from)
+        }
+        Some(Command::Status(_)) => unreachable!(),
+        None => nakamoto_node::run_with(cfg, &opts.connect, &opts.listen, interfaces),
     };
 
-    if let Err(err) = nakamoto_node::run(&opts.connect, &opts.listen, network) {
+    if let Err(err) = result {
         log::error!("Exiting: {}", err);
         std::process::exit(1);
     }
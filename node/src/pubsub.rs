@@ -0,0 +1,122 @@
+//! Publish/subscribe notifications. Lets external processes react to chain events over a
+//! plain TCP connection, without linking against the client.
+//!
+//! Subscribers connect to the notification address and receive messages, which are never
+//! sent anything back. Each message is made of three frames: the topic, the body and a
+//! little-endian `u32` sequence number, which is incremented for every message published
+//! and lets subscribers detect dropped messages. Every frame is prefixed with its length,
+//! as a little-endian `u32`.
+//!
+//! The following topics are published:
+//!
+//! * `rawheader`: the consensus-encoded header of the new chain tip.
+//! * `rawtx`: a consensus-encoded transaction paying to a watched script, once the block
+//!   containing it has been fetched.
+use std::io::{self, Write};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use nakamoto_client::event::ClientEvent;
+use nakamoto_client::handle::Handle;
+use nakamoto_p2p::bitcoin::consensus::encode;
+
+use crate::server;
+
+/// Topic of new tip header notifications.
+pub const TOPIC_RAW_HEADER: &str = "rawheader";
+/// Topic of matched transaction notifications.
+pub const TOPIC_RAW_TX: &str = "rawtx";
+
+/// Connected subscribers.
+#[derive(Debug, Default)]
+struct Subscribers {
+    streams: Vec<net::TcpStream>,
+    sequence: u32,
+}
+
+impl Subscribers {
+    /// Publish a message to all subscribers. Subscribers that can't be written to are
+    /// dropped.
+    fn publish(&mut self, topic: &str, body: &[u8]) {
+        let sequence = self.sequence.to_le_bytes();
+        let mut msg = Vec::with_capacity(topic.len() + body.len() + 16);
+
+        for frame in &[topic.as_bytes(), body, &sequence[..]] {
+            msg.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            msg.extend_from_slice(frame);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        self.streams.retain(|mut s| match s.write_all(&msg) {
+            Ok(()) => true,
+            Err(err) => {
+                log::debug!("Dropping notification subscriber: {}", err);
+                false
+            }
+        });
+    }
+}
+
+/// Publish notifications to subscribers connecting on the given address. At most
+/// [`server::MAX_CONNECTIONS`] subscribers are accepted, and subscribers that don't keep up
+/// with notifications for longer than [`server::TIMEOUT`] are dropped.
+pub fn listen<H>(addr: net::SocketAddr, handle: H) -> io::Result<thread::JoinHandle<()>>
+where
+    H: Handle + Clone + Send + 'static,
+{
+    let listener = net::TcpListener::bind(addr)?;
+    let subscribers = Arc::new(Mutex::new(Subscribers::default()));
+    let events = handle.subscribe();
+
+    log::info!("Publishing notifications on {}..", addr);
+
+    thread::spawn({
+        let subscribers = subscribers.clone();
+
+        move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|s| {
+                    s.shutdown(net::Shutdown::Read)?;
+                    s.set_write_timeout(Some(server::TIMEOUT))?;
+
+                    Ok(s)
+                });
+
+                match result {
+                    Ok(stream) => {
+                        let mut subscribers = subscribers.lock().unwrap();
+
+                        if subscribers.streams.len() >= server::MAX_CONNECTIONS {
+                            log::warn!("Too many notification subscribers, closing new connection");
+                            continue;
+                        }
+                        subscribers.streams.push(stream);
+                    }
+                    Err(err) => log::warn!("Error accepting notification subscriber: {}", err),
+                }
+            }
+        }
+    });
+
+    Ok(thread::spawn(move || {
+        // Blocks matching the watched scripts are fetched by the client, which emits the
+        // matching transactions as events once the block is received.
+        for event in events {
+            match event {
+                ClientEvent::TipChanged { hash, .. } => match handle.get_header(&hash) {
+                    Ok(Some((_, header))) => subscribers
+                        .lock()
+                        .unwrap()
+                        .publish(TOPIC_RAW_HEADER, &encode::serialize(&header)),
+                    Ok(None) => {}
+                    Err(err) => log::warn!("Error getting header {}: {}", hash, err),
+                },
+                ClientEvent::TransactionMatched { transaction, .. } => subscribers
+                    .lock()
+                    .unwrap()
+                    .publish(TOPIC_RAW_TX, &encode::serialize(&transaction)),
+                _ => {}
+            }
+        }
+    }))
+}
@@ -0,0 +1,243 @@
+//! REST interface. Serves block headers and compact filter headers from local storage,
+//! so that other applications, or other light clients, can bootstrap from a running
+//! daemon.
+//!
+//! The following endpoints are supported, for `GET` requests:
+//!
+//! * `/headers/<height>?count=<n>`: up to `n` consecutive block headers of the active
+//!   chain, starting at the given height. `n` defaults to one, and is capped at
+//!   [`MAX_HEADERS`].
+//! * `/block/<hash>/header`: the header of the given block, and its height.
+//! * `/filter/<height>`: the compact filter header at the given height, and the
+//!   hash of the filter it commits to.
+//!
+//! Responses are JSON, with headers hex-encoded. Header endpoints also support raw
+//! binary responses of consensus-encoded headers, by adding a `.bin` suffix to the path,
+//! eg. `/headers/0.bin?count=2000`.
+use std::io;
+use std::net;
+use std::thread;
+
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_client::handle::Handle;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::hashes::hex::{FromHex, ToHex};
+use nakamoto_p2p::bitcoin::{BlockHash, BlockHeader};
+
+use crate::http;
+use crate::server;
+
+/// Maximum number of headers returned in a single response.
+pub const MAX_HEADERS: u64 = 2000;
+
+/// A response, with its HTTP status, content type and body.
+type Response = (&'static str, &'static str, Vec<u8>);
+
+/// Listen for REST requests on the given address. Each connection is served on its own
+/// thread, up to [`server::MAX_CONNECTIONS`] at a time.
+pub fn listen<H>(addr: net::SocketAddr, handle: H) -> io::Result<thread::JoinHandle<()>>
+where
+    H: Handle + Clone + Send + 'static,
+{
+    let listener = net::TcpListener::bind(addr)?;
+
+    log::info!("Listening for REST requests on {}..", addr);
+
+    Ok(thread::spawn(move || {
+        let connections = server::Connections::default();
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let slot = match connections.acquire() {
+                        Some(slot) => slot,
+                        None => {
+                            log::warn!("Too many REST connections, closing new connection");
+                            continue;
+                        }
+                    };
+                    let handle = handle.clone();
+
+                    thread::spawn(move || {
+                        if let Err(err) = self::serve(stream, handle) {
+                            log::debug!("Error serving REST request: {}", err);
+                        }
+                        drop(slot);
+                    });
+                }
+                Err(err) => log::warn!("Error accepting REST connection: {}", err),
+            }
+        }
+    }))
+}
+
+/// Serve a single HTTP request.
+fn serve<H: Handle>(stream: net::TcpStream, handle: H) -> io::Result<()> {
+    stream.set_read_timeout(Some(server::TIMEOUT))?;
+    stream.set_write_timeout(Some(server::TIMEOUT))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = io::BufReader::new(stream);
+
+    let (status, content_type, body) = match http::read_request(&mut reader) {
+        Ok(request) if request.method == "GET" => self::get(&request.path, &handle),
+        Ok(_) => self::error("405 Method Not Allowed"),
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => self::error("400 Bad Request"),
+        Err(err) => return Err(err),
+    };
+    http::respond(&mut writer, status, content_type, &body)
+}
+
+/// Build the response to a `GET` request.
+fn get<H: Handle>(path: &str, handle: &H) -> Response {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (path, ""),
+    };
+    let (path, binary) = match path.strip_suffix(".bin") {
+        Some(path) => (path, true),
+        None => (path, false),
+    };
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    let result = match segments.as_slice() {
+        ["headers", height] => {
+            let height = match height.parse::<u64>() {
+                Ok(height) => height,
+                Err(_) => return self::error("400 Bad Request"),
+            };
+            let count = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("count="))
+                .map_or(Ok(1), |n| n.parse::<u64>());
+            let count = match count {
+                Ok(count) => count.min(MAX_HEADERS),
+                Err(_) => return self::error("400 Bad Request"),
+            };
+            self::headers(height, count, binary, handle)
+        }
+        ["block", hash, "header"] => match BlockHash::from_hex(hash) {
+            Ok(hash) => self::header(&hash, binary, handle),
+            Err(_) => return self::error("400 Bad Request"),
+        },
+        ["filter", height] if !binary => match height.parse::<u64>() {
+            Ok(height) => self::filter(height, handle),
+            Err(_) => return self::error("400 Bad Request"),
+        },
+        _ => return self::error("404 Not Found"),
+    };
+
+    match result {
+        Ok(Some(response)) => response,
+        Ok(None) => self::error("404 Not Found"),
+        Err(err) => {
+            log::debug!("Error handling REST request: {}", err);
+            self::error("500 Internal Server Error")
+        }
+    }
+}
+
+/// Get consecutive headers of the active chain, starting at the given height.
+fn headers<H: Handle>(
+    height: u64,
+    count: u64,
+    binary: bool,
+    handle: &H,
+) -> Result<Option<Response>, nakamoto_client::handle::Error> {
+    let mut headers = Vec::new();
+
+    for h in height..height + count {
+        match handle.get_header_by_height(h)? {
+            Some(header) => headers.push(header),
+            None => break,
+        }
+    }
+    if headers.is_empty() {
+        return Ok(None);
+    }
+    if binary {
+        return Ok(Some(self::binary(&headers)));
+    }
+    let ary = headers
+        .iter()
+        .zip(height..)
+        .map(|(header, height)| self::header_json(header, height))
+        .collect();
+
+    Ok(Some(self::json(Value::Array(ary))))
+}
+
+/// Get a block header by hash.
+fn header<H: Handle>(
+    hash: &BlockHash,
+    binary: bool,
+    handle: &H,
+) -> Result<Option<Response>, nakamoto_client::handle::Error> {
+    Ok(handle.get_header(hash)?.map(|(height, header)| {
+        if binary {
+            self::binary(&[header])
+        } else {
+            self::json(self::header_json(&header, height))
+        }
+    }))
+}
+
+/// Get a compact filter header by height.
+fn filter<H: Handle>(
+    height: u64,
+    handle: &H,
+) -> Result<Option<Response>, nakamoto_client::handle::Error> {
+    Ok(handle
+        .get_filter_header(height)?
+        .map(|(filter_hash, filter_header)| {
+            let mut obj = Object::new();
+
+            obj.insert("height".to_owned(), Value::Number(Number::U64(height)));
+            obj.insert(
+                "filter_hash".to_owned(),
+                Value::String(filter_hash.to_string()),
+            );
+            obj.insert(
+                "filter_header".to_owned(),
+                Value::String(filter_header.to_string()),
+            );
+            self::json(Value::Object(obj))
+        }))
+}
+
+/// Convert a block header to a JSON value.
+fn header_json(header: &BlockHeader, height: u64) -> Value {
+    let mut obj = Object::new();
+
+    obj.insert("height".to_owned(), Value::Number(Number::U64(height)));
+    obj.insert(
+        "hash".to_owned(),
+        Value::String(header.block_hash().to_string()),
+    );
+    obj.insert(
+        "header".to_owned(),
+        Value::String(encode::serialize(header).to_hex()),
+    );
+    Value::Object(obj)
+}
+
+/// A JSON response.
+fn json(value: Value) -> Response {
+    (
+        "200 OK",
+        "application/json",
+        json::to_string(&value).into_bytes(),
+    )
+}
+
+/// A binary response of consensus-encoded headers.
+fn binary(headers: &[BlockHeader]) -> Response {
+    let body = headers.iter().flat_map(encode::serialize).collect();
+
+    ("200 OK", "application/octet-stream", body)
+}
+
+/// An error response.
+fn error(status: &'static str) -> Response {
+    (status, "text/plain", status.as_bytes().to_vec())
+}
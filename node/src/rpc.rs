@@ -0,0 +1,288 @@
+//! JSON-RPC server. Exposes a subset of the Bitcoin Core RPC interface, backed by the
+//! light-client, so that existing tooling can be pointed at the daemon.
+//!
+//! The supported methods are `getblockcount`, `getblockhash`, `getblockheader`,
+//! `getpeerinfo` and `sendrawtransaction`. Requests are served over HTTP `POST`, one
+//! request per connection. There is no authentication, so the server should only listen
+//! on a local interface.
+use std::io;
+use std::net;
+use std::thread;
+
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_client::handle::Handle;
+use nakamoto_p2p::bitcoin::consensus::encode;
+use nakamoto_p2p::bitcoin::hashes::hex::{FromHex, ToHex};
+use nakamoto_p2p::bitcoin::{BlockHash, Transaction};
+
+use crate::http;
+use crate::server;
+
+/// An RPC error, with a Bitcoin Core compatible error code.
+#[derive(Debug)]
+struct Error {
+    code: i64,
+    message: String,
+}
+
+impl Error {
+    /// The method does not exist.
+    const METHOD_NOT_FOUND: i64 = -32601;
+    /// Invalid method parameters.
+    const INVALID_PARAMS: i64 = -32602;
+    /// Internal error.
+    const INTERNAL: i64 = -32603;
+    /// Invalid parameter value, eg. a height out of range.
+    const INVALID_PARAMETER: i64 = -8;
+    /// Invalid address or key, eg. an unknown block.
+    const INVALID_ADDRESS_OR_KEY: i64 = -5;
+    /// Transaction decoding failed.
+    const DESERIALIZATION: i64 = -22;
+
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut obj = Object::new();
+
+        obj.insert("code".to_owned(), Value::Number(Number::I64(self.code)));
+        obj.insert("message".to_owned(), Value::String(self.message.clone()));
+
+        Value::Object(obj)
+    }
+}
+
+impl From<nakamoto_client::handle::Error> for Error {
+    fn from(err: nakamoto_client::handle::Error) -> Self {
+        Self::new(Self::INTERNAL, err)
+    }
+}
+
+/// Listen for JSON-RPC requests on the given address. Each connection is served on its
+/// own thread, up to [`server::MAX_CONNECTIONS`] at a time.
+pub fn listen<H>(addr: net::SocketAddr, handle: H) -> io::Result<thread::JoinHandle<()>>
+where
+    H: Handle + Clone + Send + 'static,
+{
+    let listener = net::TcpListener::bind(addr)?;
+
+    log::info!("Listening for JSON-RPC requests on {}..", addr);
+
+    Ok(thread::spawn(move || {
+        let connections = server::Connections::default();
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let slot = match connections.acquire() {
+                        Some(slot) => slot,
+                        None => {
+                            log::warn!("Too many JSON-RPC connections, closing new connection");
+                            continue;
+                        }
+                    };
+                    let handle = handle.clone();
+
+                    thread::spawn(move || {
+                        if let Err(err) = self::serve(stream, handle) {
+                            log::debug!("Error serving JSON-RPC request: {}", err);
+                        }
+                        drop(slot);
+                    });
+                }
+                Err(err) => log::warn!("Error accepting JSON-RPC connection: {}", err),
+            }
+        }
+    }))
+}
+
+/// Serve a single HTTP request.
+fn serve<H: Handle>(stream: net::TcpStream, handle: H) -> io::Result<()> {
+    stream.set_read_timeout(Some(server::TIMEOUT))?;
+    stream.set_write_timeout(Some(server::TIMEOUT))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = io::BufReader::new(stream);
+    let respond = |w: &mut net::TcpStream, status: &str, body: &str| {
+        http::respond(w, status, "application/json", body.as_bytes())
+    };
+
+    let request = match http::read_request(&mut reader) {
+        Ok(request) => request,
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+            return respond(&mut writer, "400 Bad Request", "");
+        }
+        Err(err) => return Err(err),
+    };
+    if request.method != "POST" {
+        return respond(&mut writer, "405 Method Not Allowed", "");
+    }
+    let request = match std::str::from_utf8(&request.body)
+        .ok()
+        .and_then(|s| json::from_str::<Value>(s).ok())
+    {
+        Some(Value::Object(obj)) => obj,
+        _ => return respond(&mut writer, "400 Bad Request", ""),
+    };
+    let reply = self::reply(request, &handle);
+
+    respond(&mut writer, "200 OK", &json::to_string(&reply))
+}
+
+/// Build the reply to a JSON-RPC request.
+fn reply<H: Handle>(mut request: Object, handle: &H) -> Value {
+    let id = request.remove("id").unwrap_or(Value::Null);
+    let params = match request.remove("params") {
+        Some(Value::Array(params)) => params,
+        _ => vec![],
+    };
+    let result = match request.get("method") {
+        Some(Value::String(method)) => self::call(method, &params, handle),
+        _ => Err(Error::new(Error::METHOD_NOT_FOUND, "Method not found")),
+    };
+    let mut obj = Object::new();
+
+    match result {
+        Ok(result) => {
+            obj.insert("result".to_owned(), result);
+            obj.insert("error".to_owned(), Value::Null);
+        }
+        Err(err) => {
+            obj.insert("result".to_owned(), Value::Null);
+            obj.insert("error".to_owned(), err.to_json());
+        }
+    }
+    obj.insert("id".to_owned(), id);
+
+    Value::Object(obj)
+}
+
+/// Call an RPC method.
+fn call<H: Handle>(method: &str, params: &[Value], handle: &H) -> Result<Value, Error> {
+    let number = |n: u64| Value::Number(Number::U64(n));
+    let string = Value::String;
+
+    match method {
+        "getblockcount" => {
+            let (height, _) = handle.get_tip()?;
+
+            Ok(number(height))
+        }
+        "getblockhash" => {
+            let height = match params.get(0) {
+                Some(Value::Number(Number::U64(h))) => *h,
+                _ => return Err(Error::new(Error::INVALID_PARAMS, "Invalid height")),
+            };
+            match handle.get_header_by_height(height)? {
+                Some(header) => Ok(string(header.block_hash().to_string())),
+                None => Err(Error::new(
+                    Error::INVALID_PARAMETER,
+                    "Block height out of range",
+                )),
+            }
+        }
+        "getblockheader" => {
+            let hash = match params.get(0) {
+                Some(Value::String(s)) => BlockHash::from_hex(s)
+                    .map_err(|_| Error::new(Error::INVALID_PARAMETER, "Invalid block hash"))?,
+                _ => return Err(Error::new(Error::INVALID_PARAMS, "Invalid block hash")),
+            };
+            let verbose = !matches!(params.get(1), Some(Value::Bool(false)));
+            let (height, header) = handle
+                .get_header(&hash)?
+                .ok_or_else(|| Error::new(Error::INVALID_ADDRESS_OR_KEY, "Block not found"))?;
+
+            if !verbose {
+                return Ok(string(encode::serialize(&header).to_hex()));
+            }
+            let (tip, _) = handle.get_tip()?;
+            let mut obj = Object::new();
+
+            obj.insert("hash".to_owned(), string(hash.to_string()));
+            obj.insert(
+                "confirmations".to_owned(),
+                number((tip + 1).saturating_sub(height)),
+            );
+            obj.insert("height".to_owned(), number(height));
+            obj.insert(
+                "version".to_owned(),
+                Value::Number(Number::I64(header.version as i64)),
+            );
+            obj.insert(
+                "versionHex".to_owned(),
+                string(format!("{:08x}", header.version)),
+            );
+            obj.insert(
+                "merkleroot".to_owned(),
+                string(header.merkle_root.to_string()),
+            );
+            obj.insert("time".to_owned(), number(header.time as u64));
+            obj.insert("nonce".to_owned(), number(header.nonce as u64));
+            obj.insert("bits".to_owned(), string(format!("{:08x}", header.bits)));
+
+            if height > 0 {
+                obj.insert(
+                    "previousblockhash".to_owned(),
+                    string(header.prev_blockhash.to_string()),
+                );
+            }
+            if let Some(next) = handle.get_header_by_height(height + 1)? {
+                obj.insert(
+                    "nextblockhash".to_owned(),
+                    string(next.block_hash().to_string()),
+                );
+            }
+            Ok(Value::Object(obj))
+        }
+        "getpeerinfo" => {
+            let peers = handle
+                .peers()?
+                .into_iter()
+                .enumerate()
+                .map(|(id, p)| {
+                    let mut obj = Object::new();
+
+                    obj.insert("id".to_owned(), number(id as u64));
+                    obj.insert("addr".to_owned(), string(p.addr.to_string()));
+                    obj.insert(
+                        "services".to_owned(),
+                        string(format!("{:016x}", p.services.as_u64())),
+                    );
+                    obj.insert("inbound".to_owned(), Value::Bool(p.link.is_inbound()));
+                    obj.insert("subver".to_owned(), string(p.user_agent));
+                    obj.insert("startingheight".to_owned(), number(p.height));
+
+                    if let Some(latency) = p.latency {
+                        obj.insert(
+                            "pingtime".to_owned(),
+                            Value::Number(Number::F64(latency.as_millis() as f64 / 1000.)),
+                        );
+                    }
+                    Value::Object(obj)
+                })
+                .collect();
+
+            Ok(Value::Array(peers))
+        }
+        "sendrawtransaction" => {
+            let tx: Transaction = match params.get(0) {
+                Some(Value::String(hex)) => Vec::<u8>::from_hex(hex)
+                    .ok()
+                    .and_then(|bytes| encode::deserialize(&bytes).ok())
+                    .ok_or_else(|| Error::new(Error::DESERIALIZATION, "TX decode failed"))?,
+                _ => return Err(Error::new(Error::INVALID_PARAMS, "Invalid transaction")),
+            };
+            let txid = tx.txid();
+
+            handle.submit_transaction(tx)?;
+
+            Ok(string(txid.to_string()))
+        }
+        _ => Err(Error::new(Error::METHOD_NOT_FOUND, "Method not found")),
+    }
+}
@@ -0,0 +1,85 @@
+//! Limits shared by the daemon's servers, so that slow or misbehaving clients can't tie up
+//! an unbounded number of threads, or make the daemon buffer unbounded input.
+use std::io::{self, BufRead, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time;
+
+/// Maximum number of connections served concurrently by a server. Connections accepted
+/// over this limit are closed right away.
+pub const MAX_CONNECTIONS: usize = 32;
+/// Time after which a connection is closed if a read or write doesn't make progress.
+pub const TIMEOUT: time::Duration = time::Duration::from_secs(30);
+/// Maximum length of a line, eg. a command or an HTTP header, in bytes.
+pub const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+/// Number of connections being served by a server. Clones share the same count.
+#[derive(Debug, Clone, Default)]
+pub struct Connections(Arc<AtomicUsize>);
+
+impl Connections {
+    /// Reserve a slot for a new connection. Returns `None` if [`MAX_CONNECTIONS`] are
+    /// already being served. The slot is released when the returned guard is dropped.
+    pub fn acquire(&self) -> Option<Slot> {
+        let count = self.0.fetch_add(1, Ordering::SeqCst);
+
+        if count >= MAX_CONNECTIONS {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot(self.0.clone()))
+    }
+}
+
+/// A connection slot, released on drop.
+#[derive(Debug)]
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Read a line of at most [`MAX_LINE_LENGTH`] bytes into the buffer, including the line
+/// terminator. Returns the number of bytes read, which is zero at the end of the stream,
+/// or an error of kind [`io::ErrorKind::InvalidData`] if the line is too long.
+pub fn read_line<R: BufRead>(r: &mut R, buf: &mut String) -> io::Result<usize> {
+    let n = r.by_ref().take(MAX_LINE_LENGTH).read_line(buf)?;
+
+    if n as u64 == MAX_LINE_LENGTH && !buf.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connections() {
+        let connections = Connections::default();
+        let slots = (0..MAX_CONNECTIONS)
+            .map(|_| connections.acquire().unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(connections.acquire().is_none());
+        drop(slots);
+        assert!(connections.acquire().is_some());
+    }
+
+    #[test]
+    fn test_read_line() {
+        let mut buf = String::new();
+        let mut input = io::Cursor::new(b"ping\npong".to_vec());
+
+        assert_eq!(read_line(&mut input, &mut buf).unwrap(), 5);
+        assert_eq!(buf, "ping\n");
+
+        let long = vec![b'a'; MAX_LINE_LENGTH as usize + 1];
+        let err = read_line(&mut io::Cursor::new(long), &mut String::new()).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
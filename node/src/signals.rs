@@ -0,0 +1,67 @@
+//! Signal handling. Shuts the client down gracefully on `SIGINT` or `SIGTERM`.
+//!
+//! On the first signal, peers are disconnected and the address book is saved, after which
+//! the client stops. If this takes longer than [`SHUTDOWN_TIMEOUT`], or another signal is
+//! received in the meantime, the process exits immediately.
+use std::io;
+use std::process;
+use std::thread;
+use std::time;
+
+use crossbeam_channel as chan;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use nakamoto_client::client::Handle;
+use nakamoto_client::handle::Handle as _;
+
+use crate::Reactor;
+
+/// Maximum time to wait for the client to shut down gracefully, before exiting.
+pub const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Install signal handlers that shut down the client behind the given handle.
+pub fn install(mut handle: Handle<Reactor>) -> io::Result<()> {
+    let mut signals = Signals::new(&[SIGINT, SIGTERM])?;
+    let (sender, received) = chan::unbounded();
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if sender.send(signal).is_err() {
+                break;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let signal = match received.recv() {
+            Ok(signal) => signal,
+            Err(_) => return,
+        };
+        log::info!("Received signal {}, shutting down..", signal);
+
+        let (done, shutdown) = chan::bounded(1);
+        handle.set_timeout(SHUTDOWN_TIMEOUT);
+
+        thread::spawn(move || {
+            done.send(handle.shutdown()).ok();
+        });
+
+        chan::select! {
+            recv(shutdown) -> result => {
+                if let Ok(Err(err)) = result {
+                    log::error!("Graceful shutdown failed: {}, exiting..", err);
+                    process::exit(1);
+                }
+            }
+            recv(received) -> signal => {
+                if let Ok(signal) = signal {
+                    log::warn!("Received signal {} while shutting down, exiting..", signal);
+                    process::exit(1);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Status command. Queries a running daemon over its [control](crate::control) socket, and
+//! prints its status.
+use std::io::{self, BufRead, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use microserde::json::{self, Number, Object, Value};
+
+/// Print the status of the daemon listening on the given control socket. If `json` is set,
+/// the status is printed as returned by the daemon, otherwise it is formatted for humans.
+pub fn run(path: &Path, json: bool) -> io::Result<()> {
+    let status = self::query(path)?;
+
+    if json {
+        println!("{}", json::to_string(&Value::Object(status)));
+    } else {
+        print!("{}", self::format(&status));
+    }
+    Ok(())
+}
+
+/// Query the status of the daemon.
+fn query(path: &Path) -> io::Result<Object> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut stream = UnixStream::connect(path)?;
+    let mut reply = String::new();
+
+    writeln!(stream, r#"{{"cmd":"status"}}"#)?;
+    io::BufReader::new(stream).read_line(&mut reply)?;
+
+    match json::from_str::<Value>(&reply) {
+        Ok(Value::Object(obj)) => match obj.get("error") {
+            Some(Value::String(err)) => Err(io::Error::new(io::ErrorKind::Other, err.clone())),
+            _ => Ok(obj),
+        },
+        _ => Err(invalid(format!(
+            "invalid reply from daemon: {:?}",
+            reply.trim()
+        ))),
+    }
+}
+
+/// Format the daemon status for humans.
+fn format(status: &Object) -> String {
+    let number = |name: &str| match status.get(name) {
+        Some(Value::Number(Number::U64(n))) => Some(*n),
+        _ => None,
+    };
+    let unknown = || String::from("?");
+    let height = number("height");
+    let tip = match status.get("tip") {
+        Some(Value::String(tip)) => tip.clone(),
+        _ => unknown(),
+    };
+    let sync = match status.get("synced") {
+        Some(Value::Bool(true)) => "synced",
+        Some(Value::Bool(false)) => "syncing",
+        _ => "?",
+    };
+    let filters = match (number("filter_height"), height) {
+        (Some(filters), Some(height)) if height > 0 => format!(
+            "{}/{} ({:.1}%)",
+            filters,
+            height,
+            filters as f64 / height as f64 * 100.
+        ),
+        (filters, _) => filters.map_or_else(unknown, |n| n.to_string()),
+    };
+
+    format!(
+        "height:  {}\ntip:     {}\nsync:    {}\npeers:   {}\nfilters: {}\n",
+        height.map_or_else(unknown, |n| n.to_string()),
+        tip,
+        sync,
+        number("peers").map_or_else(unknown, |n| n.to_string()),
+        filters,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let mut status = Object::new();
+
+        status.insert("height".to_owned(), Value::Number(Number::U64(200)));
+        status.insert("tip".to_owned(), Value::String("00ff".to_owned()));
+        status.insert("synced".to_owned(), Value::Bool(false));
+        status.insert("peers".to_owned(), Value::Number(Number::U64(8)));
+        status.insert("filter_height".to_owned(), Value::Number(Number::U64(50)));
+
+        assert_eq!(
+            format(&status),
+            "height:  200\ntip:     00ff\nsync:    syncing\npeers:   8\nfilters: 50/200 (25.0%)\n"
+        );
+    }
+}
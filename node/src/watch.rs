@@ -0,0 +1,91 @@
+//! Watch workflow. Rescans the chain for transactions paying to or spending from a script,
+//! and keeps following new blocks, printing matching transactions as they are found.
+//!
+//! Scripts can be given as addresses, or as `addr(<address>)` and `raw(<hex>)` output
+//! descriptors. Descriptor checksums are accepted, but not verified.
+use std::net;
+use std::str::FromStr;
+use std::thread;
+
+use nakamoto_client::event::ClientEvent;
+use nakamoto_client::handle::Handle as _;
+use nakamoto_common::block::Height;
+use nakamoto_p2p::bitcoin::hashes::hex::FromHex;
+use nakamoto_p2p::bitcoin::{Address, Script, Transaction};
+use nakamoto_wallet::{Rescan, Wallet};
+
+use crate::{ClientBuilder, Config, Error, Reactor};
+
+/// Parse the script to watch, from an address or output descriptor.
+pub fn parse(s: &str) -> Result<Script, String> {
+    // Strip the descriptor checksum, if any.
+    let s = s.split('#').next().unwrap_or_default().trim();
+
+    if let Some(hex) = s.strip_prefix("raw(").and_then(|s| s.strip_suffix(')')) {
+        Vec::<u8>::from_hex(hex)
+            .map(Script::from)
+            .map_err(|e| format!("invalid script `{}`: {}", hex, e))
+    } else {
+        let addr = s
+            .strip_prefix("addr(")
+            .and_then(|s| s.strip_suffix(')'))
+            .unwrap_or(s);
+
+        Address::from_str(addr)
+            .map(|a| a.script_pubkey())
+            .map_err(|e| format!("invalid address or descriptor `{}`: {}", s, e))
+    }
+}
+
+/// Run the client, and watch the given script. The chain is rescanned from the given
+/// height. If peers are specified, we connect to those peers only. Doesn't return unless
+/// an error occurs.
+pub fn run(
+    mut cfg: Config,
+    connect: &[net::SocketAddr],
+    script: Script,
+    from: Height,
+) -> Result<(), Error> {
+    if !connect.is_empty() {
+        cfg.connect = connect.to_vec();
+        cfg.connect_only = true;
+    }
+    let client = ClientBuilder::from(cfg).build::<Reactor>()?;
+    let handle = client.handle();
+    let events = handle.subscribe();
+
+    thread::spawn(|| {
+        if let Err(err) = client.run() {
+            log::error!("Client error: {}", err);
+        }
+    });
+
+    let mut wallet = Wallet::new(handle.clone(), vec![]);
+    wallet.watch(script.clone());
+    wallet.rescan_with(Rescan { genesis: from }, self::print)?;
+
+    log::info!("Rescan complete, balance is {} sats", wallet.balance());
+    log::info!("Watching for new transactions..");
+
+    handle.watch(vec![script]);
+
+    // Blocks matching the watched script are fetched by the client, which emits the
+    // matching transactions as events once the block is received.
+    loop {
+        if let ClientEvent::TransactionMatched {
+            transaction,
+            height,
+            ..
+        } = events.recv()?
+        {
+            self::print(&transaction, height);
+        }
+    }
+}
+
+/// Print a matching transaction.
+fn print(tx: &Transaction, height: Height) {
+    let value = tx.output.iter().map(|o| o.value).sum::<u64>();
+
+    println!("{} {} {} sats", height, tx.txid(), value);
+}
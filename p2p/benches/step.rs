@@ -0,0 +1,146 @@
+//! Protocol benchmarks.
+//!
+//! Measures how many inputs per second go through [`Protocol::step`], with a mix of
+//! messages typical of a synced node: pings, address gossip, header and transaction
+//! announcements, and header requests from peers.
+use std::collections::HashMap;
+use std::net;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use crossbeam_channel as chan;
+use nonempty::NonEmpty;
+
+use bitcoin::consensus::params::Params;
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::{BlockHash, Txid};
+use bitcoin_hashes::Hash;
+
+use nakamoto_chain::block::cache::BlockCache;
+use nakamoto_chain::block::store;
+use nakamoto_common::block::filter::FilterHeader;
+use nakamoto_common::block::time::{AdjustedTime, LocalTime};
+use nakamoto_common::network::Network;
+use nakamoto_common::p2p::peer::KnownAddress;
+use nakamoto_p2p::protocol::{Builder, Config, Input, Link, PeerId, PROTOCOL_VERSION};
+use nakamoto_test::block::cache::model;
+use nakamoto_test::BITCOIN_HEADERS;
+
+/// Number of peers sending messages.
+const PEERS: u8 = 4;
+
+fn step(c: &mut Criterion) {
+    let network = Network::Mainnet;
+    let headers = &*BITCOIN_HEADERS;
+    let height = headers.tail.len();
+    let time = LocalTime::from_block_time(headers.last().time);
+    let tree = BlockCache::from(
+        store::Memory::new(headers.clone()),
+        Params::new(network.into()),
+        &[],
+    )
+    .unwrap();
+    let (tx, rx) = chan::unbounded();
+    let mut protocol = Builder {
+        cache: tree,
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::with_seed(0),
+        cfg: Config::default(),
+    }
+    .build(tx);
+
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+    let raw = |payload| RawNetworkMessage {
+        magic: network.magic(),
+        payload,
+    };
+    let peers = (1..=PEERS)
+        .map(|i| PeerId::from(([88, i, 16, 59], 8333)))
+        .collect::<Vec<_>>();
+
+    protocol.initialize(time);
+
+    for (i, peer) in peers.iter().enumerate() {
+        let version = VersionMessage {
+            version: PROTOCOL_VERSION,
+            services,
+            timestamp: time.block_time() as i64,
+            receiver: Address::new(&local_addr, ServiceFlags::NONE),
+            sender: Address::new(peer, services),
+            nonce: i as u64 + 1,
+            user_agent: String::from("/bench:0.0.0/"),
+            start_height: height as i32,
+            relay: true,
+        };
+        protocol.step(
+            Input::Connected {
+                addr: *peer,
+                local_addr,
+                link: Link::Inbound,
+            },
+            time,
+        );
+        protocol.step(
+            Input::received(*peer, raw(NetworkMessage::Version(version))),
+            time,
+        );
+        protocol.step(Input::received(*peer, raw(NetworkMessage::Verack)), time);
+    }
+
+    let addrs = (0..10u32)
+        .map(|i| {
+            let ip = net::Ipv4Addr::from(0x0b00_0000 + i);
+            (
+                time.block_time(),
+                Address::new(&(ip, 8333).into(), services),
+            )
+        })
+        .collect::<Vec<_>>();
+    let locator = headers.get(height - 100).unwrap().block_hash();
+    let messages = NonEmpty::from((
+        NetworkMessage::Ping(1),
+        vec![
+            NetworkMessage::Addr(addrs),
+            NetworkMessage::Headers(vec![*headers.last()]),
+            NetworkMessage::Inv(vec![Inventory::Transaction(Txid::hash(&[1]))]),
+            NetworkMessage::Inv(vec![Inventory::Block(headers.last().block_hash())]),
+            NetworkMessage::GetHeaders(GetHeadersMessage {
+                version: PROTOCOL_VERSION,
+                locator_hashes: vec![locator],
+                stop_hash: BlockHash::default(),
+            }),
+            NetworkMessage::Pong(1),
+        ],
+    ));
+    let inputs = peers
+        .iter()
+        .flat_map(|peer| {
+            messages
+                .iter()
+                .map(move |msg| Input::received(*peer, raw(msg.clone())))
+        })
+        .collect::<Vec<_>>();
+
+    rx.try_iter().for_each(drop);
+
+    let mut group = c.benchmark_group("protocol");
+    group.throughput(Throughput::Elements(inputs.len() as u64));
+    group.bench_function("step", |b| {
+        b.iter(|| {
+            for input in inputs.iter() {
+                protocol.step(input.clone(), time);
+            }
+            rx.try_iter().for_each(drop);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, step);
+criterion_main!(benches);
@@ -1,4 +1,9 @@
 //! Peer-to-peer protocol errors.
+//!
+//! Errors caused by a peer, eg. an invalid message, only affect that peer: they are
+//! handled by disconnecting it, with a [`DisconnectReason`](crate::protocol::DisconnectReason).
+//! Errors that the client can't recover from, eg. a failing store, are [`FatalError`]s:
+//! the protocol shuts down when one occurs, and the reactor returns it.
 
 use bitcoin::consensus::encode;
 
@@ -23,6 +28,21 @@ pub enum Error {
     /// A channel send or receive error.
     #[error("channel error: {0}")]
     Channel(Box<dyn std::error::Error + Send + Sync>),
+
+    /// A fatal protocol error.
+    #[error("fatal error: {0}")]
+    Fatal(#[from] FatalError),
+}
+
+/// An error the client can't recover from. The protocol shuts down when one occurs.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum FatalError {
+    /// The block header store failed.
+    #[error("block store error: {0}")]
+    BlockStore(String),
+    /// The filter header store failed.
+    #[error("filter store error: {0}")]
+    FilterStore(String),
 }
 
 impl<T: Debug + Send + Sync + 'static> From<crossbeam::SendError<T>> for Error {
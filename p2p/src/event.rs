@@ -2,6 +2,9 @@
 use std::net;
 
 use bitcoin::network::message::NetworkMessage;
+use bitcoin::Txid;
+
+use nakamoto_common::block::time::TimeOffset;
 
 use crate::protocol::PeerId;
 use crate::protocol::{addrmgr, connmgr, peermgr, spvmgr, syncmgr};
@@ -11,7 +14,8 @@ use crate::protocol::{addrmgr, connmgr, peermgr, spvmgr, syncmgr};
 pub enum Event {
     /// The node is now listening for incoming connections.
     Listening(net::SocketAddr),
-    /// Received a message from a peer.
+    /// Received a message from a peer. Only messages handled by the protocol are
+    /// decoded and published.
     Received(PeerId, NetworkMessage),
     /// An address manager event.
     AddrManager(addrmgr::Event),
@@ -23,4 +27,9 @@ pub enum Event {
     PeerManager(peermgr::Event),
     /// An SPV manager event.
     SpvManager(spvmgr::Event),
+    /// A submitted transaction was queued for sending to a peer.
+    TransactionRelayed(PeerId, Txid),
+    /// The network-adjusted time offset exceeded the maximum clock skew, suggesting
+    /// that our local clock is wrong. Our clock is no longer adjusted after this.
+    ClockSkewDetected(TimeOffset),
 }
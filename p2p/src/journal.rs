@@ -0,0 +1,134 @@
+//! Protocol journal. Records every protocol output as a line of JSON, for post-mortem
+//! analysis of sync and connection problems.
+//!
+//! Each line is an object with the local time in milliseconds since Epoch under `time`,
+//! and the kind of output under `output`, eg.
+//!
+//! ```text
+//! {"command":"getheaders","output":"message","peer":"88.13.16.59:8333","time":1600000000000}
+//! ```
+//!
+//! Protocol events are recorded with `output` set to `event`, and their debug
+//! representation under `event`.
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_common::block::time::LocalTime;
+
+use crate::protocol::Out;
+
+/// A protocol journal, writing to `W`.
+#[derive(Debug)]
+pub struct Journal<W: Write> {
+    writer: W,
+}
+
+impl Journal<io::LineWriter<fs::File>> {
+    /// Open a journal file for appending. The file is created if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self::new(io::LineWriter::new(file)))
+    }
+}
+
+impl<W: Write> Journal<W> {
+    /// Create a new journal writing to the given writer.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Record a protocol output.
+    pub fn record(&mut self, time: LocalTime, out: &Out) -> io::Result<()> {
+        let string = Value::String;
+        let number = |n: u64| Value::Number(Number::U64(n));
+        let mut obj = Object::new();
+
+        obj.insert("time".to_owned(), number(time.as_millis() as u64));
+
+        let kind = match out {
+            Out::Message(addr, msg) => {
+                obj.insert("peer".to_owned(), string(addr.to_string()));
+                obj.insert("command".to_owned(), string(msg.cmd().to_owned()));
+                "message"
+            }
+            Out::Connect(addr, timeout) => {
+                obj.insert("peer".to_owned(), string(addr.to_string()));
+                obj.insert("timeout_ms".to_owned(), number(timeout.as_millis() as u64));
+                "connect"
+            }
+            Out::Disconnect(addr, reason) => {
+                obj.insert("peer".to_owned(), string(addr.to_string()));
+                obj.insert("reason".to_owned(), string(reason.to_string()));
+                "disconnect"
+            }
+            Out::SetTimeout(timeout) => {
+                obj.insert("timeout_ms".to_owned(), number(timeout.as_millis() as u64));
+                "timeout"
+            }
+            Out::Event(event) => {
+                obj.insert("event".to_owned(), string(format!("{:?}", event)));
+                "event"
+            }
+            Out::Shutdown => "shutdown",
+            Out::Fatal(err) => {
+                obj.insert("error".to_owned(), string(err.to_string()));
+                "fatal"
+            }
+        };
+        obj.insert("output".to_owned(), string(kind.to_owned()));
+
+        writeln!(self.writer, "{}", json::to_string(&Value::Object(obj)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net;
+
+    use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+
+    use nakamoto_common::block::time::LocalDuration;
+
+    use crate::protocol::DisconnectReason;
+
+    #[test]
+    fn test_record() {
+        let mut journal = Journal::new(Vec::new());
+        let addr: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+        let time = LocalTime::from_secs(1_600_000_000);
+        let msg = RawNetworkMessage {
+            magic: 0xD9B4BEF9,
+            payload: NetworkMessage::Verack,
+        };
+
+        journal.record(time, &Out::Message(addr, msg)).unwrap();
+        journal
+            .record(time, &Out::SetTimeout(LocalDuration::from_secs(1)))
+            .unwrap();
+        journal
+            .record(time, &Out::Disconnect(addr, DisconnectReason::Command))
+            .unwrap();
+
+        let output = String::from_utf8(journal.writer).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            r#"{"command":"verack","output":"message","peer":"88.13.16.59:8333","time":1600000000000}"#
+        );
+        assert_eq!(
+            lines[1],
+            r#"{"output":"timeout","time":1600000000000,"timeout_ms":1000}"#
+        );
+        assert!(lines[2].contains(r#""output":"disconnect""#));
+    }
+}
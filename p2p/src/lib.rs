@@ -30,15 +30,24 @@
 //! To achieve this, handling of network I/O is cleanly separated into a network
 //! *reactor*. See the `nakamoto-net-poll` crate for an example of a reactor.
 //!
+//! The same separation allows protocol instances to be run against each other in a
+//! deterministic, simulated network. See the [simulator](crate::simulator) module.
+//!
 #![allow(clippy::type_complexity)]
 #![allow(clippy::new_without_default)]
 #![allow(clippy::single_match)]
 #![allow(clippy::comparison_chain)]
 #![deny(missing_docs, unsafe_code)]
+#[macro_use]
+mod trace;
+
 pub mod error;
 pub mod event;
+pub mod journal;
 pub mod protocol;
 pub mod reactor;
+pub mod replay;
+pub mod simulator;
 pub use bitcoin;
 
 pub use protocol::PeerId;
@@ -6,41 +6,58 @@ use log::*;
 pub mod addrmgr;
 pub mod channel;
 pub mod connmgr;
+pub mod interceptor;
+pub mod memory;
 pub mod peermgr;
 pub mod pingmgr;
+pub mod snapshot;
 pub mod spvmgr;
+pub mod state;
+pub mod stats;
 pub mod syncmgr;
 
 #[cfg(test)]
 mod tests;
 
 use addrmgr::AddressManager;
-use channel::Channel;
+use channel::{Channel, SetTimeout};
 use connmgr::ConnectionManager;
+use interceptor::{Context, Interceptor, Verdict};
 use peermgr::PeerManager;
 use pingmgr::PingManager;
 use spvmgr::SpvManager;
+use stats::StatsTracker;
 use syncmgr::SyncManager;
 
+use crate::error::FatalError;
 use crate::event::Event;
 
 use std::collections::HashSet;
 use std::fmt::{self, Debug};
+use std::io;
 use std::net;
 use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::consensus::encode::{self, Encodable};
 use bitcoin::consensus::params::Params;
 use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::Txid;
 
-use nakamoto_common::block::filter::Filters;
-use nakamoto_common::block::time::{AdjustedTime, LocalDuration, LocalTime};
+use nakamoto_common::block::filter::{FilterHash, FilterHeader, Filters};
+use nakamoto_common::block::time::{
+    AdjustedTime, LocalDuration, LocalTime, TimeOffset, MAX_TIME_ADJUSTMENT,
+};
 use nakamoto_common::block::tree::{self, BlockTree, ImportResult};
 use nakamoto_common::block::Transaction;
 use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::collections;
 use nakamoto_common::network::{self, Network};
+use nakamoto_common::p2p::netgroup::AsMap;
 use nakamoto_common::p2p::peer;
 
 /// Peer-to-peer protocol version.
@@ -48,6 +65,8 @@ use nakamoto_common::p2p::peer;
 pub const PROTOCOL_VERSION: u32 = 70012;
 /// User agent included in `version` messages.
 pub const USER_AGENT: &str = "/nakamoto:0.1.0/";
+/// How often caches are checked against their memory caps.
+pub const MEMORY_CHECK_INTERVAL: LocalDuration = LocalDuration::from_mins(1);
 
 /// Block locators. Consists of starting hashes and a stop hash.
 type Locators = (Vec<BlockHash>, BlockHash);
@@ -87,10 +106,21 @@ impl Link {
 pub enum Command {
     /// Get the tip of the active chain.
     GetTip(chan::Sender<(Height, BlockHeader)>),
+    /// Get a block header, along with its height.
+    GetHeader(BlockHash, chan::Sender<Option<(Height, BlockHeader)>>),
+    /// Get the block header at the given height in the active chain.
+    GetHeaderByHeight(Height, chan::Sender<Option<BlockHeader>>),
+    /// Get the stored filter header at the given height, along with its filter hash.
+    GetFilterHeader(Height, chan::Sender<Option<(FilterHash, FilterHeader)>>),
+    /// Get the height of the last stored filter header.
+    GetFilterHeight(chan::Sender<Height>),
     /// Get a block from the active chain.
     GetBlock(BlockHash),
     /// Get block filters.
-    GetFilters(Range<Height>),
+    GetFilters(
+        Range<Height>,
+        chan::Sender<Result<(), spvmgr::GetFiltersError>>,
+    ),
     /// Broadcast to outbound peers.
     Broadcast(NetworkMessage),
     /// Send a message to a random peer.
@@ -99,6 +129,28 @@ pub enum Command {
     Connect(net::SocketAddr),
     /// Disconnect from a peer.
     Disconnect(net::SocketAddr),
+    /// Connect to a peer, and keep re-dialing it whenever it disconnects.
+    AddNode(net::SocketAddr),
+    /// Stop re-dialing a peer added with [`Command::AddNode`], and disconnect from it.
+    RemoveNode(net::SocketAddr),
+    /// Ban a peer address for the given duration, with the given reason.
+    Ban(net::IpAddr, LocalDuration, String),
+    /// Lift a ban on a peer address.
+    Unban(net::IpAddr),
+    /// Get information about the negotiated peers.
+    GetPeers(chan::Sender<Vec<peermgr::PeerInfo>>),
+    /// Get the banned peer addresses.
+    GetBans(chan::Sender<Vec<(net::IpAddr, peer::Ban)>>),
+    /// Get the address book entries, for inspection.
+    ExportAddresses(chan::Sender<Vec<addrmgr::AddressInfo>>),
+    /// Get peer traffic statistics.
+    GetPeerStats(chan::Sender<stats::Snapshot>),
+    /// Get the approximate memory used by the protocol's caches.
+    GetMemoryInfo(chan::Sender<memory::MemoryInfo>),
+    /// Reset peer traffic statistics.
+    ResetPeerStats,
+    /// Get the network-adjusted time offset, in seconds.
+    GetTimeOffset(chan::Sender<TimeOffset>),
     /// Import headers directly into the block store.
     ImportHeaders(
         Vec<BlockHeader>,
@@ -130,16 +182,111 @@ pub enum Input {
     },
     /// Disconnected from peer.
     Disconnected(PeerId, DisconnectReason),
-    /// Received a message from a remote peer.
-    Received(PeerId, RawNetworkMessage),
-    /// Sent a message to a remote peer, of the given size.
-    Sent(PeerId, usize),
+    /// Received a message from a remote peer, as read from the network.
+    Received(PeerId, Envelope),
+    /// Sent a message to a remote peer, with the given command and size.
+    Sent(PeerId, &'static str, usize),
     /// An external command has been received.
     Command(Command),
     /// A timeout has been reached.
     Timeout,
 }
 
+impl Input {
+    /// A message received from a remote peer, which is encoded into an envelope. Useful
+    /// when the message wasn't read from the network, eg. in simulations.
+    pub fn received(addr: PeerId, msg: RawNetworkMessage) -> Self {
+        Self::Received(addr, msg.into())
+    }
+}
+
+/// Commands of the messages handled by the protocol. Messages with other commands are
+/// not decoded.
+pub const HANDLED_COMMANDS: &[&str] = &[
+    "version",
+    "verack",
+    "ping",
+    "pong",
+    "headers",
+    "getheaders",
+    "block",
+    "inv",
+    "cfheaders",
+    "getcfheaders",
+    "cfilter",
+    "getcfilters",
+    "addr",
+    "getaddr",
+];
+
+/// A message received from a peer, of which only the header was parsed. The message is
+/// kept encoded, and only decoded if its command is handled by the protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    /// Network magic.
+    pub magic: u32,
+    /// Message command, eg. `"headers"`.
+    pub command: String,
+    /// The complete message, including the header.
+    bytes: Vec<u8>,
+}
+
+impl Envelope {
+    /// Size of a message header: magic, command, payload length and checksum.
+    pub const HEADER_SIZE: usize = 24;
+
+    /// Create an envelope from the bytes of a complete message. Only the header is
+    /// parsed, and an error is returned if it's incomplete.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, encode::Error> {
+        if bytes.len() < Self::HEADER_SIZE {
+            return Err(encode::Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let mut magic = [0u8; 4];
+        magic.copy_from_slice(&bytes[0..4]);
+
+        // Commands are padded with zeroes. Invalid commands are caught when decoding.
+        let command = &bytes[4..16];
+        let command = &command[..command.iter().position(|b| *b == 0).unwrap_or(12)];
+        let command = String::from_utf8_lossy(command).into_owned();
+
+        Ok(Self {
+            magic: u32::from_le_bytes(magic),
+            command,
+            bytes,
+        })
+    }
+
+    /// Size of the complete message, in bytes.
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The complete message, including the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Whether the message is handled by the protocol. See [`HANDLED_COMMANDS`].
+    pub fn is_handled(&self) -> bool {
+        HANDLED_COMMANDS.contains(&self.command.as_str())
+    }
+
+    /// Decode the complete message.
+    pub fn decode(&self) -> Result<RawNetworkMessage, encode::Error> {
+        encode::deserialize(&self.bytes)
+    }
+}
+
+impl From<RawNetworkMessage> for Envelope {
+    fn from(msg: RawNetworkMessage) -> Self {
+        Self {
+            magic: msg.magic,
+            command: msg.cmd().to_owned(),
+            bytes: encode::serialize(&msg),
+        }
+    }
+}
+
 /// Output of a state transition (step) of the `Protocol` state machine.
 #[derive(Debug)]
 pub enum Out {
@@ -155,6 +302,8 @@ pub enum Out {
     Event(Event),
     /// Shutdown protocol.
     Shutdown,
+    /// Shutdown protocol, due to a fatal error.
+    Fatal(FatalError),
 }
 
 impl From<Event> for Out {
@@ -180,12 +329,22 @@ pub enum DisconnectReason {
     PeerTimeout,
     /// Connection to self was detected.
     SelfConnection,
+    /// Another connection to the same peer was detected.
+    DuplicateConnection,
+    /// Peer is banned.
+    PeerBanned,
     /// Inbound connection limit reached.
     ConnectionLimit,
+    /// Peer was rotated out to make room for a new one.
+    PeerRotated,
+    /// Peer closed the connection.
+    PeerDisconnected,
     /// Error with the underlying connection.
     ConnectionError(String),
     /// Peer was forced to disconnect by external command.
     Command,
+    /// We're shutting down.
+    Shutdown,
 }
 
 impl DisconnectReason {
@@ -193,7 +352,13 @@ impl DisconnectReason {
     /// after some time.
     pub fn is_transient(&self) -> bool {
         match self {
-            Self::ConnectionLimit | Self::PeerTimeout | Self::PeerHeight(_) => true,
+            Self::ConnectionLimit
+            | Self::PeerRotated
+            | Self::PeerTimeout
+            | Self::PeerHeight(_)
+            | Self::DuplicateConnection
+            | Self::PeerDisconnected
+            | Self::Shutdown => true,
             _ => false,
         }
     }
@@ -209,9 +374,14 @@ impl fmt::Display for DisconnectReason {
             Self::PeerMagic(magic) => write!(f, "received message with invalid magic: {}", magic),
             Self::PeerTimeout => write!(f, "peer timed out"),
             Self::SelfConnection => write!(f, "detected self-connection"),
+            Self::DuplicateConnection => write!(f, "detected duplicate connection"),
+            Self::PeerBanned => write!(f, "peer is banned"),
             Self::ConnectionLimit => write!(f, "inbound connection limit reached"),
+            Self::PeerRotated => write!(f, "peer rotated"),
+            Self::PeerDisconnected => write!(f, "peer closed the connection"),
             Self::ConnectionError(err) => write!(f, "connection error: {}", err),
             Self::Command => write!(f, "received external command"),
+            Self::Shutdown => write!(f, "shutting down"),
         }
     }
 }
@@ -258,8 +428,12 @@ pub struct Protocol<T, F, P> {
     protocol_version: u32,
     /// Consensus parameters.
     params: Params,
-    /// Peer whitelist.
+    /// Peer whitelist, shared with the sub-protocols.
     whitelist: Whitelist,
+    /// Addresses added to the whitelist by commands, rather than by configuration.
+    trusted: collections::HashSet<net::IpAddr>,
+    /// Message interceptor, if any.
+    interceptor: Option<Arc<dyn Interceptor>>,
     /// Peer address manager.
     addrmgr: AddressManager<P, Upstream>,
     /// Blockchain synchronization manager.
@@ -272,12 +446,30 @@ pub struct Protocol<T, F, P> {
     spvmgr: SpvManager<F, Upstream>,
     /// Peer manager.
     peermgr: PeerManager<Upstream>,
+    /// Peer traffic statistics.
+    stats: StatsTracker,
+    /// Memory caps of the protocol's caches.
+    memory_limits: memory::Limits,
+    /// Chain state published for other threads.
+    chain_state: state::SharedChainState,
+    /// Peers we're disconnecting from. Messages from these peers are ignored.
+    disconnecting: collections::HashSet<PeerId>,
+    /// Submitted transactions queued for sending, by peer. A transaction isn't submitted
+    /// to the same peer twice.
+    relayed: collections::HashMap<PeerId, collections::HashSet<Txid>>,
+    /// Whether a batch of inputs is being processed. See [`Protocol::step_batch`].
+    batching: bool,
+    /// Headers received during the current batch, not yet imported, and the peer they
+    /// were received from.
+    pending_headers: Option<(PeerId, Vec<BlockHeader>)>,
     /// Network-adjusted clock.
     clock: AdjustedTime<PeerId>,
     /// Informational name of this protocol instance. Used for logging purposes only.
     target: &'static str,
     /// Last time a "tick" was triggered.
     last_tick: LocalTime,
+    /// Last time caches were checked against their memory caps.
+    last_memory_check: LocalTime,
     /// Random number generator.
     rng: fastrand::Rng,
     /// Outbound channel. Used to communicate protocol events with a reactor.
@@ -323,6 +515,13 @@ pub struct Config {
     pub network: network::Network,
     /// Peers to connect to.
     pub connect: Vec<net::SocketAddr>,
+    /// Only connect to the peers in `connect`. This disables address discovery
+    /// and inbound connections.
+    pub connect_only: bool,
+    /// Anchor peers to connect to first on startup.
+    pub anchors: Vec<net::SocketAddr>,
+    /// Banned peer addresses.
+    pub bans: Vec<(net::IpAddr, peer::Ban)>,
     /// Services offered by our peer.
     pub services: ServiceFlags,
     /// Required peer services.
@@ -333,12 +532,32 @@ pub struct Config {
     pub params: Params,
     /// Our protocol version.
     pub protocol_version: u32,
-    /// Our user agent.
-    pub user_agent: &'static str,
+    /// Our user agent, in the format described in BIP 14.
+    pub user_agent: String,
     /// Target outbound peer connections.
     pub target_outbound_peers: usize,
     /// Maximum inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Target outbound block-relay-only peer connections.
+    pub block_relay_peers: usize,
+    /// Minimum number of outbound peers offering compact filters.
+    pub filter_peers: usize,
+    /// Mapping of IP ranges to AS numbers, used to diversify outbound peers.
+    pub asmap: Option<AsMap>,
+    /// Periodic outbound peer rotation. Disabled if `None`.
+    pub peer_rotation: Option<connmgr::Rotation>,
+    /// Our address advertisement policy.
+    pub advertise: addrmgr::Advertise,
+    /// File to record protocol outputs to, as JSON lines. See [`crate::journal`].
+    pub journal: Option<PathBuf>,
+    /// File to record protocol inputs to, for replay. See [`crate::replay`].
+    pub recording: Option<PathBuf>,
+    /// Interceptor of peer messages. See [`interceptor`].
+    pub interceptor: Option<Arc<dyn Interceptor>>,
+    /// Memory caps of the protocol's caches. See [`memory`].
+    pub memory_limits: memory::Limits,
+    /// Where to publish the chain state, for reading from other threads. See [`state`].
+    pub chain_state: state::SharedChainState,
     /// Log target.
     pub target: &'static str,
 }
@@ -349,13 +568,26 @@ impl Default for Config {
             network: network::Network::Mainnet,
             params: Params::new(network::Network::Mainnet.into()),
             connect: Vec::new(),
+            connect_only: false,
+            anchors: Vec::new(),
+            bans: Vec::new(),
             services: ServiceFlags::NONE,
             required_services: ServiceFlags::NETWORK,
             whitelist: Whitelist::default(),
             protocol_version: PROTOCOL_VERSION,
             target_outbound_peers: connmgr::TARGET_OUTBOUND_PEERS,
             max_inbound_peers: connmgr::MAX_INBOUND_PEERS,
-            user_agent: USER_AGENT,
+            block_relay_peers: connmgr::BLOCK_RELAY_PEERS,
+            filter_peers: connmgr::FILTER_PEERS,
+            asmap: None,
+            peer_rotation: None,
+            advertise: addrmgr::Advertise::default(),
+            user_agent: USER_AGENT.to_owned(),
+            journal: None,
+            recording: None,
+            interceptor: None,
+            memory_limits: memory::Limits::default(),
+            chain_state: state::SharedChainState::default(),
             target: "self",
         }
     }
@@ -386,26 +618,64 @@ impl Config {
 }
 
 /// Peer whitelist.
-#[derive(Debug, Clone)]
-pub struct Whitelist {
+///
+/// Clones of a whitelist share the same trusted peers, so that peers trusted while the
+/// protocol is running, eg. with [`Command::AddNode`], are trusted by all sub-protocols.
+#[derive(Debug, Clone, Default)]
+pub struct Whitelist(Arc<RwLock<Trusted>>);
+
+/// Peers trusted by a [`Whitelist`].
+#[derive(Debug, Clone, Default)]
+struct Trusted {
     /// Trusted addresses.
     addr: HashSet<net::IpAddr>,
     /// Trusted user-agents.
     user_agent: HashSet<String>,
 }
 
-impl Default for Whitelist {
-    fn default() -> Self {
-        Whitelist {
-            addr: HashSet::new(),
-            user_agent: HashSet::new(),
-        }
+impl Whitelist {
+    /// Create a whitelist of trusted addresses. Whitelisted peers are never banned or
+    /// evicted, are exempt from rate limits, and are preferred for syncing over other
+    /// peers at the same height.
+    pub fn new(addrs: impl IntoIterator<Item = net::IpAddr>) -> Self {
+        Self::with_user_agents(addrs, vec![])
+    }
+
+    /// Create a whitelist of trusted addresses and user-agents.
+    pub fn with_user_agents(
+        addrs: impl IntoIterator<Item = net::IpAddr>,
+        user_agents: impl IntoIterator<Item = String>,
+    ) -> Self {
+        Self(Arc::new(RwLock::new(Trusted {
+            addr: addrs.into_iter().collect(),
+            user_agent: user_agents.into_iter().collect(),
+        })))
+    }
+
+    /// Check whether an address is trusted.
+    pub fn contains_addr(&self, addr: &net::IpAddr) -> bool {
+        self.0.read().unwrap().addr.contains(addr)
     }
-}
 
-impl Whitelist {
     fn contains(&self, addr: &net::IpAddr, user_agent: &str) -> bool {
-        self.addr.contains(addr) || self.user_agent.contains(user_agent)
+        let trusted = self.0.read().unwrap();
+
+        trusted.addr.contains(addr) || trusted.user_agent.contains(user_agent)
+    }
+
+    /// Trust an address. Returns `false` if it was already trusted.
+    fn insert_addr(&self, addr: net::IpAddr) -> bool {
+        self.0.write().unwrap().addr.insert(addr)
+    }
+
+    /// Stop trusting an address.
+    fn remove_addr(&self, addr: &net::IpAddr) {
+        self.0.write().unwrap().addr.remove(addr);
+    }
+
+    /// Copy the whitelist, such that changes to the copy are not shared with the original.
+    fn detach(&self) -> Self {
+        Self(Arc::new(RwLock::new(self.0.read().unwrap().clone())))
     }
 }
 
@@ -423,24 +693,42 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         let Config {
             network,
             connect,
+            connect_only,
+            anchors,
+            bans,
             services,
             whitelist,
             protocol_version,
             target_outbound_peers,
             max_inbound_peers,
+            block_relay_peers,
+            filter_peers,
+            asmap,
+            peer_rotation,
+            advertise,
             user_agent,
             required_services,
+            journal: _,
+            recording: _,
+            interceptor,
+            memory_limits,
+            chain_state,
             target,
             params,
         } = config;
 
-        let upstream = Upstream::new(network, protocol_version, target, upstream);
+        // The whitelist is shared by the sub-protocols, but not with the configuration,
+        // which may be used to create other protocol instances.
+        let whitelist = whitelist.detach();
+        let upstream = Upstream::new(network, protocol_version, target, upstream)
+            .with_interceptor(interceptor.clone(), whitelist.clone());
 
         let syncmgr = SyncManager::new(
             syncmgr::Config {
                 max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
                 request_timeout: syncmgr::REQUEST_TIMEOUT,
                 params: params.clone(),
+                whitelist: whitelist.clone(),
             },
             rng.clone(),
             upstream.clone(),
@@ -450,11 +738,20 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             connmgr::Config {
                 target_outbound_peers,
                 max_inbound_peers,
+                block_relay_peers,
+                filter_peers,
                 retry: connect,
+                connect_only,
+                rotation: peer_rotation,
+                anchors,
+                bans,
                 required_services,
                 // Include services required by all sub-protocols.
                 preferred_services: syncmgr::REQUIRED_SERVICES | spvmgr::REQUIRED_SERVICES,
+                asmap,
+                whitelist: whitelist.clone(),
             },
+            rng.clone(),
         );
         let pingmgr = PingManager::new(rng.clone(), upstream.clone());
         let spvmgr = SpvManager::new(
@@ -470,22 +767,35 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 required_services,
                 services,
                 user_agent,
+                external_addr: match advertise {
+                    addrmgr::Advertise::Address(addr) => Some(addr),
+                    addrmgr::Advertise::Never | addrmgr::Advertise::Discovered(_) => None,
+                },
             },
             rng.clone(),
             upstream.clone(),
         );
         let addrmgr = AddressManager::new(
-            addrmgr::Config { required_services },
+            addrmgr::Config {
+                required_services,
+                services,
+                advertise,
+                discovery: !connect_only,
+                whitelist: whitelist.clone(),
+            },
             rng.clone(),
             peers,
             upstream.clone(),
         );
+        let stats = StatsTracker::new(rng.clone());
 
         Self {
             tree,
             network,
             protocol_version,
             whitelist,
+            trusted: collections::HashSet::with_hasher(rng.clone().into()),
+            interceptor,
             target,
             params,
             clock,
@@ -495,12 +805,49 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
             pingmgr,
             spvmgr,
             peermgr,
+            stats,
+            memory_limits,
+            chain_state,
+            disconnecting: collections::HashSet::with_hasher(rng.clone().into()),
+            relayed: collections::HashMap::with_hasher(rng.clone().into()),
+            batching: false,
+            pending_headers: None,
             last_tick: LocalTime::default(),
+            last_memory_check: LocalTime::default(),
             rng,
             upstream,
         }
     }
 
+    /// Process a batch of inputs, eg. all the inputs that became ready in one iteration of
+    /// a reactor's event loop, and advance the state machine.
+    ///
+    /// This is equivalent to stepping through the inputs one by one, except that bursts
+    /// are coalesced: consecutive `headers` messages from the same peer that extend each
+    /// other are imported at once, which saves locator computations and block store
+    /// writes, and consecutive timeouts are only handled once. Like [`Protocol::step`],
+    /// batches are deterministic.
+    pub fn step_batch<I: IntoIterator<Item = Input>>(&mut self, inputs: I, local_time: LocalTime) {
+        let mut timeout = false;
+
+        self.batching = true;
+
+        for input in inputs {
+            if let Input::Timeout = input {
+                if timeout {
+                    continue;
+                }
+                timeout = true;
+            } else {
+                timeout = false;
+            }
+            self.step(input, local_time);
+        }
+        self.import_pending_headers();
+        self.upstream.flush();
+        self.batching = false;
+    }
+
     /// Initialize the protocol. Called once before any event is sent to the state machine.
     pub fn initialize(&mut self, time: LocalTime) {
         self.clock.set_local_time(time);
@@ -508,10 +855,47 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         self.connmgr
             .initialize::<P, AddressManager<P, Channel>>(time, &mut self.addrmgr);
         self.spvmgr.initialize(time, &self.tree);
+        self.upstream.set_timeout(MEMORY_CHECK_INTERVAL);
+        self.publish_chain_state();
     }
 
     /// Process the next input and advance the state machine by one step.
+    ///
+    /// Steps are deterministic: the outputs and the next state only depend on the current
+    /// state, the input, the given time and the protocol's RNG. The system clock is never
+    /// read, and the only side effects are logging and sending outputs upstream. This
+    /// makes it possible to replay or explore sequences of inputs.
+    ///
+    /// Outbound `inv` and `addr` messages sent to a peer during a step are coalesced into
+    /// as few messages as possible. During a batch, they're coalesced over the whole batch.
     pub fn step(&mut self, input: Input, local_time: LocalTime) {
+        let _span = span!("protocol", node = self.target);
+
+        self.upstream.coalesce();
+        self.process(input, local_time);
+
+        if !self.batching {
+            self.upstream.flush();
+        }
+        self.publish_chain_state();
+    }
+
+    /// Publish the chain state, if the tip changed since it was last published.
+    fn publish_chain_state(&mut self) {
+        let (tip, _) = self.tree.tip();
+
+        if self.chain_state.get().map_or(true, |s| s.hash != tip) {
+            self.chain_state.update(&self.tree);
+        }
+    }
+
+    /// Process an input.
+    fn process(&mut self, input: Input, local_time: LocalTime) {
+        // Headers held back during a batch must be imported before any other input is
+        // processed, so that the order of inputs is preserved.
+        if !matches!(input, Input::Received(..)) {
+            self.import_pending_headers();
+        }
         self.tick(local_time);
 
         match input {
@@ -524,37 +908,112 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 link,
             } => {
                 let height = self.tree.height();
+
+                self.disconnecting.remove(&addr);
                 // This is usually not that useful, except when our local address is actually the
                 // address our peers see.
                 self.addrmgr.record_local_addr(local_addr);
                 self.addrmgr.peer_connected(&addr, local_time);
                 self.connmgr
                     .peer_connected(addr, local_addr, link, local_time);
+                // Peers rejected by the connection manager are being disconnected, and
+                // their messages are ignored.
+                if self.connmgr.is_disconnecting(&addr) {
+                    self.disconnecting.insert(addr);
+                }
                 self.peermgr
                     .peer_connected(addr, local_addr, link, height, local_time);
+                self.stats.peer_connected(addr);
             }
             Input::Disconnected(addr, reason) => {
                 debug!(target: self.target, "{}: Disconnected: {}", addr, reason);
 
                 self.spvmgr.peer_disconnected(&addr);
                 self.syncmgr.peer_disconnected(&addr);
-                self.addrmgr.peer_disconnected(&addr, reason);
+                self.addrmgr
+                    .peer_disconnected(&addr, reason.clone(), local_time);
                 self.connmgr
-                    .peer_disconnected::<P, AddressManager<P, Channel>>(&addr, &self.addrmgr);
+                    .peer_disconnected::<P, AddressManager<P, Channel>>(
+                        &addr,
+                        reason,
+                        &self.addrmgr,
+                    );
                 self.pingmgr.peer_disconnected(&addr);
                 self.peermgr.peer_disconnected(&addr);
+                self.stats.peer_disconnected(&addr);
+                self.relayed.remove(&addr);
+                if let Some(skew) = self.clock.remove_offset(&addr) {
+                    warn!(
+                        target: self.target,
+                        "Clock skew of {} seconds detected, time will no longer be adjusted", skew
+                    );
+                    self.upstream.event(Event::ClockSkewDetected(skew));
+                }
+                self.disconnecting.remove(&addr);
             }
-            Input::Received(addr, msg) => {
+            Input::Received(addr, envelope) => {
+                let size = envelope.size();
+
+                // Messages can race a disconnection, in which case the peer may no longer
+                // be tracked by the time they are received.
+                if !self.peermgr.is_connected(&addr) || self.disconnecting.contains(&addr) {
+                    debug!(
+                        target: self.target,
+                        "{}: Ignoring {:?} from disconnected peer", addr, envelope.command
+                    );
+                    self.stats.message_ignored(size);
+
+                    return;
+                }
+                self.connmgr.peer_active(&addr, local_time);
+
+                // Messages we don't handle are not decoded. Nb. this includes messages
+                // peers may send before the handshake completes, eg. `sendaddrv2`.
+                if !envelope.is_handled() {
+                    debug!(
+                        target: self.target,
+                        "{}: Ignoring {:?}", addr, envelope.command
+                    );
+                    self.stats.message_received(addr, stats::OTHER, size);
+
+                    return;
+                }
+                let mut msg = match envelope.decode() {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        debug!(
+                            target: self.target,
+                            "{}: Skipping undecodable {:?} message: {}", addr, envelope.command, err
+                        );
+                        self.stats.message_received(addr, stats::OTHER, size);
+
+                        return;
+                    }
+                };
+                self.stats.message_received(addr, msg.cmd(), size);
+
+                if let Some(interceptor) = &self.interceptor {
+                    let peer = Context::new(addr, &self.whitelist);
+
+                    if interceptor.received(&peer, &mut msg.payload) == Verdict::Drop {
+                        debug!(
+                            target: self.target,
+                            "{}: Dropping {:?}: vetoed by interceptor", addr, msg.cmd()
+                        );
+                        return;
+                    }
+                }
                 self.upstream
                     .event(Event::Received(addr, msg.payload.clone()));
                 self.receive(addr, msg);
             }
-            Input::Sent(_addr, _msg) => {}
+            Input::Sent(addr, cmd, size) => {
+                self.stats.message_sent(addr, cmd, size);
+            }
             Input::Command(cmd) => match cmd {
                 Command::Connect(addr) => {
                     debug!(target: self.target, "Received command: Connect({})", addr);
 
-                    self.whitelist.addr.insert(addr.ip());
                     self.connmgr.connect::<P, AddressManager<P, Channel>>(&addr);
                 }
                 Command::Disconnect(addr) => {
@@ -562,6 +1021,76 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
 
                     self.disconnect(addr, DisconnectReason::Command);
                 }
+                Command::AddNode(addr) => {
+                    debug!(target: self.target, "Received command: AddNode({})", addr);
+
+                    self.trust(addr.ip());
+                    self.connmgr
+                        .add_persistent::<P, AddressManager<P, Channel>>(addr);
+                }
+                Command::RemoveNode(addr) => {
+                    debug!(target: self.target, "Received command: RemoveNode({})", addr);
+
+                    self.connmgr.remove_persistent(&addr);
+                    // Addresses that were trusted because they were added are no longer
+                    // trusted, unless they were whitelisted in the configuration.
+                    if self.trusted.remove(&addr.ip()) {
+                        self.whitelist.remove_addr(&addr.ip());
+                    }
+                }
+                Command::Ban(ip, duration, reason) => {
+                    debug!(target: self.target, "Received command: Ban({}, {})", ip, duration);
+
+                    if !self
+                        .connmgr
+                        .ban(ip, peer::Ban::new(reason, local_time + duration))
+                    {
+                        debug!(target: self.target, "{}: Not banning whitelisted address", ip);
+                    }
+                }
+                Command::Unban(ip) => {
+                    debug!(target: self.target, "Received command: Unban({})", ip);
+
+                    self.connmgr.unban(&ip);
+                }
+                Command::GetPeers(reply) => {
+                    let peers = self
+                        .peermgr
+                        .peers()
+                        .filter(|p| p.is_negotiated())
+                        .map(|p| p.info())
+                        .collect();
+
+                    reply.send(peers).ok();
+                }
+                Command::GetBans(reply) => {
+                    let bans = self
+                        .connmgr
+                        .bans()
+                        .map(|(ip, ban)| (*ip, ban.clone()))
+                        .collect();
+
+                    reply.send(bans).ok();
+                }
+                Command::ExportAddresses(reply) => {
+                    debug!(target: self.target, "Received command: ExportAddresses");
+
+                    reply.send(self.addrmgr.export()).ok();
+                }
+                Command::GetPeerStats(reply) => {
+                    reply.send(self.stats.snapshot()).ok();
+                }
+                Command::GetMemoryInfo(reply) => {
+                    reply.send(self.memory_info()).ok();
+                }
+                Command::ResetPeerStats => {
+                    debug!(target: self.target, "Received command: ResetPeerStats");
+
+                    self.stats.reset();
+                }
+                Command::GetTimeOffset(reply) => {
+                    reply.send(self.clock.offset()).ok();
+                }
                 Command::Query(msg, reply) => {
                     debug!(target: self.target, "Received command: Query({:?})", msg);
 
@@ -582,6 +1111,8 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                         &self.clock,
                         &mut self.tree,
                     );
+                    // Publish before replying, so that the caller sees the new tip.
+                    self.publish_chain_state();
 
                     match result {
                         Ok(import_result) => {
@@ -598,11 +1129,27 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
 
                     reply.send((height, header)).ok();
                 }
-                Command::GetFilters(range) => {
+                Command::GetHeader(hash, reply) => {
+                    let header = self.tree.get_block(&hash).map(|(h, header)| (h, *header));
+
+                    reply.send(header).ok();
+                }
+                Command::GetHeaderByHeight(height, reply) => {
+                    let header = self.tree.get_block_by_height(height).copied();
+
+                    reply.send(header).ok();
+                }
+                Command::GetFilterHeader(height, reply) => {
+                    reply.send(self.spvmgr.get_header(height)).ok();
+                }
+                Command::GetFilterHeight(reply) => {
+                    reply.send(self.spvmgr.height()).ok();
+                }
+                Command::GetFilters(range, reply) => {
                     debug!(target: self.target,
                         "Received command: GetFilters({}..{})", range.start, range.end);
 
-                    self.spvmgr.get_cfilters(range, &self.tree);
+                    reply.send(self.spvmgr.get_cfilters(range, &self.tree)).ok();
                 }
                 Command::GetBlock(hash) => {
                     self.query(NetworkMessage::GetData(vec![Inventory::Block(hash)]), |p| {
@@ -612,9 +1159,33 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 Command::SubmitTransaction(tx) => {
                     debug!(target: self.target, "Received command: SubmitTransaction(..)");
 
-                    self.query(NetworkMessage::Tx(tx), |p| p.relay);
+                    let txid = tx.txid();
+                    let size = tx.consensus_encode(&mut io::sink()).unwrap_or_default();
+                    let relayed = &self.relayed;
+                    let peer = self.query(NetworkMessage::Tx(tx), |p| {
+                        p.relay
+                            && !relayed
+                                .get(&p.address())
+                                .map_or(false, |txids| txids.contains(&txid))
+                    });
+
+                    if let Some(addr) = peer {
+                        let rng = self.rng.clone();
+
+                        self.relayed
+                            .entry(addr)
+                            .or_insert_with(|| collections::HashSet::with_hasher(rng.into()))
+                            .insert(txid);
+                        self.stats.transaction_relayed(addr, size);
+                        self.upstream.event(Event::TransactionRelayed(addr, txid));
+                    } else {
+                        debug!(target: self.target, "No peer to submit transaction {} to", txid);
+                    }
                 }
                 Command::Shutdown => {
+                    debug!(target: self.target, "Received command: Shutdown");
+
+                    self.shutdown();
                     self.upstream.push(Out::Shutdown);
                 }
             },
@@ -628,6 +1199,12 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 self.addrmgr.received_timeout(local_time);
                 self.peermgr.received_timeout(local_time);
                 self.spvmgr.received_timeout(local_time, &self.tree);
+
+                if local_time - self.last_memory_check >= MEMORY_CHECK_INTERVAL {
+                    self.enforce_memory_limits();
+                    self.upstream.set_timeout(MEMORY_CHECK_INTERVAL);
+                    self.last_memory_check = local_time;
+                }
             }
         };
     }
@@ -656,6 +1233,43 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    /// Get the approximate memory used by the protocol's caches.
+    pub fn memory_info(&self) -> memory::MemoryInfo {
+        let (headers, orphans) = self.tree.memory_usage();
+
+        memory::MemoryInfo {
+            headers,
+            orphans,
+            addresses: self.addrmgr.memory_usage(),
+            filters: self.spvmgr.memory_usage(),
+        }
+    }
+
+    /// Trim the caches that are over their memory cap. Called periodically, every
+    /// [`MEMORY_CHECK_INTERVAL`].
+    fn enforce_memory_limits(&mut self) {
+        let info = self.memory_info();
+
+        if let Some(max) = self.memory_limits.orphans {
+            if info.orphans > max {
+                debug!(
+                    target: self.target,
+                    "Orphan headers use {} bytes, over the cap of {}", info.orphans, max
+                );
+                self.tree.prune_orphans(max);
+            }
+        }
+        if let Some(max) = self.memory_limits.addresses {
+            if info.addresses > max {
+                debug!(
+                    target: self.target,
+                    "Address book uses {} bytes, over the cap of {}", info.addresses, max
+                );
+                self.addrmgr.prune(max);
+            }
+        }
+    }
+
     fn tick(&mut self, local_time: LocalTime) {
         // The local time is set from outside the protocol.
         self.clock.set_local_time(local_time);
@@ -697,37 +1311,138 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    /// Process a `headers` message. During a batch, the headers are held back, so that they
+    /// can be imported together with the headers of the next messages from the same peer.
+    fn received_headers(&mut self, addr: PeerId, headers: Vec<BlockHeader>) {
+        if !self.batching || headers.is_empty() {
+            self.import_pending_headers();
+
+            return self.import_headers(addr, headers);
+        }
+
+        if let Some((from, pending)) = &mut self.pending_headers {
+            let extends = pending.last().map(|h| h.block_hash()) == Some(headers[0].prev_blockhash);
+
+            // Only coalesce messages that would each have been the last of a sync round,
+            // so that the length of a message still tells whether more headers are
+            // available from the peer.
+            if *from == addr
+                && extends
+                && pending.len() + headers.len() < syncmgr::MAX_MESSAGE_HEADERS
+            {
+                pending.extend(headers);
+
+                return;
+            }
+        }
+        self.import_pending_headers();
+        self.pending_headers = Some((addr, headers));
+    }
+
+    /// Import the headers held back during a batch, if any.
+    fn import_pending_headers(&mut self) {
+        if let Some((addr, headers)) = self.pending_headers.take() {
+            self.import_headers(addr, headers);
+        }
+    }
+
+    /// Import headers received from a peer.
+    fn import_headers(&mut self, addr: PeerId, headers: Vec<BlockHeader>) {
+        let _span = span!("syncmgr");
+
+        match self
+            .syncmgr
+            .received_headers(&addr, headers, &self.clock, &mut self.tree)
+        {
+            Err(e) => self.fatal(FatalError::BlockStore(e.to_string())),
+            Ok(ImportResult::TipChanged(_, _, reverted)) if !reverted.is_empty() => {
+                // By rolling back the filter headers, we will trigger
+                // a re-download of the missing headers, which should result
+                // in us having the new headers.
+                match self.spvmgr.rollback(reverted.len()) {
+                    Ok(()) => self.spvmgr.sync(&self.tree),
+                    Err(e) => self.fatal(FatalError::FilterStore(e.to_string())),
+                }
+            }
+            Ok(ImportResult::TipChanged(_, _, _)) => {
+                // Trigger a sync, since we're going to have to catch up on the new block
+                // header(s). This is not required, but reduces latency.
+                self.spvmgr.sync(&self.tree);
+            }
+            _ => {}
+        }
+    }
+
     fn receive(&mut self, addr: PeerId, msg: RawNetworkMessage) {
         let now = self.clock.local_time();
         let cmd = msg.cmd();
 
+        match &msg.payload {
+            NetworkMessage::Headers(headers) if headers.len() <= syncmgr::MAX_MESSAGE_HEADERS => {}
+            _ => self.import_pending_headers(),
+        }
+
         if msg.magic != self.network.magic() {
             // TODO: Needs test.
             return self.disconnect(addr, DisconnectReason::PeerMagic(msg.magic));
         }
 
-        if !self.peermgr.is_connected(&addr) {
-            debug!(target: self.target, "Received {:?} from unknown peer {}", cmd, addr);
-            return;
-        };
+        let _span = span!("peer", addr = %addr, cmd);
 
         debug!(
             target: self.target, "{}: Received {:?}",
             addr, cmd
         );
 
+        if let Err(reason) = self.peermgr.check_handshake(&addr, &msg.payload) {
+            return self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason));
+        }
+
         match msg.payload {
             NetworkMessage::Version(msg) => {
+                let _span = span!("peermgr");
                 let height = self.tree.height();
 
                 self.peermgr
                     .received_version(&addr, msg, height, now, &mut self.addrmgr);
             }
             NetworkMessage::Verack => {
+                let _span = span!("peermgr");
+
                 if let Some(peer) = self.peermgr.received_verack(&addr, now) {
-                    self.clock.record_offset(peer.address(), peer.time_offset);
-                    self.addrmgr
-                        .peer_negotiated(&addr, peer.services, peer.conn.link, now);
+                    let connmgr = &self.connmgr;
+                    let group = connmgr.netgroup(&addr);
+
+                    // Peers are free to claim any time, so we clamp offsets to the maximum
+                    // adjustment, and only take one sample per network group, so that a
+                    // single network operator can't easily shift our clock. Clamped offsets
+                    // still count towards detecting clock skew.
+                    if self.clock.sources().any(|s| connmgr.netgroup(s) == group) {
+                        debug!(
+                            target: self.target,
+                            "{}: Ignoring time offset from already sampled network group", addr
+                        );
+                    } else {
+                        let offset = peer
+                            .time_offset
+                            .clamp(-MAX_TIME_ADJUSTMENT, MAX_TIME_ADJUSTMENT);
+
+                        if let Some(skew) = self.clock.record_offset(addr, offset) {
+                            warn!(
+                                target: self.target,
+                                "Clock skew of {} seconds detected, time will no longer be adjusted",
+                                skew
+                            );
+                            self.upstream.event(Event::ClockSkewDetected(skew));
+                        }
+                    }
+                    self.addrmgr.peer_negotiated(
+                        &addr,
+                        peer.services,
+                        peer.conn.link,
+                        self.connmgr.is_block_relay(&addr),
+                        now,
+                    );
                     self.pingmgr.peer_negotiated(peer.address(), now);
                     self.connmgr.peer_negotiated(peer.address(), peer.services);
                     self.spvmgr.peer_negotiated(
@@ -749,37 +1464,37 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 }
             }
             NetworkMessage::Ping(nonce) => {
+                let _span = span!("pingmgr");
+
                 self.pingmgr.received_ping(addr, nonce);
             }
             NetworkMessage::Pong(nonce) => {
-                self.pingmgr.received_pong(addr, nonce, now);
+                let _span = span!("pingmgr");
+
+                if let Some(latency) = self.pingmgr.received_pong(addr, nonce, now) {
+                    self.addrmgr.peer_latency(&addr, latency);
+                    self.connmgr.peer_latency(&addr, latency);
+                    self.peermgr.peer_latency(&addr, latency);
+                    self.syncmgr.peer_latency(&addr, latency);
+                    self.spvmgr.peer_latency(&addr, latency);
+                }
+            }
+            NetworkMessage::Headers(headers) if headers.len() > syncmgr::MAX_MESSAGE_HEADERS => {
+                self.disconnect(
+                    addr,
+                    DisconnectReason::PeerMisbehaving("headers: header count exceeds maximum"),
+                );
             }
             NetworkMessage::Headers(headers) => {
-                match self
-                    .syncmgr
-                    .received_headers(&addr, headers, &self.clock, &mut self.tree)
-                {
-                    Err(e) => log::error!("Error receiving headers: {}", e),
-                    Ok(ImportResult::TipChanged(_, _, reverted)) if !reverted.is_empty() => {
-                        // By rolling back the filter headers, we will trigger
-                        // a re-download of the missing headers, which should result
-                        // in us having the new headers.
-                        self.spvmgr.rollback(reverted.len()).unwrap();
-                        self.spvmgr.sync(&self.tree);
-                    }
-                    Ok(ImportResult::TipChanged(_, _, _)) => {
-                        // Trigger a sync, since we're going to have to catch up on the new block
-                        // header(s). This is not required, but reduces latency.
-                        self.spvmgr.sync(&self.tree);
-                    }
-                    _ => {}
-                }
+                self.received_headers(addr, headers);
             }
             NetworkMessage::GetHeaders(GetHeadersMessage {
                 locator_hashes,
                 stop_hash,
                 ..
             }) => {
+                let _span = span!("syncmgr");
+
                 self.syncmgr.received_getheaders(
                     &addr,
                     (locator_hashes, stop_hash),
@@ -787,16 +1502,28 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 );
             }
             NetworkMessage::Block(block) => {
+                let _span = span!("syncmgr");
+
                 self.syncmgr.received_block(&addr, block, &self.tree);
             }
+            NetworkMessage::Inv(inventory) if inventory.len() > syncmgr::MAX_MESSAGE_INVS => {
+                self.disconnect(
+                    addr,
+                    DisconnectReason::PeerMisbehaving("inv: inventory count exceeds maximum"),
+                );
+            }
             NetworkMessage::Inv(inventory) => {
                 // Receive an `inv` message. This will happen if we are out of sync with a
                 // peer. And blocks are being announced. Otherwise, we expect to receive a
                 // `headers` message.
+                let _span = span!("syncmgr");
+
                 self.syncmgr
                     .received_inv(addr, inventory, &self.clock, &self.tree);
             }
             NetworkMessage::CFHeaders(msg) => {
+                let _span = span!("spvmgr");
+
                 match self.spvmgr.received_cfheaders(&addr, msg, &self.tree) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
@@ -805,28 +1532,52 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
                 }
             }
             NetworkMessage::GetCFHeaders(msg) => {
+                let _span = span!("spvmgr");
+
                 match self.spvmgr.received_getcfheaders(&addr, msg, &self.tree) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
                     }
+                    Err(spvmgr::Error::Filters(e)) => {
+                        self.fatal(FatalError::FilterStore(e.to_string()))
+                    }
                     _ => {}
                 }
             }
             NetworkMessage::CFilter(msg) => {
+                let _span = span!("spvmgr");
+
                 match self.spvmgr.received_cfilter(&addr, msg, &self.tree) {
                     Err(spvmgr::Error::InvalidMessage { reason, .. }) => {
                         self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason))
                     }
+                    Err(spvmgr::Error::Filters(e)) => {
+                        self.fatal(FatalError::FilterStore(e.to_string()))
+                    }
                     _ => {}
                 }
             }
             NetworkMessage::GetCFilters(msg) => {
+                let _span = span!("spvmgr");
+
                 self.spvmgr.received_getcfilters(&addr, msg, &self.tree);
             }
+            NetworkMessage::Addr(_) | NetworkMessage::GetAddr
+                if self.connmgr.is_block_relay(&addr) =>
+            {
+                // Addresses are not exchanged with block-relay-only peers.
+                debug!(target: self.target, "{}: Ignoring {:?} from block-relay peer", addr, cmd);
+            }
             NetworkMessage::Addr(addrs) => {
-                self.addrmgr.received_addr(addr, addrs);
+                let _span = span!("addrmgr");
+
+                if let Err(reason) = self.addrmgr.received_addr(addr, addrs, now) {
+                    self.disconnect(addr, DisconnectReason::PeerMisbehaving(reason));
+                }
             }
             NetworkMessage::GetAddr => {
+                let _span = span!("addrmgr");
+
                 self.addrmgr.received_getaddr(&addr);
             }
             _ => {
@@ -835,13 +1586,50 @@ impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
         }
     }
 
+    /// Say goodbye to our peers, and save the address book and headers, before shutting
+    /// down.
+    fn shutdown(&mut self) {
+        let peers = self
+            .connmgr
+            .outbound_peers()
+            .chain(self.connmgr.inbound_peers())
+            .copied()
+            .collect::<Vec<_>>();
+
+        for addr in peers {
+            self.connmgr.disconnect(addr, DisconnectReason::Shutdown);
+        }
+        self.addrmgr.flush();
+
+        if let Err(err) = self.tree.sync() {
+            error!(target: self.target, "Error writing block headers to disk: {}", err);
+        }
+        if let Err(err) = self.spvmgr.flush() {
+            error!(target: self.target, "Error writing filter headers to disk: {}", err);
+        }
+    }
+
+    /// Shut down due to an error we can't recover from.
+    fn fatal(&mut self, err: FatalError) {
+        error!(target: self.target, "Fatal error: {}", err);
+
+        self.shutdown();
+        self.upstream.push(Out::Fatal(err));
+    }
+
+    /// Trust an address added by a command.
+    fn trust(&mut self, ip: net::IpAddr) {
+        if self.whitelist.insert_addr(ip) {
+            self.trusted.insert(ip);
+        }
+    }
+
     fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         debug!(target: self.target, "{}: Disconnecting peer: {}", addr, reason);
 
-        // TODO: Trigger disconnection everywhere, as if peer disconnected. This
-        // avoids being in a state where we know a peer is about to get disconnected,
-        // but we still process messages from it as normal.
-
+        // TODO: Trigger disconnection everywhere, as if peer disconnected.
+        // Until then, we at least stop processing messages from the peer.
+        self.disconnecting.insert(addr);
         self.connmgr.disconnect(addr, reason);
     }
 }
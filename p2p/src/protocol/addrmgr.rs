@@ -2,6 +2,9 @@
 //! The peer-to-peer address manager.
 //!
 #![warn(missing_docs)]
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::mem;
 use std::net;
 
 use bitcoin::network::address::Address;
@@ -10,10 +13,11 @@ use bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
 use nakamoto_common::block::BlockTime;
 use nakamoto_common::collections::{HashMap, HashSet};
+use nakamoto_common::p2p::addr::PeerAddr;
 use nakamoto_common::p2p::peer::{AddressSource, KnownAddress, Source, Store};
 
 use super::channel::SetTimeout;
-use super::{DisconnectReason, Link, PeerId};
+use super::{DisconnectReason, Link, PeerId, Whitelist};
 
 /// Time to wait until a request times out.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -25,6 +29,38 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(30);
 const MAX_GETADDR_ADDRESSES: usize = 8;
 /// Maximum number of addresses we store for a given address range.
 const MAX_RANGE_SIZE: usize = 256;
+/// Selection score of an address we haven't tried yet.
+const NEW_ADDRESS_SCORE: f64 = 1.;
+/// Selection score of an address we've successfully connected to before.
+const GOOD_ADDRESS_SCORE: f64 = 4.;
+/// Number of distinct peers that must agree on our external address before we use it.
+const MIN_EXTERNAL_ADDR_REPORTS: usize = 2;
+/// Maximum number of distinct external IPs tracked. Beyond this, the IP with the fewest
+/// reports is dropped.
+const MAX_EXTERNAL_IPS: usize = 16;
+/// Maximum number of reports tracked for a given external IP.
+const MAX_EXTERNAL_IP_REPORTS: usize = 32;
+/// Average time between address relays to a given peer.
+const ADDR_RELAY_INTERVAL: LocalDuration = LocalDuration::from_secs(30);
+/// Maximum number of addresses relayed to a peer at once.
+const MAX_ADDR_RELAY_BATCH: usize = 10;
+/// Maximum number of addresses queued for relay to a peer. Beyond this, addresses are dropped.
+const MAX_ADDR_RELAY_QUEUE: usize = 100;
+/// Maximum number of addresses remembered per peer, to avoid relaying duplicates. Beyond
+/// this, the oldest addresses are forgotten.
+const MAX_ADDR_RELAY_KNOWN: usize = 1024;
+/// Maximum size of an `addr` message for it to be considered an announcement to relay.
+/// Larger messages are usually responses to `getaddr`.
+const MAX_ADDR_ANNOUNCEMENT: usize = 10;
+/// Number of peers each announced address is relayed to.
+const ADDR_RELAY_PEERS: usize = 2;
+/// Maximum age of an announced address for it to be relayed, in seconds.
+const MAX_ADDR_RELAY_AGE: BlockTime = 10 * 60;
+/// Maximum number of addresses in an `addr` message, as per the protocol.
+pub const MAX_ADDR_ADDRESSES: usize = 1000;
+/// Rate at which addresses received from a peer are processed, in addresses per second.
+/// Addresses received in excess of this rate are dropped.
+const ADDR_RATE: f64 = 0.1;
 
 /// Address manager event emission.
 pub trait Events {
@@ -80,21 +116,131 @@ impl std::fmt::Display for Event {
     }
 }
 
+/// Our address advertisement policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advertise {
+    /// Never advertise our address to peers. This is the most private option.
+    Never,
+    /// Advertise our external IP, as reported by our peers, with the given port.
+    Discovered(u16),
+    /// Advertise the given address.
+    Address(net::SocketAddr),
+}
+
+impl Default for Advertise {
+    fn default() -> Self {
+        Self::Never
+    }
+}
+
 /// Address manager configuration.
 #[derive(Debug)]
 pub struct Config {
     /// Services required from peers.
     pub required_services: ServiceFlags,
+    /// Services offered by us. Included in our address advertisements.
+    pub services: ServiceFlags,
+    /// Whether and what to advertise as our own address.
+    pub advertise: Advertise,
+    /// Whether to discover new addresses from peers. If disabled, we never ask peers for
+    /// addresses and ignore the ones we receive.
+    pub discovery: bool,
+    /// Trusted peers. These are exempt from rate limits.
+    pub whitelist: Whitelist,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             required_services: ServiceFlags::NONE,
+            services: ServiceFlags::NONE,
+            advertise: Advertise::default(),
+            discovery: true,
+            whitelist: Whitelist::default(),
+        }
+    }
+}
+
+/// Address relay state of a peer.
+#[derive(Debug)]
+struct Relay {
+    /// Addresses waiting to be relayed to this peer.
+    queue: Vec<(BlockTime, Address)>,
+    /// Addresses this peer is known to have, because we sent them, or because
+    /// the peer sent them to us.
+    known: HashSet<net::SocketAddr>,
+    /// Known addresses, oldest first.
+    known_order: VecDeque<net::SocketAddr>,
+    /// Time at which we can next relay addresses to this peer.
+    next: LocalTime,
+}
+
+impl Relay {
+    /// Remember that the peer knows about the given address. Returns `false` if
+    /// it was already known.
+    fn remember(&mut self, addr: net::SocketAddr) -> bool {
+        if !self.known.insert(addr) {
+            return false;
+        }
+        self.known_order.push_back(addr);
+
+        if self.known_order.len() > MAX_ADDR_RELAY_KNOWN {
+            if let Some(oldest) = self.known_order.pop_front() {
+                self.known.remove(&oldest);
+            }
         }
+        true
+    }
+}
+
+/// Limits the rate at which a peer's addresses are processed, using a token bucket.
+/// The bucket starts full, so that a peer can answer our `getaddr`.
+#[derive(Debug)]
+struct AddrLimit {
+    /// Number of addresses that can currently be processed.
+    tokens: f64,
+    /// Last time the bucket was refilled.
+    refilled: LocalTime,
+}
+
+impl AddrLimit {
+    fn new(time: LocalTime) -> Self {
+        Self {
+            tokens: MAX_ADDR_ADDRESSES as f64,
+            refilled: time,
+        }
+    }
+
+    /// Take up to `n` tokens from the bucket. Returns the number of tokens taken.
+    fn take(&mut self, n: usize, time: LocalTime) -> usize {
+        if time > self.refilled {
+            let elapsed = (time - self.refilled).as_millis() as f64 / 1000.;
+
+            self.tokens = (self.tokens + elapsed * ADDR_RATE).min(MAX_ADDR_ADDRESSES as f64);
+            self.refilled = time;
+        }
+        let taken = n.min(self.tokens as usize);
+        self.tokens -= taken as f64;
+
+        taken
     }
 }
 
+/// An address book entry, along with the information used to select it for connection.
+#[derive(Debug, Clone)]
+pub struct AddressInfo {
+    /// IP address, as stored in the address book.
+    pub ip: net::IpAddr,
+    /// The known address.
+    pub known: KnownAddress,
+    /// Selection score. Higher scores are more likely to be picked.
+    pub score: f64,
+    /// Address range bucket, as returned by [`addr_key`].
+    pub bucket: u8,
+    /// Whether we're currently connected to this address.
+    pub connected: bool,
+}
+
 /// Manages peer network addresses.
 #[derive(Debug)]
 pub struct AddressManager<P, U> {
@@ -104,6 +250,12 @@ pub struct AddressManager<P, U> {
     connected: HashSet<net::IpAddr>,
     sources: HashSet<net::SocketAddr>,
     local_addrs: HashSet<net::SocketAddr>,
+    /// Our external IPs, as reported by outbound peers, along with the reporting peers.
+    external_ips: HashMap<net::IpAddr, HashSet<net::IpAddr>>,
+    /// Address relay state of peers we relay addresses to.
+    relays: HashMap<PeerId, Relay>,
+    /// Rate limits on addresses received from peers.
+    limits: HashMap<PeerId, AddrLimit>,
     /// The last time we asked our peers for new addresses.
     last_request: Option<LocalTime>,
     /// The last time we idled.
@@ -115,10 +267,25 @@ pub struct AddressManager<P, U> {
 
 impl<P: Store, U> AddressManager<P, U> {
     /// Iterate over all addresses.
-    pub fn iter(&self) -> impl Iterator<Item = &Address> {
+    pub fn iter(&self) -> impl Iterator<Item = &PeerAddr> {
         self.peers.iter().map(|(_, ka)| &ka.addr)
     }
 
+    /// Export the address book, with selection scores, buckets and timestamps.
+    /// Useful to understand how peers are selected.
+    pub fn export(&self) -> Vec<AddressInfo> {
+        self.peers
+            .iter()
+            .map(|(ip, ka)| AddressInfo {
+                ip: *ip,
+                known: ka.clone(),
+                score: self::score(ka),
+                bucket: self::addr_key(ip),
+                connected: self.connected.contains(ip),
+            })
+            .collect()
+    }
+
     /// Check whether we have unused addresses.
     pub fn is_exhausted(&self) -> bool {
         for (addr, _) in self.peers.iter() {
@@ -141,22 +308,100 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
     /// Called when we receive a `getaddr` message.
     pub fn received_getaddr(&mut self, from: &net::SocketAddr) {
         // TODO: Use `sample` here when it returns an iterator.
-        let addrs = self
+        let mut addrs = self
+            .peers
             .iter()
             // Don't send the peer their own address, nor non-TCP addresses.
-            .filter(|a| a.socket_addr().map_or(false, |s| &s != from))
+            .filter(|(_, ka)| ka.addr.socket_addr().map_or(false, |s| &s != from))
+            .collect::<Vec<_>>();
+
+        // The iteration order of the address store is arbitrary, so we sort the addresses
+        // before shuffling them. This way, which addresses are sent only depends on our RNG.
+        addrs.sort_unstable_by_key(|(ip, _)| *ip);
+        self.rng.shuffle(&mut addrs);
+
+        let addrs = addrs
+            .into_iter()
             .take(MAX_GETADDR_ADDRESSES)
             // TODO: Return a non-zero time value.
-            .map(|a| (0, a.clone()))
+            .filter_map(|(_, ka)| ka.address().map(|addr| (0, addr)))
             .collect();
 
         self.upstream.send_addresses(*from, addrs);
     }
 
+    /// Called when we received an `addr` message from a peer. Returns an error if the
+    /// message is a protocol violation.
+    ///
+    /// Addresses received in excess of the peer's rate limit are dropped.
+    pub fn received_addr(
+        &mut self,
+        peer: net::SocketAddr,
+        mut addrs: Vec<(BlockTime, Address)>,
+        local_time: LocalTime,
+    ) -> Result<(), &'static str> {
+        if addrs.len() > MAX_ADDR_ADDRESSES {
+            return Err("addr: address count exceeds maximum");
+        }
+        if addrs.is_empty() {
+            // Peer misbehaving, got empty message.
+            return Ok(());
+        }
+        if !self.cfg.discovery {
+            return Ok(());
+        }
+        // Whitelisted peers are exempt from rate limiting.
+        if !self.cfg.whitelist.contains_addr(&peer.ip()) {
+            let allowed = self
+                .limits
+                .entry(peer)
+                .or_insert_with(|| AddrLimit::new(local_time))
+                .take(addrs.len(), local_time);
+
+            if allowed < addrs.len() {
+                log::debug!(
+                    "Dropping {} address(es) from {}: rate limit exceeded",
+                    addrs.len() - allowed,
+                    peer
+                );
+                addrs.truncate(allowed);
+
+                if addrs.is_empty() {
+                    return Ok(());
+                }
+            }
+        }
+        let source = Source::Peer(peer);
+
+        self.upstream.event(Event::AddressesReceived {
+            count: addrs.len(),
+            source,
+        });
+
+        // Don't relay these addresses back to the peer who sent them.
+        if let Some(relay) = self.relays.get_mut(&peer) {
+            for (_, addr) in addrs.iter() {
+                if let Ok(addr) = addr.socket_addr() {
+                    relay.remember(addr);
+                }
+            }
+        }
+        if addrs.len() <= MAX_ADDR_ANNOUNCEMENT {
+            self.relay(&peer, &addrs, local_time);
+        }
+        self.insert(addrs.into_iter(), source);
+
+        Ok(())
+    }
+
     /// Called when a timeout is received.
     pub fn received_timeout(&mut self, local_time: LocalTime) {
+        // Trickle queued addresses to peers whose relay timer has expired.
+        self.flush_relays(local_time);
+
         // If we're already using all the addresses we have available, we should fetch more.
-        if local_time - self.last_request.unwrap_or_default() >= REQUEST_TIMEOUT
+        if self.cfg.discovery
+            && local_time - self.last_request.unwrap_or_default() >= REQUEST_TIMEOUT
             && self.is_exhausted()
         {
             self.get_addresses();
@@ -166,10 +411,7 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
 
         // If it's been a while, save addresses to store.
         if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
-            if let Err(err) = self.peers.flush() {
-                self.upstream
-                    .event(Event::Error(format!("flush to disk failed: {}", err)));
-            }
+            self.flush();
             self.upstream.set_timeout(IDLE_TIMEOUT);
         }
     }
@@ -179,6 +421,7 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         // We're only interested in connection attempts for addresses we keep track of.
         if let Some(ka) = self.peers.get_mut(&addr.ip()) {
             ka.last_attempt = Some(time);
+            ka.attempts = ka.attempts.saturating_add(1);
         }
     }
 
@@ -190,19 +433,43 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         self.connected.insert(addr.ip());
     }
 
-    /// Called when a peer has handshaked.
+    /// Called when a peer has handshaked. Block-relay-only peers are not used as a
+    /// source of addresses.
     pub fn peer_negotiated(
         &mut self,
         addr: &net::SocketAddr,
         services: ServiceFlags,
         link: Link,
+        block_relay: bool,
         time: LocalTime,
     ) {
         if !self.connected.contains(&addr.ip()) {
             return;
         }
-        if link.is_outbound() {
+        if !block_relay {
+            self.relays.insert(
+                *addr,
+                Relay {
+                    queue: Vec::new(),
+                    known: HashSet::with_hasher(self.rng.clone().into()),
+                    known_order: VecDeque::new(),
+                    next: time + self::relay_delay(&self.rng),
+                },
+            );
+        }
+        if link.is_outbound() && !block_relay {
             self.sources.insert(*addr);
+
+            // Let our peer know how to reach us, if we're willing to share it.
+            if let Some(local_addr) = self.advertised_addr() {
+                self.upstream.send_addresses(
+                    *addr,
+                    vec![(
+                        time.block_time(),
+                        Address::new(&local_addr, self.cfg.services),
+                    )],
+                );
+            }
         }
 
         // We're only interested in peers we already know, eg. from DNS or peer
@@ -210,17 +477,39 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
         // or are discovered via a DNS seed.
         if let Some(ka) = self.peers.get_mut(&addr.ip()) {
             // Only ask for addresses when connecting for the first time.
-            if ka.last_success.is_none() {
+            if ka.last_success.is_none() && !block_relay && self.cfg.discovery {
                 self.upstream.get_addresses(*addr);
             }
             // Keep track of when the last successful handshake was.
             ka.last_success = Some(time);
-            ka.addr.services = services;
+            ka.services = services;
+        }
+    }
+
+    /// Called when we've measured the round-trip latency of a peer.
+    pub fn peer_latency(&mut self, addr: &net::SocketAddr, latency: LocalDuration) {
+        if let Some(ka) = self.peers.get_mut(&addr.ip()) {
+            ka.latency = Some(latency);
         }
     }
 
     /// Called when a peer disconnected.
-    pub fn peer_disconnected(&mut self, addr: &net::SocketAddr, reason: DisconnectReason) {
+    pub fn peer_disconnected(
+        &mut self,
+        addr: &net::SocketAddr,
+        reason: DisconnectReason,
+        time: LocalTime,
+    ) {
+        // If we never completed the handshake with this peer, count it as a failed attempt.
+        if let Some(ka) = self.peers.get_mut(&addr.ip()) {
+            if ka.is_pending() {
+                ka.last_failure = Some(time);
+            }
+        }
+
+        self.relays.remove(addr);
+        self.limits.remove(addr);
+
         if self.connected.contains(&addr.ip()) {
             // Disconnected peers cannot be used as a source for new addresses.
             self.sources.remove(&addr);
@@ -232,6 +521,75 @@ impl<P: Store, U: SyncAddresses + SetTimeout + Events> AddressManager<P, U> {
             }
         }
     }
+
+    ////////////////////////////////////////////////////////////////////////////
+
+    /// Queue recently announced addresses for relay to a few random peers.
+    fn relay(&mut self, from: &PeerId, addrs: &[(BlockTime, Address)], local_time: LocalTime) {
+        let now = local_time.block_time();
+        let mut peers = self
+            .relays
+            .keys()
+            .filter(|p| *p != from)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.rng.shuffle(&mut peers);
+        peers.truncate(ADDR_RELAY_PEERS);
+
+        for (timestamp, addr) in addrs {
+            // Only relay fresh addresses that others can actually connect to.
+            if now.saturating_sub(*timestamp) > MAX_ADDR_RELAY_AGE {
+                continue;
+            }
+            match addr.socket_addr() {
+                Ok(a) if self::is_routable(&a.ip()) && !self::is_local(&a.ip()) => {
+                    for peer in peers.iter() {
+                        if let Some(relay) = self.relays.get_mut(peer) {
+                            if relay.queue.len() < MAX_ADDR_RELAY_QUEUE && relay.remember(a) {
+                                relay.queue.push((*timestamp, addr.clone()));
+                            }
+                        }
+                    }
+                }
+                _ => continue,
+            }
+        }
+        self.schedule_relays(local_time);
+    }
+
+    /// Send queued addresses to peers whose relay timer has expired, in small batches.
+    fn flush_relays(&mut self, local_time: LocalTime) {
+        for (addr, relay) in self.relays.iter_mut() {
+            if relay.queue.is_empty() || relay.next > local_time {
+                continue;
+            }
+            let n = relay.queue.len().min(MAX_ADDR_RELAY_BATCH);
+            let batch = relay.queue.drain(..n).collect();
+
+            self.upstream.send_addresses(*addr, batch);
+            relay.next = local_time + self::relay_delay(&self.rng);
+        }
+        self.schedule_relays(local_time);
+    }
+
+    /// Make sure we wake up in time for the next pending relay.
+    fn schedule_relays(&self, local_time: LocalTime) {
+        let next = self
+            .relays
+            .values()
+            .filter(|r| !r.queue.is_empty())
+            .map(|r| r.next)
+            .min();
+
+        if let Some(next) = next {
+            if next > local_time {
+                self.upstream.set_timeout(next - local_time);
+            } else {
+                self.upstream.set_timeout(LocalDuration::from_secs(0));
+            }
+        }
+    }
 }
 
 impl<P, U> AddressManager<P, U> {
@@ -240,12 +598,69 @@ impl<P, U> AddressManager<P, U> {
     pub fn record_local_addr(&mut self, addr: net::SocketAddr) {
         self.local_addrs.insert(addr);
     }
+
+    /// Record our external IP, as reported by a remote peer in its `version` message.
+    /// Non-routable and local IPs are ignored. Only the latest report of a peer is kept.
+    pub fn record_external_ip(&mut self, ip: net::IpAddr, peer: &PeerId) {
+        if !self::is_routable(&ip) || self::is_local(&ip) {
+            return;
+        }
+        let rng = self.rng.clone();
+        let reporter = peer.ip();
+
+        for (_, peers) in self.external_ips.iter_mut().filter(|(i, _)| **i != ip) {
+            peers.remove(&reporter);
+        }
+        self.external_ips.retain(|_, peers| !peers.is_empty());
+
+        let peers = self
+            .external_ips
+            .entry(ip)
+            .or_insert_with(|| HashSet::with_hasher(rng.into()));
+        if peers.len() < MAX_EXTERNAL_IP_REPORTS {
+            peers.insert(reporter);
+        }
+
+        if self.external_ips.len() > MAX_EXTERNAL_IPS {
+            let least = self
+                .external_ips
+                .iter()
+                .filter(|(i, _)| **i != ip)
+                .min_by_key(|(i, peers)| (peers.len(), **i))
+                .map(|(i, _)| *i);
+
+            if let Some(least) = least {
+                self.external_ips.remove(&least);
+            }
+        }
+    }
+
+    /// Get our external IP, if enough peers agree on it.
+    pub fn external_ip(&self) -> Option<net::IpAddr> {
+        self.external_ips
+            .iter()
+            .filter(|(_, peers)| peers.len() >= MIN_EXTERNAL_ADDR_REPORTS)
+            .max_by_key(|(ip, peers)| (peers.len(), **ip))
+            .map(|(ip, _)| *ip)
+    }
+
+    /// Get the address we should advertise to peers, if any.
+    pub fn advertised_addr(&self) -> Option<net::SocketAddr> {
+        match self.cfg.advertise {
+            Advertise::Never => None,
+            Advertise::Discovered(port) => self.external_ip().map(|ip| (ip, port).into()),
+            Advertise::Address(addr) => Some(addr),
+        }
+    }
 }
 
 impl<P: Store, U: Events> AddressManager<P, U> {
     /// Create a new, empty address manager.
     pub fn new(cfg: Config, rng: fastrand::Rng, peers: P, upstream: U) -> Self {
-        let ips = peers.iter().map(|(ip, _)| *ip).collect::<Vec<_>>();
+        let mut ips = peers.iter().map(|(ip, _)| *ip).collect::<Vec<_>>();
+        // Populate the address ranges in a fixed order, since the store's order is arbitrary.
+        ips.sort_unstable();
+
         let mut addrmgr = Self {
             cfg,
             peers,
@@ -253,6 +668,9 @@ impl<P: Store, U: Events> AddressManager<P, U> {
             connected: HashSet::with_hasher(rng.clone().into()),
             sources: HashSet::with_hasher(rng.clone().into()),
             local_addrs: HashSet::with_hasher(rng.clone().into()),
+            external_ips: HashMap::with_hasher(rng.clone().into()),
+            relays: HashMap::with_hasher(rng.clone().into()),
+            limits: HashMap::with_hasher(rng.clone().into()),
             last_request: None,
             last_idle: None,
             upstream,
@@ -275,25 +693,68 @@ impl<P: Store, U: Events> AddressManager<P, U> {
         self.len() == 0
     }
 
+    /// Save addresses to the store. Errors are reported as events.
+    pub fn flush(&mut self) {
+        if let Err(err) = self.peers.flush() {
+            self.upstream
+                .event(Event::Error(format!("flush to disk failed: {}", err)));
+        }
+    }
+
     /// Clear the address manager of all peers.
     pub fn clear(&mut self) {
         self.peers.clear();
         self.address_ranges.clear();
     }
 
-    /// Called when we received an `addr` message from a peer.
-    pub fn received_addr(&mut self, peer: net::SocketAddr, addrs: Vec<(BlockTime, Address)>) {
-        if addrs.is_empty() {
-            // Peer misbehaving, got empty message.
-            return;
+    /// Approximate memory used by the address book, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.peers.len() * mem::size_of::<(net::IpAddr, KnownAddress)>()
+    }
+
+    /// Iterate over all known addresses, along with their connection history.
+    pub fn known(&self) -> impl Iterator<Item = &KnownAddress> {
+        self.peers.iter().map(|(_, ka)| ka)
+    }
+
+    /// Restore known addresses along with their connection history, eg. from a snapshot.
+    /// Addresses that are already known are left untouched.
+    pub fn restore(&mut self, addrs: impl Iterator<Item = KnownAddress>) {
+        for ka in addrs {
+            let ip = match ka.addr.socket_addr() {
+                Some(addr) => addr.ip(),
+                None => continue,
+            };
+            if self.peers.insert(ip, ka) {
+                self.populate_address_ranges(&ip);
+            }
         }
-        let source = Source::Peer(peer);
+    }
 
-        self.upstream.event(Event::AddressesReceived {
-            count: addrs.len(),
-            source,
-        });
-        self.insert(addrs.into_iter(), source);
+    /// Discard addresses until the address book uses at most the given number of bytes.
+    /// The addresses with the worst connection history go first. Addresses of connected
+    /// peers are kept.
+    pub fn prune(&mut self, max: usize) {
+        let excess = self
+            .peers
+            .len()
+            .saturating_sub(max / mem::size_of::<(net::IpAddr, KnownAddress)>());
+
+        if excess == 0 {
+            return;
+        }
+        let mut candidates = self
+            .peers
+            .iter()
+            .filter(|(ip, _)| !self.connected.contains(ip))
+            .map(|(ip, ka)| (self::score(ka), *ip))
+            .collect::<Vec<_>>();
+        candidates
+            .sort_by(|(a, x), (b, y)| a.partial_cmp(b).unwrap_or(Ordering::Equal).then(x.cmp(y)));
+
+        for (_, ip) in candidates.into_iter().take(excess) {
+            self.discard(&ip);
+        }
     }
 
     /// Add addresses to the address manager. The input matches that of the `addr` message
@@ -362,10 +823,10 @@ impl<P: Store, U: Events> AddressManager<P, U> {
                 continue;
             }
 
-            if !self
-                .peers
-                .insert(ip, KnownAddress::new(addr.clone(), source.clone()))
-            {
+            if !self.peers.insert(
+                ip,
+                KnownAddress::new(net_addr.into(), addr.services, source.clone()),
+            ) {
                 // Ignore addresses we already know.
                 continue;
             }
@@ -384,6 +845,10 @@ impl<P: Store, U: Events> AddressManager<P, U> {
     ///
     /// This works under the assumption that adversaries are *localized*.
     ///
+    /// Within an address range, addresses are picked based on their connection history:
+    /// addresses that have worked well in the past are favored, while untried addresses
+    /// are still picked regularly, and failing addresses are avoided.
+    ///
     /// ```
     /// use std::collections::HashMap;
     ///
@@ -425,6 +890,7 @@ impl<P: Store, U: Events> AddressManager<P, U> {
     ///
     /// for _ in 0..99 {
     ///     let (addr, _) = addrmgr.sample(ServiceFlags::NONE).unwrap();
+    ///     let addr = Address::new(&addr.socket_addr().unwrap(), ServiceFlags::NONE);
     ///
     ///     if adversary_addrs.contains(&addr) {
     ///         adversary += 1;
@@ -440,63 +906,87 @@ impl<P: Store, U: Events> AddressManager<P, U> {
     /// ```
     /// TODO: Should return an iterator.
     ///
-    pub fn sample(&self, services: ServiceFlags) -> Option<(&Address, Source)> {
+    pub fn sample(&self, services: ServiceFlags) -> Option<(&PeerAddr, Source)> {
+        self.sample_with(services, |_| true)
+    }
+
+    /// Like [`AddressManager::sample`], but only returns addresses whose IP matches the
+    /// given predicate.
+    pub fn sample_with(
+        &self,
+        services: ServiceFlags,
+        predicate: impl Fn(&net::IpAddr) -> bool,
+    ) -> Option<(&PeerAddr, Source)> {
         if self.is_empty() {
             return None;
         }
-        assert!(!self.address_ranges.is_empty());
 
-        // Keep track of the addresses we've visited, to make sure we don't
-        // loop forever.
-        let mut visited = HashSet::with_hasher(self.rng.clone().into());
+        // Visit the address ranges in random order, so that no range is favored.
+        let mut ranges = self.address_ranges.values().collect::<Vec<_>>();
+        self.rng.shuffle(&mut ranges);
 
-        while visited.len() < self.peers.len() {
-            // First select a random address range.
-            let ix = self.rng.usize(..self.address_ranges.len());
-            let range = self.address_ranges.values().nth(ix)?;
+        for range in ranges {
+            // Then select an address in that range, biased by the quality of the address.
+            let candidates = range
+                .iter()
+                .filter_map(|ip| self.peers.get(ip).map(|ka| (ip, ka)))
+                .filter(|(ip, ka)| self.is_candidate(ip, ka, services) && predicate(ip))
+                .map(|(_, ka)| ka)
+                .collect::<Vec<_>>();
 
-            assert!(!range.is_empty());
+            if let Some(ka) = self.choose(&candidates) {
+                return Some((&ka.addr, ka.source));
+            }
+        }
 
-            // Then select a random address in that range.
-            let ix = self.rng.usize(..range.len());
-            let ip = range.iter().nth(ix)?;
-            let ka = self.peers.get(&ip).expect("address must exist");
+        None
+    }
 
-            visited.insert(ip);
+    ////////////////////////////////////////////////////////////////////////////
 
-            // FIXME
-            if ka.last_attempt.is_some() {
-                continue;
-            }
-            if !ka.addr.services.has(services) {
-                match ka.source {
-                    Source::Dns => {
-                        // If we've negotiated with this peer and it hasn't signaled the
-                        // required services, we know not to return it.
-                        // The reason we check this is that DNS-sourced addresses don't include
-                        // service information, so we can only know once negotiated.
-                        if ka.last_success.is_some() {
-                            continue;
-                        }
-                    }
-                    Source::Peer(_) => {
-                        // Peer-sourced addresses come with service information. It's safe to
-                        // skip this address if it doesn't have the required services.
-                        continue;
+    /// Check whether an address can be returned by [`AddressManager::sample`].
+    fn is_candidate(&self, ip: &net::IpAddr, ka: &KnownAddress, services: ServiceFlags) -> bool {
+        // Don't return addresses we're already trying to connect to.
+        if ka.is_pending() {
+            return false;
+        }
+        if !ka.services.has(services) {
+            match ka.source {
+                Source::Dns => {
+                    // If we've negotiated with this peer and it hasn't signaled the
+                    // required services, we know not to return it.
+                    // The reason we check this is that DNS-sourced addresses don't include
+                    // service information, so we can only know once negotiated.
+                    if ka.last_success.is_some() {
+                        return false;
                     }
                 }
+                Source::Peer(_) | Source::Imported => {
+                    // Peer-sourced and imported addresses come with service information.
+                    // It's safe to skip this address if it doesn't have the required services.
+                    return false;
+                }
             }
+        }
+        !self.connected.contains(ip)
+    }
 
-            if !self.connected.contains(&ip) {
-                return Some((&ka.addr, ka.source));
+    /// Pick one of the given addresses at random, weighted by [`self::score`].
+    fn choose<'a>(&self, candidates: &[&'a KnownAddress]) -> Option<&'a KnownAddress> {
+        let total: f64 = candidates.iter().map(|ka| self::score(ka)).sum();
+        let mut r = self.rng.f64() * total;
+
+        for ka in candidates {
+            let score = self::score(ka);
+
+            if r < score {
+                return Some(*ka);
             }
+            r -= score;
         }
-
-        None
+        candidates.last().copied()
     }
 
-    ////////////////////////////////////////////////////////////////////////////
-
     /// Populate address ranges with an IP. This may remove an existing IP if
     /// its range is full. Returns the range key that was used.
     fn populate_address_ranges(&mut self, ip: &net::IpAddr) -> u8 {
@@ -511,14 +1001,11 @@ impl<P: Store, U: Events> AddressManager<P, U> {
         // before inserting this new one.
         if range.len() == MAX_RANGE_SIZE {
             let ix = self.rng.usize(..range.len());
-            let addr = range
-                .iter()
-                .cloned()
-                .nth(ix)
-                .expect("the range is not empty");
 
-            range.remove(&addr);
-            self.peers.remove(&addr);
+            if let Some(addr) = range.iter().cloned().nth(ix) {
+                range.remove(&addr);
+                self.peers.remove(&addr);
+            }
         }
         range.insert(*ip);
 
@@ -549,8 +1036,35 @@ impl<P: Store, U: Events> AddressManager<P, U> {
 }
 
 impl<P: Store, U: Events + SyncAddresses> AddressSource for AddressManager<P, U> {
-    fn sample(&self, services: ServiceFlags) -> Option<(&Address, Source)> {
-        AddressManager::sample(&self, services)
+    fn sample_with(
+        &self,
+        services: ServiceFlags,
+        predicate: impl Fn(&net::IpAddr) -> bool,
+    ) -> Option<(&PeerAddr, Source)> {
+        AddressManager::sample_with(&self, services, predicate)
+    }
+}
+
+/// Get a randomized delay until the next address relay to a peer. Randomizing the delay
+/// makes it harder to infer the origin of an address from the timing of relays.
+fn relay_delay(rng: &fastrand::Rng) -> LocalDuration {
+    LocalDuration::from_secs(rng.u64(..=ADDR_RELAY_INTERVAL.as_secs() * 2))
+}
+
+/// Score an address based on its connection history. Higher is better.
+///
+/// Addresses we haven't tried get a baseline score, so that new addresses keep being
+/// probed. Addresses we've successfully connected to are preferred, the more so the lower
+/// their latency, while addresses that keep failing are progressively avoided.
+fn score(ka: &KnownAddress) -> f64 {
+    if ka.is_failing() {
+        NEW_ADDRESS_SCORE / (1 + ka.attempts) as f64
+    } else if ka.last_success.is_some() {
+        let latency = ka.latency.map_or(0., |l| l.as_millis() as f64 / 1000.);
+
+        GOOD_ADDRESS_SCORE / (1. + latency)
+    } else {
+        NEW_ADDRESS_SCORE
     }
 }
 
@@ -574,7 +1088,7 @@ pub fn is_local(addr: &net::IpAddr) -> bool {
 
 /// Get the 8-bit key of an IP address. This key is based on the IP address's
 /// range, and is used as a key to group IP addresses by range.
-fn addr_key(ip: &net::IpAddr) -> u8 {
+pub fn addr_key(ip: &net::IpAddr) -> u8 {
     match ip {
         net::IpAddr::V4(ip) => {
             // Use the /16 range (first two components) of the IP address to key into the
@@ -679,6 +1193,145 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sample_quality() {
+        let time = LocalTime::from_secs(1);
+        let good = net::SocketAddr::from(([111, 8, 0, 1], 8333));
+        let bad = net::SocketAddr::from(([111, 8, 0, 2], 8333));
+        let pending = net::SocketAddr::from(([111, 8, 0, 3], 8333));
+
+        let mut addrmgr =
+            AddressManager::new(Config::default(), fastrand::Rng::new(), HashMap::new(), ());
+
+        addrmgr.insert(
+            vec![good, bad, pending]
+                .into_iter()
+                .map(|a| (BlockTime::default(), Address::new(&a, ServiceFlags::NONE))),
+            Source::Dns,
+        );
+
+        {
+            let ka = addrmgr.peers.get_mut(&good.ip()).unwrap();
+            ka.last_attempt = Some(time);
+            ka.last_success = Some(time);
+            ka.attempts = 1;
+        }
+        {
+            let ka = addrmgr.peers.get_mut(&bad.ip()).unwrap();
+            ka.last_attempt = Some(time);
+            ka.last_failure = Some(time);
+            ka.attempts = 3;
+        }
+        {
+            let ka = addrmgr.peers.get_mut(&pending.ip()).unwrap();
+            ka.last_attempt = Some(time);
+            ka.attempts = 1;
+        }
+
+        let mut picked = HashMap::new();
+        for _ in 0..99 {
+            let (addr, _) = addrmgr.sample(ServiceFlags::NONE).unwrap();
+            *picked.entry(addr.socket_addr().unwrap()).or_insert(0) += 1;
+        }
+        let good = picked.get(&good).copied().unwrap_or(0);
+        let bad = picked.get(&bad).copied().unwrap_or(0);
+
+        assert!(
+            good > bad * 2,
+            "addresses with a good history are preferred"
+        );
+        assert!(
+            !picked.contains_key(&pending),
+            "addresses with a pending attempt are never picked"
+        );
+    }
+
+    #[test]
+    fn test_external_ip() {
+        let external = net::IpAddr::from([183, 8, 55, 2]);
+        let cfg = Config {
+            advertise: Advertise::Discovered(8333),
+            ..Config::default()
+        };
+        let mut addrmgr = AddressManager::new(cfg, fastrand::Rng::new(), HashMap::new(), ());
+
+        addrmgr.record_external_ip(
+            net::IpAddr::from([192, 168, 1, 2]),
+            &([88, 13, 16, 59], 8333).into(),
+        );
+        addrmgr.record_external_ip(external, &([88, 13, 16, 59], 8333).into());
+        addrmgr.record_external_ip(external, &([88, 13, 16, 59], 18333).into());
+        assert_eq!(
+            addrmgr.external_ip(),
+            None,
+            "a single peer is not enough to determine our external IP"
+        );
+
+        addrmgr.record_external_ip(external, &([99, 45, 180, 58], 8333).into());
+        assert_eq!(addrmgr.external_ip(), Some(external));
+        assert_eq!(addrmgr.advertised_addr(), Some((external, 8333).into()));
+
+        addrmgr.cfg.advertise = Advertise::Never;
+        assert_eq!(addrmgr.advertised_addr(), None);
+
+        // Only the latest report of a peer counts, and the number of tracked IPs is capped.
+        for i in 0..MAX_EXTERNAL_IPS as u8 * 2 {
+            addrmgr.record_external_ip([183, 8, 56, i].into(), &([99, 45, 180, 58], 8333).into());
+        }
+        assert_eq!(addrmgr.external_ips.len(), 2);
+        assert_eq!(addrmgr.external_ip(), None);
+
+        for i in 0..MAX_EXTERNAL_IPS as u8 * 2 {
+            addrmgr.record_external_ip([183, 8, 57, i].into(), &([77, 1, 1, i], 8333).into());
+        }
+        assert!(addrmgr.external_ips.len() <= MAX_EXTERNAL_IPS);
+
+        let addr = net::SocketAddr::from(([211, 48, 99, 4], 8334));
+        addrmgr.cfg.advertise = Advertise::Address(addr);
+        assert_eq!(addrmgr.advertised_addr(), Some(addr));
+    }
+
+    #[test]
+    fn test_relay_known() {
+        let mut relay = Relay {
+            queue: Vec::new(),
+            known: HashSet::with_hasher(fastrand::Rng::new().into()),
+            known_order: VecDeque::new(),
+            next: LocalTime::default(),
+        };
+        let addr = |i: usize| net::SocketAddr::from(([88, 13, (i / 256) as u8, i as u8], 8333));
+
+        for i in 0..MAX_ADDR_RELAY_KNOWN {
+            assert!(relay.remember(addr(i)));
+        }
+        assert!(!relay.remember(addr(0)));
+
+        // Only the oldest address is forgotten when the limit is exceeded.
+        assert!(relay.remember(addr(MAX_ADDR_RELAY_KNOWN)));
+        assert!(!relay.remember(addr(MAX_ADDR_RELAY_KNOWN - 1)));
+        assert!(relay.remember(addr(0)));
+        assert_eq!(relay.known.len(), MAX_ADDR_RELAY_KNOWN);
+    }
+
+    #[test]
+    fn test_addr_limit() {
+        let time = LocalTime::from_secs(1_000_000);
+        let mut limit = AddrLimit::new(time);
+
+        // Peers can answer our `getaddr` in full.
+        assert_eq!(limit.take(MAX_ADDR_ADDRESSES, time), MAX_ADDR_ADDRESSES);
+        assert_eq!(limit.take(1, time), 0);
+
+        // After that, addresses are processed at a limited rate.
+        let time = time + LocalDuration::from_secs(100);
+        assert_eq!(limit.take(100, time), 10);
+        assert_eq!(limit.take(100, time), 0);
+
+        // The bucket never holds more than a full message.
+        let time = time + LocalDuration::from_mins(60 * 24);
+        assert_eq!(limit.take(MAX_ADDR_ADDRESSES + 1, time), MAX_ADDR_ADDRESSES);
+    }
+
     #[test]
     fn test_addr_key() {
         assert_eq!(
@@ -5,6 +5,7 @@
 //! communicate with the main protocol and network.
 use log::*;
 use std::net;
+use std::sync::{Arc, Mutex};
 
 use crossbeam_channel as chan;
 
@@ -18,8 +19,9 @@ use nakamoto_common::block::time::LocalDuration;
 use nakamoto_common::block::tree::ImportResult;
 use nakamoto_common::block::{BlockHash, BlockHeader, BlockTime, Height};
 
-use crate::protocol::{DisconnectReason, Event, Out, PeerId};
+use crate::protocol::{DisconnectReason, Event, Out, PeerId, Whitelist};
 
+use super::interceptor::{Context, Interceptor, Verdict};
 use super::network::Network;
 use super::{addrmgr, connmgr, message, peermgr, pingmgr, spvmgr, syncmgr, Link, Locators};
 
@@ -34,6 +36,13 @@ pub struct Channel {
     builder: message::Builder,
     /// Log target.
     target: &'static str,
+    /// Message interceptor, if any.
+    interceptor: Option<Arc<dyn Interceptor>>,
+    /// Peer whitelist, passed on to the interceptor.
+    whitelist: Whitelist,
+    /// Messages held back to be coalesced, by peer, if coalescing. Shared between clones,
+    /// since each sub-protocol has its own. See [`Channel::coalesce`].
+    coalescing: Arc<Mutex<Option<Vec<(PeerId, NetworkMessage)>>>>,
 }
 
 impl Channel {
@@ -49,28 +58,127 @@ impl Channel {
             outbound,
             builder: message::Builder::new(network),
             target,
+            interceptor: None,
+            whitelist: Whitelist::default(),
+            coalescing: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Pass all outgoing messages through the given interceptor.
+    pub fn with_interceptor(
+        mut self,
+        interceptor: Option<Arc<dyn Interceptor>>,
+        whitelist: Whitelist,
+    ) -> Self {
+        self.interceptor = interceptor;
+        self.whitelist = whitelist;
+        self
+    }
+
     /// Push an output to the channel.
     pub fn push(&self, output: Out) {
         self.outbound.send(output).unwrap();
     }
 
     /// Push a message to the channel.
-    pub fn message(&self, addr: PeerId, message: NetworkMessage) -> &Self {
+    pub fn message(&self, addr: PeerId, mut message: NetworkMessage) -> &Self {
+        if let Some(interceptor) = &self.interceptor {
+            let peer = Context::new(addr, &self.whitelist);
+
+            if interceptor.sending(&peer, &mut message) == Verdict::Drop {
+                debug!(
+                    "{}: Dropping {:?}: vetoed by interceptor",
+                    addr,
+                    message.cmd()
+                );
+                return self;
+            }
+        }
         debug!("{}: Sending {:?}", addr, message.cmd());
 
+        let mut coalescing = self.coalescing.lock().unwrap();
+
+        if let Some(pending) = coalescing.as_mut() {
+            // Messages to a peer are sent in order, so a held back message is either merged
+            // with the next message, or sent before it.
+            if let Some(ix) = pending.iter().position(|(a, _)| *a == addr) {
+                match self::merge(&mut pending[ix].1, message) {
+                    Ok(()) => return self,
+                    Err(unmerged) => {
+                        let (_, held) = pending.remove(ix);
+
+                        self.push(self.builder.message(addr, held));
+                        message = unmerged;
+                    }
+                }
+            }
+            if matches!(message, NetworkMessage::Inv(_) | NetworkMessage::Addr(_)) {
+                pending.push((addr, message));
+
+                return self;
+            }
+        }
+        drop(coalescing);
+
         self.push(self.builder.message(addr, message));
         self
     }
 
+    /// Start coalescing outbound messages. Consecutive `inv` or `addr` messages to the same
+    /// peer are merged into one message, as long as it's within the protocol limits. Messages
+    /// are held back until another kind of output for the same peer is pushed, or until
+    /// [`Channel::flush`] is called.
+    pub fn coalesce(&self) {
+        self.coalescing.lock().unwrap().get_or_insert_with(Vec::new);
+    }
+
+    /// Send the messages held back, and stop coalescing.
+    pub fn flush(&self) {
+        let pending = self.coalescing.lock().unwrap().take();
+
+        for (addr, msg) in pending.into_iter().flatten() {
+            self.push(self.builder.message(addr, msg));
+        }
+    }
+
+    /// Send the messages held back for the given peer, if any.
+    fn release(&self, addr: &PeerId) {
+        let mut coalescing = self.coalescing.lock().unwrap();
+
+        if let Some(pending) = coalescing.as_mut() {
+            if let Some(ix) = pending.iter().position(|(a, _)| a == addr) {
+                let (_, held) = pending.remove(ix);
+
+                self.push(self.builder.message(*addr, held));
+            }
+        }
+    }
+
     /// Push an event to the channel.
     pub fn event(&self, event: Event) {
         self.push(Out::Event(event));
     }
 }
 
+/// Merge a message into a message of the same kind. Returns the message if it can't be
+/// merged.
+fn merge(held: &mut NetworkMessage, msg: NetworkMessage) -> Result<(), NetworkMessage> {
+    match (held, msg) {
+        (NetworkMessage::Inv(held), NetworkMessage::Inv(inv))
+            if held.len() + inv.len() <= syncmgr::MAX_MESSAGE_INVS =>
+        {
+            held.extend(inv);
+        }
+        (NetworkMessage::Addr(held), NetworkMessage::Addr(addrs))
+            if held.len() + addrs.len() <= addrmgr::MAX_ADDR_ADDRESSES =>
+        {
+            held.extend(addrs);
+        }
+        (_, msg) => return Err(msg),
+    }
+    Ok(())
+}
+
 /// Ability to disconnect from peers.
 pub trait Disconnect {
     /// Disconnect from peer.
@@ -79,6 +187,7 @@ pub trait Disconnect {
 
 impl Disconnect for Channel {
     fn disconnect(&self, addr: net::SocketAddr, reason: DisconnectReason) {
+        self.release(&addr);
         self.push(Out::Disconnect(addr, reason));
     }
 }
@@ -174,9 +283,7 @@ impl syncmgr::SyncHeaders for Channel {
     }
 
     fn send_headers(&self, addr: PeerId, headers: Vec<BlockHeader>) {
-        let msg = self.builder.message(addr, NetworkMessage::Headers(headers));
-
-        self.push(msg);
+        self.message(addr, NetworkMessage::Headers(headers));
     }
 
     fn negotiate(&self, addr: PeerId) {
@@ -232,7 +339,7 @@ impl spvmgr::SyncFilters for Channel {
     }
 
     fn send_cfheaders(&self, addr: PeerId, headers: CFHeaders) {
-        todo!()
+        self.message(addr, NetworkMessage::CFHeaders(headers));
     }
 
     fn get_cfilters(
@@ -253,7 +360,7 @@ impl spvmgr::SyncFilters for Channel {
     }
 
     fn send_cfilter(&self, addr: PeerId, cfilter: CFilter) {
-        todo!()
+        self.message(addr, NetworkMessage::CFilter(cfilter));
     }
 }
 
@@ -1,25 +1,50 @@
 //! Peer connection manager.
 
-use std::collections::{HashMap, HashSet};
 use std::net;
 
 use bitcoin::network::constants::ServiceFlags;
 
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
-use nakamoto_common::p2p::peer::{self, AddressSource, Source};
+use nakamoto_common::collections::{HashMap, HashSet};
+use nakamoto_common::p2p::netgroup::{AsMap, NetGroup};
+use nakamoto_common::p2p::peer::{self, AddressSource, Ban, Source};
 
+use super::addrmgr;
 use super::channel::{Disconnect, SetTimeout};
-use crate::protocol::{DisconnectReason, Link, PeerId, Timeout};
+use crate::protocol::{DisconnectReason, Link, PeerId, Timeout, Whitelist};
 
 /// Time to wait for a new connection.
 /// TODO: Should be in config.
 pub const CONNECTION_TIMEOUT: LocalDuration = LocalDuration::from_secs(3);
 /// Time to wait until idle.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
+/// Time after which a peer we haven't received any message from is disconnected.
+/// Negotiated peers are pinged regularly, so they only become inactive if they stop
+/// responding altogether.
+pub const INACTIVITY_TIMEOUT: LocalDuration = LocalDuration::from_mins(20);
 /// Target number of concurrent outbound peer connections.
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 /// Maximum number of inbound peer connections.
 pub const MAX_INBOUND_PEERS: usize = 16;
+/// Target number of concurrent outbound block-relay-only peer connections.
+pub const BLOCK_RELAY_PEERS: usize = 2;
+/// Minimum number of outbound peers offering compact filters.
+pub const FILTER_PEERS: usize = 2;
+/// Maximum number of outbound peers picked from the address book in the same network
+/// group. See [`NetGroup`].
+pub const MAX_OUTBOUND_PER_NETGROUP: usize = 1;
+/// Maximum number of anchor peers to connect to on startup.
+pub const MAX_ANCHORS: usize = 2;
+
+/// Maximum number of addresses picked from the address book each time we try to
+/// maintain our outbound connections.
+const MAX_CONNECT_ATTEMPTS: usize = 16;
+/// Number of inbound peers from distinct address ranges protected from eviction.
+const EVICTION_PROTECT_RANGE: usize = 4;
+/// Number of lowest-latency inbound peers protected from eviction.
+const EVICTION_PROTECT_LATENCY: usize = 8;
+/// Number of most recently active inbound peers protected from eviction.
+const EVICTION_PROTECT_ACTIVE: usize = 4;
 
 /// Ability to connect to peers.
 pub trait Connect {
@@ -42,10 +67,16 @@ pub enum Event {
     /// This event is triggered *after* the peer handshake
     /// has successfully completed.
     Connected(PeerId, Link),
+    /// An outbound block-relay-only peer has negotiated. Such peers make good anchors.
+    BlockRelayNegotiated(PeerId),
     /// A peer has been disconnected.
-    Disconnected(PeerId),
+    Disconnected(PeerId, DisconnectReason),
     /// Address book exhausted when trying to connect.
     AddressBookExhausted,
+    /// A peer address was banned.
+    Banned(net::IpAddr, Ban),
+    /// A peer address ban was lifted, or expired.
+    Unbanned(net::IpAddr),
 }
 
 impl std::fmt::Display for Event {
@@ -55,14 +86,33 @@ impl std::fmt::Display for Event {
                 write!(fmt, "Connecting to peer {} from source `{}`", addr, source)
             }
             Event::Connected(addr, link) => write!(fmt, "{}: Peer connected ({:?})", &addr, link),
-            Event::Disconnected(addr) => write!(fmt, "Disconnected from {}", &addr),
+            Event::BlockRelayNegotiated(addr) => {
+                write!(fmt, "{}: Block-relay peer negotiated", &addr)
+            }
+            Event::Disconnected(addr, reason) => {
+                write!(fmt, "Disconnected from {} ({})", &addr, reason)
+            }
             Event::AddressBookExhausted => {
                 write!(fmt, "Address book exhausted when attempting to connect..")
             }
+            Event::Banned(ip, ban) => {
+                write!(fmt, "Banned {} until {} ({})", ip, ban.until, ban.reason)
+            }
+            Event::Unbanned(ip) => write!(fmt, "Ban on {} was lifted", ip),
         }
     }
 }
 
+/// Periodic outbound peer rotation. Rotating peers makes it harder for any single peer
+/// to link our requests together over long periods of time.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    /// How often to rotate peers.
+    pub interval: LocalDuration,
+    /// Maximum number of outbound peers to rotate at each interval.
+    pub count: usize,
+}
+
 /// Connection manager configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -70,13 +120,35 @@ pub struct Config {
     pub target_outbound_peers: usize,
     /// Maximum number of inbound peer connections.
     pub max_inbound_peers: usize,
+    /// Target number of outbound block-relay-only peer connections. These connections
+    /// are in addition to the regular outbound connections, and are not used for
+    /// address exchange, which makes them harder to discover for an attacker.
+    pub block_relay_peers: usize,
+    /// Minimum number of outbound peers offering compact filters to stay connected to.
+    /// If our regular outbound peers don't offer enough of them, additional connections
+    /// are made. Should be zero if filter sync is disabled.
+    pub filter_peers: usize,
     /// Peer addresses that should always be retried.
     pub retry: Vec<net::SocketAddr>,
+    /// Only connect to the peers in the retry list, and don't accept inbound connections.
+    pub connect_only: bool,
+    /// Outbound peer rotation. Disabled if `None`.
+    pub rotation: Option<Rotation>,
+    /// Anchor peers, ie. block-relay peers from a previous session. These are connected
+    /// to first on startup, but aren't retried if the connection fails.
+    pub anchors: Vec<net::SocketAddr>,
+    /// Banned peer addresses.
+    pub bans: Vec<(net::IpAddr, Ban)>,
     /// Peer services required.
     pub required_services: ServiceFlags,
     /// Peer services preferred. We try to maintain as many
     /// connections to peers with these services.
     pub preferred_services: ServiceFlags,
+    /// Mapping of IP ranges to AS numbers, used to group outbound peers by network
+    /// operator. If not set, peers are grouped by address range.
+    pub asmap: Option<AsMap>,
+    /// Trusted peers. These are never banned or evicted.
+    pub whitelist: Whitelist,
 }
 
 /// A connected peer.
@@ -92,6 +164,10 @@ struct Peer {
     services: ServiceFlags,
     /// Time connected.
     time: LocalTime,
+    /// Last time we received a message from this peer.
+    last_active: LocalTime,
+    /// Last measured round-trip latency of this peer.
+    latency: Option<LocalDuration>,
 }
 
 /// Manages peer connections.
@@ -101,35 +177,78 @@ pub struct ConnectionManager<U> {
     pub config: Config,
     /// Set of outbound peers being connected to.
     connecting: HashSet<PeerId>,
+    /// Set of outbound block-relay-only peers, connected or being connected to.
+    block_relay: HashSet<PeerId>,
+    /// Set of outbound peers connected or being connected to for their compact filters,
+    /// and which haven't yet negotiated.
+    filter: HashSet<PeerId>,
+    /// Peers added manually, which are always re-dialed when disconnected.
+    persistent: HashSet<PeerId>,
     /// Set of all connected peers.
     connected: HashMap<PeerId, Peer>,
+    /// Set of connected peers we're disconnecting from.
+    disconnecting: HashSet<PeerId>,
     /// Set of disconnected peers.
     disconnected: HashSet<PeerId>,
+    /// Banned peer addresses.
+    banned: HashMap<net::IpAddr, Ban>,
+    /// Last known local time.
+    local_time: LocalTime,
     /// Last time we were idle.
     last_idle: Option<LocalTime>,
+    /// Last time we rotated our outbound peers.
+    last_rotation: Option<LocalTime>,
+    /// Random number generator.
+    rng: fastrand::Rng,
     /// Channel to the network.
     upstream: U,
 }
 
 impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
     /// Create a new connection manager.
-    pub fn new(upstream: U, config: Config) -> Self {
+    pub fn new(upstream: U, config: Config, rng: fastrand::Rng) -> Self {
+        let mut banned = HashMap::with_hasher(rng.clone().into());
+        banned.extend(
+            config
+                .bans
+                .iter()
+                .filter(|(ip, _)| !config.whitelist.contains_addr(ip))
+                .cloned(),
+        );
+
         Self {
-            connecting: HashSet::new(),
-            connected: HashMap::new(),
-            disconnected: HashSet::new(),
+            connecting: HashSet::with_hasher(rng.clone().into()),
+            block_relay: HashSet::with_hasher(rng.clone().into()),
+            filter: HashSet::with_hasher(rng.clone().into()),
+            persistent: HashSet::with_hasher(rng.clone().into()),
+            connected: HashMap::with_hasher(rng.clone().into()),
+            disconnecting: HashSet::with_hasher(rng.clone().into()),
+            disconnected: HashSet::with_hasher(rng.clone().into()),
+            banned,
+            local_time: LocalTime::default(),
             last_idle: None,
+            last_rotation: None,
+            rng,
             config,
             upstream,
         }
     }
 
     /// Initialize the connection manager. Must be called once.
-    pub fn initialize<S: peer::Store, A: AddressSource>(
-        &mut self,
-        _time: LocalTime,
-        addrs: &mut A,
-    ) {
+    pub fn initialize<S: peer::Store, A: AddressSource>(&mut self, time: LocalTime, addrs: &mut A) {
+        self.local_time = time;
+
+        if let Some(rotation) = self.config.rotation {
+            self.last_rotation = Some(time);
+            self.upstream.set_timeout(rotation.interval);
+        }
+        if self.config.connect_only {
+            self.upstream.set_timeout(IDLE_TIMEOUT);
+            self.maintain_connections::<S, A>(addrs);
+
+            return;
+        }
+
         let retry = self
             .config
             .retry
@@ -141,30 +260,118 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
         for addr in retry {
             self.connect::<S, A>(&addr);
         }
+
+        // Connect to our anchors first, before picking addresses from the address book.
+        // This makes it harder for an attacker to eclipse us by waiting for a restart.
+        // Since anchors were block-relay peers in the previous session, they are
+        // reconnected to as such.
+        let anchors = self
+            .config
+            .anchors
+            .iter()
+            .take(MAX_ANCHORS.min(self.config.block_relay_peers))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for addr in anchors {
+            if self.connect::<S, A>(&addr) {
+                self.block_relay.insert(addr);
+            }
+        }
         self.upstream.set_timeout(IDLE_TIMEOUT);
         self.maintain_connections::<S, A>(addrs);
     }
 
-    /// Connect to a peer.
+    /// Connect to a peer. Returns `false` if we're already connected or connecting to this
+    /// peer, or if the peer is banned.
     pub fn connect<S: peer::Store, A: AddressSource>(&mut self, addr: &PeerId) -> bool {
         if self.connected.contains_key(&addr) || self.connecting.contains(addr) {
             return false;
         }
+        if self.is_banned(&addr.ip(), self.local_time) {
+            return false;
+        }
         self.connecting.insert(*addr);
         self.upstream.connect(*addr, CONNECTION_TIMEOUT);
 
         true
     }
 
+    /// Add a persistent peer. Persistent peers are connected to regardless of our outbound
+    /// peer target, and are re-dialed when disconnected, until removed.
+    pub fn add_persistent<S: peer::Store, A: AddressSource>(&mut self, addr: PeerId) {
+        self.persistent.insert(addr);
+        self.connect::<S, A>(&addr);
+    }
+
+    /// Remove a persistent peer, and disconnect from it. Returns `false` if the peer
+    /// wasn't added as a persistent peer.
+    pub fn remove_persistent(&mut self, addr: &PeerId) -> bool {
+        if self.persistent.remove(addr) {
+            self.disconnect(*addr, DisconnectReason::Command);
+            return true;
+        }
+        false
+    }
+
+    /// Returns persistent peer addresses.
+    pub fn persistent_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.persistent.iter()
+    }
+
+    /// Check whether the given peer is an outbound block-relay-only peer.
+    pub fn is_block_relay(&self, addr: &PeerId) -> bool {
+        self.block_relay.contains(addr)
+    }
+
     /// Disconnect from a peer.
     pub fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         if self.connected.contains_key(&addr) {
             debug_assert!(!self.disconnected.contains(&addr));
 
+            self.disconnecting.insert(addr);
             self.upstream.disconnect(addr, reason);
         }
     }
 
+    /// Ban a peer address. Disconnects any peer connected from that address. Returns `false`
+    /// if the address is whitelisted, in which case it isn't banned.
+    pub fn ban(&mut self, ip: net::IpAddr, ban: Ban) -> bool {
+        if self.config.whitelist.contains_addr(&ip) {
+            return false;
+        }
+        for addr in self.connected.keys().filter(|a| a.ip() == ip) {
+            self.disconnecting.insert(*addr);
+            self.upstream
+                .disconnect(*addr, DisconnectReason::PeerBanned);
+        }
+        self.banned.insert(ip, ban.clone());
+        self.upstream.event(Event::Banned(ip, ban));
+
+        true
+    }
+
+    /// Lift a ban on a peer address. Returns `true` if the address was banned.
+    pub fn unban(&mut self, ip: &net::IpAddr) -> bool {
+        if self.banned.remove(ip).is_some() {
+            self.upstream.event(Event::Unbanned(*ip));
+            return true;
+        }
+        false
+    }
+
+    /// Check whether a peer address is banned at the given time.
+    pub fn is_banned(&self, ip: &net::IpAddr, now: LocalTime) -> bool {
+        self.banned
+            .get(ip)
+            .map_or(false, |ban| !ban.is_expired(now))
+    }
+
+    /// Returns banned peer addresses.
+    pub fn bans(&self) -> impl Iterator<Item = (&net::IpAddr, &Ban)> {
+        self.banned.iter()
+    }
+
     /// Call when a peer connected.
     pub fn peer_connected(
         &mut self,
@@ -175,51 +382,105 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
     ) {
         debug_assert!(!self.connected.contains_key(&address));
 
+        self.local_time = time;
         Events::event(&self.upstream, Event::Connected(address, link));
 
-        match link {
-            Link::Inbound if self.inbound_peers().count() >= self.config.max_inbound_peers => {
-                // Don't allow inbound connections beyond the configured limit.
-                self.upstream
-                    .disconnect(address, DisconnectReason::ConnectionLimit);
+        let rejected = match link {
+            // Don't allow inbound connections from banned peers.
+            Link::Inbound if self.is_banned(&address.ip(), time) => {
+                Some(DisconnectReason::PeerBanned)
             }
-            _ => {
-                self.disconnected.remove(&address);
-                self.connecting.remove(&address);
-                self.connected.insert(
-                    address,
-                    Peer {
-                        address,
-                        local_address,
-                        services: ServiceFlags::NONE,
-                        link,
-                        time,
-                    },
-                );
+            // Inbound connections are not allowed in connect-only mode.
+            Link::Inbound if self.config.connect_only => Some(DisconnectReason::ConnectionLimit),
+            // Don't allow inbound connections beyond the configured limit, unless the peer
+            // is whitelisted, or we were able to make room for it.
+            Link::Inbound
+                if self.inbound().count() >= self.config.max_inbound_peers
+                    && !self.config.whitelist.contains_addr(&address.ip())
+                    && !self.evict() =>
+            {
+                Some(DisconnectReason::ConnectionLimit)
             }
+            _ => None,
+        };
+
+        self.disconnected.remove(&address);
+        self.connecting.remove(&address);
+        self.connected.insert(
+            address,
+            Peer {
+                address,
+                local_address,
+                services: ServiceFlags::NONE,
+                link,
+                time,
+                last_active: time,
+                latency: None,
+            },
+        );
+
+        // Rejected peers are tracked until they are disconnected, but aren't counted
+        // towards our inbound peers.
+        if let Some(reason) = rejected {
+            self.disconnect(address, reason);
         }
     }
 
+    /// Check whether we're disconnecting from the given peer.
+    pub fn is_disconnecting(&self, addr: &PeerId) -> bool {
+        self.disconnecting.contains(addr)
+    }
+
     /// Call when a peer negotiated.
     pub fn peer_negotiated(&mut self, address: net::SocketAddr, services: ServiceFlags) {
-        let peer = self.connected.get_mut(&address).expect(
-            "ConnectionManager::peer_negotiated: negotiated peers should be connected first",
-        );
+        // The peer may have been disconnected in the meantime.
+        let peer = if let Some(peer) = self.connected.get_mut(&address) {
+            peer
+        } else {
+            return;
+        };
         peer.services = services;
+
+        // From now on, this peer is counted by the services it signals.
+        self.filter.remove(&address);
+
+        if self.block_relay.contains(&address) {
+            self.upstream.event(Event::BlockRelayNegotiated(address));
+        }
+    }
+
+    /// Call when a message was received from a peer.
+    pub fn peer_active(&mut self, address: &net::SocketAddr, time: LocalTime) {
+        self.local_time = time;
+
+        if let Some(peer) = self.connected.get_mut(address) {
+            peer.last_active = time;
+        }
+    }
+
+    /// Call when the round-trip latency of a peer was measured.
+    pub fn peer_latency(&mut self, address: &net::SocketAddr, latency: LocalDuration) {
+        if let Some(peer) = self.connected.get_mut(address) {
+            peer.latency = Some(latency);
+        }
     }
 
     /// Call when a peer disconnected.
     pub fn peer_disconnected<S: peer::Store, A: AddressSource>(
         &mut self,
         addr: &net::SocketAddr,
+        reason: DisconnectReason,
         addrs: &A,
     ) {
         debug_assert!(self.connected.contains_key(&addr));
         debug_assert!(!self.disconnected.contains(&addr));
 
-        Events::event(&self.upstream, Event::Disconnected(*addr));
+        Events::event(&self.upstream, Event::Disconnected(*addr, reason));
 
         self.disconnected.insert(*addr);
+        self.disconnecting.remove(addr);
+        self.block_relay.remove(addr);
+        self.filter.remove(addr);
 
         if let Some(peer) = self.connected.remove(&addr) {
             // If an outbound peer disconnected, we should make sure to maintain
@@ -238,13 +499,56 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
         local_time: LocalTime,
         addrs: &A,
     ) {
+        self.local_time = local_time;
+
+        let expired = self
+            .banned
+            .iter()
+            .filter(|(_, ban)| ban.is_expired(local_time))
+            .map(|(ip, _)| *ip)
+            .collect::<Vec<_>>();
+
+        for ip in expired {
+            self.unban(&ip);
+        }
+
+        if let Some(rotation) = self.config.rotation {
+            if local_time - self.last_rotation.unwrap_or_default() >= rotation.interval {
+                self.rotate(rotation, local_time);
+                self.upstream.set_timeout(rotation.interval);
+                self.last_rotation = Some(local_time);
+            }
+        }
+
         if local_time - self.last_idle.unwrap_or_default() >= IDLE_TIMEOUT {
+            self.disconnect_inactive(local_time);
+
+            // Re-dial persistent peers we aren't connected to. This is only done on idle,
+            // to avoid re-dialing peers that are unreachable in a tight loop.
+            for addr in self.persistent.iter().cloned().collect::<Vec<_>>() {
+                self.connect::<S, A>(&addr);
+            }
             self.maintain_connections::<S, A>(addrs);
             self.upstream.set_timeout(IDLE_TIMEOUT);
             self.last_idle = Some(local_time);
         }
     }
 
+    /// Disconnect peers we haven't received a message from in a while.
+    fn disconnect_inactive(&mut self, local_time: LocalTime) {
+        let inactive = self
+            .connected
+            .values()
+            .filter(|p| !self.disconnecting.contains(&p.address))
+            .filter(|p| local_time - p.last_active >= INACTIVITY_TIMEOUT)
+            .map(|p| p.address)
+            .collect::<Vec<_>>();
+
+        for addr in inactive {
+            self.disconnect(addr, DisconnectReason::PeerTimeout);
+        }
+    }
+
     /// Returns outbound peer addresses.
     pub fn outbound_peers(&self) -> impl Iterator<Item = &PeerId> {
         self.connected
@@ -261,24 +565,115 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
             .map(|(addr, _)| addr)
     }
 
+    /// Returns the number of outbound full-relay and block-relay-only peers, including
+    /// the ones we're connecting to.
+    fn outbound_count(&self) -> (usize, usize) {
+        let total = self.outbound().count() + self.connecting.len();
+        let block_relay = self.block_relay.len();
+
+        (total.saturating_sub(block_relay), block_relay)
+    }
+
+    /// Returns the number of outbound full-relay peers offering compact filters, including
+    /// the ones we're connecting to for that reason.
+    fn filter_count(&self) -> usize {
+        let negotiated = self
+            .outbound()
+            .filter(|p| !self.block_relay.contains(&p.address))
+            .filter(|p| p.services.has(ServiceFlags::COMPACT_FILTERS))
+            .count();
+
+        negotiated + self.filter.len()
+    }
+
+    /// Returns the network group of the given address.
+    pub fn netgroup(&self, addr: &net::SocketAddr) -> NetGroup {
+        NetGroup::of(&addr.ip(), self.config.asmap.as_ref())
+    }
+
+    /// Returns the number of outbound peers in each network group, including the ones
+    /// we're connecting to.
+    fn outbound_netgroups(&self) -> HashMap<NetGroup, usize> {
+        let mut netgroups = HashMap::with_hasher(self.rng.clone().into());
+
+        for addr in self.outbound_peers().chain(self.connecting.iter()) {
+            *netgroups.entry(self.netgroup(addr)).or_insert(0) += 1;
+        }
+        netgroups
+    }
+
     /// Attempt to maintain a certain number of outbound peers.
+    /// Full-relay peers take priority over block-relay-only peers. If not enough of our
+    /// full-relay peers offer compact filters, we connect to additional peers that do.
+    ///
+    /// In connect-only mode, we instead try to stay connected to all the configured peers,
+    /// and never pick addresses from the address book.
     fn maintain_connections<S: peer::Store, A: AddressSource>(&mut self, addrs: &A) {
-        while self.outbound().count() + self.connecting.len() < self.config.target_outbound_peers {
-            // Prefer addresses with the preferred services.
-            let result = addrs
-                .sample(self.config.preferred_services)
-                .or_else(|| addrs.sample(self.config.required_services));
+        if self.config.connect_only {
+            for addr in self.config.retry.clone() {
+                self.connect::<S, A>(&addr);
+            }
+            return;
+        }
+
+        let filter_services = self.config.required_services | ServiceFlags::COMPACT_FILTERS;
+
+        // Addresses we fail to connect to are skipped, up to a maximum number of attempts,
+        // so that a few bad addresses don't leave our outbound slots unfilled.
+        for _ in 0..MAX_CONNECT_ATTEMPTS {
+            // Limit the number of outbound peers in any one network group, so that
+            // a single network operator can't easily control all our connections.
+            let netgroups = self.outbound_netgroups();
+            let asmap = self.config.asmap.as_ref();
+            let diverse = |ip: &net::IpAddr| {
+                netgroups
+                    .get(&NetGroup::of(ip, asmap))
+                    .map_or(true, |n| *n < MAX_OUTBOUND_PER_NETGROUP)
+                    && !self.is_banned(ip, self.local_time)
+                    && !self.connecting.iter().any(|a| a.ip() == *ip)
+            };
+
+            let (full_relay, block_relay) = self.outbound_count();
+            let filters_needed = self.filter_count() < self.config.filter_peers;
+            let (is_block_relay, is_filter) = if full_relay < self.config.target_outbound_peers {
+                (false, filters_needed)
+            } else if filters_needed
+                && full_relay < self.config.target_outbound_peers + self.config.filter_peers
+            {
+                (false, true)
+            } else if block_relay < self.config.block_relay_peers {
+                (true, false)
+            } else {
+                break;
+            };
+            let result = if is_filter && full_relay >= self.config.target_outbound_peers {
+                // Additional connections are only made to peers offering compact filters.
+                addrs.sample_with(filter_services, &diverse)
+            } else if is_filter {
+                addrs
+                    .sample_with(filter_services, &diverse)
+                    .or_else(|| addrs.sample_with(self.config.required_services, &diverse))
+            } else {
+                // Prefer addresses with the preferred services.
+                addrs
+                    .sample_with(self.config.preferred_services, &diverse)
+                    .or_else(|| addrs.sample_with(self.config.required_services, &diverse))
+            };
 
             if let Some((addr, source)) = result {
                 // TODO: Support Tor?
-                if let Ok(sockaddr) = addr.socket_addr() {
+                if let Some(sockaddr) = addr.socket_addr() {
                     // TODO: Remove this assertion once address manager no longer cares about
                     // connections.
                     debug_assert!(!self.connected.contains_key(&sockaddr));
 
                     if self.connect::<S, A>(&sockaddr) {
+                        if is_block_relay {
+                            self.block_relay.insert(sockaddr);
+                        } else if is_filter {
+                            self.filter.insert(sockaddr);
+                        }
                         self.upstream.event(Event::Connecting(sockaddr, source));
-                        break;
                     }
                 }
             } else {
@@ -290,8 +685,197 @@ impl<U: Connect + Disconnect + Events + SetTimeout> ConnectionManager<U> {
         }
     }
 
+    /// Disconnect our oldest outbound peers, so that they are replaced with fresh ones.
+    ///
+    /// Only full-relay peers that have been connected for at least the rotation interval
+    /// are rotated. Block-relay peers are kept, since they protect us against eclipse
+    /// attacks, and are not used for requests that could be linked to us. Persistent and
+    /// whitelisted peers are never rotated.
+    fn rotate(&mut self, rotation: Rotation, local_time: LocalTime) {
+        if self.config.connect_only {
+            return;
+        }
+        let mut peers = self
+            .outbound()
+            .filter(|p| !self.disconnecting.contains(&p.address))
+            .filter(|p| !self.block_relay.contains(&p.address))
+            .filter(|p| !self.persistent.contains(&p.address))
+            .filter(|p| !self.config.whitelist.contains_addr(&p.address.ip()))
+            .filter(|p| local_time - p.time >= rotation.interval)
+            .map(|p| (p.time, p.address))
+            .collect::<Vec<_>>();
+
+        peers.sort();
+
+        for (_, addr) in peers.into_iter().take(rotation.count) {
+            self.disconnect(addr, DisconnectReason::PeerRotated);
+        }
+    }
+
+    /// Evict an inbound peer to make room for a new one. Returns `true` if a peer was
+    /// evicted.
+    fn evict(&mut self) -> bool {
+        if let Some(addr) = self.eviction_candidate() {
+            self.disconnecting.insert(addr);
+            self.upstream
+                .disconnect(addr, DisconnectReason::ConnectionLimit);
+
+            return true;
+        }
+        false
+    }
+
+    /// Select an inbound peer to evict, if any.
+    ///
+    /// Similar to Bitcoin Core, we first protect peers that are hard for an attacker to
+    /// imitate: peers in rare address ranges, peers with low latency, recently active
+    /// peers, and long-lived connections. Out of the remaining peers, we evict the
+    /// youngest connection in the most represented address range. Whitelisted peers,
+    /// and peers we're already disconnecting from, are never evicted.
+    fn eviction_candidate(&self) -> Option<PeerId> {
+        let mut candidates = self
+            .inbound()
+            .filter(|p| !self.config.whitelist.contains_addr(&p.address.ip()))
+            .collect::<Vec<_>>();
+        let mut ranges = HashMap::with_hasher(self.rng.clone().into());
+
+        for peer in &candidates {
+            *ranges
+                .entry(addrmgr::addr_key(&peer.address.ip()))
+                .or_insert(0) += 1;
+        }
+        let range_size = |p: &Peer| ranges[&addrmgr::addr_key(&p.address.ip())];
+
+        self::protect(&mut candidates, EVICTION_PROTECT_RANGE, |p| {
+            (range_size(p), p.time)
+        });
+        self::protect(&mut candidates, EVICTION_PROTECT_LATENCY, |p| {
+            p.latency.unwrap_or(LocalDuration::from_millis(u128::MAX))
+        });
+        self::protect(&mut candidates, EVICTION_PROTECT_ACTIVE, |p| {
+            std::cmp::Reverse(p.last_active)
+        });
+        let half = candidates.len() / 2;
+        self::protect(&mut candidates, half, |p| p.time);
+
+        candidates
+            .into_iter()
+            .max_by_key(|p| (range_size(*p), p.time))
+            .map(|p| p.address)
+    }
+
+    /// Get inbound peers, excluding the ones we're disconnecting from.
+    fn inbound(&self) -> impl Iterator<Item = &Peer> + Clone {
+        self.connected
+            .values()
+            .filter(move |p| p.link.is_inbound() && !self.disconnecting.contains(&p.address))
+    }
+
     /// Get outbound peers.
     fn outbound(&self) -> impl Iterator<Item = &Peer> + Clone {
         self.connected.values().filter(|p| p.link.is_outbound())
     }
 }
+
+/// Remove the `n` best peers from the list of eviction candidates, according to the given
+/// key, where lower is better.
+fn protect<K: Ord>(candidates: &mut Vec<&Peer>, n: usize, key: impl Fn(&Peer) -> K) {
+    candidates.sort_by_key(|p| key(*p));
+    candidates.drain(..n.min(candidates.len()));
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel as chan;
+
+    use nakamoto_common::network::Network;
+
+    use crate::protocol::channel::Channel;
+    use crate::protocol::{Out, PROTOCOL_VERSION};
+
+    use super::*;
+
+    fn connmgr(network: Network) -> (ConnectionManager<Channel>, chan::Receiver<Out>) {
+        let (sender, receiver) = chan::unbounded();
+        let connmgr = ConnectionManager::new(
+            Channel::new(network, PROTOCOL_VERSION, "test", sender),
+            Config {
+                target_outbound_peers: TARGET_OUTBOUND_PEERS,
+                max_inbound_peers: MAX_INBOUND_PEERS,
+                block_relay_peers: BLOCK_RELAY_PEERS,
+                filter_peers: FILTER_PEERS,
+                retry: vec![],
+                connect_only: false,
+                rotation: None,
+                anchors: vec![],
+                bans: vec![],
+                required_services: ServiceFlags::NETWORK,
+                preferred_services: ServiceFlags::COMPACT_FILTERS,
+                asmap: None,
+                whitelist: Whitelist::default(),
+            },
+            fastrand::Rng::new(),
+        );
+        (connmgr, receiver)
+    }
+
+    #[test]
+    fn test_disconnect_inactive() {
+        let (mut connmgr, rx) = self::connmgr(Network::Mainnet);
+        let local_addr: PeerId = ([152, 168, 3, 33], 8333).into();
+        let alice: PeerId = ([88, 13, 16, 59], 8333).into();
+        let bob: PeerId = ([99, 45, 180, 58], 8333).into();
+        let time = LocalTime::from_secs(1_600_000_000);
+        let disconnected = |rx: &chan::Receiver<Out>| {
+            rx.try_iter()
+                .filter_map(|o| match o {
+                    Out::Disconnect(addr, DisconnectReason::PeerTimeout) => Some(addr),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        connmgr.peer_connected(alice, local_addr, Link::Outbound, time);
+        connmgr.peer_connected(bob, local_addr, Link::Inbound, time);
+        connmgr.peer_active(&bob, time + LocalDuration::from_mins(10));
+
+        connmgr.disconnect_inactive(time + INACTIVITY_TIMEOUT - LocalDuration::from_secs(1));
+        assert!(disconnected(&rx).is_empty());
+
+        connmgr.disconnect_inactive(time + INACTIVITY_TIMEOUT);
+        assert_eq!(
+            disconnected(&rx),
+            vec![alice],
+            "only alice has been inactive"
+        );
+        assert!(connmgr.is_disconnecting(&alice));
+
+        // Peers we're already disconnecting from aren't disconnected again.
+        connmgr.disconnect_inactive(time + INACTIVITY_TIMEOUT + LocalDuration::from_secs(1));
+        assert!(disconnected(&rx).is_empty());
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let (mut connmgr, rx) = self::connmgr(Network::Mainnet);
+        let local_addr: PeerId = ([152, 168, 3, 33], 8333).into();
+        let alice: PeerId = ([88, 13, 16, 59], 8333).into();
+        let bob: PeerId = ([99, 45, 180, 58], 8333).into();
+        let time = LocalTime::from_secs(1_600_000_000);
+
+        connmgr.config.whitelist = Whitelist::new(vec![alice.ip()]);
+        connmgr.config.max_inbound_peers = 1;
+        connmgr.peer_connected(alice, local_addr, Link::Inbound, time);
+
+        assert!(!connmgr.ban(alice.ip(), Ban::new("test", time + IDLE_TIMEOUT)));
+        assert!(!connmgr.is_banned(&alice.ip(), time));
+
+        // Bob can't take Alice's place, since she is whitelisted.
+        connmgr.peer_connected(bob, local_addr, Link::Inbound, time);
+        assert!(rx.try_iter().all(|o| !matches!(
+            o,
+            Out::Disconnect(addr, _) if addr == alice
+        )));
+        assert!(!connmgr.connected.contains_key(&bob));
+    }
+}
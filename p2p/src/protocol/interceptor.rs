@@ -0,0 +1,72 @@
+//! Message interception.
+//!
+//! An [`Interceptor`] sees every message received from and sent to peers, and may
+//! observe, modify or drop them, eg. to enforce a custom relay policy, or to record
+//! traffic for research purposes. Received messages the protocol doesn't handle are
+//! not decoded, and are therefore not intercepted.
+//!
+//! ```
+//! use nakamoto_p2p::bitcoin::network::message::NetworkMessage;
+//! use nakamoto_p2p::protocol::interceptor::{Context, Interceptor, Verdict};
+//!
+//! /// Ignores address messages from untrusted peers.
+//! #[derive(Debug)]
+//! struct Untrusting;
+//!
+//! impl Interceptor for Untrusting {
+//!     fn received(&self, peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+//!         match msg {
+//!             NetworkMessage::Addr(_) if !peer.whitelisted => Verdict::Drop,
+//!             _ => Verdict::Accept,
+//!         }
+//!     }
+//! }
+//! ```
+use std::fmt;
+
+use bitcoin::network::message::NetworkMessage;
+
+use super::{PeerId, Whitelist};
+
+/// What to do with an intercepted message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Let the message through, possibly modified.
+    Accept,
+    /// Drop the message.
+    Drop,
+}
+
+/// The peer an intercepted message is received from or sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Context {
+    /// Peer address.
+    pub addr: PeerId,
+    /// Whether the peer address is whitelisted.
+    pub whitelisted: bool,
+}
+
+impl Context {
+    /// Create the context of the given peer.
+    pub fn new(addr: PeerId, whitelist: &Whitelist) -> Self {
+        Self {
+            addr,
+            whitelisted: whitelist.contains_addr(&addr.ip()),
+        }
+    }
+}
+
+/// Intercepts peer messages. By default, all messages are accepted unchanged.
+pub trait Interceptor: fmt::Debug + Send + Sync {
+    /// Called with every message received from a peer, before it is processed.
+    /// Dropped messages are ignored.
+    fn received(&self, _peer: &Context, _msg: &mut NetworkMessage) -> Verdict {
+        Verdict::Accept
+    }
+
+    /// Called with every message about to be sent to a peer. Dropped messages
+    /// are not sent.
+    fn sending(&self, _peer: &Context, _msg: &mut NetworkMessage) -> Verdict {
+        Verdict::Accept
+    }
+}
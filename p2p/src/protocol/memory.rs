@@ -0,0 +1,42 @@
+//! Memory accounting.
+//!
+//! Keeps an approximate count of the memory used by the protocol's caches, so that
+//! embedders with little memory, eg. on mobile, can monitor and bound the footprint of
+//! the client. Sizes are estimated from the number of entries and their in-memory size,
+//! and don't include allocator or hash table overhead.
+//!
+//! Only caches that can be trimmed without affecting correctness are capped: orphan and
+//! stale headers can be fetched again from peers, and addresses can be re-discovered. The
+//! active header chain and filter header chain are only reported. Caps are checked
+//! periodically, so caches may briefly grow over them.
+
+/// Approximate memory used by the protocol's caches, in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryInfo {
+    /// Headers of the active chain, and their index.
+    pub headers: usize,
+    /// Orphan and stale headers.
+    pub orphans: usize,
+    /// Address book.
+    pub addresses: usize,
+    /// Filter headers.
+    pub filters: usize,
+}
+
+impl MemoryInfo {
+    /// Total memory used, in bytes.
+    pub fn total(&self) -> usize {
+        self.headers + self.orphans + self.addresses + self.filters
+    }
+}
+
+/// Memory caps, in bytes. Caches are trimmed back under their cap when they exceed it.
+/// Caches without a cap are unbounded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Cap on orphan and stale headers. The oldest headers are discarded first.
+    pub orphans: Option<usize>,
+    /// Cap on the address book. The addresses with the worst connection history are
+    /// discarded first.
+    pub addresses: Option<usize>,
+}
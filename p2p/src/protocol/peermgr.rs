@@ -14,10 +14,14 @@
 //!   3. Send `verack` message.
 //!   4. Expect `verack` message from remote.
 //!
+//! Any other ordering of messages, or messages other than `version` and `verack` before
+//! the handshake is done, are protocol violations.
+//!
 use std::net;
 
 use bitcoin::network::address::Address;
 use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message_network::VersionMessage;
 
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
@@ -55,6 +59,10 @@ pub enum Event {
     PeerNegotiated {
         /// The peer's id.
         addr: PeerId,
+        /// Link direction of the peer connection.
+        link: Link,
+        /// Services offered by the peer.
+        services: ServiceFlags,
     },
 }
 
@@ -66,7 +74,7 @@ impl std::fmt::Display for Event {
                 "{}: Peer version = {}, height = {}, agent = {}, services = {}, timestamp = {}",
                 addr, msg.version, msg.start_height, msg.user_agent, msg.services, msg.timestamp
             ),
-            Self::PeerNegotiated { addr } => write!(fmt, "{}: Peer negotiated..", addr),
+            Self::PeerNegotiated { addr, .. } => write!(fmt, "{}: Peer negotiated..", addr),
         }
     }
 }
@@ -97,7 +105,10 @@ pub struct Config {
     /// Services required by peers.
     pub required_services: ServiceFlags,
     /// Our user agent.
-    pub user_agent: &'static str,
+    pub user_agent: String,
+    /// Our external address, sent to peers in our `version` message. If not set, an
+    /// unroutable address is sent instead, so that we don't leak our local address.
+    pub external_addr: Option<net::SocketAddr>,
 }
 
 /// Peer states.
@@ -126,6 +137,25 @@ pub struct Connection {
     pub since: LocalTime,
 }
 
+/// Information about a negotiated peer, as returned to users of the protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Remote peer address.
+    pub addr: net::SocketAddr,
+    /// Whether this is an inbound or outbound peer connection.
+    pub link: Link,
+    /// Peer user agent string.
+    pub user_agent: String,
+    /// The peer's services.
+    pub services: ServiceFlags,
+    /// The peer's best height.
+    pub height: Height,
+    /// Smoothed round-trip latency, if measured.
+    pub latency: Option<LocalDuration>,
+    /// Connected since this time.
+    pub since: LocalTime,
+}
+
 /// A peer with connection and protocol information.
 #[derive(Debug)]
 pub struct Peer {
@@ -143,8 +173,10 @@ pub struct Peer {
     pub time_offset: TimeOffset,
     /// Whether this peer relays transactions.
     pub relay: bool,
+    /// Smoothed round-trip latency, if measured.
+    pub latency: Option<LocalDuration>,
 
-    /// Peer nonce. Used to detect self-connections.
+    /// Peer nonce. Used to detect duplicate connections.
     nonce: u64,
     /// Peer state.
     state: PeerState,
@@ -160,6 +192,19 @@ impl Peer {
     pub fn is_negotiated(&self) -> bool {
         matches!(self.state, PeerState::Negotiated { .. })
     }
+
+    /// Get information about this peer.
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            addr: self.conn.addr,
+            link: self.conn.link,
+            user_agent: self.user_agent.clone(),
+            services: self.services,
+            height: self.height,
+            latency: self.latency,
+            since: self.conn.since,
+        }
+    }
 }
 
 /// Manages peers and peer negotiation.
@@ -169,6 +214,9 @@ pub struct PeerManager<U> {
     connections: HashMap<net::SocketAddr, Connection>,
     peers: HashMap<PeerId, Peer>,
     upstream: U,
+    /// Nonces sent in our `version` messages to outbound peers, which are fresh for each
+    /// connection. Used to detect self-connections.
+    nonces: HashMap<PeerId, u64>,
     rng: fastrand::Rng,
 }
 
@@ -177,12 +225,14 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
     pub fn new(config: Config, rng: fastrand::Rng, upstream: U) -> Self {
         let connections = HashMap::with_hasher(rng.clone().into());
         let peers = HashMap::with_hasher(rng.clone().into());
+        let nonces = HashMap::with_hasher(rng.clone().into());
 
         Self {
             config,
             connections,
             peers,
             upstream,
+            nonces,
             rng,
         }
     }
@@ -192,6 +242,30 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
         self.connections.contains_key(addr) || self.peers.contains_key(addr)
     }
 
+    /// Check that the given message is expected from the peer, given the state of its
+    /// handshake. If it isn't, returns the reason it's a protocol violation.
+    pub fn check_handshake(&self, addr: &PeerId, msg: &NetworkMessage) -> Result<(), &'static str> {
+        if self.connections.contains_key(addr) {
+            return match msg {
+                NetworkMessage::Version(_) => Ok(()),
+                NetworkMessage::Verack => Err("`verack` received before `version`"),
+                _ => Err("message received before `version`"),
+            };
+        }
+        if let Some(peer) = self.peers.get(addr) {
+            return match (msg, peer.state) {
+                (NetworkMessage::Version(_), _) => Err("duplicate `version` received"),
+                (NetworkMessage::Verack, PeerState::AwaitingVerack { .. }) => Ok(()),
+                (NetworkMessage::Verack, PeerState::Negotiated { .. }) => {
+                    Err("duplicate `verack` received")
+                }
+                (_, PeerState::AwaitingVerack { .. }) => Err("message received before `verack`"),
+                (_, PeerState::Negotiated { .. }) => Ok(()),
+            };
+        }
+        Ok(())
+    }
+
     /// Iterator over outbound, negotiated peers.
     pub fn outbound(&self) -> impl Iterator<Item = &Peer> + Clone {
         self.peers
@@ -226,11 +300,11 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
         match link {
             Link::Inbound => { /* Wait for their version message.. */ }
             Link::Outbound => {
-                let nonce = self.rng.u64(..);
-                self.upstream.version(
-                    addr,
-                    self.version(addr, local_addr, nonce, height, local_time),
-                );
+                let nonce = self.nonce();
+
+                self.nonces.insert(addr, nonce);
+                self.upstream
+                    .version(addr, self.version(addr, nonce, height, local_time));
             }
         }
         // Set a timeout for receiving the `version` message.
@@ -241,6 +315,14 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
     pub fn peer_disconnected(&mut self, addr: &net::SocketAddr) {
         self.peers.remove(&addr);
         self.connections.remove(&addr);
+        self.nonces.remove(&addr);
+    }
+
+    /// Called when the round-trip latency of a peer was measured.
+    pub fn peer_latency(&mut self, addr: &PeerId, latency: LocalDuration) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.latency = Some(latency);
+        }
     }
 
     /// Called when a `version` message was received.
@@ -310,19 +392,45 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                     .upstream
                     .disconnect(*addr, DisconnectReason::PeerHeight(start_height as Height));
             }
-            // Check for self-connections. We only need to check one link direction,
+            // Check for self-connections, ie. an inbound connection sending us the nonce
+            // of one of our outbound connections. We only need to check one link direction,
             // since in the case of a self-connection, we will see both link directions.
-            for (_, peer) in self.peers.iter() {
-                if conn.link.is_outbound() && peer.nonce == nonce {
+            if conn.link.is_inbound() && self.nonces.values().any(|n| *n == nonce) {
+                return self
+                    .upstream
+                    .disconnect(*addr, DisconnectReason::SelfConnection);
+            }
+            // Check for duplicate connections to the same node, eg. when we dial a peer
+            // that is also dialing us, using the remote's nonce. This works with nodes
+            // that send the same nonce on all their connections. Only one connection is
+            // kept: if both are in the same direction, the existing one, otherwise the
+            // outbound one, since we chose to make it.
+            //
+            // Some implementations always send a zero nonce, which can't be used to tell
+            // nodes apart, eg. behind a NAT, so those peers are never considered duplicates.
+            if let Some(other) = self
+                .peers
+                .values()
+                .find(|p| nonce != 0 && p.nonce == nonce && p.conn.addr.ip() == addr.ip())
+            {
+                if other.conn.link == conn.link || other.conn.link.is_outbound() {
                     return self
                         .upstream
-                        .disconnect(*addr, DisconnectReason::SelfConnection);
+                        .disconnect(*addr, DisconnectReason::DuplicateConnection);
                 }
+                self.upstream
+                    .disconnect(other.address(), DisconnectReason::DuplicateConnection);
             }
 
             // Record the address this peer has of us.
-            if let Ok(addr) = receiver.socket_addr() {
-                addrs.record_local_addr(addr);
+            if let Ok(local_addr) = receiver.socket_addr() {
+                addrs.record_local_addr(local_addr);
+
+                // Only outbound peers are trusted to tell us our external IP, since
+                // inbound connections are trivial for an attacker to make.
+                if conn.link.is_outbound() {
+                    addrs.record_external_ip(local_addr.ip(), addr);
+                }
             }
 
             match conn.link {
@@ -335,7 +443,7 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                     self.upstream
                         .version(
                             conn.addr,
-                            self.version(conn.addr, conn.local_addr, nonce, height, now),
+                            self.version(conn.addr, self.nonce(), height, now),
                         )
                         .verack(conn.addr)
                         .set_timeout(HANDSHAKE_TIMEOUT);
@@ -353,25 +461,27 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
                     user_agent,
                     state: PeerState::AwaitingVerack { since: now },
                     relay,
+                    latency: None,
                 },
             );
         }
     }
 
     /// Called when a `verack` message was received.
+    ///
+    /// *Nb. The message should be checked with [`PeerManager::check_handshake`] first.*
     pub fn received_verack(&mut self, addr: &PeerId, local_time: LocalTime) -> Option<&Peer> {
         if let Some(peer) = self.peers.get_mut(addr) {
             if let PeerState::AwaitingVerack { .. } = peer.state {
-                self.upstream.event(Event::PeerNegotiated { addr: *addr });
+                self.upstream.event(Event::PeerNegotiated {
+                    addr: *addr,
+                    link: peer.conn.link,
+                    services: peer.services,
+                });
 
                 peer.state = PeerState::Negotiated { since: local_time };
 
                 return Some(peer);
-            } else {
-                self.upstream.disconnect(
-                    *addr,
-                    DisconnectReason::PeerMisbehaving("unexpected `verack` message received"),
-                );
             }
         }
         None
@@ -403,17 +513,26 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
         }
     }
 
+    /// Generate a nonce for a `version` message. Zero is reserved for peers that don't
+    /// use nonces, see [`PeerManager::received_version`].
+    fn nonce(&self) -> u64 {
+        self.rng.u64(1..)
+    }
+
     /// Create a `version` message for this peer.
     pub fn version(
         &self,
         addr: net::SocketAddr,
-        local_addr: net::SocketAddr,
         nonce: u64,
         start_height: Height,
         local_time: LocalTime,
     ) -> VersionMessage {
         let start_height = start_height as i32;
         let timestamp = local_time.block_time() as i64;
+        let sender = self
+            .config
+            .external_addr
+            .unwrap_or_else(|| ([0, 0, 0, 0], 0).into());
 
         VersionMessage {
             // Our max supported protocol version.
@@ -424,12 +543,13 @@ impl<U: Handshake + SetTimeout + Disconnect + Events> PeerManager<U> {
             timestamp,
             // Receiver address and services, as perceived by us.
             receiver: Address::new(&addr, ServiceFlags::NONE),
-            // Local address (unreliable) and local services (same as `services` field)
-            sender: Address::new(&local_addr, self.config.services),
+            // Our external address, if advertised, and local services (same as `services`
+            // field).
+            sender: Address::new(&sender, self.config.services),
             // A nonce to detect connections to self.
             nonce,
             // Our user agent string.
-            user_agent: self.config.user_agent.to_owned(),
+            user_agent: self.config.user_agent.clone(),
             // Our best height.
             start_height,
             // Whether we want to receive transaction `inv` messages.
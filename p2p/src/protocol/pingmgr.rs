@@ -40,8 +40,7 @@ struct Peer {
 }
 
 impl Peer {
-    /// Calculate the average latency of this peer.
-    #[allow(dead_code)]
+    /// Calculate the smoothed latency of this peer, ie. the average of the recorded latencies.
     fn latency(&self) -> LocalDuration {
         let sum: LocalDuration = self.latencies.iter().sum();
 
@@ -125,7 +124,22 @@ impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
         self.upstream.pong(addr, nonce);
     }
 
-    pub fn received_pong(&mut self, addr: PeerId, nonce: u64, now: LocalTime) {
+    /// Get the smoothed round-trip latency of a peer, if it was measured.
+    pub fn latency(&self, addr: &PeerId) -> Option<LocalDuration> {
+        self.peers
+            .get(addr)
+            .filter(|p| !p.latencies.is_empty())
+            .map(|p| p.latency())
+    }
+
+    /// Called when a `pong` is received. Returns the peer's smoothed round-trip latency,
+    /// if the `pong` was expected.
+    pub fn received_pong(
+        &mut self,
+        addr: PeerId,
+        nonce: u64,
+        now: LocalTime,
+    ) -> Option<LocalDuration> {
         if let Some(peer) = self.peers.get_mut(&addr) {
             match peer.state {
                 State::AwaitingPong {
@@ -133,13 +147,69 @@ impl<U: Ping + SetTimeout + Disconnect> PingManager<U> {
                     since,
                 } => {
                     if nonce == last_nonce {
-                        peer.record_latency(now - since);
+                        let latency = now - since;
+
+                        peer.record_latency(latency);
                         peer.state = State::Idle { since: now };
+
+                        return Some(peer.latency());
                     }
                 }
                 // Unsolicited or redundant `pong`. Ignore.
                 State::Idle { .. } => {}
             }
         }
+        None
+    }
+}
+
+/// Pick a random candidate among the fastest half of the given candidates, by latency.
+/// Candidates whose latency hasn't been measured yet are only picked if no latency
+/// is known for any of the candidates.
+pub fn pick_low_latency<T>(
+    mut candidates: Vec<(T, Option<LocalDuration>)>,
+    rng: &fastrand::Rng,
+) -> Option<T> {
+    if candidates.is_empty() {
+        return None;
+    }
+    candidates.sort_by_key(|(_, latency)| (latency.is_none(), *latency));
+
+    let measured = candidates.iter().filter(|(_, l)| l.is_some()).count();
+    let n = if measured > 0 {
+        (measured + 1) / 2
+    } else {
+        candidates.len()
+    };
+    let ix = rng.usize(..n);
+
+    Some(candidates.swap_remove(ix).0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_low_latency() {
+        let rng = fastrand::Rng::with_seed(1);
+        let candidates = vec![
+            ('a', Some(LocalDuration::from_millis(300))),
+            ('b', None),
+            ('c', Some(LocalDuration::from_millis(20))),
+            ('d', Some(LocalDuration::from_millis(800))),
+            ('e', Some(LocalDuration::from_millis(45))),
+        ];
+
+        for _ in 0..32 {
+            let picked = pick_low_latency(candidates.clone(), &rng).unwrap();
+            assert!(picked == 'c' || picked == 'e', "{}", picked);
+        }
+        assert_eq!(
+            pick_low_latency(vec![('b', None)], &rng),
+            Some('b'),
+            "unmeasured candidates are picked when nothing else is available"
+        );
+        assert_eq!(pick_low_latency::<char>(vec![], &rng), None);
     }
 }
@@ -0,0 +1,390 @@
+//! Protocol state snapshots.
+//!
+//! A [`Snapshot`] captures the state of a protocol that isn't already persisted by the
+//! block and filter stores: the negotiated peers, the address book with its connection
+//! history, and the bans. Sync progress is recorded as the tips of the header and filter
+//! header chains, which are restored from the stores themselves.
+//!
+//! Snapshots serve two purposes:
+//!
+//! * Fast test setup. Instead of going through the handshake and initial sync of each
+//!   peer, a protocol can be put in an "already synced with N peers" state by restoring
+//!   a snapshot on top of a synced block tree.
+//! * Warm restarts. A snapshot saved on shutdown can be restored on startup, keeping the
+//!   address book and bans. Since connections don't survive a restart, the peers of the
+//!   snapshot should be passed as anchors instead, with [`Snapshot::anchors`].
+//!
+//! Restored peers are re-negotiated by feeding the protocol the inputs of a handshake, as
+//! if the peers had just connected, so every sub-protocol tracks them as it normally
+//! would. Outputs are sent upstream as usual, including handshake messages, which should
+//! be discarded by the caller.
+//!
+//! ```ignore
+//! let snapshot = alice.snapshot();
+//! snapshot.save("alice.json")?;
+//!
+//! let mut bob = builder.build(tx);
+//! bob.initialize(time);
+//! bob.restore(&Snapshot::load("alice.json")?, time);
+//! ```
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net;
+use std::path::Path;
+
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+use bitcoin::network::message_network::VersionMessage;
+use log::*;
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::{LocalTime, TimeOffset};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, Height};
+use nakamoto_common::p2p::peer::{self, Ban, KnownAddress};
+
+use super::{message, Input, Link, Protocol, PROTOCOL_VERSION};
+
+/// A negotiated peer, as recorded in a snapshot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    /// Remote peer address.
+    pub addr: net::SocketAddr,
+    /// Local address of the connection.
+    pub local_addr: net::SocketAddr,
+    /// Whether this is an inbound or outbound peer connection.
+    pub link: Link,
+    /// The peer's best height.
+    pub height: Height,
+    /// The peer's services.
+    pub services: ServiceFlags,
+    /// Peer user agent string.
+    pub user_agent: String,
+    /// Whether this peer relays transactions.
+    pub relay: bool,
+    /// Offset in seconds between this peer's clock and ours.
+    pub time_offset: TimeOffset,
+}
+
+/// A snapshot of the protocol state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Local time at which the snapshot was taken.
+    pub time: LocalTime,
+    /// Height of the active chain.
+    pub height: Height,
+    /// Tip of the active chain.
+    pub tip: BlockHash,
+    /// Height of the filter header chain.
+    pub filter_height: Height,
+    /// Negotiated peers.
+    pub peers: Vec<Peer>,
+    /// Address book, with connection history.
+    pub addresses: Vec<KnownAddress>,
+    /// Banned addresses.
+    pub bans: Vec<(net::IpAddr, Ban)>,
+}
+
+impl Snapshot {
+    /// Addresses of the outbound peers, to reconnect to first on a warm restart.
+    pub fn anchors(&self) -> Vec<net::SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|p| p.link.is_outbound())
+            .map(|p| p.addr)
+            .collect()
+    }
+
+    /// Save the snapshot to a file, as JSON. An existing file is overwritten.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+
+        file.write_all(json::to_string(&self.to_json()).as_bytes())?;
+        file.sync_all()
+    }
+
+    /// Load a snapshot saved with [`Snapshot::save`].
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut s = String::new();
+        fs::File::open(path)?.read_to_string(&mut s)?;
+
+        let val = json::from_str(&s).map_err(|_| invalid_data("invalid JSON"))?;
+
+        Self::from_json(val)
+    }
+
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> Value {
+        let mut obj = Object::new();
+
+        obj.insert("time".to_owned(), number(self.time.as_millis() as u64));
+        obj.insert("height".to_owned(), number(self.height));
+        obj.insert("tip".to_owned(), string(&self.tip));
+        obj.insert("filter_height".to_owned(), number(self.filter_height));
+        obj.insert(
+            "peers".to_owned(),
+            Value::Array(self.peers.iter().map(Peer::to_json).collect()),
+        );
+        obj.insert(
+            "addresses".to_owned(),
+            Value::Array(self.addresses.iter().map(KnownAddress::to_json).collect()),
+        );
+        obj.insert(
+            "bans".to_owned(),
+            Value::Array(
+                self.bans
+                    .iter()
+                    .map(|(ip, ban)| {
+                        let mut obj = Object::new();
+
+                        obj.insert("ip".to_owned(), string(ip));
+                        obj.insert("ban".to_owned(), ban.to_json());
+
+                        Value::Object(obj)
+                    })
+                    .collect(),
+            ),
+        );
+        Value::Object(obj)
+    }
+
+    /// Convert from a JSON value.
+    pub fn from_json(val: Value) -> io::Result<Self> {
+        let fields = Fields::from(val)?;
+
+        let peers = fields
+            .array("peers")?
+            .iter()
+            .cloned()
+            .map(Peer::from_json)
+            .collect::<io::Result<_>>()?;
+        let addresses = fields
+            .array("addresses")?
+            .iter()
+            .cloned()
+            .map(|v| KnownAddress::from_json(v).map_err(|_| invalid_data("invalid address")))
+            .collect::<io::Result<_>>()?;
+        let bans = fields
+            .array("bans")?
+            .iter()
+            .cloned()
+            .map(|v| {
+                let fields = Fields::from(v)?;
+                let ban = match fields.0.get("ban") {
+                    Some(v) => {
+                        Ban::from_json(v.clone()).map_err(|_| invalid_data("invalid ban"))?
+                    }
+                    None => return Err(invalid_data("missing field \"ban\"")),
+                };
+                Ok((fields.parse("ip")?, ban))
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            time: LocalTime::from_millis(fields.u64("time")? as u128),
+            height: fields.u64("height")?,
+            tip: fields.parse("tip")?,
+            filter_height: fields.u64("filter_height")?,
+            peers,
+            addresses,
+            bans,
+        })
+    }
+}
+
+impl Peer {
+    fn to_json(&self) -> Value {
+        let mut obj = Object::new();
+
+        obj.insert("addr".to_owned(), string(&self.addr));
+        obj.insert("local_addr".to_owned(), string(&self.local_addr));
+        obj.insert(
+            "link".to_owned(),
+            string(match self.link {
+                Link::Inbound => "inbound",
+                Link::Outbound => "outbound",
+            }),
+        );
+        obj.insert("height".to_owned(), number(self.height));
+        obj.insert("services".to_owned(), number(self.services.as_u64()));
+        obj.insert("user_agent".to_owned(), string(&self.user_agent));
+        obj.insert("relay".to_owned(), Value::Bool(self.relay));
+        obj.insert(
+            "time_offset".to_owned(),
+            Value::Number(Number::I64(self.time_offset)),
+        );
+
+        Value::Object(obj)
+    }
+
+    fn from_json(val: Value) -> io::Result<Self> {
+        let fields = Fields::from(val)?;
+
+        Ok(Self {
+            addr: fields.parse("addr")?,
+            local_addr: fields.parse("local_addr")?,
+            link: match fields.str("link")? {
+                "inbound" => Link::Inbound,
+                "outbound" => Link::Outbound,
+                other => return Err(invalid_data(format!("unknown link {:?}", other))),
+            },
+            height: fields.u64("height")?,
+            services: ServiceFlags::from(fields.u64("services")?),
+            user_agent: fields.str("user_agent")?.to_owned(),
+            relay: match fields.0.get("relay") {
+                Some(Value::Bool(b)) => *b,
+                _ => return Err(invalid_data("missing boolean field \"relay\"")),
+            },
+            time_offset: match fields.0.get("time_offset") {
+                Some(Value::Number(Number::I64(n))) => *n,
+                Some(Value::Number(Number::U64(n))) => *n as TimeOffset,
+                _ => return Err(invalid_data("missing number field \"time_offset\"")),
+            },
+        })
+    }
+}
+
+impl<T: BlockTree, F: Filters, P: peer::Store> Protocol<T, F, P> {
+    /// Take a snapshot of the protocol state.
+    pub fn snapshot(&self) -> Snapshot {
+        let (tip, _) = self.tree.tip();
+
+        Snapshot {
+            time: self.clock.local_time(),
+            height: self.tree.height(),
+            tip,
+            filter_height: self.spvmgr.height(),
+            peers: self
+                .peermgr
+                .peers()
+                .filter(|p| p.is_negotiated())
+                .map(|p| Peer {
+                    addr: p.conn.addr,
+                    local_addr: p.conn.local_addr,
+                    link: p.conn.link,
+                    height: p.height,
+                    services: p.services,
+                    user_agent: p.user_agent.clone(),
+                    relay: p.relay,
+                    time_offset: p.time_offset,
+                })
+                .collect(),
+            addresses: self.addrmgr.known().cloned().collect(),
+            bans: self
+                .connmgr
+                .bans()
+                .map(|(ip, ban)| (*ip, ban.clone()))
+                .collect(),
+        }
+    }
+
+    /// Restore a snapshot taken with [`Protocol::snapshot`]. Should be called after
+    /// [`Protocol::initialize`], with a block tree and filter store that are at least as
+    /// far along as the snapshot.
+    ///
+    /// The peers of the snapshot are negotiated again, and are expected to be connected.
+    pub fn restore(&mut self, snapshot: &Snapshot, local_time: LocalTime) {
+        let (tip, _) = self.tree.tip();
+
+        if tip != snapshot.tip {
+            warn!(
+                target: self.target,
+                "Restoring snapshot taken at tip {} ({}), but our tip is {} ({})",
+                snapshot.tip,
+                snapshot.height,
+                tip,
+                self.tree.height()
+            );
+        }
+        for (ip, ban) in &snapshot.bans {
+            self.connmgr.ban(*ip, ban.clone());
+        }
+        self.addrmgr.restore(snapshot.addresses.iter().cloned());
+
+        let msg = message::Builder::new(self.network);
+
+        for peer in &snapshot.peers {
+            let version = VersionMessage {
+                version: PROTOCOL_VERSION,
+                services: peer.services,
+                timestamp: local_time.block_time() as i64 + peer.time_offset,
+                receiver: Address::new(&peer.local_addr, ServiceFlags::NONE),
+                sender: Address::new(&peer.addr, peer.services),
+                // Nonces are only used to detect self and duplicate connections.
+                nonce: self.rng.u64(1..),
+                user_agent: peer.user_agent.clone(),
+                start_height: peer.height as i32,
+                relay: peer.relay,
+            };
+            let inputs = vec![
+                Input::Connected {
+                    addr: peer.addr,
+                    local_addr: peer.local_addr,
+                    link: peer.link,
+                },
+                Input::received(peer.addr, msg.raw(NetworkMessage::Version(version))),
+                Input::received(peer.addr, msg.raw(NetworkMessage::Verack)),
+            ];
+            for input in inputs {
+                self.step(input, local_time);
+            }
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn string<T: ToString + ?Sized>(s: &T) -> Value {
+    Value::String(s.to_string())
+}
+
+fn number(n: u64) -> Value {
+    Value::Number(Number::U64(n))
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Fields of a JSON object.
+struct Fields(Object);
+
+impl Fields {
+    fn from(val: Value) -> io::Result<Self> {
+        match val {
+            Value::Object(obj) => Ok(Self(obj)),
+            _ => Err(invalid_data("expected a JSON object")),
+        }
+    }
+
+    fn str(&self, key: &str) -> io::Result<&str> {
+        match self.0.get(key) {
+            Some(Value::String(s)) => Ok(s.as_str()),
+            _ => Err(invalid_data(format!("missing string field {:?}", key))),
+        }
+    }
+
+    fn u64(&self, key: &str) -> io::Result<u64> {
+        match self.0.get(key) {
+            Some(Value::Number(Number::U64(n))) => Ok(*n),
+            _ => Err(invalid_data(format!("missing number field {:?}", key))),
+        }
+    }
+
+    fn array(&self, key: &str) -> io::Result<&[Value]> {
+        match self.0.get(key) {
+            Some(Value::Array(ary)) => Ok(ary.as_slice()),
+            _ => Err(invalid_data(format!("missing array field {:?}", key))),
+        }
+    }
+
+    fn parse<T>(&self, key: &str) -> io::Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: ToString,
+    {
+        self.str(key)?.parse().map_err(invalid_data)
+    }
+}
@@ -5,7 +5,6 @@
 
 use std::ops::Range;
 
-use nonempty::NonEmpty;
 use thiserror::Error;
 
 use bitcoin::network::constants::ServiceFlags;
@@ -18,7 +17,7 @@ use nakamoto_common::block::{BlockHash, Height};
 use nakamoto_common::collections::HashMap;
 
 use super::channel::SetTimeout;
-use super::{Link, PeerId, Timeout};
+use super::{pingmgr, Link, PeerId, Timeout};
 
 /// Idle timeout.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::BLOCK_INTERVAL;
@@ -56,6 +55,17 @@ pub enum Error {
     Filters(#[from] filter::Error),
 }
 
+/// An error requesting compact filters.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum GetFiltersError {
+    /// No peers are available to request filters from.
+    #[error("not connected to any peer with compact filters support")]
+    NotConnected,
+    /// The requested range is beyond our block header chain.
+    #[error("the requested range {}..{} is invalid", .0.start, .0.end)]
+    InvalidRange(Range<Height>),
+}
+
 /// An event originating in the SPV manager.
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -197,6 +207,8 @@ impl Default for Config {
 struct Peer {
     height: Height,
     last_active: LocalTime,
+    /// Smoothed round-trip latency, if measured.
+    latency: Option<LocalDuration>,
 }
 
 /// A compact block filter manager.
@@ -226,6 +238,26 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         }
     }
 
+    /// Get the stored filter header at the given height, along with its filter hash.
+    pub fn get_header(&self, height: Height) -> Option<(filter::FilterHash, FilterHeader)> {
+        self.filters.get_header(height)
+    }
+
+    /// Get the height of the last stored filter header.
+    pub fn height(&self) -> Height {
+        self.filters.height()
+    }
+
+    /// Get the approximate memory used by the filter header chain, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.filters.memory_usage()
+    }
+
+    /// Write the filter header chain to disk.
+    pub fn flush(&mut self) -> Result<(), filter::Error> {
+        self.filters.sync()
+    }
+
     /// Initialize the spv manager. Should only be called once.
     pub fn initialize<T: BlockTree>(&mut self, now: LocalTime, tree: &T) {
         self.idle(now, tree);
@@ -250,34 +282,38 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         self.filters.rollback(n)
     }
 
-    /// Send a `getcfilters` message to a random peer.
-    ///
-    /// *Panics if there are no peers available.*
-    ///
-    pub fn get_cfilters<T: BlockTree>(&mut self, range: Range<Height>, tree: &T) {
+    /// Send a `getcfilters` message to a random peer. Peers with a lower latency
+    /// are preferred.
+    pub fn get_cfilters<T: BlockTree>(
+        &mut self,
+        range: Range<Height>,
+        tree: &T,
+    ) -> Result<(), GetFiltersError> {
         // TODO: Consolidate this code with the `get_cfheaders` code.
         // TODO: Should buffer the request for when new peers connect.
-        if let Some(peers) = NonEmpty::from_vec(self.peers.keys().collect()) {
-            let iter = HeightIterator {
-                start: range.start,
-                stop: range.end,
-                step: MAX_MESSAGE_CFILTERS as Height,
-            };
-            for r in iter {
-                let ix = self.rng.usize(..peers.len());
-                let peer = *peers.get(ix).unwrap(); // Can't fail.
-
-                // TODO: Return an error instead.
-                let stop_hash = tree.get_block_by_height(r.end).unwrap().block_hash();
-                let timeout = self.config.request_timeout;
-
-                self.upstream
-                    .get_cfilters(*peer, r.start, stop_hash, timeout);
-            }
-        } else {
-            // TODO: Return an error instead.
-            panic!("SpvManager::get_cfilters: called without any available peers!");
+        if self.peers.is_empty() {
+            return Err(GetFiltersError::NotConnected);
+        }
+        if range.end > tree.height() {
+            return Err(GetFiltersError::InvalidRange(range));
         }
+        let iter = HeightIterator {
+            start: range.start,
+            stop: range.end,
+            step: MAX_MESSAGE_CFILTERS as Height,
+        };
+        for r in iter {
+            let peer = self.pick_peer().ok_or(GetFiltersError::NotConnected)?;
+            let stop_hash = tree
+                .get_block_by_height(r.end)
+                .ok_or_else(|| GetFiltersError::InvalidRange(r.clone()))?
+                .block_hash();
+            let timeout = self.config.request_timeout;
+
+            self.upstream
+                .get_cfilters(peer, r.start, stop_hash, timeout);
+        }
+        Ok(())
     }
 
     /// Handle a `cfheaders` message from a peer.
@@ -289,6 +325,13 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
     ) -> Result<Height, Error> {
         let from = *from;
 
+        // Filter headers are only requested from negotiated peers.
+        if !self.peers.contains_key(&from) {
+            return Err(Error::Ignored {
+                msg: "cfheaders",
+                from,
+            });
+        }
         if msg.filter_type != 0x0 {
             return Err(Error::InvalidMessage {
                 from,
@@ -395,14 +438,22 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
                 from,
             });
         };
+        if start_height > stop_height {
+            return Err(Error::InvalidMessage {
+                from,
+                reason: "getcfheaders: start height is greater than stop height",
+            });
+        }
 
         let headers = self.filters.get_headers(start_height..stop_height);
         if !headers.is_empty() {
             let hashes = headers.iter().map(|(hash, _)| *hash);
+            // All headers up to the tip must exist, so this can only fail if the store
+            // is corrupted.
             let prev_header = self
                 .filters
                 .get_prev_header(start_height)
-                .expect("SpvManager::received_getcfheaders: all headers up to the tip must exist");
+                .ok_or_else(|| filter::Error::NotFound(start_height.saturating_sub(1)))?;
 
             self.upstream.send_cfheaders(
                 from,
@@ -432,6 +483,13 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
     ) -> Result<(), Error> {
         let from = *from;
 
+        // Filters are only requested from negotiated peers.
+        if !self.peers.contains_key(&from) {
+            return Err(Error::Ignored {
+                msg: "cfilter",
+                from,
+            });
+        }
         if msg.filter_type != 0x0 {
             return Err(Error::Ignored {
                 msg: "cfilter",
@@ -460,12 +518,12 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             });
         };
 
-        // Note that in case this fails, we have a bug in our implementation, since filter
-        // headers are supposed to be downloaded in-order.
+        // Note that in case this fails, the store is corrupted, since filter headers are
+        // supposed to be downloaded in-order.
         let prev_header = self
             .filters
             .get_prev_header(height)
-            .expect("SpvManager::received_cfilter: all headers up to the tip must exist");
+            .ok_or_else(|| filter::Error::NotFound(height.saturating_sub(1)))?;
         let filter = BlockFilter::new(&msg.filter);
 
         if filter.filter_id(&prev_header.into()) != header.into() {
@@ -503,6 +561,13 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         self.peers.remove(id);
     }
 
+    /// Called when the round-trip latency of a peer was measured.
+    pub fn peer_latency(&mut self, id: &PeerId, latency: LocalDuration) {
+        if let Some(peer) = self.peers.get_mut(id) {
+            peer.latency = Some(latency);
+        }
+    }
+
     /// Called when a new peer was negotiated.
     pub fn peer_negotiated<T: BlockTree>(
         &mut self,
@@ -525,6 +590,7 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
             Peer {
                 last_active: clock.local_time(),
                 height,
+                latency: None,
             },
         );
         self.sync(tree);
@@ -548,11 +614,14 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         // Cap request to `MAX_MESSAGE_CFHEADERS`.
         let stop_hash = if count > MAX_MESSAGE_CFHEADERS {
             let stop_height = range.start + MAX_MESSAGE_CFHEADERS as Height - 1;
-            let stop_block = tree
-                .get_block_by_height(stop_height)
-                .expect("all headers up to the tip exist");
 
-            stop_block.block_hash()
+            if let Some(stop_block) = tree.get_block_by_height(stop_height) {
+                stop_block.block_hash()
+            } else {
+                // All headers up to the tip exist, unless the block tree is inconsistent.
+                log::error!("Block header at height {} not found", stop_height);
+                return None;
+            }
         } else {
             let (hash, _) = tree.tip();
 
@@ -560,22 +629,28 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
         };
 
         // TODO: We should select peers that are caught up to the requested height.
-        if let Some(peers) = NonEmpty::from_vec(self.peers.keys().collect()) {
-            let ix = self.rng.usize(..peers.len());
-            let peer = *peers.get(ix).unwrap(); // Can't fail.
+        if let Some(peer) = self.pick_peer() {
             let start_height = range.start;
 
-            self.upstream.get_cfheaders(
-                *peer,
-                start_height,
-                stop_hash,
-                self.config.request_timeout,
-            );
-            return Some((*peer, start_height, stop_hash));
+            self.upstream
+                .get_cfheaders(peer, start_height, stop_hash, self.config.request_timeout);
+            return Some((peer, start_height, stop_hash));
         }
         None
     }
 
+    /// Pick a random peer to request filters or filter headers from,
+    /// preferring peers with a lower latency.
+    fn pick_peer(&self) -> Option<PeerId> {
+        let candidates = self
+            .peers
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.latency))
+            .collect();
+
+        pingmgr::pick_low_latency(candidates, &self.rng)
+    }
+
     /// Attempt to sync the filter header chain.
     pub fn sync<T: BlockTree>(&mut self, tree: &T) {
         let filter_height = self.filters.height();
@@ -596,7 +671,13 @@ impl<F: Filters, U: SyncFilters + Events + SetTimeout> SpvManager<F, U> {
                 });
             }
         } else if filter_height > block_height {
-            panic!("SpvManager::idle: filter chain is longer than header chain!");
+            // This can happen after a re-org to a shorter chain. Roll back the filter
+            // header chain, so that it can be synced again from the new tip.
+            let n = (filter_height - block_height) as usize;
+
+            if let Err(err) = self.rollback(n) {
+                log::error!("Error rolling back {} filter header(s): {}", n, err);
+            }
         }
     }
 }
@@ -633,6 +714,7 @@ mod tests {
     use nakamoto_chain::block::{cache::BlockCache, store};
     use nakamoto_chain::filter::cache::FilterCache;
     use nakamoto_common::block::filter::FilterHash;
+    use nakamoto_common::block::time::AdjustedTime;
     use nakamoto_common::network::Network;
     use nakamoto_test::BITCOIN_HEADERS;
 
@@ -694,6 +776,22 @@ mod tests {
 
             SpvManager::new(Config::default(), rng, cache, upstream)
         };
+        let clock = AdjustedTime::<PeerId>::default();
+
+        // Messages from peers we haven't negotiated with are ignored.
+        assert!(matches!(
+            spvmgr.received_cfilter(
+                peer,
+                CFilter {
+                    filter_type: 0x0,
+                    block_hash: BITCOIN_HEADERS.head.block_hash(),
+                    filter: FILTERS[0].to_vec(),
+                },
+                &tree
+            ),
+            Err(Error::Ignored { .. })
+        ));
+        spvmgr.peer_negotiated(*peer, 0, REQUIRED_SERVICES, Link::Outbound, &clock, &tree);
 
         // Import the headers.
         {
@@ -731,6 +829,20 @@ mod tests {
         for msg in cfilters {
             spvmgr.received_cfilter(peer, msg, &tree).unwrap();
         }
+
+        // Requests with a start height past the stop block are invalid.
+        assert!(matches!(
+            spvmgr.received_getcfheaders(
+                peer,
+                GetCFHeaders {
+                    filter_type: 0x0,
+                    start_height: 10,
+                    stop_hash: BITCOIN_HEADERS.iter().nth(5).unwrap().block_hash(),
+                },
+                &tree
+            ),
+            Err(Error::InvalidMessage { .. })
+        ));
     }
 
     #[test]
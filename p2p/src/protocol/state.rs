@@ -0,0 +1,139 @@
+//! Chain state shared with other threads.
+//!
+//! The protocol owns the block tree, so reading it from another thread, eg. a client
+//! handle, normally requires a round-trip through the event loop, which may be busy
+//! syncing. Instead, the protocol publishes an immutable snapshot of the chain tip and the
+//! most recent headers whenever the tip changes, which can be read at any time.
+//!
+//! Older headers are served from an index of the active chain, which the protocol updates
+//! along with the snapshot.
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+
+/// Number of headers, up to and including the tip, included in the chain state.
+pub const RECENT_HEADERS: usize = 144;
+
+/// Snapshot of the chain state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainState {
+    /// Height of the chain.
+    pub height: Height,
+    /// Hash of the tip.
+    pub hash: BlockHash,
+    /// Headers of the most recent blocks, in order. The last one is the tip.
+    pub headers: Vec<BlockHeader>,
+}
+
+impl ChainState {
+    /// Take a snapshot of the given block tree.
+    pub fn from<T: BlockTree>(tree: &T) -> Self {
+        let (hash, _) = tree.tip();
+        let height = tree.height();
+        let start = (height + 1).saturating_sub(RECENT_HEADERS as Height);
+        let headers = (start..=height)
+            .filter_map(|h| tree.get_block_by_height(h).copied())
+            .collect();
+
+        Self {
+            height,
+            hash,
+            headers,
+        }
+    }
+
+    /// Get the tip of the chain.
+    pub fn tip(&self) -> (Height, BlockHeader) {
+        (
+            self.height,
+            *self.headers.last().expect("the tip is always included"),
+        )
+    }
+
+    /// Get a recent header by height, if it's included in the snapshot.
+    pub fn get_header_by_height(&self, height: Height) -> Option<BlockHeader> {
+        let start = self.height + 1 - self.headers.len() as Height;
+        let ix = height.checked_sub(start)?;
+
+        self.headers.get(ix as usize).copied()
+    }
+}
+
+/// Index of the headers in the active chain.
+#[derive(Debug, Default)]
+struct ChainIndex {
+    /// Headers of the active chain, by height.
+    headers: Vec<BlockHeader>,
+    /// Heights of the headers in the active chain, by hash.
+    heights: HashMap<BlockHash, Height>,
+}
+
+impl ChainIndex {
+    /// Update the index to match the active chain of the given block tree.
+    fn update<T: BlockTree>(&mut self, tree: &T) {
+        let height = tree.height();
+        let mut len = self.headers.len().min(height as usize + 1);
+
+        // Find the last header we have in common with the tree. This is usually the
+        // previous tip.
+        while len > 0 && tree.get_block_by_height(len as Height - 1) != self.headers.get(len - 1) {
+            len -= 1;
+        }
+        for header in self.headers.drain(len..) {
+            self.heights.remove(&header.block_hash());
+        }
+        for h in len as Height..=height {
+            if let Some(header) = tree.get_block_by_height(h) {
+                self.heights.insert(header.block_hash(), h);
+                self.headers.push(*header);
+            }
+        }
+    }
+}
+
+/// Shared handle to the latest published chain state. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct SharedChainState {
+    state: Arc<RwLock<Option<Arc<ChainState>>>>,
+    index: Arc<RwLock<ChainIndex>>,
+}
+
+impl SharedChainState {
+    /// Get the latest chain state, if any was published yet.
+    pub fn get(&self) -> Option<Arc<ChainState>> {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Publish a new chain state.
+    pub fn publish(&self, state: ChainState) {
+        *self.state.write().unwrap() = Some(Arc::new(state));
+    }
+
+    /// Publish the chain state of the given block tree, and update the chain index.
+    pub fn update<T: BlockTree>(&self, tree: &T) {
+        self.index.write().unwrap().update(tree);
+        self.publish(ChainState::from(tree));
+    }
+
+    /// Get a header of the active chain, and its height, by hash. Only headers published
+    /// with [`SharedChainState::update`] are found.
+    pub fn get_header(&self, hash: &BlockHash) -> Option<(Height, BlockHeader)> {
+        let index = self.index.read().unwrap();
+        let height = *index.heights.get(hash)?;
+
+        index.headers.get(height as usize).map(|h| (height, *h))
+    }
+
+    /// Get a header of the active chain by height. Only headers published with
+    /// [`SharedChainState::update`] are found.
+    pub fn get_header_by_height(&self, height: Height) -> Option<BlockHeader> {
+        self.index
+            .read()
+            .unwrap()
+            .headers
+            .get(height as usize)
+            .copied()
+    }
+}
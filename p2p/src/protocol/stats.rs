@@ -0,0 +1,157 @@
+//! Peer traffic statistics.
+//!
+//! Keeps track of the number of messages and bytes exchanged with each connected peer,
+//! broken down by message type, as well as totals across all peers, including
+//! peers we've since disconnected from.
+use std::collections::BTreeMap;
+
+use nakamoto_common::collections::HashMap;
+
+use super::PeerId;
+
+/// Message type under which received messages that weren't decoded are counted, ie.
+/// messages the protocol doesn't handle, and messages that failed to decode.
+pub const OTHER: &str = "other";
+
+/// Message and byte counts.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    /// Number of messages.
+    pub messages: u64,
+    /// Number of bytes, including message headers.
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn record(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Traffic statistics, of a single peer or in aggregate.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Traffic sent.
+    pub sent: Traffic,
+    /// Traffic received.
+    pub received: Traffic,
+    /// Traffic sent, by message type, eg. `"headers"`.
+    pub sent_by_message: BTreeMap<&'static str, Traffic>,
+    /// Traffic received, by message type, eg. `"headers"`. Messages that weren't decoded
+    /// are counted under [`OTHER`].
+    pub received_by_message: BTreeMap<&'static str, Traffic>,
+    /// Traffic received from peers we were disconnecting or had disconnected from,
+    /// which was ignored. Only counted in the totals.
+    pub ignored: Traffic,
+    /// Submitted transactions queued for sending, and their size. Counted when queued,
+    /// and also counted as sent `"tx"` traffic once written out.
+    pub relayed: Traffic,
+}
+
+impl Stats {
+    fn record_sent(&mut self, cmd: &'static str, bytes: usize) {
+        self.sent.record(bytes);
+        self.sent_by_message.entry(cmd).or_default().record(bytes);
+    }
+
+    fn record_received(&mut self, cmd: &'static str, bytes: usize) {
+        self.received.record(bytes);
+        self.received_by_message
+            .entry(cmd)
+            .or_default()
+            .record(bytes);
+    }
+}
+
+/// A snapshot of the traffic statistics.
+#[derive(Debug, Default, Clone)]
+pub struct Snapshot {
+    /// Statistics across all peers, since the last reset. This includes traffic with
+    /// peers that are no longer connected.
+    pub total: Stats,
+    /// Statistics of each connected peer, since the peer connected or since the last
+    /// reset, whichever is most recent.
+    pub peers: Vec<(PeerId, Stats)>,
+}
+
+/// Tracks traffic statistics.
+#[derive(Debug)]
+pub struct StatsTracker {
+    /// Statistics of connected peers.
+    peers: HashMap<PeerId, Stats>,
+    /// Statistics across all peers.
+    total: Stats,
+}
+
+impl StatsTracker {
+    /// Create a new stats tracker.
+    pub fn new(rng: fastrand::Rng) -> Self {
+        Self {
+            peers: HashMap::with_hasher(rng.into()),
+            total: Stats::default(),
+        }
+    }
+
+    /// Called when a peer connected. Only connected peers have their own statistics.
+    pub fn peer_connected(&mut self, addr: PeerId) {
+        self.peers.insert(addr, Stats::default());
+    }
+
+    /// Called when a message was sent to a peer.
+    pub fn message_sent(&mut self, addr: PeerId, cmd: &'static str, bytes: usize) {
+        if let Some(stats) = self.peers.get_mut(&addr) {
+            stats.record_sent(cmd, bytes);
+        }
+        self.total.record_sent(cmd, bytes);
+    }
+
+    /// Called when a message was received from a peer.
+    pub fn message_received(&mut self, addr: PeerId, cmd: &'static str, bytes: usize) {
+        if let Some(stats) = self.peers.get_mut(&addr) {
+            stats.record_received(cmd, bytes);
+        }
+        self.total.record_received(cmd, bytes);
+    }
+
+    /// Called when a submitted transaction of the given size was queued for sending to
+    /// a peer.
+    pub fn transaction_relayed(&mut self, addr: PeerId, bytes: usize) {
+        if let Some(stats) = self.peers.get_mut(&addr) {
+            stats.relayed.record(bytes);
+        }
+        self.total.relayed.record(bytes);
+    }
+
+    /// Called when a message was ignored, because its sender is no longer tracked.
+    pub fn message_ignored(&mut self, bytes: usize) {
+        self.total.ignored.record(bytes);
+    }
+
+    /// Called when a peer disconnected. The peer's traffic remains accounted for
+    /// in the totals.
+    pub fn peer_disconnected(&mut self, addr: &PeerId) {
+        self.peers.remove(addr);
+    }
+
+    /// Reset all statistics.
+    pub fn reset(&mut self) {
+        self.total = Stats::default();
+
+        for stats in self.peers.values_mut() {
+            *stats = Stats::default();
+        }
+    }
+
+    /// Get a snapshot of the current statistics.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            total: self.total.clone(),
+            peers: self
+                .peers
+                .iter()
+                .map(|(addr, stats)| (*addr, stats.clone()))
+                .collect(),
+        }
+    }
+}
@@ -2,8 +2,8 @@
 //! Manages header synchronization with peers.
 //!
 #![warn(missing_docs)]
+use std::cmp;
 use std::sync::Arc;
-use std::time::SystemTime;
 
 use nonempty::NonEmpty;
 
@@ -19,7 +19,7 @@ use nakamoto_common::block::{BlockHash, BlockHeader, Height};
 use nakamoto_common::collections::HashMap;
 
 use super::channel::{Disconnect, SetTimeout};
-use super::{DisconnectReason, Link, Locators, PeerId, Timeout};
+use super::{DisconnectReason, Link, Locators, PeerId, Timeout, Whitelist};
 
 /// How long to wait for a request, eg. `getheaders` to be fulfilled.
 pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
@@ -28,6 +28,8 @@ pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 pub const TIP_STALE_DURATION: LocalDuration = LocalDuration::from_mins(60 * 2);
 /// Maximum number of headers sent in a `headers` message.
 pub const MAX_MESSAGE_HEADERS: usize = 2000;
+/// Maximum number of inventories sent in an `inv` message.
+pub const MAX_MESSAGE_INVS: usize = 50000;
 /// Idle timeout.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::BLOCK_INTERVAL;
 /// Services required from peers for header sync.
@@ -35,6 +37,14 @@ pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
 
 /// Maximum headers announced in a `headers` message, when unsolicited.
 const MAX_HEADERS_ANNOUNCED: usize = 8;
+/// Maximum number of unsolicited headers a peer may send us per [`UNSOLICITED_HEADERS_INTERVAL`].
+/// Peers exceeding this limit are disconnected.
+const MAX_UNSOLICITED_HEADERS: usize = MAX_HEADERS_ANNOUNCED * 8;
+/// Interval over which unsolicited headers are counted.
+const UNSOLICITED_HEADERS_INTERVAL: LocalDuration = LocalDuration::BLOCK_INTERVAL;
+/// Maximum number of `headers` messages that don't connect to our chain, a peer may send us.
+/// Peers exceeding this limit are disconnected.
+const MAX_UNCONNECTING_HEADERS: usize = 10;
 /// How long to wait between checks for longer chains from peers.
 const PEER_SAMPLE_INTERVAL: LocalDuration = LocalDuration::from_mins(60);
 
@@ -66,8 +76,18 @@ struct PeerState {
     height: Height,
     tip: BlockHash,
     link: Link,
+    services: ServiceFlags,
     last_active: Option<LocalTime>,
     last_asked: Option<Locators>,
+    /// Smoothed round-trip latency, if measured.
+    latency: Option<LocalDuration>,
+    /// Number of requests this peer failed to respond to, or responded to with
+    /// invalid headers.
+    failures: usize,
+    /// Number of unsolicited headers received from this peer, since the given time.
+    unsolicited: (usize, LocalTime),
+    /// Number of `headers` messages received from this peer that didn't connect to our chain.
+    unconnecting: usize,
 }
 
 /// Sync manager configuration.
@@ -79,6 +99,9 @@ pub struct Config {
     pub request_timeout: LocalDuration,
     /// Consensus parameters.
     pub params: Params,
+    /// Trusted peers. These are exempt from rate limits, and preferred for syncing over
+    /// other peers at the same height.
+    pub whitelist: Whitelist,
 }
 
 /// The sync manager state.
@@ -94,8 +117,6 @@ pub struct SyncManager<U> {
     last_peer_sample: Option<LocalTime>,
     /// Last time we idled.
     last_idle: Option<LocalTime>,
-    /// Random number generator.
-    rng: fastrand::Rng,
     /// In-flight requests to peers.
     inflight: HashMap<PeerId, GetHeaders>,
     /// Upstream protocol channel.
@@ -111,6 +132,8 @@ pub enum Event {
     InvalidHeadersReceived(PeerId, Arc<Error>),
     /// Unsolicited headers received.
     UnsolicitedHeadersReceived(PeerId, usize),
+    /// Headers that don't connect to our chain received.
+    UnconnectingHeadersReceived(PeerId, usize),
     /// Block received.
     BlockReceived(PeerId, Block, Height),
     /// A new block was discovered via a peer.
@@ -143,6 +166,13 @@ impl std::fmt::Display for Event {
             Event::UnsolicitedHeadersReceived(from, count) => {
                 write!(fmt, "Received {} unsolicited headers from {}", count, from)
             }
+            Event::UnconnectingHeadersReceived(from, count) => {
+                write!(
+                    fmt,
+                    "Received {} header(s) that don't connect to our chain from {}",
+                    count, from
+                )
+            }
             Event::HeadersImported(import_result) => {
                 write!(fmt, "Headers imported: {:?}", &import_result)
             }
@@ -154,12 +184,10 @@ impl std::fmt::Display for Event {
                 write!(fmt, "{}: Discovered new block: {}", from, &hash)
             }
             Event::StaleTipDetected(last_update) => {
-                let elapsed = LocalTime::from(SystemTime::now()) - *last_update;
-
                 write!(
                     fmt,
-                    "Potential stale tip detected (last update is {} ago)",
-                    elapsed
+                    "Potential stale tip detected (last update was at {})",
+                    last_update
                 )
             }
         }
@@ -206,7 +234,6 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             last_tip_update,
             last_peer_sample,
             last_idle,
-            rng,
             inflight,
             upstream,
         }
@@ -239,7 +266,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         if link.is_outbound() && !services.has(REQUIRED_SERVICES) {
             return;
         }
-        self.register(id, height, link);
+        self.register(id, height, services, link, clock.local_time());
         self.upstream.negotiate(id);
         self.sync(clock.local_time(), tree);
     }
@@ -249,6 +276,13 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         self.unregister(id);
     }
 
+    /// Called when the round-trip latency of a peer was measured.
+    pub fn peer_latency(&mut self, id: &PeerId, latency: LocalDuration) {
+        if let Some(peer) = self.peers.get_mut(id) {
+            peer.latency = Some(latency);
+        }
+    }
+
     /// Called when we received a `getheaders` message from a peer.
     pub fn received_getheaders<T: BlockTree>(
         &self,
@@ -327,19 +361,40 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         } else {
             return Ok(ImportResult::TipUnchanged);
         }
+        let prev = headers.first().prev_blockhash;
+
+        // Headers we already have are ignored. This is common when several peers send us
+        // the same headers. If they were in response to our request, the request is fulfilled.
+        if tree.contains(&best) || headers.iter().all(|h| tree.is_known(&h.block_hash())) {
+            if let Some(GetHeaders { locators, .. }) = self.inflight.get(from) {
+                if locators.0.contains(&prev) {
+                    self.inflight.remove(from);
+                }
+            }
+            return Ok(ImportResult::TipUnchanged);
+        }
         self.upstream
             .event(Event::HeadersReceived(*from, headers.len()));
 
-        if tree.contains(&best) {
+        let request = self.inflight.remove(from);
+
+        // The first header should connect to a block we know of, or to one of the locators
+        // we supplied to the peer. Otherwise, there's no point in trying to import them.
+        if !tree.is_known(&prev)
+            && !request
+                .as_ref()
+                .map_or(false, |r| r.locators.0.contains(&prev))
+        {
+            self.received_unconnecting(from, headers, clock, tree);
+
             return Ok(ImportResult::TipUnchanged);
         }
+        if let Some(peer) = self.peers.get_mut(from) {
+            peer.unconnecting = 0;
+        }
 
-        match self.inflight.remove(from) {
-            Some(GetHeaders { locators, .. })
-                if headers
-                    .iter()
-                    .any(|h| locators.0.contains(&h.prev_blockhash)) =>
-            {
+        match request {
+            Some(GetHeaders { locators, .. }) if locators.0.contains(&prev) => {
                 // Requested headers. These should extend our main chain.
                 // Check whether the start of the header chain matches one of the locators we
                 // supplied to the peer. Otherwise, we consider them unsolicited.
@@ -352,11 +407,11 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 }
 
                 if let Ok(ImportResult::TipChanged(tip, height, _)) = result {
-                    let peer = self.peers.get_mut(from).unwrap();
-
-                    if height > peer.height {
-                        peer.tip = tip;
-                        peer.height = height;
+                    if let Some(peer) = self.peers.get_mut(from) {
+                        if height > peer.height {
+                            peer.tip = tip;
+                            peer.height = height;
+                        }
                     }
                 }
 
@@ -400,6 +455,10 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             }
             // Header announcement.
             _ if length <= MAX_HEADERS_ANNOUNCED => {
+                // Announcements are validated, so we limit how many we'll accept.
+                if !self.allow_unsolicited(from, length, clock.local_time()) {
+                    return Ok(ImportResult::TipUnchanged);
+                }
                 let root = headers.first().block_hash();
 
                 match tree.import_blocks(headers.into_iter(), clock) {
@@ -423,10 +482,11 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                         Ok(import_result)
                     }
                     Ok(ImportResult::TipChanged(tip, height, reverted)) => {
-                        let peer = self.peers.get_mut(from).unwrap();
-                        if height > peer.height {
-                            peer.tip = tip;
-                            peer.height = height;
+                        if let Some(peer) = self.peers.get_mut(from) {
+                            if height > peer.height {
+                                peer.tip = tip;
+                                peer.height = height;
+                            }
                         }
 
                         self.upstream
@@ -455,6 +515,61 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
     }
 
+    /// Called when we receive headers that don't connect to our chain.
+    ///
+    /// The headers are discarded. If they were announced, we may simply be missing some
+    /// blocks, so we ask the peer for the headers leading up to them. Peers repeatedly sending
+    /// headers that don't connect are disconnected.
+    fn received_unconnecting<T: BlockTree>(
+        &mut self,
+        from: &PeerId,
+        headers: NonEmpty<BlockHeader>,
+        clock: &impl Clock,
+        tree: &T,
+    ) {
+        let length = headers.len();
+
+        self.record_misbehavior(from);
+        self.upstream
+            .event(Event::UnconnectingHeadersReceived(*from, length));
+
+        let peer = if let Some(peer) = self.peers.get_mut(from) {
+            peer
+        } else {
+            return;
+        };
+        peer.unconnecting += 1;
+
+        if peer.unconnecting > MAX_UNCONNECTING_HEADERS
+            && !self.config.whitelist.contains_addr(&from.ip())
+        {
+            self.unregister(from);
+            self.upstream.disconnect(
+                *from,
+                DisconnectReason::PeerMisbehaving("too many non-connecting headers"),
+            );
+        } else if length <= MAX_HEADERS_ANNOUNCED {
+            // Try to find a common ancestor that leads up to the first header in
+            // the list we received.
+            let locators = (
+                tree.locator_hashes(tree.height()),
+                headers.first().block_hash(),
+            );
+            let timeout = self.config.request_timeout;
+
+            if peer.last_asked.as_ref() == Some(&locators) {
+                return;
+            }
+            self.request(
+                *from,
+                locators,
+                clock.local_time(),
+                timeout,
+                OnTimeout::Ignore,
+            );
+        }
+    }
+
     fn request(
         &mut self,
         addr: PeerId,
@@ -488,6 +603,15 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         clock: &impl Clock,
         tree: &mut T,
     ) -> Result<ImportResult, Error> {
+        let (tip, _) = tree.tip();
+        let prev = headers.first().prev_blockhash;
+
+        // Headers forking off our active chain, eg. after a network partition, can't
+        // extend our tip. They are imported as a whole instead, which re-orgs our chain
+        // if they have more work.
+        if prev != tip && !headers.iter().any(|h| h.block_hash() == tip) {
+            return tree.import_blocks(headers.into_iter(), clock);
+        }
         let mut import_result = ImportResult::TipUnchanged;
 
         for header in headers.into_iter() {
@@ -583,7 +707,11 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                         .disconnect(*peer, DisconnectReason::PeerTimeout);
                 }
                 OnTimeout::Ignore => {
-                    // It's likely that the peer just didn't have the requested header.
+                    // It's likely that the peer just didn't have the requested header,
+                    // but we'd rather sync with someone else next time.
+                    if let Some(peer) = self.peers.get_mut(peer) {
+                        peer.failures += 1;
+                    }
                 }
             }
             self.upstream.event(Event::TimedOut(*peer));
@@ -598,6 +726,9 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
     }
 
     /// Get the best known height out of all our peers.
+    ///
+    /// Peers are only registered once negotiated, so peers still in handshake aren't
+    /// taken into account.
     pub fn best_height(&self) -> Option<Height> {
         self.peers.iter().map(|(_, p)| p.height).max()
     }
@@ -610,6 +741,22 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             // this up, because we can't handle it here.
             Error::Store(e) => Err(e),
 
+            // Headers that are invalid no matter what our clock says can't be sent by
+            // an honest peer.
+            Error::InvalidBlockPoW | Error::InvalidBlockTarget(_, _)
+                if !self.config.whitelist.contains_addr(&from.ip()) =>
+            {
+                self.upstream
+                    .event(Event::InvalidHeadersReceived(*from, Arc::new(err)));
+                self.unregister(from);
+                self.upstream.disconnect(
+                    *from,
+                    DisconnectReason::PeerMisbehaving("invalid headers received"),
+                );
+
+                Ok(())
+            }
+
             // If we got a bad block from the peer, we can handle it here.
             Error::InvalidBlockPoW
             | Error::InvalidBlockTarget(_, _)
@@ -631,8 +778,42 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
     }
 
-    fn record_misbehavior(&mut self, _peer: &PeerId) {
-        // TODO
+    /// Account for unsolicited headers received from a peer. Returns `false` if the peer
+    /// exceeded its allowance, in which case it is disconnected and the headers should
+    /// be ignored.
+    fn allow_unsolicited(&mut self, from: &PeerId, count: usize, now: LocalTime) -> bool {
+        if self.config.whitelist.contains_addr(&from.ip()) {
+            return self.peers.contains_key(from);
+        }
+        let peer = if let Some(peer) = self.peers.get_mut(from) {
+            peer
+        } else {
+            return false;
+        };
+        let (received, since) = &mut peer.unsolicited;
+
+        if now - *since >= UNSOLICITED_HEADERS_INTERVAL {
+            *received = 0;
+            *since = now;
+        }
+        *received += count;
+
+        if *received <= MAX_UNSOLICITED_HEADERS {
+            return true;
+        }
+        self.unregister(from);
+        self.upstream.disconnect(
+            *from,
+            DisconnectReason::PeerMisbehaving("too many unsolicited headers"),
+        );
+
+        false
+    }
+
+    fn record_misbehavior(&mut self, peer: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.failures += 1;
+        }
     }
 
     /// Check whether our current tip is stale.
@@ -665,7 +846,14 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
     }
 
     /// Register a new peer.
-    fn register(&mut self, id: PeerId, height: Height, link: Link) {
+    fn register(
+        &mut self,
+        id: PeerId,
+        height: Height,
+        services: ServiceFlags,
+        link: Link,
+        now: LocalTime,
+    ) {
         let last_active = None;
         let last_asked = None;
         let tip = BlockHash::default();
@@ -677,8 +865,13 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
                 height,
                 tip,
                 link,
+                services,
                 last_active,
                 last_asked,
+                latency: None,
+                failures: 0,
+                unsolicited: (0, now),
+                unconnecting: 0,
             },
         );
     }
@@ -688,24 +881,29 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         self.peers.remove(id);
     }
 
-    /// Pick a random peer we could sync with using the given locators.
-    fn random_sync_candidate<T: BlockTree>(
+    /// Pick the best peer we could sync with using the given locators.
+    ///
+    /// Peers are ranked by advertised height, then by the number of requests they failed,
+    /// then by latency, with peers of unknown latency ranked last. Remaining ties are
+    /// broken by address, so that the choice is deterministic.
+    fn best_sync_candidate<T: BlockTree>(
         &self,
         locators: &[BlockHash],
         tree: &T,
     ) -> Option<&PeerState> {
-        let candidates = self
-            .peers
+        self.peers
             .values()
-            .filter(|p| self.is_sync_candidate(p, locators, tree));
-
-        if let Some(peers) = NonEmpty::from_vec(candidates.collect()) {
-            let ix = self.rng.usize(..peers.len());
-
-            return peers.get(ix).cloned();
-        }
-
-        None
+            .filter(|p| self.is_sync_candidate(p, locators, tree))
+            .min_by_key(|p| {
+                (
+                    cmp::Reverse(p.height),
+                    !self.config.whitelist.contains_addr(&p.id.ip()),
+                    p.failures,
+                    p.latency.is_none(),
+                    p.latency,
+                    p.id,
+                )
+            })
     }
 
     /// Check whether a peer can be synced with using the given locators.
@@ -716,12 +914,16 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         tree: &T,
     ) -> bool {
         peer.link.is_outbound()
+            && peer.services.has(REQUIRED_SERVICES)
             && peer.height > tree.height()
             && !self.inflight.contains_key(&peer.id)
             && peer.last_asked.as_ref().map_or(true, |l| l.0 != locators)
     }
 
     /// Check whether or not we are in sync with the network.
+    ///
+    /// We are in sync when our chain is at least as long as the longest chain of our
+    /// negotiated peers. A single lagging peer doesn't make us think we're in sync.
     fn is_synced<T: BlockTree>(&mut self, now: LocalTime, tree: &T) -> bool {
         if let Some(last_update) = self.stale_tip(now, tree) {
             self.upstream.event(Event::StaleTipDetected(last_update));
@@ -731,7 +933,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         let height = tree.height();
 
         // Find the peer with the longest chain and compare our height to it.
-        if let Some(peer_height) = self.peers.values().map(|p| p.height).max() {
+        if let Some(peer_height) = self.best_height() {
             return height >= peer_height;
         }
 
@@ -777,7 +979,7 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
             return;
         }
 
-        if let Some(peer) = self.random_sync_candidate(&locators.0, tree) {
+        if let Some(peer) = self.best_sync_candidate(&locators.0, tree) {
             let timeout = self.config.request_timeout;
             let addr = peer.id;
 
@@ -821,3 +1023,210 @@ impl<U: SetTimeout + SyncHeaders + Disconnect> SyncManager<U> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::Hash;
+    use crossbeam_channel as chan;
+
+    use nakamoto_common::block::time::AdjustedTime;
+    use nakamoto_common::network::Network;
+    use nakamoto_test::block::cache::model;
+
+    use crate::protocol::channel::Channel;
+    use crate::protocol::{Out, PROTOCOL_VERSION};
+
+    use super::*;
+
+    fn syncmgr(network: Network) -> (SyncManager<Channel>, chan::Receiver<Out>) {
+        let (sender, receiver) = chan::unbounded();
+        let syncmgr = SyncManager::new(
+            Config {
+                max_message_headers: MAX_MESSAGE_HEADERS,
+                request_timeout: REQUEST_TIMEOUT,
+                params: network.params(),
+                whitelist: Whitelist::default(),
+            },
+            fastrand::Rng::new(),
+            Channel::new(network, PROTOCOL_VERSION, "test", sender),
+        );
+        (syncmgr, receiver)
+    }
+
+    #[test]
+    fn test_best_sync_candidate() {
+        let network = Network::Mainnet;
+        let tree = model::Cache::new(network.genesis());
+        let locators = tree.locator_hashes(tree.height());
+        let (mut syncmgr, _rx) = self::syncmgr(network);
+        let alice: PeerId = ([88, 13, 16, 59], 8333).into();
+        let bob: PeerId = ([88, 13, 16, 60], 8333).into();
+        let carol: PeerId = ([99, 45, 180, 58], 8333).into();
+        let dave: PeerId = ([131, 25, 4, 7], 8333).into();
+        let time = LocalTime::default();
+        let best =
+            |syncmgr: &SyncManager<_>| syncmgr.best_sync_candidate(&locators, &tree).map(|p| p.id);
+
+        syncmgr.register(alice, 144, ServiceFlags::NETWORK, Link::Outbound, time);
+        syncmgr.register(bob, 144, ServiceFlags::NETWORK, Link::Outbound, time);
+        syncmgr.register(carol, 143, ServiceFlags::NETWORK, Link::Outbound, time);
+        syncmgr.register(dave, 200, ServiceFlags::NONE, Link::Outbound, time);
+
+        assert_eq!(best(&syncmgr), Some(alice), "ties are broken by address");
+
+        syncmgr.peer_latency(&bob, LocalDuration::from_millis(60));
+        assert_eq!(
+            best(&syncmgr),
+            Some(bob),
+            "peers with known latency are preferred"
+        );
+
+        syncmgr.peer_latency(&alice, LocalDuration::from_millis(30));
+        assert_eq!(best(&syncmgr), Some(alice), "faster peers are preferred");
+
+        syncmgr.record_misbehavior(&alice);
+        assert_eq!(best(&syncmgr), Some(bob), "peers with failures are avoided");
+
+        syncmgr.register(carol, 145, ServiceFlags::NETWORK, Link::Outbound, time);
+        assert_eq!(best(&syncmgr), Some(carol), "higher peers are preferred");
+
+        syncmgr.register(carol, 145, ServiceFlags::NETWORK, Link::Inbound, time);
+        assert_eq!(
+            best(&syncmgr),
+            Some(bob),
+            "only outbound peers are synced with"
+        );
+
+        syncmgr.register(carol, 145, ServiceFlags::NETWORK, Link::Outbound, time);
+        syncmgr.config.whitelist = Whitelist::new(vec![alice.ip()]);
+        assert_eq!(
+            best(&syncmgr),
+            Some(carol),
+            "whitelisted peers aren't preferred over higher peers"
+        );
+
+        syncmgr.register(carol, 144, ServiceFlags::NETWORK, Link::Outbound, time);
+        assert_eq!(
+            best(&syncmgr),
+            Some(alice),
+            "whitelisted peers are preferred among peers at the same height"
+        );
+    }
+
+    #[test]
+    fn test_unsolicited_headers_limit() {
+        let (mut syncmgr, rx) = self::syncmgr(Network::Mainnet);
+        let alice: PeerId = ([88, 13, 16, 59], 8333).into();
+        let time = LocalTime::default();
+
+        syncmgr.register(alice, 144, ServiceFlags::NETWORK, Link::Outbound, time);
+
+        for _ in 0..MAX_UNSOLICITED_HEADERS / MAX_HEADERS_ANNOUNCED {
+            assert!(syncmgr.allow_unsolicited(&alice, MAX_HEADERS_ANNOUNCED, time));
+        }
+        // The allowance is replenished after some time.
+        let time = time + UNSOLICITED_HEADERS_INTERVAL;
+        assert!(syncmgr.allow_unsolicited(&alice, MAX_UNSOLICITED_HEADERS, time));
+
+        // Peers exceeding their allowance are disconnected.
+        assert!(!syncmgr.allow_unsolicited(&alice, 1, time));
+        assert!(!syncmgr.peers.contains_key(&alice));
+        assert!(rx.try_iter().any(|o| matches!(
+            o,
+            Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if addr == alice
+        )));
+    }
+
+    #[test]
+    fn test_unconnecting_headers() {
+        let network = Network::Mainnet;
+        let mut tree = model::Cache::new(network.genesis());
+        let (mut syncmgr, rx) = self::syncmgr(network);
+        let alice: PeerId = ([88, 13, 16, 59], 8333).into();
+        let time = LocalTime::default();
+        let clock = AdjustedTime::<PeerId>::new(time);
+        let header = |nonce| BlockHeader {
+            version: 1,
+            prev_blockhash: BlockHash::hash(&[0xff]),
+            merkle_root: Default::default(),
+            time: 0,
+            bits: 0,
+            nonce,
+        };
+
+        syncmgr.register(alice, 144, ServiceFlags::NETWORK, Link::Outbound, time);
+        syncmgr
+            .received_headers(&alice, vec![header(0)], &clock, &mut tree)
+            .unwrap();
+
+        assert_eq!(syncmgr.peers[&alice].failures, 1);
+        assert!(
+            syncmgr.inflight.contains_key(&alice),
+            "we ask for the missing headers"
+        );
+        assert!(rx.try_iter().any(|o| matches!(
+            o,
+            Out::Event(crate::event::Event::SyncManager(Event::UnconnectingHeadersReceived(addr, 1)))
+                if addr == alice
+        )));
+
+        for nonce in 1..=MAX_UNCONNECTING_HEADERS as u32 {
+            syncmgr
+                .received_headers(&alice, vec![header(nonce)], &clock, &mut tree)
+                .unwrap();
+        }
+        assert_eq!(tree.height(), 0, "nothing was imported");
+        assert!(!syncmgr.peers.contains_key(&alice));
+        assert!(rx.try_iter().any(|o| matches!(
+            o,
+            Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if addr == alice
+        )));
+    }
+
+    #[test]
+    fn test_duplicate_headers() {
+        let network = Network::Mainnet;
+        let genesis = network.genesis();
+        let mut tree = model::Cache::new(genesis);
+        let (mut syncmgr, rx) = self::syncmgr(network);
+        let alice: PeerId = ([88, 13, 16, 59], 8333).into();
+        let time = LocalTime::default();
+        let clock = AdjustedTime::<PeerId>::new(time);
+        let h1 = BlockHeader {
+            prev_blockhash: genesis.block_hash(),
+            nonce: 1,
+            ..genesis
+        };
+        let h2 = BlockHeader {
+            prev_blockhash: h1.block_hash(),
+            nonce: 2,
+            ..genesis
+        };
+        let headers = vec![h1, h2];
+
+        tree.import_blocks(headers.iter().cloned(), &clock).unwrap();
+        syncmgr.register(alice, 2, ServiceFlags::NETWORK, Link::Outbound, time);
+        syncmgr.request(
+            alice,
+            (vec![genesis.block_hash()], BlockHash::default()),
+            time,
+            REQUEST_TIMEOUT,
+            OnTimeout::Disconnect,
+        );
+        rx.try_iter().for_each(drop);
+
+        assert_eq!(
+            syncmgr
+                .received_headers(&alice, headers, &clock, &mut tree)
+                .unwrap(),
+            ImportResult::TipUnchanged
+        );
+        assert_eq!(tree.height(), 2);
+        assert_eq!(rx.try_iter().count(), 0, "no events are emitted");
+        assert!(
+            !syncmgr.inflight.contains_key(&alice),
+            "the request was fulfilled"
+        );
+        assert_eq!(syncmgr.peers[&alice].failures, 0);
+    }
+}
@@ -1,10 +1,13 @@
 #![cfg(test)]
+mod conformance;
 pub mod simulator;
 
 use super::*;
 
 use simulator::PeerConfig;
 
+use crate::simulator::{LinkConfig, Simulation};
+
 use bitcoin::consensus::params::Params;
 use bitcoin::network::message_blockdata::Inventory;
 use bitcoin::network::Address;
@@ -21,13 +24,14 @@ use nakamoto_chain::block::store;
 use nakamoto_common::block::filter::FilterHeader;
 use nakamoto_common::block::store::{Genesis, Store};
 use nakamoto_common::block::BlockHeader;
+use nakamoto_common::p2p::netgroup::NetGroup;
 use nakamoto_common::p2p::peer::{KnownAddress, Source};
 
 use nakamoto_test::block::cache::model;
-use nakamoto_test::logger;
 use nakamoto_test::BITCOIN_HEADERS;
 
 use crate::protocol::{connmgr, pingmgr, Builder, Protocol};
+use crate::simulator::logger;
 
 fn payload(o: &Out) -> Option<(net::SocketAddr, &NetworkMessage)> {
     match o {
@@ -46,6 +50,9 @@ mod setup {
             network: network::Network::Mainnet,
             params: Params::new(network::Network::Mainnet.into()),
             connect: vec![],
+            connect_only: false,
+            anchors: vec![],
+            bans: vec![],
             // Pretend that we're a full-node, to fool connections
             // between instances of this protocol in tests.
             services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
@@ -53,11 +60,18 @@ mod setup {
             protocol_version: PROTOCOL_VERSION,
             target_outbound_peers: 8,
             max_inbound_peers: 8,
-            user_agent: USER_AGENT,
-            whitelist: Whitelist {
-                addr: HashSet::new(),
-                user_agent: vec![USER_AGENT.to_owned()].into_iter().collect(),
-            },
+            block_relay_peers: 0,
+            filter_peers: 0,
+            asmap: None,
+            peer_rotation: None,
+            advertise: addrmgr::Advertise::Never,
+            user_agent: USER_AGENT.to_owned(),
+            whitelist: Whitelist::with_user_agents(vec![], vec![USER_AGENT.to_owned()]),
+            journal: None,
+            recording: None,
+            interceptor: None,
+            memory_limits: memory::Limits::default(),
+            chain_state: state::SharedChainState::default(),
             target: "self",
         };
     }
@@ -128,17 +142,17 @@ mod setup {
         let (alice_tx, alice_rx) = chan::unbounded();
         let (bob_tx, bob_rx) = chan::unbounded();
 
-        let mut alice = builder.clone().build(alice_tx);
-        let mut bob = builder.build(bob_tx);
+        let alice = builder.clone().build(alice_tx);
+        let bob = builder.build(bob_tx);
 
         let alice_addr = ([152, 168, 3, 33], 3333).into();
         let bob_addr = ([152, 168, 7, 77], 7777).into();
 
-        simulator::handshake(
-            &mut alice,
+        let (alice, bob) = simulator::handshake(
+            alice,
             alice_addr,
             alice_rx.clone(),
-            &mut bob,
+            bob,
             bob_addr,
             bob_rx.clone(),
             time,
@@ -181,7 +195,11 @@ mod setup {
             if !addrmgr::is_routable(&addr.ip()) {
                 continue;
             }
-            if addrs.iter().any(|a| addr == *a) {
+            // Keep peers in distinct network groups, since we limit outbound
+            // connections to any one group.
+            if addrs.iter().any(|a: &net::SocketAddr| {
+                NetGroup::of(&addr.ip(), None) == NetGroup::of(&a.ip(), None)
+            }) {
                 continue;
             }
             addrs.push(addr);
@@ -202,6 +220,8 @@ mod setup {
                 // between instances of this protocol in tests.
                 services: config.required_services,
                 target: peer_cfg.name,
+                // Block-relay peers are tested separately.
+                block_relay_peers: 0,
                 ..config.clone()
             };
             configure(&mut cfg);
@@ -257,28 +277,18 @@ fn test_handshake() {
     let (alice_tx, alice_rx) = chan::unbounded();
     let (bob_tx, bob_rx) = chan::unbounded();
 
-    let mut alice = builder.clone().build(alice_tx);
-    let mut bob = builder.build(bob_tx);
+    let alice = builder.clone().build(alice_tx);
+    let bob = builder.build(bob_tx);
 
-    simulator::run(
-        vec![
-            (alice_addr, &mut alice, alice_rx),
-            (bob_addr, &mut bob, bob_rx),
-        ],
-        vec![
-            vec![Input::Connected {
-                addr: bob_addr,
-                local_addr: alice_addr,
-                link: Link::Outbound,
-            }],
-            vec![Input::Connected {
-                addr: alice_addr,
-                local_addr: bob_addr,
-                link: Link::Inbound,
-            }],
-        ],
-        local_time,
-    );
+    let mut sim = Simulation::new(local_time, fastrand::Rng::new(), LinkConfig::default());
+    sim.add_peer(alice_addr, alice, alice_rx);
+    sim.add_peer(bob_addr, bob, bob_rx);
+    sim.initialize();
+    sim.connect(&alice_addr, &bob_addr);
+    sim.run_until(local_time);
+
+    let alice = sim.peer(&alice_addr).unwrap();
+    let bob = sim.peer(&bob_addr).unwrap();
 
     assert!(
         alice.peermgr.peers().all(|p| p.is_negotiated()),
@@ -293,6 +303,421 @@ fn test_handshake() {
     );
 }
 
+#[test]
+fn test_simulation() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let builder = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::new(),
+        cfg: setup::CONFIG.clone(),
+    };
+    let alice: PeerId = ([152, 168, 3, 33], 8333).into();
+    let bob: PeerId = ([152, 168, 7, 77], 8333).into();
+    let eve: PeerId = ([152, 168, 9, 99], 8333).into();
+    let negotiated = |sim: &Simulation<_, _, _>, addr: &PeerId, remote: &PeerId| {
+        sim.peer(addr)
+            .unwrap()
+            .peermgr
+            .peers()
+            .any(|p| p.address() == *remote && p.is_negotiated())
+    };
+
+    let mut sim = Simulation::new(time, fastrand::Rng::with_seed(1), LinkConfig::default());
+    for addr in &[alice, bob, eve] {
+        let (tx, rx) = chan::unbounded();
+        sim.add_peer(*addr, builder.clone().build(tx), rx);
+    }
+    sim.set_link(
+        &alice,
+        &bob,
+        LinkConfig {
+            latency: LocalDuration::from_millis(100),
+            jitter: LocalDuration::from_millis(50),
+            ..LinkConfig::default()
+        },
+    );
+    sim.partition(&[alice], &[eve]);
+    sim.initialize();
+    sim.connect(&alice, &bob);
+    sim.connect(&alice, &eve);
+
+    // Messages take time to arrive.
+    sim.elapse(LocalDuration::from_millis(50));
+    assert!(!negotiated(&sim, &alice, &bob));
+
+    sim.elapse(LocalDuration::from_secs(1));
+    assert!(negotiated(&sim, &alice, &bob));
+    assert!(negotiated(&sim, &bob, &alice));
+
+    // Messages never cross a partition.
+    assert!(!negotiated(&sim, &alice, &eve));
+    assert!(!negotiated(&sim, &eve, &alice));
+}
+
+#[test]
+fn test_simulation_logs() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let builder = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::new(),
+        cfg: setup::CONFIG.clone(),
+    };
+    // Each simulation only captures the records of its own peers.
+    let simulate = |alice: PeerId, bob: PeerId| {
+        let logs = logger::Capture::new(Level::Debug);
+        let mut sim = Simulation::new(time, fastrand::Rng::with_seed(1), LinkConfig::default());
+
+        for addr in &[alice, bob] {
+            let (tx, rx) = chan::unbounded();
+            sim.add_peer(*addr, builder.clone().build(tx), rx);
+        }
+        sim.set_log_sink(logs.clone());
+        sim.initialize();
+        sim.connect(&alice, &bob);
+        sim.elapse(LocalDuration::from_secs(1));
+
+        logs
+    };
+    let first = simulate(
+        ([152, 168, 3, 33], 8333).into(),
+        ([152, 168, 7, 77], 8333).into(),
+    );
+    let second = simulate(
+        ([152, 169, 3, 33], 8333).into(),
+        ([152, 169, 7, 77], 8333).into(),
+    );
+
+    assert!(first.contains("152.168.7.77"));
+    assert!(!first.contains("152.169."));
+    assert!(second.contains("152.169.7.77"));
+    assert!(!second.contains("152.168."));
+    assert!(first
+        .entries()
+        .iter()
+        .all(|e| e.level <= Level::Debug && e.time >= time));
+
+    // Records emitted outside of a simulation aren't captured.
+    let count = first.entries().len();
+    debug!("Outside of a simulation");
+    assert_eq!(first.entries().len(), count);
+}
+
+#[test]
+fn test_simulation_costs() {
+    let network = Network::Mainnet;
+    let time = LocalTime::from_block_time(BITCOIN_HEADERS.last().time);
+    let alice: PeerId = ([152, 168, 3, 33], 8333).into();
+    let bob: PeerId = ([152, 168, 7, 77], 8333).into();
+    let latency = 100;
+    let negotiated = |sim: &Simulation<_, _, _>| {
+        [(alice, bob), (bob, alice)].iter().all(|(addr, remote)| {
+            sim.peer(addr)
+                .unwrap()
+                .peermgr
+                .peers()
+                .any(|p| p.address() == *remote && p.is_negotiated())
+        })
+    };
+
+    let mut sim = Simulation::new(
+        time,
+        fastrand::Rng::with_seed(1),
+        LinkConfig {
+            latency: LocalDuration::from_millis(latency),
+            ..LinkConfig::default()
+        },
+    );
+    for (addr, cache, seed) in vec![
+        (alice, model::Cache::new(network.genesis()), 1),
+        (bob, model::Cache::from(BITCOIN_HEADERS.clone()), 2),
+    ] {
+        let (tx, rx) = chan::unbounded();
+        let protocol = Builder {
+            cache,
+            clock: AdjustedTime::new(time),
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+            rng: fastrand::Rng::with_seed(seed),
+            cfg: setup::CONFIG.clone(),
+        }
+        .build(tx);
+
+        sim.add_peer(addr, protocol, rx);
+    }
+    sim.initialize();
+    sim.connect(&alice, &bob);
+
+    // The handshake completes within two round trips.
+    let elapsed = sim
+        .elapse_until(LocalDuration::from_secs(10), negotiated)
+        .expect("the handshake completes");
+    assert!(
+        elapsed <= LocalDuration::from_millis(latency * 4),
+        "handshake took {}",
+        elapsed
+    );
+    assert_eq!(sim.messages(&alice, "version"), 1);
+    assert_eq!(sim.messages(&alice, "verack"), 1);
+
+    // All headers fit in a single `headers` message, so syncing shouldn't take more than
+    // one request, and one to check that there are no more headers.
+    let height = BITCOIN_HEADERS.tail.len() as Height;
+    let synced = sim.elapse_until(LocalDuration::from_mins(1), |sim| {
+        sim.peer(&alice).unwrap().tree.height() == height
+    });
+
+    assert!(synced.is_some(), "alice is synced");
+    assert!(sim.messages(&alice, "getheaders") <= 2);
+    assert!(sim.messages(&bob, "headers") <= 2);
+}
+
+#[test]
+fn test_simulated_clock() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let builder = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::new(),
+        cfg: setup::CONFIG.clone(),
+    };
+    let alice: PeerId = ([152, 168, 3, 33], 8333).into();
+    let bob: PeerId = ([152, 168, 7, 77], 8333).into();
+    let eve: PeerId = ([152, 168, 9, 99], 8333).into();
+    let disconnected = |events: &[Event], addr: &PeerId| {
+        events.iter().any(
+            |e| matches!(e, Event::ConnManager(connmgr::Event::Disconnected(a, _)) if a == addr),
+        )
+    };
+
+    let mut sim = Simulation::new(time, fastrand::Rng::with_seed(1), LinkConfig::default());
+    for addr in &[alice, bob, eve] {
+        let (tx, rx) = chan::unbounded();
+        sim.add_peer(*addr, builder.clone().build(tx), rx);
+    }
+    sim.partition(&[alice], &[eve]);
+    sim.initialize();
+    sim.connect(&alice, &bob);
+    sim.connect(&alice, &eve);
+    sim.elapse(LocalDuration::from_secs(1));
+
+    assert!(sim.next_timeout(&alice).is_some());
+    assert!(sim.events(&alice).count() > 0);
+
+    // Eve never completes the handshake.
+    sim.elapse(peermgr::HANDSHAKE_TIMEOUT);
+    let events = sim.events(&alice).collect::<Vec<_>>();
+
+    assert!(disconnected(&events, &eve));
+    assert!(!disconnected(&events, &bob));
+
+    // Without new blocks, our tip eventually becomes stale. Bob stays connected, since
+    // he answers our pings.
+    sim.elapse(syncmgr::TIP_STALE_DURATION);
+    let events = sim.events(&alice).collect::<Vec<_>>();
+
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::SyncManager(syncmgr::Event::StaleTipDetected(_)))));
+    assert!(!disconnected(&events, &bob));
+
+    // Bob stops answering pings.
+    sim.partition(&[alice], &[bob]);
+    sim.elapse(pingmgr::PING_INTERVAL + pingmgr::PING_TIMEOUT + connmgr::IDLE_TIMEOUT);
+    let events = sim.events(&alice).collect::<Vec<_>>();
+
+    assert!(disconnected(&events, &bob));
+}
+
+#[test]
+fn test_simulation_scale() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let mut rng = fastrand::Rng::with_seed(1);
+    let addrs = (0..1000)
+        .map(|i| ([20 + (i % 200) as u8, 13, (i / 200) as u8, 1], 8333).into())
+        .collect::<Vec<PeerId>>();
+    let link = LinkConfig {
+        latency: LocalDuration::from_millis(20),
+        jitter: LocalDuration::from_millis(20),
+        ..LinkConfig::default()
+    };
+
+    let mut sim = Simulation::new(time, rng.clone(), link);
+    for (i, addr) in addrs.iter().enumerate() {
+        let (tx, rx) = chan::unbounded();
+        let protocol = Builder {
+            cache: model::Cache::new(genesis),
+            clock: AdjustedTime::new(time),
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+            rng: fastrand::Rng::with_seed(i as u64),
+            cfg: setup::CONFIG.clone(),
+        }
+        .build(tx);
+
+        sim.add_peer(*addr, protocol, rx);
+    }
+    sim.set_event_limit(8);
+    sim.initialize();
+
+    // Peers form a ring, with a random chord out of every peer.
+    let mut links = HashSet::new();
+    for i in 0..addrs.len() {
+        let j = rng.usize(..addrs.len());
+
+        for &k in &[(i + 1) % addrs.len(), j] {
+            if k != i && links.insert((i.min(k), i.max(k))) {
+                sim.connect(&addrs[i], &addrs[k]);
+            }
+        }
+    }
+    sim.elapse(LocalDuration::from_mins(1));
+
+    assert_eq!(sim.addresses().count(), addrs.len());
+
+    for addr in &addrs {
+        assert!(
+            sim.peer(addr)
+                .unwrap()
+                .peermgr
+                .peers()
+                .any(|p| p.is_negotiated()),
+            "{} is connected",
+            addr
+        );
+        assert!(sim.events(addr).count() <= 8);
+    }
+}
+
+#[test]
+fn test_partition_healing() {
+    use nakamoto_common::block::tree::ImportResult;
+    use nakamoto_test::block::solve;
+
+    let network = Network::Regtest;
+    let params = Params::new(network.into());
+    let genesis = network.genesis();
+    let time = LocalTime::from_block_time(genesis.time + 60 * 60 * 24);
+    // Mine a chain of the given length on top of the given block.
+    let mine = |prev: &BlockHeader, length: usize, salt: u32| {
+        let mut chain: Vec<BlockHeader> = Vec::with_capacity(length);
+
+        for _ in 0..length {
+            let prev = chain.last().unwrap_or(prev);
+            let mut header = BlockHeader {
+                version: 1,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: Default::default(),
+                bits: prev.bits,
+                time: prev.time + 600 + salt,
+                nonce: 0,
+            };
+            solve(&mut header);
+            chain.push(header);
+        }
+        chain
+    };
+    let alice: PeerId = ([152, 168, 3, 33], 8333).into();
+    let bob: PeerId = ([152, 168, 7, 77], 8333).into();
+    let carol: PeerId = ([152, 169, 3, 33], 8333).into();
+    let dave: PeerId = ([152, 169, 7, 77], 8333).into();
+    let reverted =
+        |events: &[Event]| {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    Event::SyncManager(syncmgr::Event::HeadersImported(
+                        ImportResult::TipChanged(_, _, reverted),
+                    )) if !reverted.is_empty() => Some(reverted.iter().cloned().collect()),
+                    _ => None,
+                })
+                .collect::<Vec<HashSet<_>>>()
+        };
+
+    let mut sim = Simulation::new(time, fastrand::Rng::with_seed(1), LinkConfig::default());
+    for (i, addr) in [alice, bob, carol, dave].iter().enumerate() {
+        let (tx, rx) = chan::unbounded();
+        let protocol = Builder {
+            cache: BlockCache::from(store::Memory::genesis(network), params.clone(), &[]).unwrap(),
+            clock: AdjustedTime::new(time),
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+            rng: fastrand::Rng::with_seed(i as u64),
+            cfg: Config {
+                network,
+                params: params.clone(),
+                ..setup::CONFIG.clone()
+            },
+        }
+        .build(tx);
+
+        sim.add_peer(*addr, protocol, rx);
+    }
+    sim.initialize();
+    // Peers announce new blocks to their inbound peers.
+    sim.connect(&bob, &alice);
+    sim.connect(&dave, &carol);
+    sim.connect(&alice, &carol);
+    sim.elapse(LocalDuration::from_secs(1));
+
+    // Each side of the partition builds its own chain. Carol's side has more work.
+    sim.partition(&[alice, bob], &[carol, dave]);
+
+    let a = mine(&genesis, 3, 0);
+    let b = mine(&genesis, 5, 1);
+
+    sim.import_headers(&alice, a.clone()).unwrap().unwrap();
+    sim.import_headers(&carol, b.clone()).unwrap().unwrap();
+    sim.elapse(LocalDuration::from_secs(1));
+
+    assert_eq!(sim.peer(&bob).unwrap().tree.tip().0, a[2].block_hash());
+    assert_eq!(sim.peer(&dave).unwrap().tree.tip().0, b[4].block_hash());
+
+    // Once the partition heals, the next block brings everyone onto the most-work chain.
+    sim.heal();
+
+    let b = [b, mine(&b[4], 1, 1)].concat();
+
+    sim.import_headers(&carol, b[5..].to_vec())
+        .unwrap()
+        .unwrap();
+    sim.elapse(LocalDuration::from_secs(1));
+
+    for addr in &[alice, bob, carol, dave] {
+        let peer = sim.peer(addr).unwrap();
+
+        assert_eq!(peer.tree.tip().0, b[5].block_hash(), "{}", addr);
+        assert_eq!(peer.tree.height(), 6, "{}", addr);
+    }
+
+    // Alice and Bob re-org'ed away from their chain, while Carol and Dave didn't re-org.
+    let stale = a.iter().map(|h| h.block_hash()).collect::<HashSet<_>>();
+
+    for addr in &[alice, bob] {
+        let events = sim.events(addr).collect::<Vec<_>>();
+        assert_eq!(reverted(&events), vec![stale.clone()], "{}", addr);
+    }
+    for addr in &[carol, dave] {
+        let events = sim.events(addr).collect::<Vec<_>>();
+        assert!(reverted(&events).is_empty(), "{}", addr);
+    }
+}
+
 #[test]
 #[allow(clippy::redundant_clone)]
 fn test_initial_sync() {
@@ -329,7 +754,7 @@ fn test_initial_sync() {
     )
     .unwrap();
 
-    let mut alice = Protocol::new(
+    let alice = Protocol::new(
         alice_tree,
         filters.clone(),
         peers.clone(),
@@ -340,8 +765,8 @@ fn test_initial_sync() {
     );
 
     // Bob connects to Alice.
-    {
-        let mut bob = Protocol::new(
+    let mut alice = {
+        let bob = Protocol::new(
             bob_tree,
             filters,
             peers,
@@ -351,11 +776,11 @@ fn test_initial_sync() {
             bob_tx,
         );
 
-        simulator::handshake(
-            &mut bob,
+        let (bob, alice) = simulator::handshake(
+            bob,
             bob_addr,
             bob_rx.clone(),
-            &mut alice,
+            alice,
             alice_addr,
             alice_rx.clone(),
             local_time,
@@ -363,7 +788,9 @@ fn test_initial_sync() {
 
         assert_eq!(alice.tree.height(), height);
         assert_eq!(bob.tree.height(), height);
-    }
+
+        alice
+    };
     alice.step(
         Input::Disconnected(bob_addr, DisconnectReason::PeerTimeout),
         local_time,
@@ -428,7 +855,7 @@ fn test_getheaders_timeout() {
             .unwrap();
 
     local.step(
-        Input::Received(
+        Input::received(
             remote_addr,
             msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hash)])),
         ),
@@ -577,7 +1004,7 @@ fn test_getheaders_retry(seed: u64) {
     // Trigger a `getheaders` by sending an inventory message to Alice.
     let result = sim.input(
         &alice,
-        Input::Received(
+        Input::received(
             bob,
             msg.raw(NetworkMessage::Inv(vec![Inventory::Block(hash)])),
         ),
@@ -661,13 +1088,11 @@ fn test_handshake_verack_timeout() {
         );
 
         instance.step(
-            Input::Received(
+            Input::received(
                 remote,
                 RawNetworkMessage {
                     magic: network.magic(),
-                    payload: NetworkMessage::Version(
-                        instance.peermgr.version(local, remote, 0, 0, time),
-                    ),
+                    payload: NetworkMessage::Version(instance.peermgr.version(local, 0, 0, time)),
                 },
             ),
             time,
@@ -717,19 +1142,17 @@ fn test_handshake_initial_messages() {
     );
 
     instance.step(
-        Input::Received(
+        Input::received(
             remote,
             RawNetworkMessage {
                 magic: network.magic(),
-                payload: NetworkMessage::Version(
-                    instance.peermgr.version(local, remote, 0, 0, time),
-                ),
+                payload: NetworkMessage::Version(instance.peermgr.version(local, 0, 0, time)),
             },
         ),
         time,
     );
     instance.step(
-        Input::Received(
+        Input::received(
             remote,
             RawNetworkMessage {
                 magic: network.magic(),
@@ -825,7 +1248,7 @@ fn test_getaddr() {
     let toto: net::SocketAddr = ([14, 45, 16, 57], 8333).into();
     sim.input(
         &alice,
-        Input::Received(
+        Input::received(
             peer,
             msg.raw(NetworkMessage::Addr(vec![(
                 0,
@@ -868,7 +1291,7 @@ fn test_stale_tip() {
         .peer("bob")
         .protocol
         .peermgr
-        .version(alice, bob, 1, 144, time);
+        .version(alice, 1, 144, time);
 
     // Handshake.
     sim.input(
@@ -881,15 +1304,15 @@ fn test_stale_tip() {
     );
     sim.input(
         &alice,
-        Input::Received(bob, msg.raw(NetworkMessage::Version(version))),
+        Input::received(bob, msg.raw(NetworkMessage::Version(version))),
     );
     sim.input(
         &alice,
-        Input::Received(bob, msg.raw(NetworkMessage::Verack)),
+        Input::received(bob, msg.raw(NetworkMessage::Verack)),
     );
     sim.input(
         &alice,
-        Input::Received(
+        Input::received(
             bob,
             msg.raw(NetworkMessage::Headers(vec![*BITCOIN_HEADERS
                 .get(1)
@@ -914,7 +1337,7 @@ fn test_stale_tip() {
     // Now send another header and wait until the chain update is stale.
     sim.input(
         &alice,
-        Input::Received(
+        Input::received(
             bob,
             msg.raw(NetworkMessage::Headers(vec![*BITCOIN_HEADERS
                 .get(2)
@@ -959,7 +1382,7 @@ fn test_addrs() {
     // Let alice know about these amazing peers.
     sim.input(
         &alice,
-        Input::Received(
+        Input::received(
             bob,
             msg.raw(NetworkMessage::Addr(vec![
                 (0, Address::new(&jak, setup::CONFIG.required_services)),
@@ -972,7 +1395,7 @@ fn test_addrs() {
     // Let's make sure Alice has these addresses.
     let result = sim.input(
         &alice,
-        Input::Received(bob, msg.raw(NetworkMessage::GetAddr)),
+        Input::received(bob, msg.raw(NetworkMessage::GetAddr)),
     );
     let (_, msg) = result.message(|_, msg| matches!(msg, NetworkMessage::Addr(_)));
 
@@ -1053,3 +1476,2315 @@ fn prop_connect_timeout(seed: u64) {
         })
         .expect("Alice tries to connect to another peer");
 }
+
+#[test]
+fn test_ban() {
+    let (mut alice, rx, time) = setup::singleton(Network::Mainnet);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(
+        Input::Command(Command::Ban(
+            bob.ip(),
+            LocalDuration::from_mins(60),
+            "misbehaving".to_owned(),
+        )),
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::ConnManager(connmgr::Event::Banned(ip, _))) if ip == bob.ip()
+    )));
+
+    // We don't connect to banned peers.
+    alice.step(Input::Command(Command::Connect(bob)), time);
+    assert!(!rx.try_iter().any(|o| matches!(o, Out::Connect(_, _))));
+
+    // Nor do we accept connections from them.
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::PeerBanned) if addr == bob
+    )));
+
+    // Once the ban expires, it is lifted.
+    alice.step(Input::Timeout, time + LocalDuration::from_mins(60));
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Event(Event::ConnManager(connmgr::Event::Unbanned(ip))) if ip == bob.ip()
+    )));
+}
+
+#[test]
+fn test_rejected_inbound_peer() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(
+        Input::Command(Command::Ban(
+            bob.ip(),
+            LocalDuration::from_mins(60),
+            "misbehaving".to_owned(),
+        )),
+        time,
+    );
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::PeerBanned) if addr == bob
+    )));
+    assert!(alice.connmgr.is_disconnecting(&bob));
+
+    // Messages sent before the disconnection completes are ignored.
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Ping(42))),
+        time,
+    );
+    assert!(!rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Message(addr, _) if addr == bob)));
+
+    // Once disconnected, the peer is no longer tracked.
+    alice.step(Input::Disconnected(bob, DisconnectReason::PeerBanned), time);
+    assert!(!alice.connmgr.inbound_peers().any(|a| *a == bob));
+    assert!(!alice.connmgr.is_disconnecting(&bob));
+}
+
+#[test]
+fn test_ban_expired() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let carol: net::SocketAddr = ([99, 45, 180, 58], 8333).into();
+    let (tx, rx) = chan::unbounded();
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            bans: vec![
+                (
+                    bob.ip(),
+                    peer::Ban::new("spam", time - LocalDuration::from_mins(1)),
+                ),
+                (
+                    carol.ip(),
+                    peer::Ban::new("spam", time + LocalDuration::from_mins(60)),
+                ),
+            ],
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+
+    alice.initialize(time);
+    alice.addrmgr.insert(
+        vec![
+            (0, Address::new(&bob, ServiceFlags::NETWORK)),
+            (0, Address::new(&carol, ServiceFlags::NETWORK)),
+        ]
+        .into_iter(),
+        Source::Dns,
+    );
+    rx.try_iter().for_each(drop);
+
+    // Expired bans don't prevent connections, even before they are lifted.
+    alice.step(Input::Command(Command::Connect(bob)), time);
+    assert!(rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)));
+
+    // Banned addresses are never picked from the address book.
+    alice.step(Input::Timeout, time + connmgr::IDLE_TIMEOUT);
+    assert!(!rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == carol)));
+}
+
+#[test]
+fn test_inbound_eviction() {
+    let (mut alice, rx, time) = setup::singleton(Network::Mainnet);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let max = 24;
+
+    alice.connmgr.config.max_inbound_peers = max;
+
+    for i in 0..max {
+        alice.step(
+            Input::Connected {
+                addr: ([88, i as u8, 16, 1], 8333).into(),
+                local_addr,
+                link: Link::Inbound,
+            },
+            time,
+        );
+    }
+    assert!(!rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Disconnect(_, DisconnectReason::ConnectionLimit))));
+
+    // When the inbound limit is reached, an existing peer is evicted to make room.
+    let peer: net::SocketAddr = ([99, 99, 16, 1], 8333).into();
+    alice.step(
+        Input::Connected {
+            addr: peer,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time + LocalDuration::from_secs(1),
+    );
+
+    let evicted = rx
+        .try_iter()
+        .find_map(|o| match o {
+            Out::Disconnect(addr, DisconnectReason::ConnectionLimit) => Some(addr),
+            _ => None,
+        })
+        .expect("a peer is evicted");
+
+    assert_ne!(evicted, peer);
+    assert!(alice.connmgr.inbound_peers().any(|a| *a == peer));
+
+    // Peers that are already being evicted aren't picked again, eg. during a burst of
+    // inbound connections.
+    let peer: net::SocketAddr = ([99, 98, 16, 1], 8333).into();
+    alice.step(
+        Input::Connected {
+            addr: peer,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time + LocalDuration::from_secs(2),
+    );
+    let evicted_again = rx
+        .try_iter()
+        .find_map(|o| match o {
+            Out::Disconnect(addr, DisconnectReason::ConnectionLimit) => Some(addr),
+            _ => None,
+        })
+        .expect("another peer is evicted");
+
+    assert_ne!(evicted_again, evicted);
+    assert_ne!(evicted_again, peer);
+}
+
+#[test]
+fn test_block_relay_peers() {
+    let (mut alice, rx, mut time) = setup::singleton(Network::Mainnet);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let msg = message::Builder::new(Network::Mainnet);
+    let addrs: Vec<net::SocketAddr> = vec![
+        ([88, 13, 16, 59], 8333).into(),
+        ([99, 45, 180, 58], 8333).into(),
+        ([14, 48, 141, 57], 8333).into(),
+    ];
+
+    alice.connmgr.config.target_outbound_peers = 2;
+    alice.connmgr.config.block_relay_peers = 1;
+    alice.addrmgr.insert(
+        addrs
+            .iter()
+            .map(|a| (0, Address::new(a, setup::CONFIG.required_services))),
+        Source::Dns,
+    );
+
+    // We connect to the full-relay peers first, then to the block-relay peers.
+    let mut connecting = Vec::new();
+    for _ in 0..addrs.len() {
+        time = time + connmgr::IDLE_TIMEOUT;
+        alice.step(Input::Timeout, time);
+
+        let outputs = rx.try_iter().collect::<Vec<_>>();
+        for o in outputs {
+            if let Out::Connect(addr, _) = o {
+                alice.step(Input::Connecting { addr }, time);
+                connecting.push(addr);
+            }
+        }
+    }
+    assert_eq!(connecting.len(), addrs.len());
+
+    let block_relay = connecting
+        .iter()
+        .filter(|a| alice.connmgr.is_block_relay(a))
+        .collect::<Vec<_>>();
+    assert_eq!(block_relay, vec![connecting.last().unwrap()]);
+
+    // Once our limits are reached, we don't connect to more peers.
+    time = time + connmgr::IDLE_TIMEOUT;
+    alice.step(Input::Timeout, time);
+    assert!(!rx.try_iter().any(|o| matches!(o, Out::Connect(_, _))));
+
+    // Addresses aren't exchanged with block-relay peers.
+    let peer = *block_relay[0];
+    alice.step(
+        Input::Connected {
+            addr: peer,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(
+            peer,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local_addr, 0, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::received(peer, msg.raw(NetworkMessage::Verack)), time);
+    assert!(
+        rx.try_iter().any(|o| matches!(
+            o,
+            Out::Event(Event::ConnManager(connmgr::Event::BlockRelayNegotiated(a))) if a == peer
+        )),
+        "block-relay peers are reported, so that they can be used as anchors"
+    );
+    alice.step(
+        Input::received(peer, msg.raw(NetworkMessage::GetAddr)),
+        time,
+    );
+    assert!(!rx
+        .try_iter()
+        .any(|o| matches!(payload(&o), Some((_, NetworkMessage::Addr(_))))));
+}
+
+#[test]
+fn test_filter_peers() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let mut time = LocalTime::from_secs(genesis.time as u64);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let source: net::SocketAddr = ([45, 12, 138, 2], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let carol: net::SocketAddr = ([99, 45, 180, 58], 8333).into();
+    let dave: net::SocketAddr = ([14, 48, 141, 57], 8333).into();
+    let (tx, rx) = chan::unbounded();
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            target_outbound_peers: 1,
+            required_services: ServiceFlags::NETWORK,
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+
+    alice.initialize(time);
+    alice.addrmgr.insert(
+        vec![(0, Address::new(&bob, ServiceFlags::NETWORK))].into_iter(),
+        Source::Peer(source),
+    );
+
+    // We connect to a regular peer, which doesn't offer compact filters.
+    time = time + connmgr::IDLE_TIMEOUT;
+    alice.step(Input::Timeout, time);
+    assert!(rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)));
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.connmgr.peer_negotiated(bob, ServiceFlags::NETWORK);
+
+    // Once we require a compact filter peer, an additional connection is made to a
+    // peer offering them.
+    alice.connmgr.config.filter_peers = 1;
+    alice.addrmgr.insert(
+        vec![
+            (0, Address::new(&carol, ServiceFlags::NETWORK)),
+            (
+                0,
+                Address::new(&dave, ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS),
+            ),
+        ]
+        .into_iter(),
+        Source::Peer(source),
+    );
+    time = time + connmgr::IDLE_TIMEOUT;
+    alice.step(Input::Timeout, time);
+
+    let connecting = rx
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Connect(addr, _) => Some(addr),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(connecting, vec![dave]);
+
+    // No other connections are made.
+    alice.step(Input::Connecting { addr: dave }, time);
+    time = time + connmgr::IDLE_TIMEOUT;
+    alice.step(Input::Timeout, time);
+    assert!(!rx.try_iter().any(|o| matches!(o, Out::Connect(_, _))));
+}
+
+#[test]
+fn test_connect_only() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let eve: net::SocketAddr = ([99, 45, 180, 58], 8333).into();
+    let (tx, rx) = chan::unbounded();
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            connect: vec![bob],
+            connect_only: true,
+            target_outbound_peers: 0,
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+
+    // We connect to the configured peer, regardless of the outbound peer target.
+    alice.initialize(time);
+    assert!(rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)));
+
+    // Inbound connections are refused.
+    alice.step(
+        Input::Connected {
+            addr: eve,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::ConnectionLimit) if addr == eve
+    )));
+
+    // Addresses received from our peer are ignored.
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local_addr, 0, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    alice.step(
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Addr(vec![(
+                0,
+                Address::new(&eve, setup::CONFIG.required_services),
+            )])),
+        ),
+        time,
+    );
+    assert!(alice.addrmgr.is_empty());
+
+    // When our peer disconnects, we reconnect to it.
+    alice.step(
+        Input::Disconnected(bob, DisconnectReason::PeerTimeout),
+        time,
+    );
+    assert!(rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)));
+}
+
+#[test]
+fn test_peer_rotation() {
+    let (mut alice, rx, time) = setup::singleton(Network::Mainnet);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let interval = LocalDuration::from_mins(30);
+    let peers: Vec<net::SocketAddr> = vec![
+        ([88, 13, 16, 59], 8333).into(),
+        ([99, 45, 180, 58], 8333).into(),
+        ([14, 48, 141, 57], 8333).into(),
+    ];
+
+    alice.connmgr.config.rotation = Some(connmgr::Rotation { interval, count: 1 });
+
+    for (i, addr) in peers.iter().enumerate() {
+        alice.step(
+            Input::Connected {
+                addr: *addr,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time + LocalDuration::from_secs(i as u64),
+        );
+    }
+    rx.try_iter().for_each(drop);
+
+    // Once the rotation interval has elapsed, the oldest outbound peer is disconnected.
+    let now = time + interval + LocalDuration::from_secs(peers.len() as u64);
+    for addr in &peers {
+        alice.connmgr.peer_active(addr, now);
+    }
+    alice.step(Input::Timeout, now);
+    let rotated = rx
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Disconnect(addr, DisconnectReason::PeerRotated) => Some(addr),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(rotated, vec![peers[0]]);
+
+    // Peers we're already disconnecting from aren't rotated again.
+    let now = now + interval;
+    for addr in &peers {
+        alice.connmgr.peer_active(addr, now);
+    }
+    alice.step(Input::Timeout, now);
+    let rotated = rx
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Disconnect(addr, DisconnectReason::PeerRotated) => Some(addr),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(rotated, vec![peers[1]]);
+}
+
+#[test]
+fn test_addr_relay() {
+    let (mut alice, rx, time) = setup::singleton(Network::Mainnet);
+    let services = setup::CONFIG.required_services;
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let eve: net::SocketAddr = ([14, 48, 141, 57], 8333).into();
+    let peers: Vec<net::SocketAddr> = vec![
+        bob,
+        ([99, 45, 180, 58], 8333).into(),
+        ([183, 8, 55, 2], 8333).into(),
+        ([211, 48, 99, 4], 8333).into(),
+    ];
+
+    for peer in peers.iter() {
+        alice.addrmgr.peer_connected(peer, time);
+        alice
+            .addrmgr
+            .peer_negotiated(peer, services, Link::Outbound, false, time);
+    }
+    rx.try_iter().for_each(drop);
+
+    // Bob announces a fresh address to us.
+    let announcement = vec![(time.block_time(), Address::new(&eve, services))];
+    alice
+        .addrmgr
+        .received_addr(bob, announcement.clone(), time)
+        .unwrap();
+
+    // Addresses are not relayed immediately.
+    assert!(!rx
+        .try_iter()
+        .any(|o| matches!(payload(&o), Some((_, NetworkMessage::Addr(_))))));
+
+    // After some time, the address is relayed to two peers, but not back to Bob.
+    alice.step(Input::Timeout, time + LocalDuration::from_mins(1));
+    let relayed = rx
+        .try_iter()
+        .filter_map(|o| match payload(&o) {
+            Some((addr, NetworkMessage::Addr(addrs))) => {
+                assert_eq!(addrs, &announcement);
+                Some(addr)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(relayed.len(), 2);
+    assert!(!relayed.contains(&bob));
+
+    // The same address is not relayed twice to the same peer.
+    for peer in relayed.iter() {
+        alice
+            .addrmgr
+            .received_addr(*peer, announcement.clone(), time)
+            .unwrap();
+    }
+    alice.step(Input::Timeout, time + LocalDuration::from_mins(2));
+    assert!(!rx.try_iter().any(
+        |o| matches!(payload(&o), Some((addr, NetworkMessage::Addr(_))) if relayed.contains(&addr))
+    ));
+}
+
+#[test]
+fn test_addr_flood() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let services = setup::CONFIG.required_services;
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local_addr, 0, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // Bob sends more addresses than allowed in a single message.
+    let addrs = (0..1001u32)
+        .map(|i| {
+            let ip = net::Ipv4Addr::from(0x0b00_0000 + i);
+            (0, Address::new(&(ip, 8333).into(), services))
+        })
+        .collect();
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Addr(addrs))),
+        time,
+    );
+
+    assert!(alice.addrmgr.is_empty());
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if addr == bob
+    )));
+}
+
+#[test]
+fn test_peer_disconnected() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let carol: net::SocketAddr = ([99, 45, 180, 58], 8333).into();
+
+    alice.addrmgr.insert(
+        vec![bob, carol]
+            .iter()
+            .map(|a| (0, Address::new(a, setup::CONFIG.required_services))),
+        Source::Dns,
+    );
+    for (addr, reason) in vec![
+        (bob, DisconnectReason::PeerDisconnected),
+        (
+            carol,
+            DisconnectReason::ConnectionError("connection reset".to_owned()),
+        ),
+    ] {
+        alice.step(
+            Input::Connected {
+                addr,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(Input::Disconnected(addr, reason), time);
+    }
+    assert_eq!(
+        alice.addrmgr.len(),
+        1,
+        "peers closing the connection are kept in the address book"
+    );
+}
+
+#[test]
+fn test_oversized_messages() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let header = BITCOIN_HEADERS.tail[0];
+    let block = header.block_hash();
+
+    for oversized in vec![
+        NetworkMessage::Headers(vec![header; syncmgr::MAX_MESSAGE_HEADERS + 1]),
+        NetworkMessage::Inv(vec![Inventory::Block(block); syncmgr::MAX_MESSAGE_INVS + 1]),
+    ] {
+        alice.step(
+            Input::Connected {
+                addr: bob,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::received(
+                bob,
+                msg.raw(NetworkMessage::Version(
+                    alice.peermgr.version(local_addr, 0, 144, time),
+                )),
+            ),
+            time,
+        );
+        alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+        rx.try_iter().for_each(drop);
+
+        alice.step(Input::received(bob, msg.raw(oversized)), time);
+
+        let outputs = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(alice.tree.height(), 0, "headers aren't imported");
+        assert!(
+            !outputs
+                .iter()
+                .any(|o| matches!(payload(o), Some((_, NetworkMessage::GetHeaders(_))))),
+            "headers aren't requested"
+        );
+        assert!(outputs
+            .iter()
+            .any(|o| matches!(o, Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if *addr == bob)));
+
+        alice.step(Input::Disconnected(bob, DisconnectReason::Command), time);
+    }
+}
+
+#[test]
+fn test_handshake_ordering() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let version = alice.peermgr.version(local_addr, 0, 0, time);
+    let cases: &[(&[NetworkMessage], &str)] = &[
+        (
+            &[NetworkMessage::Verack],
+            "`verack` received before `version`",
+        ),
+        (
+            &[NetworkMessage::GetAddr],
+            "message received before `version`",
+        ),
+        (
+            &[
+                NetworkMessage::Version(version.clone()),
+                NetworkMessage::Version(version.clone()),
+            ],
+            "duplicate `version` received",
+        ),
+        (
+            &[
+                NetworkMessage::Version(version.clone()),
+                NetworkMessage::Ping(1),
+            ],
+            "message received before `verack`",
+        ),
+        (
+            &[
+                NetworkMessage::Version(version.clone()),
+                NetworkMessage::Verack,
+                NetworkMessage::Verack,
+            ],
+            "duplicate `verack` received",
+        ),
+    ];
+
+    for (msgs, reason) in cases {
+        alice.step(
+            Input::Connected {
+                addr: bob,
+                local_addr,
+                link: Link::Inbound,
+            },
+            time,
+        );
+        for m in msgs.iter() {
+            alice.step(Input::received(bob, msg.raw(m.clone())), time);
+        }
+        assert!(
+            rx.try_iter().any(|o| matches!(
+                o,
+                Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(r)) if addr == bob && r == *reason
+            )),
+            "{}",
+            reason
+        );
+        alice.step(
+            Input::Disconnected(bob, DisconnectReason::PeerMisbehaving(*reason)),
+            time,
+        );
+    }
+}
+
+#[test]
+fn test_handshake_unsolicited_traffic() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let headers = BITCOIN_HEADERS.tail.iter().take(2).cloned().collect();
+    let block = BITCOIN_HEADERS.tail[0].block_hash();
+
+    for unsolicited in vec![
+        NetworkMessage::Headers(headers),
+        NetworkMessage::Inv(vec![Inventory::Block(block)]),
+    ] {
+        alice.step(
+            Input::Connected {
+                addr: bob,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        alice.step(
+            Input::received(
+                bob,
+                msg.raw(NetworkMessage::Version(
+                    alice.peermgr.version(local_addr, 0, 144, time),
+                )),
+            ),
+            time,
+        );
+        rx.try_iter().for_each(drop);
+
+        // Bob hasn't sent his `verack` yet.
+        alice.step(Input::received(bob, msg.raw(unsolicited)), time);
+
+        let outputs = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(alice.tree.height(), 0, "headers aren't imported");
+        assert!(
+            !outputs
+                .iter()
+                .any(|o| matches!(payload(o), Some((_, NetworkMessage::GetHeaders(_))))),
+            "headers aren't requested"
+        );
+        assert!(outputs
+            .iter()
+            .any(|o| matches!(o, Out::Disconnect(addr, DisconnectReason::PeerMisbehaving(_)) if *addr == bob)));
+
+        alice.step(Input::Disconnected(bob, DisconnectReason::Command), time);
+    }
+}
+
+#[test]
+fn test_get_header() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let genesis = network.genesis();
+
+    let (tx, rx) = chan::bounded(1);
+    alice.step(
+        Input::Command(Command::GetHeader(genesis.block_hash(), tx)),
+        time,
+    );
+    assert_eq!(rx.recv().unwrap(), Some((0, genesis)));
+
+    let (tx, rx) = chan::bounded(1);
+    alice.step(Input::Command(Command::GetHeaderByHeight(0, tx)), time);
+    assert_eq!(rx.recv().unwrap(), Some(genesis));
+
+    let (tx, rx) = chan::bounded(1);
+    alice.step(Input::Command(Command::GetHeaderByHeight(1, tx)), time);
+    assert_eq!(rx.recv().unwrap(), None);
+
+    let (tx, rx) = chan::bounded(1);
+    alice.step(Input::Command(Command::GetFilterHeader(0, tx)), time);
+    assert_eq!(
+        rx.recv().unwrap().map(|(_, header)| header),
+        Some(FilterHeader::genesis(network))
+    );
+
+    let (tx, rx) = chan::bounded(1);
+    alice.step(Input::Command(Command::GetFilterHeight(tx)), time);
+    assert_eq!(rx.recv().unwrap(), 0);
+}
+
+#[test]
+fn test_get_filters_without_peers() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+
+    let (tx, rx) = chan::bounded(1);
+    alice.step(Input::Command(Command::GetFilters(0..1, tx)), time);
+    assert_eq!(
+        rx.recv().unwrap(),
+        Err(spvmgr::GetFiltersError::NotConnected)
+    );
+}
+
+#[test]
+fn test_get_peers() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let get_peers = |alice: &mut Protocol<_, _, _>| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetPeers(tx)), time);
+        rx.recv().unwrap()
+    };
+    let version = alice.peermgr.version(local_addr, 1, 144, time);
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Version(version.clone()))),
+        time,
+    );
+    assert!(
+        get_peers(&mut alice).is_empty(),
+        "peers are only listed once negotiated"
+    );
+
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+
+    let peers: Vec<peermgr::PeerInfo> = get_peers(&mut alice);
+    let peer = &peers[0];
+
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peer.addr, bob);
+    assert_eq!(peer.link, Link::Inbound);
+    assert_eq!(peer.height, 144);
+    assert_eq!(peer.user_agent, version.user_agent);
+    assert_eq!(peer.since, time);
+}
+
+#[test]
+fn test_synced_best_height() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let carol: net::SocketAddr = ([99, 45, 180, 58], 8333).into();
+    let dave: net::SocketAddr = ([131, 25, 4, 7], 8333).into();
+    let synced = |rx: &chan::Receiver<Out>| {
+        rx.try_iter().any(|o| {
+            matches!(
+                o,
+                Out::Event(Event::SyncManager(syncmgr::Event::Synced(_, _)))
+            )
+        })
+    };
+    let connect = |alice: &mut Protocol<_, _, _>, addr, height| {
+        alice.step(
+            Input::Connected {
+                addr,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        let version = alice
+            .peermgr
+            .version(local_addr, fastrand::u64(..), height, time);
+
+        alice.step(
+            Input::received(addr, msg.raw(NetworkMessage::Version(version))),
+            time,
+        );
+    };
+
+    connect(&mut alice, bob, 0);
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    assert!(synced(&rx), "we're as high as our only peer");
+
+    // Carol is ahead of us, but hasn't completed the handshake yet.
+    connect(&mut alice, carol, 144);
+    connect(&mut alice, dave, 0);
+    alice.step(Input::received(dave, msg.raw(NetworkMessage::Verack)), time);
+    assert!(synced(&rx), "peers in handshake are not taken into account");
+
+    // Once Carol is negotiated, we're behind, even though other peers are lagging.
+    alice.step(
+        Input::received(carol, msg.raw(NetworkMessage::Verack)),
+        time,
+    );
+
+    let outputs = rx.try_iter().collect::<Vec<_>>();
+    assert!(!outputs.iter().any(|o| matches!(
+        o,
+        Out::Event(Event::SyncManager(syncmgr::Event::Synced(_, _)))
+    )));
+    assert!(outputs.iter().any(|o| matches!(
+        o,
+        Out::Message(addr, RawNetworkMessage { payload: NetworkMessage::GetHeaders(_), .. })
+            if *addr == carol
+    )));
+}
+
+#[test]
+fn test_time_offset_samples() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let negotiate = |alice: &mut Protocol<_, _, _>, addr, timestamp| {
+        alice.step(
+            Input::Connected {
+                addr,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        let version = alice
+            .peermgr
+            .version(local_addr, fastrand::u64(..), 0, timestamp);
+
+        alice.step(
+            Input::received(addr, msg.raw(NetworkMessage::Version(version))),
+            time,
+        );
+        alice.step(Input::received(addr, msg.raw(NetworkMessage::Verack)), time);
+    };
+    let ahead = time + LocalDuration::from_secs(60);
+
+    // Peers in distinct network groups are all sampled.
+    for ip in [
+        [88, 13, 16, 59],
+        [99, 45, 180, 58],
+        [131, 25, 4, 7],
+        [44, 8, 1, 9],
+    ]
+    .iter()
+    {
+        negotiate(&mut alice, (*ip, 8333).into(), ahead);
+    }
+    assert_eq!(alice.clock.sources().count(), 4);
+    assert_eq!(alice.clock.offset(), 60);
+
+    // Peers in an already sampled network group are not.
+    negotiate(&mut alice, ([88, 13, 200, 1], 8333).into(), ahead);
+    assert_eq!(alice.clock.sources().count(), 4);
+
+    // Samples are removed when peers disconnect, making room for other peers in their
+    // network group.
+    alice.step(
+        Input::Disconnected(
+            ([88, 13, 16, 59], 8333).into(),
+            DisconnectReason::PeerTimeout,
+        ),
+        time,
+    );
+    assert_eq!(alice.clock.sources().count(), 3);
+    assert_eq!(alice.clock.offset(), 0);
+
+    negotiate(&mut alice, ([88, 13, 99, 1], 8333).into(), ahead);
+    assert_eq!(alice.clock.sources().count(), 4);
+    assert_eq!(alice.clock.offset(), 60);
+
+    // Offsets beyond the maximum adjustment are clamped, as are bogus timestamps.
+    negotiate(
+        &mut alice,
+        ([51, 2, 3, 4], 8333).into(),
+        time + LocalDuration::from_secs(MAX_TIME_ADJUSTMENT as u64 + 1),
+    );
+    negotiate(
+        &mut alice,
+        ([62, 2, 3, 4], 8333).into(),
+        LocalTime::default(),
+    );
+    assert_eq!(alice.clock.sources().count(), 6);
+    assert_eq!(alice.clock.offset(), 60);
+    assert_eq!(alice.clock.skew(), None);
+
+    let (tx, rx) = chan::bounded(1);
+    alice.step(Input::Command(Command::GetTimeOffset(tx)), time);
+    assert_eq!(rx.recv().unwrap(), 60);
+}
+
+#[test]
+fn test_clock_skew() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let ahead = time + LocalDuration::from_secs(2 * 60 * 60);
+    let skewed = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Event(Event::ClockSkewDetected(offset)) => Some(offset),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Our peers all agree that our clock is two hours behind.
+    for i in 1..8 {
+        let addr: net::SocketAddr = ([88, i, 16, 59], 8333).into();
+
+        alice.step(
+            Input::Connected {
+                addr,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        let version = alice
+            .peermgr
+            .version(local_addr, fastrand::u64(..), 0, ahead);
+
+        alice.step(
+            Input::received(addr, msg.raw(NetworkMessage::Version(version))),
+            time,
+        );
+        alice.step(Input::received(addr, msg.raw(NetworkMessage::Verack)), time);
+
+        if i == 4 {
+            assert_eq!(
+                skewed(&rx),
+                vec![MAX_TIME_ADJUSTMENT],
+                "the skew is detected once enough samples are collected"
+            );
+        }
+    }
+    assert!(skewed(&rx).is_empty(), "the skew is only reported once");
+    assert_eq!(alice.clock.skew(), Some(MAX_TIME_ADJUSTMENT));
+    assert_eq!(alice.clock.offset(), 0, "our clock is not adjusted");
+}
+
+#[test]
+fn test_duplicate_connection() {
+    let network = Network::Mainnet;
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let outbound: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let inbound: net::SocketAddr = ([88, 13, 16, 59], 49152).into();
+    let handshake = |alice: &mut Protocol<_, _, _>, addr, link, nonce, time| {
+        alice.step(
+            Input::Connected {
+                addr,
+                local_addr,
+                link,
+            },
+            time,
+        );
+        let version = alice.peermgr.version(local_addr, nonce, 0, time);
+
+        alice.step(
+            Input::received(addr, msg.raw(NetworkMessage::Version(version))),
+            time,
+        );
+    };
+    let duplicates = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Disconnect(addr, DisconnectReason::DuplicateConnection) => Some(addr),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Bob sends the same nonce on both connections, so the one we initiated is kept,
+    // whichever connection came first.
+    let (mut alice, rx, time) = setup::singleton(network);
+    handshake(&mut alice, outbound, Link::Outbound, 1, time);
+    handshake(&mut alice, inbound, Link::Inbound, 1, time);
+    assert_eq!(duplicates(&rx), vec![inbound]);
+
+    let (mut alice, rx, time) = setup::singleton(network);
+    handshake(&mut alice, inbound, Link::Inbound, u64::MAX, time);
+    handshake(&mut alice, outbound, Link::Outbound, u64::MAX, time);
+    assert_eq!(duplicates(&rx), vec![inbound]);
+
+    // Connections from different nodes are kept.
+    let (mut alice, rx, time) = setup::singleton(network);
+    handshake(&mut alice, outbound, Link::Outbound, 1, time);
+    handshake(&mut alice, inbound, Link::Inbound, 2, time);
+    assert!(duplicates(&rx).is_empty());
+
+    // Zero nonces don't identify a node.
+    let (mut alice, rx, time) = setup::singleton(network);
+    handshake(&mut alice, outbound, Link::Outbound, 0, time);
+    handshake(&mut alice, inbound, Link::Inbound, 0, time);
+    assert!(duplicates(&rx).is_empty());
+}
+
+#[test]
+fn test_self_connection() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let outbound: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let other: net::SocketAddr = ([88, 13, 16, 60], 8333).into();
+    let inbound: net::SocketAddr = ([152, 168, 3, 33], 49152).into();
+    let nonces = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match payload(&o) {
+                Some((_, NetworkMessage::Version(version))) => Some(version.nonce),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for addr in [outbound, other].iter() {
+        alice.step(
+            Input::Connected {
+                addr: *addr,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+    }
+    let sent = nonces(&rx);
+
+    assert_eq!(sent.len(), 2);
+    assert_ne!(sent[0], sent[1], "each connection gets a fresh nonce");
+
+    // Our outbound connection reaches ourselves, and shows up as an inbound connection.
+    alice.step(
+        Input::Connected {
+            addr: inbound,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    let version = alice.peermgr.version(local_addr, sent[0], 0, time);
+
+    alice.step(
+        Input::received(inbound, msg.raw(NetworkMessage::Version(version))),
+        time,
+    );
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::SelfConnection) if addr == inbound
+    )));
+}
+
+#[test]
+fn test_transaction_relayed() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let tx = Transaction {
+        version: 1,
+        lock_time: 0,
+        input: vec![],
+        output: vec![],
+    };
+    let size = bitcoin::consensus::encode::serialize(&tx).len();
+    let relayed = |rx: &chan::Receiver<Out>| {
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Event(Event::TransactionRelayed(addr, txid)) => Some((addr, txid)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let get_stats = |alice: &mut Protocol<_, _, _>| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetPeerStats(tx)), time);
+        rx.recv().unwrap()
+    };
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    let mut version = alice.peermgr.version(local_addr, 0, 0, time);
+    version.relay = true;
+
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Version(version))),
+        time,
+    );
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // The transaction is accounted for as soon as it's queued for sending.
+    alice.step(Input::Command(Command::SubmitTransaction(tx.clone())), time);
+    assert_eq!(relayed(&rx), vec![(bob, tx.txid())]);
+
+    let snapshot: stats::Snapshot = get_stats(&mut alice);
+    let (_, peer) = snapshot.peers.first().unwrap();
+
+    assert_eq!(peer.relayed.messages, 1);
+    assert_eq!(peer.relayed.bytes, size as u64);
+    assert_eq!(snapshot.total.relayed, peer.relayed);
+
+    // Unrelated `tx` messages being sent don't affect relay accounting.
+    alice.step(Input::Sent(bob, "tx", 84), time);
+    assert!(relayed(&rx).is_empty());
+    assert_eq!(get_stats(&mut alice).total.relayed.messages, 1);
+
+    // The same transaction isn't submitted to a peer that already has it.
+    alice.step(Input::Command(Command::SubmitTransaction(tx.clone())), time);
+    assert!(relayed(&rx).is_empty());
+    assert!(!rx
+        .try_iter()
+        .any(|o| matches!(payload(&o), Some((_, NetworkMessage::Tx(_))))));
+}
+
+#[test]
+fn test_version_sender() {
+    let network = Network::Mainnet;
+    let local_addr: net::SocketAddr = ([192, 168, 1, 2], 8333).into();
+    let external_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let remote: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let sender = |mut protocol: Protocol<_, _, _>, rx: chan::Receiver<Out>, time| {
+        protocol.step(
+            Input::Connected {
+                addr: remote,
+                local_addr,
+                link: Link::Outbound,
+            },
+            time,
+        );
+        rx.try_iter()
+            .find_map(|o| match payload(&o) {
+                Some((_, NetworkMessage::Version(version))) => version.sender.socket_addr().ok(),
+                _ => None,
+            })
+            .expect("a version message is sent")
+    };
+
+    // Our local address is never sent.
+    let (alice, rx, time) = setup::singleton(network);
+    assert_eq!(sender(alice, rx, time), ([0, 0, 0, 0], 0).into());
+
+    // Unless configured, our external address isn't sent either.
+    let (tx, rx) = chan::unbounded();
+    let bob = Builder {
+        cache: model::Cache::new(network.genesis()),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            advertise: addrmgr::Advertise::Address(external_addr),
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+    assert_eq!(sender(bob, rx, time), external_addr);
+}
+
+#[test]
+fn test_shutdown() {
+    let (mut alice, rx, time) = setup::singleton(Network::Mainnet);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    rx.try_iter().for_each(drop);
+
+    alice.step(Input::Command(Command::Shutdown), time);
+
+    let outputs = rx.try_iter().collect::<Vec<_>>();
+    let disconnect = outputs.iter().position(
+        |o| matches!(o, Out::Disconnect(addr, DisconnectReason::Shutdown) if *addr == bob),
+    );
+    let shutdown = outputs.iter().position(|o| matches!(o, Out::Shutdown));
+
+    assert!(
+        disconnect.unwrap() < shutdown.unwrap(),
+        "peers are disconnected before shutting down"
+    );
+}
+
+#[test]
+fn test_ignore_disconnecting_peer() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(Input::Command(Command::Disconnect(bob)), time);
+    rx.try_iter().for_each(drop);
+
+    // Bob's message was sent before he learned about the disconnection.
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Ping(42))),
+        time,
+    );
+    assert!(
+        !rx.try_iter()
+            .any(|o| matches!(o, Out::Message(addr, _) if addr == bob)),
+        "messages from disconnecting peers are ignored"
+    );
+}
+
+#[test]
+fn test_peer_stats() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let get_stats = |alice: &mut Protocol<_, _, _>| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetPeerStats(tx)), time);
+        rx.recv().unwrap()
+    };
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local_addr, 0, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    alice.step(Input::Command(Command::ResetPeerStats), time);
+
+    // A `ping` message is a 24 byte header followed by an 8 byte nonce.
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Ping(42))),
+        time,
+    );
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Ping(43))),
+        time,
+    );
+    alice.step(Input::Sent(bob, "pong", 32), time);
+
+    let snapshot: stats::Snapshot = get_stats(&mut alice);
+    let (addr, peer) = &snapshot.peers[0];
+
+    assert_eq!(*addr, bob);
+    assert_eq!(peer.received.messages, 2);
+    assert_eq!(peer.received.bytes, 64);
+    assert_eq!(peer.received_by_message["ping"].messages, 2);
+    assert_eq!(peer.sent.bytes, 32);
+    assert_eq!(peer.sent_by_message["pong"].messages, 1);
+    assert_eq!(snapshot.total, *peer);
+
+    // Once the peer disconnects, its traffic is still accounted for in the totals.
+    alice.step(
+        Input::Disconnected(bob, DisconnectReason::PeerTimeout),
+        time,
+    );
+    let snapshot = get_stats(&mut alice);
+
+    assert!(snapshot.peers.is_empty());
+    assert_eq!(snapshot.total.received.messages, 2);
+    assert_eq!(snapshot.total.sent.messages, 1);
+
+    // Messages racing the disconnection are ignored, and only counted in the totals.
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Ping(44))),
+        time,
+    );
+    let snapshot = get_stats(&mut alice);
+
+    assert!(snapshot.peers.is_empty());
+    assert_eq!(snapshot.total.received.messages, 2);
+    assert_eq!(snapshot.total.ignored.messages, 1);
+
+    // Messages sent to a disconnected peer don't bring back its statistics.
+    alice.step(Input::Sent(bob, "pong", 32), time);
+    let snapshot = get_stats(&mut alice);
+
+    assert!(snapshot.peers.is_empty());
+    assert_eq!(snapshot.total.sent.messages, 2);
+
+    // Statistics can be reset.
+    alice.step(Input::Command(Command::ResetPeerStats), time);
+    assert_eq!(get_stats(&mut alice).total, stats::Stats::default());
+}
+
+#[test]
+fn test_unhandled_messages() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    rx.try_iter().for_each(drop);
+
+    // Messages we don't handle are ignored, even before the handshake, and messages
+    // that can't be decoded are skipped.
+    let mut corrupted = Envelope::from(msg.raw(NetworkMessage::Ping(42)))
+        .as_bytes()
+        .to_vec();
+    corrupted[20] ^= 0xff; // Bad checksum.
+
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::SendHeaders)),
+        time,
+    );
+    alice.step(
+        Input::Received(bob, Envelope::from_bytes(corrupted).unwrap()),
+        time,
+    );
+    assert!(
+        !rx.try_iter().any(|o| matches!(
+            o,
+            Out::Message(..) | Out::Disconnect(..) | Out::Event(Event::Received(..))
+        )),
+        "unhandled and undecodable messages are ignored"
+    );
+
+    let (tx, stats) = chan::bounded(1);
+    alice.step(Input::Command(Command::GetPeerStats(tx)), time);
+
+    let snapshot: stats::Snapshot = stats.recv().unwrap();
+    assert_eq!(snapshot.total.received.messages, 2);
+    assert_eq!(snapshot.total.received_by_message[stats::OTHER].messages, 2);
+}
+
+#[test]
+fn test_memory_limits() {
+    let network = Network::Mainnet;
+    let params = Params::new(network.into());
+    let time = LocalTime::from_block_time(BITCOIN_HEADERS.last().time);
+    let orphan_size = std::mem::size_of::<(BlockHash, BlockHeader)>();
+    let address_size = std::mem::size_of::<(net::IpAddr, KnownAddress)>();
+    let (tx, _rx) = chan::unbounded();
+    let mut alice = Builder {
+        cache: BlockCache::from(store::Memory::genesis(network), params.clone(), &[]).unwrap(),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::with_seed(1),
+        cfg: Config {
+            params,
+            memory_limits: memory::Limits {
+                orphans: Some(orphan_size * 4),
+                addresses: Some(address_size * 10),
+            },
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+    let get_memory_info = |alice: &mut Protocol<_, _, _>| {
+        let (tx, rx) = chan::bounded(1);
+        alice.step(Input::Command(Command::GetMemoryInfo(tx)), time);
+        rx.recv().unwrap()
+    };
+    let info: memory::MemoryInfo = get_memory_info(&mut alice);
+
+    assert!(info.headers > 0);
+    assert!(info.filters > 0);
+    assert_eq!(info.orphans, 0);
+    assert_eq!(info.addresses, 0);
+
+    // Import headers that don't connect to our chain, one at a time, since each of them
+    // is rejected.
+    let orphans = &BITCOIN_HEADERS.tail[1..11];
+    for header in orphans {
+        let (tx, _rx) = chan::bounded(1);
+        alice.step(
+            Input::Command(Command::ImportHeaders(vec![*header], tx)),
+            time,
+        );
+    }
+    alice.addrmgr.insert(
+        (0..20).map(|i| {
+            (
+                Default::default(),
+                Address::new(&([44, 1, i, 2], 8333).into(), ServiceFlags::NONE),
+            )
+        }),
+        Source::Dns,
+    );
+
+    // Caches are only trimmed periodically.
+    let info = get_memory_info(&mut alice);
+
+    assert!(info.orphans > orphan_size * 4);
+    assert!(info.addresses > address_size * 10);
+
+    // Caches are trimmed back under their cap, keeping the most recent orphans.
+    alice.step(Input::Timeout, time + MEMORY_CHECK_INTERVAL);
+    let info = get_memory_info(&mut alice);
+
+    assert_eq!(info.orphans, orphan_size * 4);
+    assert_eq!(info.addresses, address_size * 10);
+    assert_eq!(alice.addrmgr.len(), 10);
+    assert!(orphans[..6]
+        .iter()
+        .all(|h| !alice.tree.is_known(&h.block_hash())));
+    assert!(orphans[6..]
+        .iter()
+        .all(|h| alice.tree.is_known(&h.block_hash())));
+    assert_eq!(
+        info.total(),
+        info.headers + info.orphans + info.addresses + info.filters
+    );
+}
+
+#[test]
+fn test_snapshot_restore() {
+    use microserde::json;
+    use snapshot::Snapshot;
+
+    let network = Network::Mainnet;
+    let (mut alice, _rx, time) = setup::singleton(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let negotiated = |addr: net::SocketAddr, link, time_offset| snapshot::Peer {
+        addr,
+        local_addr,
+        link,
+        height: 144,
+        services: setup::CONFIG.required_services,
+        user_agent: USER_AGENT.to_owned(),
+        relay: true,
+        time_offset,
+    };
+    let mut peers = vec![
+        negotiated(([88, 13, 16, 59], 8333).into(), Link::Outbound, 3),
+        negotiated(([99, 45, 180, 58], 8333).into(), Link::Inbound, -2),
+    ];
+    peers.sort_by_key(|p| p.addr);
+
+    let snapshot = Snapshot {
+        time,
+        height: 0,
+        tip: network.genesis_hash(),
+        filter_height: 0,
+        peers,
+        addresses: vec![KnownAddress {
+            addr: net::SocketAddr::from(([44, 1, 2, 3], 8333)).into(),
+            services: ServiceFlags::NETWORK,
+            source: Source::Dns,
+            last_success: Some(time),
+            last_attempt: Some(time),
+            last_failure: None,
+            attempts: 1,
+            latency: Some(LocalDuration::from_millis(80)),
+        }],
+        bans: vec![(
+            [183, 8, 55, 2].into(),
+            peer::Ban::new("spam", time + LocalDuration::from_mins(10)),
+        )],
+    };
+
+    // Snapshots survive a JSON roundtrip.
+    let encoded = json::to_string(&snapshot.to_json());
+    assert_eq!(
+        Snapshot::from_json(json::from_str(&encoded).unwrap()).unwrap(),
+        snapshot
+    );
+
+    alice.initialize(time);
+    alice.restore(&snapshot, time);
+
+    // Restored peers are negotiated, and tracked by the sub-protocols.
+    assert_eq!(
+        alice.peermgr.peers().filter(|p| p.is_negotiated()).count(),
+        2
+    );
+    assert_eq!(alice.syncmgr.best_height(), Some(144));
+    assert_eq!(alice.addrmgr.known().count(), 1);
+    assert_eq!(alice.snapshot().anchors(), snapshot.anchors());
+
+    let mut restored = alice.snapshot();
+    restored.peers.sort_by_key(|p| p.addr);
+
+    assert_eq!(restored, snapshot);
+}
+
+#[test]
+fn test_add_remove_node() {
+    let (mut alice, rx, mut time) = setup::singleton(Network::Mainnet);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(Input::Command(Command::AddNode(bob)), time);
+    assert!(rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)));
+    // Added peers are trusted by the sub-protocols.
+    assert!(alice.connmgr.config.whitelist.contains_addr(&bob.ip()));
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::Disconnected(bob, DisconnectReason::PeerTimeout),
+        time,
+    );
+
+    // The peer is re-dialed on the next idle.
+    time = time + connmgr::IDLE_TIMEOUT;
+    alice.step(Input::Timeout, time);
+    assert!(rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)));
+
+    alice.step(Input::Connecting { addr: bob }, time);
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+
+    // Once removed, we disconnect from the peer and don't re-dial it.
+    alice.step(Input::Command(Command::RemoveNode(bob)), time);
+    assert!(rx.try_iter().any(|o| matches!(
+        o,
+        Out::Disconnect(addr, DisconnectReason::Command) if addr == bob
+    )));
+    alice.step(Input::Disconnected(bob, DisconnectReason::Command), time);
+    assert!(!alice.connmgr.config.whitelist.contains_addr(&bob.ip()));
+
+    time = time + connmgr::IDLE_TIMEOUT;
+    alice.step(Input::Timeout, time);
+    assert!(!rx
+        .try_iter()
+        .any(|o| matches!(o, Out::Connect(addr, _) if addr == bob)));
+}
+
+#[test]
+fn test_interceptor() {
+    use interceptor::{Context, Interceptor, Verdict};
+
+    /// Ignores pings with a zero nonce, and tampers with pongs.
+    #[derive(Debug)]
+    struct Tamper;
+
+    impl Interceptor for Tamper {
+        fn received(&self, _peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+            match msg {
+                NetworkMessage::Ping(0) => Verdict::Drop,
+                _ => Verdict::Accept,
+            }
+        }
+
+        fn sending(&self, _peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+            if let NetworkMessage::Pong(nonce) = msg {
+                *nonce += 1;
+            }
+            Verdict::Accept
+        }
+    }
+
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_secs(genesis.time as u64);
+    let (tx, rx) = chan::unbounded();
+    let mut alice = Builder {
+        cache: model::Cache::new(genesis),
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::new(),
+        cfg: Config {
+            interceptor: Some(Arc::new(Tamper)),
+            ..setup::CONFIG.clone()
+        },
+    }
+    .build(tx);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Inbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local_addr, 0, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Ping(0))), time);
+    assert!(
+        !rx.try_iter()
+            .any(|o| matches!(payload(&o), Some((_, NetworkMessage::Pong(_))))),
+        "vetoed messages are not processed"
+    );
+
+    alice.step(
+        Input::received(bob, msg.raw(NetworkMessage::Ping(42))),
+        time,
+    );
+    assert!(
+        rx.try_iter()
+            .any(|o| matches!(payload(&o), Some((addr, NetworkMessage::Pong(43))) if addr == bob)),
+        "outgoing messages can be modified"
+    );
+}
+
+#[test]
+fn test_adversaries() {
+    use crate::simulator::adversary;
+    use interceptor::Interceptor;
+
+    let network = Network::Mainnet;
+    let params = Params::new(network.into());
+    let time = LocalTime::from_block_time(BITCOIN_HEADERS.last().time);
+    let height = 144;
+    let tree = |height| {
+        let mut store = store::Memory::new(BITCOIN_HEADERS.clone());
+        store.rollback(height).unwrap();
+
+        BlockCache::from(store, params.clone(), &[]).unwrap()
+    };
+    let alice: PeerId = ([152, 168, 3, 33], 8333).into();
+    let bob: PeerId = ([152, 168, 7, 77], 8333).into();
+    let eve: PeerId = ([152, 168, 9, 99], 8333).into();
+
+    // Eve's behavior, her actual height, and whether Alice should disconnect her.
+    let cases: Vec<(Arc<dyn Interceptor>, Height, bool)> = vec![
+        (Arc::new(adversary::Silent), height, true),
+        (
+            Arc::new(adversary::Stale { height: height * 2 }),
+            height / 2,
+            false,
+        ),
+        (Arc::new(adversary::InvalidPoW), height, true),
+        (Arc::new(adversary::WithholdFilters), height, false),
+        (Arc::new(adversary::AddrFlood { count: 1001 }), height, true),
+    ];
+
+    for (behavior, eve_height, disconnected) in cases {
+        let mut sim = Simulation::new(time, fastrand::Rng::with_seed(1), LinkConfig::default());
+        let peers = vec![
+            (alice, 0, None),
+            (bob, height, None),
+            (eve, eve_height, Some(behavior.clone())),
+        ];
+
+        for (i, (addr, height, interceptor)) in peers.into_iter().enumerate() {
+            let (tx, rx) = chan::unbounded();
+            let protocol = Builder {
+                cache: tree(height),
+                clock: AdjustedTime::new(time),
+                filters: model::FilterCache::new(FilterHeader::genesis(network)),
+                peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+                rng: fastrand::Rng::with_seed(i as u64),
+                cfg: Config {
+                    interceptor,
+                    ..setup::CONFIG.clone()
+                },
+            }
+            .build(tx);
+
+            sim.add_peer(addr, protocol, rx);
+        }
+        sim.initialize();
+        // Alice connects to Eve first, so that she's the first peer Alice syncs with.
+        sim.connect(&alice, &eve);
+        sim.connect(&alice, &bob);
+        sim.elapse(LocalDuration::from_mins(5));
+
+        let alice = sim.peer(&alice).unwrap();
+
+        assert_eq!(
+            alice.tree.height(),
+            height,
+            "{:?}: alice syncs with bob",
+            behavior
+        );
+        assert!(
+            alice.peermgr.peers().any(|p| p.address() == bob),
+            "{:?}: alice stays connected to bob",
+            behavior
+        );
+        assert_eq!(
+            !alice.peermgr.peers().any(|p| p.address() == eve),
+            disconnected,
+            "{:?}: alice disconnects eve",
+            behavior
+        );
+    }
+}
+
+#[test]
+fn test_step_deterministic() {
+    let network = Network::Mainnet;
+    let genesis = network.genesis();
+    let time = LocalTime::from_block_time(genesis.time);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let remote: PeerId = ([88, 13, 16, 59], 8333).into();
+    let msg = message::Builder::new(network);
+    let mut ips = (1..=64)
+        .map(|i| net::IpAddr::from([23, i, 0, 1]))
+        .collect::<Vec<_>>();
+
+    // Run the protocol with an address store populated with the given IPs, and return
+    // its outputs.
+    let run = |ips: &[net::IpAddr]| {
+        let mut peers = HashMap::new();
+        for ip in ips {
+            let addr = net::SocketAddr::from((*ip, 8333));
+            peers.insert(
+                *ip,
+                KnownAddress::new(addr.into(), ServiceFlags::NETWORK, Source::Dns),
+            );
+        }
+        let (tx, rx) = chan::unbounded();
+        let mut protocol = Builder {
+            cache: model::Cache::new(genesis),
+            clock: AdjustedTime::new(time),
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers,
+            rng: fastrand::Rng::with_seed(1),
+            cfg: setup::CONFIG.clone(),
+        }
+        .build(tx);
+        let mut time = time;
+
+        protocol.initialize(time);
+        protocol.step(
+            Input::Connected {
+                addr: remote,
+                local_addr,
+                link: Link::Inbound,
+            },
+            time,
+        );
+        let version = protocol.peermgr.version(local_addr, 0, 0, time);
+        for m in vec![
+            NetworkMessage::Version(version),
+            NetworkMessage::Verack,
+            NetworkMessage::GetAddr,
+        ] {
+            protocol.step(Input::received(remote, msg.raw(m)), time);
+        }
+        for _ in 0..10 {
+            time = time + LocalDuration::from_mins(1);
+            protocol.step(Input::Timeout, time);
+        }
+        rx.try_iter()
+            .map(|o| format!("{:?}", o))
+            .collect::<Vec<_>>()
+    };
+
+    // The order in which the address store yields its addresses doesn't affect the
+    // outputs, only the inputs, time and RNG do.
+    let expected = run(&ips);
+    ips.reverse();
+
+    assert!(!expected.is_empty());
+    assert_eq!(run(&ips), expected);
+}
+
+#[test]
+fn test_replay() {
+    use crate::replay;
+
+    let network = Network::Mainnet;
+    let time = LocalTime::from_block_time(BITCOIN_HEADERS.last().time);
+    let alice: PeerId = ([152, 168, 3, 33], 8333).into();
+    let bob: PeerId = ([152, 168, 7, 77], 8333).into();
+    let builder = |cache, seed| Builder {
+        cache,
+        clock: AdjustedTime::new(time),
+        filters: model::FilterCache::new(FilterHeader::genesis(network)),
+        peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+        rng: fastrand::Rng::with_seed(seed),
+        cfg: setup::CONFIG.clone(),
+    };
+
+    // Alice syncs with Bob, while her inputs are recorded.
+    let mut sim = Simulation::new(time, fastrand::Rng::with_seed(1), LinkConfig::default());
+    for (addr, cache, seed) in vec![
+        (alice, model::Cache::new(network.genesis()), 1),
+        (bob, model::Cache::from(BITCOIN_HEADERS.clone()), 2),
+    ] {
+        let (tx, rx) = chan::unbounded();
+        sim.add_peer(addr, builder(cache, seed).build(tx), rx);
+    }
+    sim.record(&alice);
+    sim.initialize();
+    sim.connect(&alice, &bob);
+    sim.elapse(LocalDuration::from_mins(1));
+
+    assert_eq!(
+        sim.peer(&alice).unwrap().tree.height(),
+        BITCOIN_HEADERS.tail.len() as Height
+    );
+    let events = sim
+        .events(&alice)
+        .map(|e| format!("{:?}", e))
+        .collect::<Vec<_>>();
+
+    // Replaying the recording on a fresh instance reproduces the same events.
+    let (tx, rx) = chan::unbounded();
+    let mut replayed = builder(model::Cache::new(network.genesis()), 1).build(tx);
+
+    replay::replay(&mut replayed, sim.recording(&alice).unwrap()).unwrap();
+
+    assert_eq!(replayed.tree.height(), BITCOIN_HEADERS.tail.len() as Height);
+    assert_eq!(
+        rx.try_iter()
+            .filter_map(|o| match o {
+                Out::Event(e) => Some(format!("{:?}", e)),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        events
+    );
+}
+
+#[test]
+fn test_mutated_messages() {
+    let network = Network::Mainnet;
+    let params = Params::new(network.into());
+    let time = LocalTime::from_block_time(BITCOIN_HEADERS.last().time);
+    let alice: PeerId = ([152, 168, 3, 33], 8333).into();
+    let bob: PeerId = ([152, 168, 7, 77], 8333).into();
+
+    for seed in 0..8 {
+        let mut sim = Simulation::new(
+            time,
+            fastrand::Rng::with_seed(seed),
+            LinkConfig {
+                corruption: 0.1,
+                duplication: 0.1,
+                ..LinkConfig::default()
+            },
+        );
+        let (tx, rx) = chan::unbounded();
+        let protocol = Builder {
+            cache: BlockCache::from(store::Memory::genesis(network), params.clone(), &[]).unwrap(),
+            clock: AdjustedTime::new(time),
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+            rng: fastrand::Rng::with_seed(1),
+            cfg: setup::CONFIG.clone(),
+        }
+        .build(tx);
+        sim.add_peer(alice, protocol, rx);
+
+        let (tx, rx) = chan::unbounded();
+        let protocol = Builder {
+            cache: model::Cache::from(BITCOIN_HEADERS.clone()),
+            clock: AdjustedTime::new(time),
+            filters: model::FilterCache::new(FilterHeader::genesis(network)),
+            peers: HashMap::<net::IpAddr, KnownAddress>::new(),
+            rng: fastrand::Rng::with_seed(2),
+            cfg: setup::CONFIG.clone(),
+        }
+        .build(tx);
+        sim.add_peer(bob, protocol, rx);
+
+        sim.initialize();
+        sim.connect(&alice, &bob);
+        sim.elapse(LocalDuration::from_mins(10));
+
+        // Alice may not be synced, or may have disconnected Bob, but she never imports
+        // corrupted headers.
+        let tree = &sim.peer(&alice).unwrap().tree;
+
+        assert!(tree.height() <= BITCOIN_HEADERS.tail.len() as Height);
+        for height in 0..=tree.height() {
+            assert_eq!(
+                tree.get_block_by_height(height),
+                BITCOIN_HEADERS.get(height as usize),
+                "seed {}: block {} is from the main chain",
+                seed,
+                height
+            );
+        }
+    }
+}
+
+#[test]
+fn test_step_batch() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, _) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let time = LocalTime::from_block_time(BITCOIN_HEADERS.last().time);
+    let headers = |range: Range<usize>| {
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Headers(
+                BITCOIN_HEADERS.tail[range].to_vec(),
+            )),
+        )
+    };
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local_addr, 0, 144, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // Two consecutive `headers` messages are imported at once, while the third one, which
+    // comes after another input, is imported separately.
+    alice.step_batch(
+        vec![
+            headers(0..2),
+            headers(2..4),
+            Input::Timeout,
+            Input::Timeout,
+            headers(4..5),
+        ],
+        time,
+    );
+    assert_eq!(alice.tree.height(), 5);
+
+    let received = rx
+        .try_iter()
+        .filter_map(|o| match o {
+            Out::Event(Event::SyncManager(syncmgr::Event::HeadersReceived(_, n))) => Some(n),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(received, vec![4, 1]);
+
+    // Messages that don't extend each other aren't coalesced.
+    alice.step_batch(vec![headers(5..6), headers(7..8)], time);
+    assert_eq!(alice.tree.height(), 6);
+}
+
+#[test]
+fn test_outbound_coalescing() {
+    let network = Network::Mainnet;
+    let (mut alice, rx, time) = setup::singleton(network);
+    let msg = message::Builder::new(network);
+    let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+    let bob: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+    let inv = |i: usize| {
+        NetworkMessage::Inv(vec![Inventory::Block(BITCOIN_HEADERS.tail[i].block_hash())])
+    };
+
+    alice.step(
+        Input::Connected {
+            addr: bob,
+            local_addr,
+            link: Link::Outbound,
+        },
+        time,
+    );
+    alice.step(
+        Input::received(
+            bob,
+            msg.raw(NetworkMessage::Version(
+                alice.peermgr.version(local_addr, 0, 0, time),
+            )),
+        ),
+        time,
+    );
+    alice.step(Input::received(bob, msg.raw(NetworkMessage::Verack)), time);
+    rx.try_iter().for_each(drop);
+
+    // Messages sent in the same batch are merged, up to the next different message.
+    alice.step_batch(
+        vec![
+            Input::Command(Command::Broadcast(inv(0))),
+            Input::Command(Command::Broadcast(inv(1))),
+            Input::Command(Command::Broadcast(NetworkMessage::Ping(1))),
+            Input::Command(Command::Broadcast(inv(2))),
+        ],
+        time,
+    );
+    let sent = rx
+        .try_iter()
+        .filter_map(|o| payload(&o).map(|(_, m)| m.clone()))
+        .filter(|m| matches!(m, NetworkMessage::Inv(_) | NetworkMessage::Ping(_)))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        sent,
+        vec![
+            NetworkMessage::Inv(vec![
+                Inventory::Block(BITCOIN_HEADERS.tail[0].block_hash()),
+                Inventory::Block(BITCOIN_HEADERS.tail[1].block_hash()),
+            ]),
+            NetworkMessage::Ping(1),
+            inv(2),
+        ]
+    );
+
+    // Messages sent in separate steps aren't.
+    alice.step(Input::Command(Command::Broadcast(inv(3))), time);
+    alice.step(Input::Command(Command::Broadcast(inv(4))), time);
+
+    let sent = rx
+        .try_iter()
+        .filter_map(|o| payload(&o).map(|(_, m)| m.clone()))
+        .filter(|m| matches!(m, NetworkMessage::Inv(_) | NetworkMessage::Ping(_)))
+        .collect::<Vec<_>>();
+    assert_eq!(sent, vec![inv(3), inv(4)]);
+}
+
+#[test]
+fn test_chain_state() {
+    let network = Network::Mainnet;
+    let (mut alice, _rx, _) = setup::singleton(network);
+    let time = LocalTime::from_block_time(BITCOIN_HEADERS.last().time);
+    let chain_state = state::SharedChainState::default();
+
+    alice.chain_state = chain_state.clone();
+    alice.initialize(time);
+
+    let state = chain_state.get().unwrap();
+    assert_eq!(state.tip(), (0, network.genesis()));
+
+    let headers = BITCOIN_HEADERS.tail[..200].to_vec();
+    let (tx, rx) = chan::bounded(1);
+    alice.step(
+        Input::Command(Command::ImportHeaders(headers.clone(), tx)),
+        time,
+    );
+    rx.recv().unwrap().unwrap();
+
+    let state = chain_state.get().unwrap();
+    assert_eq!(state.tip(), (200, headers[199]));
+    assert_eq!(state.headers.len(), state::RECENT_HEADERS);
+    assert_eq!(state.get_header_by_height(100), Some(headers[99]));
+    assert_eq!(state.get_header_by_height(50), None);
+    assert_eq!(state.get_header_by_height(201), None);
+
+    // Headers that aren't part of the snapshot are served from the chain index.
+    assert_eq!(chain_state.get_header_by_height(50), Some(headers[49]));
+    assert_eq!(chain_state.get_header_by_height(0), Some(network.genesis()));
+    assert_eq!(chain_state.get_header_by_height(201), None);
+    assert_eq!(
+        chain_state.get_header(&headers[49].block_hash()),
+        Some((50, headers[49]))
+    );
+
+    // Headers that are no longer part of the active chain are removed from the index.
+    let mut fork = BITCOIN_HEADERS.tail[..100].to_vec();
+    fork[99].nonce += 1;
+
+    chain_state.update(&model::Cache::from(NonEmpty::from((
+        network.genesis(),
+        fork.clone(),
+    ))));
+    assert_eq!(chain_state.get().unwrap().height, 100);
+    assert_eq!(chain_state.get_header(&headers[99].block_hash()), None);
+    assert_eq!(chain_state.get_header(&headers[149].block_hash()), None);
+    assert_eq!(
+        chain_state.get_header(&fork[99].block_hash()),
+        Some((100, fork[99]))
+    );
+    assert_eq!(chain_state.get_header_by_height(99), Some(headers[98]));
+    assert_eq!(chain_state.get_header_by_height(150), None);
+}
@@ -0,0 +1,295 @@
+//! Protocol conformance test vectors.
+//!
+//! Each [`Vector`] is a sequence of inputs from a single remote peer, fed to a fresh
+//! protocol instance, interleaved with expectations on the protocol's outputs and state.
+//! Vectors only describe observable behavior, so that changes to the protocol's internal
+//! structure can be checked against them. To cover new behavior, add a vector to
+//! [`vectors`].
+use super::*;
+
+use bitcoin::network::message_network::VersionMessage;
+
+use crate::protocol::peermgr::HANDSHAKE_TIMEOUT;
+
+/// Network the vectors run on.
+const NETWORK: Network = Network::Mainnet;
+
+type Proto = Protocol<model::Cache, model::FilterCache, HashMap<net::IpAddr, KnownAddress>>;
+
+/// A step of a test vector.
+enum Step {
+    /// The remote peer connected.
+    Connected(Link),
+    /// A message was received from the remote peer.
+    Received(NetworkMessage),
+    /// The remote peer disconnected.
+    Disconnected,
+    /// Time elapsed, and a timeout fired.
+    Elapse(LocalDuration),
+    /// The last input produced an output matching the predicate.
+    Expect(&'static str, fn(&Out) -> bool),
+    /// The last input produced no output matching the predicate.
+    Never(&'static str, fn(&Out) -> bool),
+    /// The protocol state satisfies the predicate.
+    Check(&'static str, fn(&Proto) -> bool),
+}
+
+/// A named sequence of steps.
+struct Vector {
+    name: &'static str,
+    steps: Vec<Step>,
+}
+
+impl Vector {
+    fn new(name: &'static str, steps: impl IntoIterator<Item = Step>) -> Self {
+        Self {
+            name,
+            steps: steps.into_iter().collect(),
+        }
+    }
+
+    /// Run the vector against a fresh protocol, panicking on the first unmet expectation.
+    fn run(&self) {
+        let (mut protocol, rx, mut time) = setup::singleton(NETWORK);
+        let msg = message::Builder::new(NETWORK);
+
+        protocol.initialize(time);
+
+        let mut outputs = rx.try_iter().collect::<Vec<_>>();
+
+        for (i, step) in self.steps.iter().enumerate() {
+            let input = match step {
+                Step::Connected(link) => Input::Connected {
+                    addr: remote(),
+                    local_addr: local(),
+                    link: *link,
+                },
+                Step::Received(m) => Input::received(remote(), msg.raw(m.clone())),
+                Step::Disconnected => Input::Disconnected(remote(), DisconnectReason::Command),
+                Step::Elapse(duration) => {
+                    time = time + *duration;
+                    Input::Timeout
+                }
+                Step::Expect(desc, pred) => {
+                    assert!(
+                        outputs.iter().any(pred),
+                        "{} (step {}): expected {}, got {:#?}",
+                        self.name,
+                        i,
+                        desc,
+                        outputs
+                    );
+                    continue;
+                }
+                Step::Never(desc, pred) => {
+                    assert!(
+                        !outputs.iter().any(pred),
+                        "{} (step {}): expected {}, got {:#?}",
+                        self.name,
+                        i,
+                        desc,
+                        outputs
+                    );
+                    continue;
+                }
+                Step::Check(desc, pred) => {
+                    assert!(pred(&protocol), "{} (step {}): {}", self.name, i, desc);
+                    continue;
+                }
+            };
+            protocol.step(input, time);
+            outputs = rx.try_iter().collect();
+        }
+    }
+}
+
+/// Our address, as seen by the remote peer.
+fn local() -> net::SocketAddr {
+    ([152, 168, 3, 33], 8333).into()
+}
+
+/// Address of the remote peer.
+fn remote() -> PeerId {
+    ([88, 13, 16, 59], 8333).into()
+}
+
+/// The remote peer's `version` message, claiming the given height.
+fn version(height: Height) -> NetworkMessage {
+    let services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+
+    NetworkMessage::Version(VersionMessage {
+        version: PROTOCOL_VERSION,
+        services,
+        timestamp: NETWORK.genesis().time as i64,
+        receiver: Address::new(&local(), ServiceFlags::NONE),
+        sender: Address::new(&remote(), services),
+        nonce: 42,
+        user_agent: USER_AGENT.to_owned(),
+        start_height: height as i32,
+        relay: false,
+    })
+}
+
+fn sent_version(o: &Out) -> bool {
+    matches!(payload(o), Some((a, NetworkMessage::Version(_))) if a == remote())
+}
+
+fn sent_verack(o: &Out) -> bool {
+    matches!(payload(o), Some((a, NetworkMessage::Verack)) if a == remote())
+}
+
+fn negotiated(o: &Out) -> bool {
+    matches!(
+        o,
+        Out::Event(Event::PeerManager(peermgr::Event::PeerNegotiated { addr, .. })) if *addr == remote()
+    )
+}
+
+fn misbehaving(o: &Out) -> bool {
+    matches!(o, Out::Disconnect(a, DisconnectReason::PeerMisbehaving(_)) if *a == remote())
+}
+
+/// A complete handshake with the remote peer, who claims the given height.
+fn handshake(link: Link, height: Height) -> Vec<Step> {
+    let mut steps = vec![Step::Connected(link)];
+
+    match link {
+        Link::Inbound => steps.extend(vec![
+            Step::Never("no `version` before theirs", sent_version),
+            Step::Received(version(height)),
+            Step::Expect("`version` sent", sent_version),
+            Step::Expect("`verack` sent", sent_verack),
+        ]),
+        Link::Outbound => steps.extend(vec![
+            Step::Expect("`version` sent", sent_version),
+            Step::Received(version(height)),
+            Step::Expect("`verack` sent", sent_verack),
+            Step::Never("no second `version`", sent_version),
+        ]),
+    }
+    steps.extend(vec![
+        Step::Received(NetworkMessage::Verack),
+        Step::Expect("peer negotiated", negotiated),
+        Step::Check("peer is negotiated", |p| {
+            p.peermgr
+                .peers()
+                .any(|p| p.address() == remote() && p.is_negotiated())
+        }),
+    ]);
+    steps
+}
+
+/// A handshake that is aborted by the given messages, for misbehavior.
+fn misbehavior(msgs: Vec<NetworkMessage>) -> Vec<Step> {
+    let mut steps = vec![Step::Connected(Link::Inbound)];
+
+    steps.extend(msgs.into_iter().map(Step::Received));
+    steps.extend(vec![
+        Step::Expect("peer disconnected for misbehavior", misbehaving),
+        Step::Never("peer not negotiated", negotiated),
+    ]);
+    steps
+}
+
+/// All conformance test vectors.
+fn vectors() -> Vec<Vector> {
+    let headers = BITCOIN_HEADERS
+        .tail
+        .iter()
+        .take(2)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    vec![
+        Vector::new("inbound handshake", handshake(Link::Inbound, 0)),
+        Vector::new("outbound handshake", handshake(Link::Outbound, 0)),
+        Vector::new(
+            "outbound handshake timeout",
+            vec![
+                Step::Connected(Link::Outbound),
+                Step::Elapse(HANDSHAKE_TIMEOUT),
+                Step::Expect("peer timed out", |o| {
+                    matches!(o, Out::Disconnect(a, DisconnectReason::PeerTimeout) if *a == remote())
+                }),
+            ],
+        ),
+        Vector::new(
+            "verack timeout",
+            vec![
+                Step::Connected(Link::Inbound),
+                Step::Received(version(0)),
+                Step::Elapse(HANDSHAKE_TIMEOUT),
+                Step::Expect("peer timed out", |o| {
+                    matches!(o, Out::Disconnect(a, DisconnectReason::PeerTimeout) if *a == remote())
+                }),
+            ],
+        ),
+        Vector::new(
+            "`verack` before `version`",
+            misbehavior(vec![NetworkMessage::Verack]),
+        ),
+        Vector::new(
+            "message before `version`",
+            misbehavior(vec![NetworkMessage::Ping(1)]),
+        ),
+        Vector::new(
+            "duplicate `version`",
+            misbehavior(vec![version(0), version(0)]),
+        ),
+        Vector::new(
+            "message before `verack`",
+            misbehavior(vec![version(0), NetworkMessage::Ping(1)]),
+        ),
+        Vector::new(
+            "headers before `verack`",
+            misbehavior(vec![version(2), NetworkMessage::Headers(headers.clone())])
+                .into_iter()
+                .chain(vec![Step::Check("headers not imported", |p| {
+                    p.tree.height() == 0
+                })]),
+        ),
+        Vector::new(
+            "duplicate `verack`",
+            handshake(Link::Inbound, 0).into_iter().chain(vec![
+                Step::Received(NetworkMessage::Verack),
+                Step::Expect("peer disconnected for misbehavior", misbehaving),
+            ]),
+        ),
+        Vector::new(
+            "ping",
+            handshake(Link::Inbound, 0).into_iter().chain(vec![
+                Step::Received(NetworkMessage::Ping(7)),
+                Step::Expect("`pong` sent", |o| {
+                    matches!(payload(o), Some((a, NetworkMessage::Pong(7))) if a == remote())
+                }),
+            ]),
+        ),
+        Vector::new(
+            "initial sync",
+            handshake(Link::Outbound, 2).into_iter().chain(vec![
+                Step::Expect("headers requested", |o| {
+                    matches!(payload(o), Some((a, NetworkMessage::GetHeaders(_))) if a == remote())
+                }),
+                Step::Received(NetworkMessage::Headers(headers)),
+                Step::Never("peer not disconnected", |o| {
+                    matches!(o, Out::Disconnect(a, _) if *a == remote())
+                }),
+                Step::Check("headers imported", |p| p.tree.height() == 2),
+            ]),
+        ),
+        Vector::new(
+            "disconnect",
+            handshake(Link::Inbound, 0).into_iter().chain(vec![
+                Step::Disconnected,
+                Step::Check("peer forgotten", |p| p.peermgr.peers().next().is_none()),
+            ]),
+        ),
+    ]
+}
+
+#[test]
+fn test_conformance() {
+    for vector in vectors() {
+        vector.run();
+    }
+}
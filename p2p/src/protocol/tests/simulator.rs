@@ -1,6 +1,8 @@
-//! A simple P2P network simulator. Acts as the _reactor_, but without doing any I/O.
+//! Simulated networks of peers, for protocol tests. See also [`crate::simulator`].
 use super::*;
 
+use crate::simulator::{LinkConfig, Simulation};
+
 use nakamoto_common::block::filter::{FilterHash, FilterHeader};
 use nakamoto_common::collections::HashMap;
 
@@ -309,7 +311,7 @@ impl Sim {
         match out {
             Out::Message(receiver, msg) => {
                 info!("(sim) {} -> {}: {:?}", peer, receiver, msg);
-                inbox.push_back((receiver, Input::Received(peer, msg)))
+                inbox.push_back((receiver, Input::received(peer, msg)))
             }
             Out::Connect(remote, _timeout) => {
                 assert!(remote != peer, "self-connections are not allowed");
@@ -399,72 +401,27 @@ impl Sim {
 }
 
 pub fn handshake<T: BlockTree, F: Filters, P: peer::Store>(
-    alice: &mut Protocol<T, F, P>,
+    alice: Protocol<T, F, P>,
     alice_addr: net::SocketAddr,
     alice_rx: chan::Receiver<Out>,
-    bob: &mut Protocol<T, F, P>,
+    bob: Protocol<T, F, P>,
     bob_addr: net::SocketAddr,
     bob_rx: chan::Receiver<Out>,
     local_time: LocalTime,
-) {
-    self::run(
-        vec![(alice_addr, alice, alice_rx), (bob_addr, bob, bob_rx)],
-        vec![
-            vec![Input::Connected {
-                addr: bob_addr,
-                local_addr: alice_addr,
-                link: Link::Outbound,
-            }],
-            vec![Input::Connected {
-                addr: alice_addr,
-                local_addr: bob_addr,
-                link: Link::Inbound,
-            }],
-        ],
-        local_time,
-    );
+) -> (Protocol<T, F, P>, Protocol<T, F, P>) {
+    let mut sim = Simulation::new(local_time, fastrand::Rng::new(), LinkConfig::default());
 
-    assert!(alice.peermgr.peers().all(|p| p.is_negotiated()));
-    assert!(bob.peermgr.peers().all(|p| p.is_negotiated()));
-}
+    sim.add_peer(alice_addr, alice, alice_rx);
+    sim.add_peer(bob_addr, bob, bob_rx);
+    sim.initialize();
+    sim.connect(&alice_addr, &bob_addr);
+    sim.run_until(local_time);
 
-pub fn run<T: BlockTree, F: Filters, P: peer::Store>(
-    peers: Vec<(PeerId, &mut Protocol<T, F, P>, chan::Receiver<Out>)>,
-    inputs: Vec<Vec<Input>>,
-    local_time: LocalTime,
-) {
-    let mut sim: HashMap<PeerId, (&mut Protocol<T, F, P>, VecDeque<Input>, chan::Receiver<Out>)> =
-        HashMap::with_hasher(fastrand::Rng::new().into());
-    let mut events = VecDeque::new();
-    let mut tmp = Vec::new();
-
-    // Add peers to simulator.
-    for ((addr, proto, rx), evs) in peers.into_iter().zip(inputs.into_iter()) {
-        proto.initialize(local_time);
-
-        for o in rx.try_iter() {
-            Sim::schedule(&mut tmp, &mut events, &addr, o);
-        }
-        for e in evs.into_iter() {
-            events.push_back((addr, e));
-        }
-        sim.insert(addr, (proto, VecDeque::new(), rx));
-    }
+    let (alice, _) = sim.remove_peer(&alice_addr).unwrap();
+    let (bob, _) = sim.remove_peer(&bob_addr).unwrap();
 
-    while !events.is_empty() || sim.values().any(|(_, q, _)| !q.is_empty()) {
-        // Prepare event queues.
-        for (receiver, event) in events.drain(..) {
-            let (_, q, _) = sim.get_mut(&receiver).unwrap();
-            q.push_back(event);
-        }
+    assert!(alice.peermgr.peers().all(|p| p.is_negotiated()));
+    assert!(bob.peermgr.peers().all(|p| p.is_negotiated()));
 
-        for (peer, (proto, queue, rx)) in sim.iter_mut() {
-            if let Some(event) = queue.pop_front() {
-                proto.step(event, local_time);
-                for out in rx.try_iter() {
-                    Sim::schedule(&mut tmp, &mut events, peer, out);
-                }
-            }
-        }
-    }
+    (alice, bob)
 }
@@ -14,7 +14,7 @@ use crate::protocol::{self, Command};
 /// Any network reactor that can drive the light-client protocol.
 pub trait Reactor {
     /// The type of waker this reactor uses.
-    type Waker: Send;
+    type Waker: Send + Sync + Clone;
 
     /// Create a new reactor, initializing it with a channel to send protocol events on, and
     /// a channel to receive commands.
@@ -0,0 +1,604 @@
+//! Recording and replay of protocol inputs, to reproduce bugs.
+//!
+//! Protocol steps are deterministic (see [`Protocol::step`]), so a run can be reproduced
+//! by feeding the same inputs, at the same times, to a protocol built with the same
+//! initial state and RNG seed. A [`Recorder`] writes every input fed to a protocol as a
+//! line of JSON, with the local time in milliseconds since Epoch under `time`, and the
+//! kind of input under `input`, eg.
+//!
+//! ```text
+//! {"input":"connected","link":"outbound","local_addr":"0.0.0.0:0","peer":"88.13.16.59:8333","time":1600000000000}
+//! ```
+//!
+//! Recordings are fed back to a protocol with [`replay`]. This turns a recording attached
+//! to a bug report into a regression test:
+//!
+//! ```ignore
+//! let (tx, rx) = chan::unbounded();
+//! let mut protocol = builder.build(tx);
+//!
+//! replay::replay(&mut protocol, io::BufReader::new(fs::File::open("bug.jsonl")?))?;
+//! ```
+//!
+//! Commands are recorded without their reply channels, so replies are dropped on replay.
+//! The reason given for disconnecting a misbehaving peer isn't recorded either.
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net;
+use std::path::Path;
+
+use bitcoin::consensus::encode;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::BlockHash;
+use crossbeam_channel as chan;
+use microserde::json::{self, Number, Object, Value};
+
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::p2p::peer;
+
+use crate::protocol::{Command, DisconnectReason, Envelope, Input, Link, Protocol};
+
+/// Commands of the messages we may send. Used to recover the `&'static str` command of
+/// [`Input::Sent`] on replay.
+const COMMANDS: &[&str] = &[
+    "version",
+    "verack",
+    "addr",
+    "inv",
+    "getdata",
+    "notfound",
+    "getblocks",
+    "getheaders",
+    "mempool",
+    "tx",
+    "block",
+    "headers",
+    "sendheaders",
+    "getaddr",
+    "ping",
+    "pong",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "alert",
+    "reject",
+    "feefilter",
+    "unknown",
+];
+
+/// Size of an encoded block header.
+const HEADER_SIZE: usize = 80;
+
+/// Reason given for misbehavior disconnects on replay, since the original isn't recorded.
+const MISBEHAVIOR: &str = "misbehavior (replayed)";
+
+/// A recorded step of a protocol.
+#[derive(Debug)]
+pub enum Entry {
+    /// The protocol was initialized at the given time.
+    Initialize(LocalTime),
+    /// The protocol was fed an input at the given time.
+    Input(LocalTime, Input),
+    /// The given number of inputs that follow were fed to the protocol as one batch.
+    Batch(LocalTime, usize),
+}
+
+/// Records protocol inputs to `W`.
+#[derive(Debug)]
+pub struct Recorder<W: Write> {
+    writer: W,
+}
+
+impl Recorder<io::LineWriter<fs::File>> {
+    /// Create a recording file. An existing file is truncated, since recordings must
+    /// start with the protocol's initialization.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = fs::File::create(path)?;
+
+        Ok(Self::new(io::LineWriter::new(file)))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    /// Create a new recorder writing to the given writer.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Get a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Record the protocol's initialization.
+    pub fn initialize(&mut self, time: LocalTime) -> io::Result<()> {
+        self.write(time, "initialize", Object::new())
+    }
+
+    /// Record the start of a batch of the given size. The inputs of the batch must be
+    /// recorded next.
+    pub fn batch(&mut self, time: LocalTime, size: usize) -> io::Result<()> {
+        let mut obj = Object::new();
+        obj.insert("size".to_owned(), number(size as u64));
+
+        self.write(time, "batch", obj)
+    }
+
+    /// Record a protocol input.
+    pub fn record(&mut self, time: LocalTime, input: &Input) -> io::Result<()> {
+        let mut obj = Object::new();
+
+        let kind = match input {
+            Input::Connecting { addr } => {
+                obj.insert("peer".to_owned(), string(addr));
+                "connecting"
+            }
+            Input::Connected {
+                addr,
+                local_addr,
+                link,
+            } => {
+                let link = match link {
+                    Link::Inbound => "inbound",
+                    Link::Outbound => "outbound",
+                };
+                obj.insert("peer".to_owned(), string(addr));
+                obj.insert("local_addr".to_owned(), string(local_addr));
+                obj.insert("link".to_owned(), string(link));
+                "connected"
+            }
+            Input::Disconnected(addr, reason) => {
+                obj.insert("peer".to_owned(), string(addr));
+                self::encode_reason(reason, &mut obj);
+                "disconnected"
+            }
+            Input::Received(addr, envelope) => {
+                obj.insert("peer".to_owned(), string(addr));
+                obj.insert("message".to_owned(), string(&envelope.as_bytes().to_hex()));
+                "received"
+            }
+            Input::Sent(addr, cmd, size) => {
+                obj.insert("peer".to_owned(), string(addr));
+                obj.insert("command".to_owned(), string(cmd));
+                obj.insert("size".to_owned(), number(*size as u64));
+                "sent"
+            }
+            Input::Command(cmd) => {
+                self::encode_command(cmd, &mut obj);
+                "command"
+            }
+            Input::Timeout => "timeout",
+        };
+        self.write(time, kind, obj)
+    }
+
+    fn write(&mut self, time: LocalTime, kind: &str, mut obj: Object) -> io::Result<()> {
+        obj.insert("time".to_owned(), number(time.as_millis() as u64));
+        obj.insert("input".to_owned(), string(kind));
+
+        writeln!(self.writer, "{}", json::to_string(&Value::Object(obj)))
+    }
+}
+
+/// Read the entries of a recording.
+pub fn read<R: BufRead>(reader: R) -> impl Iterator<Item = io::Result<Entry>> {
+    reader
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(|line| self::decode(&line?))
+}
+
+/// Replay a recording against the given protocol, which should have been built with the
+/// same initial state and RNG seed as the recorded one. Outputs are sent upstream, as
+/// usual.
+pub fn replay<T: BlockTree, F: Filters, P: peer::Store, R: BufRead>(
+    protocol: &mut Protocol<T, F, P>,
+    reader: R,
+) -> io::Result<()> {
+    let mut entries = self::read(reader);
+
+    while let Some(entry) = entries.next() {
+        match entry? {
+            Entry::Initialize(time) => protocol.initialize(time),
+            Entry::Input(time, input) => protocol.step(input, time),
+            Entry::Batch(time, size) => {
+                let mut batch = Vec::with_capacity(size);
+
+                for entry in entries.by_ref().take(size) {
+                    match entry? {
+                        Entry::Input(_, input) => batch.push(input),
+                        _ => return Err(invalid_data("batch is missing inputs")),
+                    }
+                }
+                if batch.len() != size {
+                    return Err(invalid_data("batch is missing inputs"));
+                }
+                protocol.step_batch(batch, time);
+            }
+        }
+    }
+    Ok(())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn string<T: ToString + ?Sized>(s: &T) -> Value {
+    Value::String(s.to_string())
+}
+
+fn number(n: u64) -> Value {
+    Value::Number(Number::U64(n))
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn encode_reason(reason: &DisconnectReason, obj: &mut Object) {
+    let kind = match reason {
+        DisconnectReason::PeerMisbehaving(_) => "misbehaving",
+        DisconnectReason::PeerProtocolVersion(version) => {
+            obj.insert("value".to_owned(), number(*version as u64));
+            "protocol_version"
+        }
+        DisconnectReason::PeerServices(services) => {
+            obj.insert("value".to_owned(), number(services.as_u64()));
+            "services"
+        }
+        DisconnectReason::PeerHeight(height) => {
+            obj.insert("value".to_owned(), number(*height));
+            "height"
+        }
+        DisconnectReason::PeerMagic(magic) => {
+            obj.insert("value".to_owned(), number(*magic as u64));
+            "magic"
+        }
+        DisconnectReason::PeerTimeout => "timeout",
+        DisconnectReason::SelfConnection => "self_connection",
+        DisconnectReason::DuplicateConnection => "duplicate_connection",
+        DisconnectReason::PeerBanned => "banned",
+        DisconnectReason::ConnectionLimit => "connection_limit",
+        DisconnectReason::PeerRotated => "rotated",
+        DisconnectReason::PeerDisconnected => "peer_disconnected",
+        DisconnectReason::ConnectionError(err) => {
+            obj.insert("value".to_owned(), string(err));
+            "connection_error"
+        }
+        DisconnectReason::Command => "command",
+        DisconnectReason::Shutdown => "shutdown",
+    };
+    obj.insert("reason".to_owned(), string(kind));
+}
+
+fn encode_command(cmd: &Command, obj: &mut Object) {
+    // Messages are encoded with a zero magic, which is ignored on replay.
+    let message = |msg: &NetworkMessage| {
+        string(
+            &encode::serialize(&RawNetworkMessage {
+                magic: 0,
+                payload: msg.clone(),
+            })
+            .to_hex(),
+        )
+    };
+
+    let kind = match cmd {
+        Command::GetTip(_) => "get_tip",
+        Command::GetHeader(hash, _) => {
+            obj.insert("hash".to_owned(), string(hash));
+            "get_header"
+        }
+        Command::GetHeaderByHeight(height, _) => {
+            obj.insert("height".to_owned(), number(*height));
+            "get_header_by_height"
+        }
+        Command::GetFilterHeader(height, _) => {
+            obj.insert("height".to_owned(), number(*height));
+            "get_filter_header"
+        }
+        Command::GetFilterHeight(_) => "get_filter_height",
+        Command::GetBlock(hash) => {
+            obj.insert("hash".to_owned(), string(hash));
+            "get_block"
+        }
+        Command::GetFilters(range, _) => {
+            obj.insert("start".to_owned(), number(range.start));
+            obj.insert("end".to_owned(), number(range.end));
+            "get_filters"
+        }
+        Command::Broadcast(msg) => {
+            obj.insert("message".to_owned(), message(msg));
+            "broadcast"
+        }
+        Command::Query(msg, _) => {
+            obj.insert("message".to_owned(), message(msg));
+            "query"
+        }
+        Command::Connect(addr) => {
+            obj.insert("peer".to_owned(), string(addr));
+            "connect"
+        }
+        Command::Disconnect(addr) => {
+            obj.insert("peer".to_owned(), string(addr));
+            "disconnect"
+        }
+        Command::AddNode(addr) => {
+            obj.insert("peer".to_owned(), string(addr));
+            "add_node"
+        }
+        Command::RemoveNode(addr) => {
+            obj.insert("peer".to_owned(), string(addr));
+            "remove_node"
+        }
+        Command::Ban(ip, duration, reason) => {
+            obj.insert("ip".to_owned(), string(ip));
+            obj.insert(
+                "duration_ms".to_owned(),
+                number(duration.as_millis() as u64),
+            );
+            obj.insert("reason".to_owned(), string(reason));
+            "ban"
+        }
+        Command::Unban(ip) => {
+            obj.insert("ip".to_owned(), string(ip));
+            "unban"
+        }
+        Command::GetPeers(_) => "get_peers",
+        Command::GetBans(_) => "get_bans",
+        Command::ExportAddresses(_) => "export_addresses",
+        Command::GetPeerStats(_) => "get_peer_stats",
+        Command::ResetPeerStats => "reset_peer_stats",
+        Command::GetMemoryInfo(_) => "get_memory_info",
+        Command::GetTimeOffset(_) => "get_time_offset",
+        Command::ImportHeaders(headers, _) => {
+            // Headers are encoded back to back, since they have a fixed size.
+            let bytes = headers
+                .iter()
+                .flat_map(encode::serialize)
+                .collect::<Vec<u8>>();
+
+            obj.insert("headers".to_owned(), string(&bytes.to_hex()));
+            "import_headers"
+        }
+        Command::SubmitTransaction(tx) => {
+            obj.insert(
+                "transaction".to_owned(),
+                string(&encode::serialize(tx).to_hex()),
+            );
+            "submit_transaction"
+        }
+        Command::Shutdown => "shutdown",
+    };
+    obj.insert("command".to_owned(), string(kind));
+}
+
+/// Fields of a recorded entry.
+struct Fields(Object);
+
+impl Fields {
+    fn str(&self, key: &str) -> io::Result<&str> {
+        match self.0.get(key) {
+            Some(Value::String(s)) => Ok(s.as_str()),
+            _ => Err(invalid_data(format!("missing string field {:?}", key))),
+        }
+    }
+
+    fn u64(&self, key: &str) -> io::Result<u64> {
+        match self.0.get(key) {
+            Some(Value::Number(Number::U64(n))) => Ok(*n),
+            _ => Err(invalid_data(format!("missing number field {:?}", key))),
+        }
+    }
+
+    fn parse<T>(&self, key: &str) -> io::Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: ToString,
+    {
+        self.str(key)?.parse().map_err(invalid_data)
+    }
+
+    fn bytes(&self, key: &str) -> io::Result<Vec<u8>> {
+        Vec::<u8>::from_hex(self.str(key)?).map_err(invalid_data)
+    }
+
+    fn decode<T: encode::Decodable>(&self, key: &str) -> io::Result<T> {
+        encode::deserialize(&self.bytes(key)?).map_err(invalid_data)
+    }
+}
+
+fn decode(line: &str) -> io::Result<Entry> {
+    let fields = match json::from_str::<Value>(line).map_err(|_| invalid_data("invalid JSON"))? {
+        Value::Object(obj) => Fields(obj),
+        _ => return Err(invalid_data("expected a JSON object")),
+    };
+    let time = LocalTime::from_millis(fields.u64("time")? as u128);
+
+    let input = match fields.str("input")? {
+        "initialize" => return Ok(Entry::Initialize(time)),
+        "batch" => return Ok(Entry::Batch(time, fields.u64("size")? as usize)),
+        "connecting" => Input::Connecting {
+            addr: fields.parse("peer")?,
+        },
+        "connected" => Input::Connected {
+            addr: fields.parse("peer")?,
+            local_addr: fields.parse("local_addr")?,
+            link: match fields.str("link")? {
+                "inbound" => Link::Inbound,
+                "outbound" => Link::Outbound,
+                other => return Err(invalid_data(format!("unknown link {:?}", other))),
+            },
+        },
+        "disconnected" => Input::Disconnected(fields.parse("peer")?, self::decode_reason(&fields)?),
+        "received" => Input::Received(
+            fields.parse("peer")?,
+            Envelope::from_bytes(fields.bytes("message")?).map_err(invalid_data)?,
+        ),
+        "sent" => {
+            let cmd = fields.str("command")?;
+            let cmd = COMMANDS
+                .iter()
+                .find(|c| **c == cmd)
+                .ok_or_else(|| invalid_data(format!("unknown command {:?}", cmd)))?;
+
+            Input::Sent(fields.parse("peer")?, *cmd, fields.u64("size")? as usize)
+        }
+        "command" => Input::Command(self::decode_command(&fields)?),
+        "timeout" => Input::Timeout,
+        other => return Err(invalid_data(format!("unknown input {:?}", other))),
+    };
+    Ok(Entry::Input(time, input))
+}
+
+fn decode_reason(fields: &Fields) -> io::Result<DisconnectReason> {
+    let reason = match fields.str("reason")? {
+        "misbehaving" => DisconnectReason::PeerMisbehaving(MISBEHAVIOR),
+        "protocol_version" => DisconnectReason::PeerProtocolVersion(fields.u64("value")? as u32),
+        "services" => DisconnectReason::PeerServices(ServiceFlags::from(fields.u64("value")?)),
+        "height" => DisconnectReason::PeerHeight(fields.u64("value")?),
+        "magic" => DisconnectReason::PeerMagic(fields.u64("value")? as u32),
+        "timeout" => DisconnectReason::PeerTimeout,
+        "self_connection" => DisconnectReason::SelfConnection,
+        "duplicate_connection" => DisconnectReason::DuplicateConnection,
+        "banned" => DisconnectReason::PeerBanned,
+        "connection_limit" => DisconnectReason::ConnectionLimit,
+        "rotated" => DisconnectReason::PeerRotated,
+        "peer_disconnected" => DisconnectReason::PeerDisconnected,
+        "connection_error" => DisconnectReason::ConnectionError(fields.str("value")?.to_owned()),
+        "command" => DisconnectReason::Command,
+        "shutdown" => DisconnectReason::Shutdown,
+        other => return Err(invalid_data(format!("unknown reason {:?}", other))),
+    };
+    Ok(reason)
+}
+
+fn decode_command(fields: &Fields) -> io::Result<Command> {
+    // Replies are sent to channels nobody listens on.
+    fn reply<T>() -> chan::Sender<T> {
+        chan::bounded(1).0
+    }
+    let message = |key: &str| {
+        fields
+            .decode::<RawNetworkMessage>(key)
+            .map(|raw| raw.payload)
+    };
+    let hash = |key: &str| -> io::Result<BlockHash> {
+        BlockHash::from_hex(fields.str(key)?).map_err(invalid_data)
+    };
+    let addr = |key: &str| fields.parse::<net::SocketAddr>(key);
+
+    let cmd = match fields.str("command")? {
+        "get_tip" => Command::GetTip(reply()),
+        "get_header" => Command::GetHeader(hash("hash")?, reply()),
+        "get_header_by_height" => Command::GetHeaderByHeight(fields.u64("height")?, reply()),
+        "get_filter_header" => Command::GetFilterHeader(fields.u64("height")?, reply()),
+        "get_filter_height" => Command::GetFilterHeight(reply()),
+        "get_block" => Command::GetBlock(hash("hash")?),
+        "get_filters" => Command::GetFilters(fields.u64("start")?..fields.u64("end")?, reply()),
+        "broadcast" => Command::Broadcast(message("message")?),
+        "query" => Command::Query(message("message")?, reply()),
+        "connect" => Command::Connect(addr("peer")?),
+        "disconnect" => Command::Disconnect(addr("peer")?),
+        "add_node" => Command::AddNode(addr("peer")?),
+        "remove_node" => Command::RemoveNode(addr("peer")?),
+        "ban" => Command::Ban(
+            fields.parse("ip")?,
+            LocalDuration::from_millis(fields.u64("duration_ms")? as u128),
+            fields.str("reason")?.to_owned(),
+        ),
+        "unban" => Command::Unban(fields.parse("ip")?),
+        "get_peers" => Command::GetPeers(reply()),
+        "get_bans" => Command::GetBans(reply()),
+        "export_addresses" => Command::ExportAddresses(reply()),
+        "get_peer_stats" => Command::GetPeerStats(reply()),
+        "reset_peer_stats" => Command::ResetPeerStats,
+        "get_memory_info" => Command::GetMemoryInfo(reply()),
+        "get_time_offset" => Command::GetTimeOffset(reply()),
+        "import_headers" => {
+            let bytes = Vec::<u8>::from_hex(fields.str("headers")?).map_err(invalid_data)?;
+            let headers = bytes
+                .chunks(HEADER_SIZE)
+                .map(encode::deserialize)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid_data)?;
+
+            Command::ImportHeaders(headers, reply())
+        }
+        "submit_transaction" => Command::SubmitTransaction(fields.decode("transaction")?),
+        "shutdown" => Command::Shutdown,
+        other => return Err(invalid_data(format!("unknown command {:?}", other))),
+    };
+    Ok(cmd)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nakamoto_common::network::Network;
+
+    #[test]
+    fn test_roundtrip() {
+        let network = Network::Mainnet;
+        let addr: net::SocketAddr = ([88, 13, 16, 59], 8333).into();
+        let local_addr: net::SocketAddr = ([152, 168, 3, 33], 8333).into();
+        let time = LocalTime::from_secs(1_600_000_000);
+        let inputs = vec![
+            Input::Connecting { addr },
+            Input::Connected {
+                addr,
+                local_addr,
+                link: Link::Outbound,
+            },
+            Input::received(
+                addr,
+                RawNetworkMessage {
+                    magic: network.magic(),
+                    payload: NetworkMessage::Ping(42),
+                },
+            ),
+            Input::Sent(addr, "pong", 32),
+            Input::Command(Command::Ban(
+                addr.ip(),
+                LocalDuration::from_mins(1),
+                "reason".to_owned(),
+            )),
+            Input::Command(Command::ImportHeaders(
+                vec![network.genesis(), network.genesis()],
+                chan::bounded(1).0,
+            )),
+            Input::Disconnected(addr, DisconnectReason::ConnectionError("reset".to_owned())),
+            Input::Timeout,
+        ];
+
+        let mut recorder = Recorder::new(Vec::new());
+        recorder.initialize(time).unwrap();
+        for input in &inputs {
+            recorder.record(time, input).unwrap();
+        }
+
+        let entries = read(recorder.get_ref().as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert!(matches!(entries[0], Entry::Initialize(t) if t == time));
+        assert_eq!(entries.len(), inputs.len() + 1);
+
+        for (entry, input) in entries[1..].iter().zip(inputs) {
+            match entry {
+                Entry::Input(t, actual) => {
+                    assert_eq!(*t, time);
+                    assert_eq!(format!("{:?}", actual), format!("{:?}", input));
+                }
+                other => panic!("unexpected entry {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,639 @@
+//! A deterministic P2P network simulator.
+//!
+//! Acts as the _reactor_ for a set of protocol instances, but without doing any I/O.
+//! Protocol outputs are scheduled as inputs of other peers, according to the
+//! configuration of the link between them: messages can be delayed, lost, reordered,
+//! or blocked by a network partition. All randomness comes from the given RNG, so a
+//! simulation can be replayed exactly from its seed.
+//!
+//! The simulation has its own clock, which only moves when it is advanced, eg. with
+//! [`Simulation::elapse`]. Timeouts requested by peers fire as the clock reaches them,
+//! so that timeout behavior can be tested without waiting.
+//!
+//! ```ignore
+//! let mut sim = Simulation::new(time, fastrand::Rng::with_seed(seed), LinkConfig::default());
+//!
+//! sim.add_peer(alice_addr, alice, alice_rx);
+//! sim.add_peer(bob_addr, bob, bob_rx);
+//! sim.initialize();
+//! sim.connect(&alice_addr, &bob_addr);
+//! sim.elapse(LocalDuration::from_secs(60));
+//! ```
+//!
+//! Mining is simulated by importing headers into a peer, with
+//! [`Simulation::import_headers`]. Misbehaving peers can be simulated with the behaviors
+//! in [`adversary`]. The inputs of a peer can be recorded with [`Simulation::record`],
+//! to be replayed with [`crate::replay`]. Log records of a simulation can be captured
+//! with [`Simulation::set_log_sink`].
+//!
+//! To catch regressions in how chatty or slow the protocol is, messages sent by each peer
+//! are counted, see [`Simulation::messages`], and the simulated time it takes to reach a
+//! given state can be measured with [`Simulation::elapse_until`].
+//!
+//! The simulation is meant to scale to thousands of peers: timeouts of a peer that fire
+//! at the same time are delivered once, the state of a link is dropped when it is
+//! disconnected, and the number of events kept per peer can be bounded with
+//! [`Simulation::set_event_limit`].
+pub mod adversary;
+pub mod logger;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+use bitcoin::consensus::encode;
+use bitcoin::network::message::RawNetworkMessage;
+use bitcoin_hashes::{sha256d, Hash};
+use crossbeam_channel as chan;
+use log::*;
+
+use nakamoto_common::block::filter::Filters;
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+use nakamoto_common::block::tree::{self, BlockTree, ImportResult};
+use nakamoto_common::block::BlockHeader;
+use nakamoto_common::p2p::peer;
+
+use crate::event::Event;
+use crate::protocol::{Command, DisconnectReason, Input, Link, Out, PeerId, Protocol};
+use crate::replay::Recorder;
+
+use logger::Sink;
+
+/// Configuration of a network link between two peers.
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    /// Minimum time it takes for a message to be delivered.
+    pub latency: LocalDuration,
+    /// Maximum random delay added to the latency of each message.
+    pub jitter: LocalDuration,
+    /// Probability that a message is lost, between `0.0` and `1.0`.
+    pub loss: f64,
+    /// Whether messages may overtake each other, when delays differ. Otherwise,
+    /// messages are delivered in the order they were sent, as with TCP.
+    pub reorder: bool,
+    /// Probability that a message is corrupted, by flipping a bit of its payload or
+    /// truncating it. The length and checksum of corrupted messages are fixed up, as a
+    /// misbehaving peer would. Messages that can no longer be decoded are dropped, as
+    /// the reactor would.
+    pub corruption: f64,
+    /// Probability that a message is delivered twice.
+    pub duplication: f64,
+}
+
+impl Default for LinkConfig {
+    /// A perfect link: messages are delivered instantly, in order, and never lost.
+    fn default() -> Self {
+        Self {
+            latency: LocalDuration::from_millis(0),
+            jitter: LocalDuration::from_millis(0),
+            loss: 0.,
+            reorder: false,
+            corruption: 0.,
+            duplication: 0.,
+        }
+    }
+}
+
+/// A simulated peer.
+#[derive(Debug)]
+struct Node<T, F, P> {
+    protocol: Protocol<T, F, P>,
+    outbound: chan::Receiver<Out>,
+    events: VecDeque<Event>,
+    /// Times at which a timeout is scheduled for this peer.
+    timeouts: BTreeSet<LocalTime>,
+    recorder: Option<Recorder<Vec<u8>>>,
+}
+
+/// A simulated network of peers.
+#[derive(Debug)]
+pub struct Simulation<T, F, P> {
+    /// Sink of the log records emitted while stepping peers, if any.
+    sink: Option<Box<dyn Sink>>,
+    /// Peers in the network, ordered by address, for determinism.
+    peers: BTreeMap<PeerId, Node<T, F, P>>,
+    /// Inputs scheduled for delivery, ordered by delivery time, and then by the order
+    /// in which they were scheduled.
+    inbox: BTreeMap<(LocalTime, u64), (PeerId, Input)>,
+    /// Number of inputs scheduled so far. Used to order inputs delivered at the same time.
+    scheduled: u64,
+    /// Link configuration used for links that aren't configured explicitly.
+    link: LinkConfig,
+    /// Configuration of individual links.
+    links: HashMap<(PeerId, PeerId), LinkConfig>,
+    /// Last delivery time of each link, used to keep messages in order.
+    deliveries: HashMap<(PeerId, PeerId), LocalTime>,
+    /// Number of messages sent by each peer, by command.
+    messages: HashMap<(PeerId, &'static str), usize>,
+    /// Network partitions, as pairs of groups of peers that can't reach each other.
+    partitions: Vec<(HashSet<PeerId>, HashSet<PeerId>)>,
+    /// Maximum number of events kept per peer, if any.
+    event_limit: Option<usize>,
+    /// Current simulation time.
+    time: LocalTime,
+    /// Source of all randomness in the simulation.
+    rng: fastrand::Rng,
+}
+
+impl<T: BlockTree, F: Filters, P: peer::Store> Simulation<T, F, P> {
+    /// Create a new, empty simulation, starting at the given time. Links between peers
+    /// use the given configuration, unless configured otherwise.
+    pub fn new(time: LocalTime, rng: fastrand::Rng, link: LinkConfig) -> Self {
+        Self {
+            sink: None,
+            peers: BTreeMap::new(),
+            inbox: BTreeMap::new(),
+            scheduled: 0,
+            link,
+            links: HashMap::new(),
+            deliveries: HashMap::new(),
+            messages: HashMap::new(),
+            partitions: Vec::new(),
+            event_limit: None,
+            time,
+            rng,
+        }
+    }
+
+    /// Add a peer to the simulation, given its protocol instance and the receiving end
+    /// of its output channel.
+    pub fn add_peer(
+        &mut self,
+        addr: PeerId,
+        protocol: Protocol<T, F, P>,
+        outbound: chan::Receiver<Out>,
+    ) {
+        self.peers.insert(
+            addr,
+            Node {
+                protocol,
+                outbound,
+                events: VecDeque::new(),
+                timeouts: BTreeSet::new(),
+                recorder: None,
+            },
+        );
+    }
+
+    /// Remove a peer from the simulation, returning its protocol instance and output
+    /// channel. Inputs still scheduled for the peer are discarded.
+    pub fn remove_peer(
+        &mut self,
+        addr: &PeerId,
+    ) -> Option<(Protocol<T, F, P>, chan::Receiver<Out>)> {
+        self.deliveries.retain(|(a, b), _| a != addr && b != addr);
+        self.peers.remove(addr).map(|n| (n.protocol, n.outbound))
+    }
+
+    /// Get the addresses of all peers in the simulation, in order.
+    pub fn addresses(&self) -> impl Iterator<Item = &PeerId> + '_ {
+        self.peers.keys()
+    }
+
+    /// Get a peer's protocol instance.
+    pub fn peer(&self, addr: &PeerId) -> Option<&Protocol<T, F, P>> {
+        self.peers.get(addr).map(|n| &n.protocol)
+    }
+
+    /// Drain the events emitted by the given peer.
+    pub fn events(&mut self, addr: &PeerId) -> impl Iterator<Item = Event> + '_ {
+        self.peers
+            .get_mut(addr)
+            .into_iter()
+            .flat_map(|n| n.events.drain(..))
+    }
+
+    /// Keep at most the given number of events per peer, discarding the oldest ones.
+    /// Useful for large simulations, where events are rarely drained.
+    pub fn set_event_limit(&mut self, limit: usize) {
+        self.event_limit = Some(limit);
+
+        for node in self.peers.values_mut() {
+            while node.events.len() > limit {
+                node.events.pop_front();
+            }
+        }
+    }
+
+    /// Send the log records emitted by the simulation and its peers to the given sink.
+    /// Installs the simulation logger, see [`logger`].
+    pub fn set_log_sink(&mut self, sink: impl Sink + 'static) {
+        logger::install();
+
+        self.sink = Some(Box::new(sink));
+    }
+
+    /// Start recording the inputs of the given peer. To be replayable, the recording
+    /// should start before the peer is initialized.
+    pub fn record(&mut self, addr: &PeerId) {
+        if let Some(node) = self.peers.get_mut(addr) {
+            node.recorder = Some(Recorder::new(Vec::new()));
+        }
+    }
+
+    /// Get the inputs recorded for the given peer, in the format read by
+    /// [`crate::replay::read`].
+    pub fn recording(&self, addr: &PeerId) -> Option<&[u8]> {
+        self.peers
+            .get(addr)
+            .and_then(|n| n.recorder.as_ref())
+            .map(|r| r.get_ref().as_slice())
+    }
+
+    /// The current simulation time.
+    pub fn time(&self) -> LocalTime {
+        self.time
+    }
+
+    /// Check whether there are no inputs left to deliver.
+    pub fn is_idle(&self) -> bool {
+        self.inbox.is_empty()
+    }
+
+    /// Configure the link between two peers, in both directions.
+    pub fn set_link(&mut self, a: &PeerId, b: &PeerId, config: LinkConfig) {
+        self.links.insert((*a, *b), config.clone());
+        self.links.insert((*b, *a), config);
+    }
+
+    /// Partition the network, such that the peers in `a` can no longer reach the
+    /// peers in `b`, and vice-versa. Messages sent across the partition are lost, and
+    /// connection attempts fail.
+    pub fn partition(&mut self, a: &[PeerId], b: &[PeerId]) {
+        self.partitions
+            .push((a.iter().cloned().collect(), b.iter().cloned().collect()));
+    }
+
+    /// Remove all network partitions.
+    pub fn heal(&mut self) {
+        self.partitions.clear();
+    }
+
+    /// Initialize all peers, scheduling their initial outputs.
+    pub fn initialize(&mut self) {
+        let addrs = self.peers.keys().cloned().collect::<Vec<_>>();
+
+        for addr in addrs {
+            self.logging(|sim| {
+                if let Some(node) = sim.peers.get_mut(&addr) {
+                    debug!("(sim) Initializing {}", addr);
+
+                    if let Some(recorder) = &mut node.recorder {
+                        // Writing to memory can't fail.
+                        recorder.initialize(sim.time).ok();
+                    }
+                    node.protocol.initialize(sim.time);
+                }
+                sim.drain(&addr);
+            });
+        }
+    }
+
+    /// Establish a connection from one peer to another, without going through a
+    /// connection attempt.
+    pub fn connect(&mut self, addr: &PeerId, remote: &PeerId) {
+        self.schedule(
+            *addr,
+            Input::Connected {
+                addr: *remote,
+                local_addr: *addr,
+                link: Link::Outbound,
+            },
+            self.time,
+        );
+        self.schedule(
+            *remote,
+            Input::Connected {
+                addr: *addr,
+                local_addr: *remote,
+                link: Link::Inbound,
+            },
+            self.time,
+        );
+    }
+
+    /// Send an input to a peer immediately, scheduling the resulting outputs.
+    pub fn input(&mut self, addr: &PeerId, input: Input) {
+        self.logging(|sim| {
+            if let Some(node) = sim.peers.get_mut(addr) {
+                if let Some(recorder) = &mut node.recorder {
+                    recorder.record(sim.time, &input).ok();
+                }
+                node.protocol.step(input, sim.time);
+            }
+            sim.drain(addr);
+        });
+    }
+
+    /// Import headers into a peer's block tree, as if the peer had mined them. The peer
+    /// announces them to its peers as usual. Returns `None` if the peer doesn't exist.
+    pub fn import_headers(
+        &mut self,
+        addr: &PeerId,
+        headers: Vec<BlockHeader>,
+    ) -> Option<Result<ImportResult, tree::Error>> {
+        let (tx, rx) = chan::bounded(1);
+
+        self.input(addr, Input::Command(Command::ImportHeaders(headers, tx)));
+
+        rx.try_recv().ok()
+    }
+
+    /// Deliver the next scheduled input, advancing time to its delivery time.
+    /// Returns `false` if there was nothing left to deliver.
+    pub fn step(&mut self) -> bool {
+        let key = match self.inbox.keys().next() {
+            Some(key) => *key,
+            None => return false,
+        };
+        let (time, _) = key;
+
+        if let Some((addr, input)) = self.inbox.remove(&key) {
+            self.time = time;
+
+            if let Input::Timeout = input {
+                if let Some(node) = self.peers.get_mut(&addr) {
+                    node.timeouts.remove(&time);
+                }
+            }
+            self.input(&addr, input);
+        }
+        true
+    }
+
+    /// Deliver all inputs scheduled up to the given time, and advance time to it.
+    pub fn run_until(&mut self, time: LocalTime) {
+        while let Some((t, _)) = self.inbox.keys().next() {
+            if *t > time {
+                break;
+            }
+            self.step();
+        }
+        if time > self.time {
+            self.time = time;
+        }
+    }
+
+    /// Advance the clock by the given duration, delivering all inputs that become due,
+    /// including timeouts.
+    pub fn elapse(&mut self, duration: LocalDuration) {
+        self.run_until(self.time + duration);
+    }
+
+    /// Advance the clock, delivering inputs, until the given predicate holds, or the given
+    /// timeout has elapsed. Returns the time it took for the predicate to hold, if it did.
+    pub fn elapse_until<F>(&mut self, timeout: LocalDuration, predicate: F) -> Option<LocalDuration>
+    where
+        F: Fn(&Self) -> bool,
+    {
+        let start = self.time;
+        let deadline = start + timeout;
+
+        loop {
+            if predicate(self) {
+                return Some(self.time - start);
+            }
+            match self.inbox.keys().next() {
+                Some((t, _)) if *t <= deadline => {
+                    self.step();
+                }
+                _ => {
+                    self.run_until(deadline);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Get the number of messages with the given command sent by the given peer, whether
+    /// or not they were delivered.
+    pub fn messages(&self, addr: &PeerId, cmd: &str) -> usize {
+        self.messages
+            .iter()
+            .filter(|((a, c), _)| a == addr && *c == cmd)
+            .map(|(_, n)| n)
+            .sum()
+    }
+
+    /// Reset the message counts, eg. to only count messages sent from now on.
+    pub fn reset_messages(&mut self) {
+        self.messages.clear();
+    }
+
+    /// Get the time at which the next timeout of the given peer fires, if any.
+    pub fn next_timeout(&self, addr: &PeerId) -> Option<LocalTime> {
+        self.peers
+            .get(addr)
+            .and_then(|n| n.timeouts.iter().next())
+            .cloned()
+    }
+
+    /// Run the given function, sending the log records it emits to the simulation's sink.
+    fn logging<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let sink = self.sink.take();
+        let time = self.time;
+        let (sink, result) = logger::scope(sink, time, || f(self));
+
+        self.sink = sink;
+
+        result
+    }
+
+    /// Schedule the outputs of the given peer.
+    fn drain(&mut self, addr: &PeerId) {
+        let outputs = match self.peers.get(addr) {
+            Some(node) => node.outbound.try_iter().collect::<Vec<_>>(),
+            None => return,
+        };
+        for out in outputs {
+            self.output(*addr, out);
+        }
+    }
+
+    /// Process a protocol output of the given peer.
+    fn output(&mut self, peer: PeerId, out: Out) {
+        match out {
+            Out::Message(receiver, msg) => {
+                *self.messages.entry((peer, msg.cmd())).or_default() += 1;
+
+                let link = self
+                    .links
+                    .get(&(peer, receiver))
+                    .unwrap_or(&self.link)
+                    .clone();
+
+                if self.is_partitioned(&peer, &receiver) {
+                    info!(
+                        "(sim) {} -> {}: Partitioned {:?}",
+                        peer,
+                        receiver,
+                        msg.cmd()
+                    );
+                    return;
+                }
+                if link.loss > 0. && self.rng.f64() < link.loss {
+                    info!("(sim) {} -> {}: Lost {:?}", peer, receiver, msg.cmd());
+                    return;
+                }
+                let msg = if link.corruption > 0. && self.rng.f64() < link.corruption {
+                    match self.corrupt(&msg) {
+                        Some(corrupted) => corrupted,
+                        None => {
+                            info!(
+                                "(sim) {} -> {}: Corrupted {:?} beyond decoding",
+                                peer,
+                                receiver,
+                                msg.cmd()
+                            );
+                            return;
+                        }
+                    }
+                } else {
+                    msg
+                };
+                let mut time = self.time + self.delay(&link);
+
+                if !link.reorder {
+                    if let Some(last) = self.deliveries.get(&(peer, receiver)) {
+                        time = time.max(*last);
+                    }
+                    self.deliveries.insert((peer, receiver), time);
+                }
+                info!("(sim) {} -> {}: {:?}", peer, receiver, msg);
+
+                if link.duplication > 0. && self.rng.f64() < link.duplication {
+                    self.schedule(receiver, Input::received(peer, msg.clone()), time);
+                }
+
+                self.schedule(receiver, Input::received(peer, msg), time);
+            }
+            Out::Connect(remote, timeout) => {
+                assert!(remote != peer, "self-connections are not allowed");
+
+                self.schedule(peer, Input::Connecting { addr: remote }, self.time);
+
+                if self.is_partitioned(&peer, &remote) || !self.peers.contains_key(&remote) {
+                    info!("(sim) {} =/> {}", peer, remote);
+
+                    self.schedule(
+                        peer,
+                        Input::Disconnected(
+                            remote,
+                            DisconnectReason::ConnectionError(String::from("connection timed out")),
+                        ),
+                        self.time + timeout,
+                    );
+                    return;
+                }
+                info!("(sim) {} => {}", peer, remote);
+
+                let link = self
+                    .links
+                    .get(&(peer, remote))
+                    .unwrap_or(&self.link)
+                    .clone();
+                let time = self.time + self.delay(&link);
+
+                self.schedule(
+                    remote,
+                    Input::Connected {
+                        addr: peer,
+                        local_addr: remote,
+                        link: Link::Inbound,
+                    },
+                    time,
+                );
+                self.schedule(
+                    peer,
+                    Input::Connected {
+                        addr: remote,
+                        local_addr: peer,
+                        link: Link::Outbound,
+                    },
+                    time,
+                );
+            }
+            Out::Disconnect(remote, reason) => {
+                info!("(sim) {} =/= {} ({})", peer, remote, reason);
+
+                self.deliveries.remove(&(peer, remote));
+                self.deliveries.remove(&(remote, peer));
+
+                self.schedule(
+                    remote,
+                    Input::Disconnected(peer, DisconnectReason::PeerDisconnected),
+                    self.time,
+                );
+                self.schedule(peer, Input::Disconnected(remote, reason), self.time);
+            }
+            Out::SetTimeout(timeout) => {
+                let time = self.time + timeout;
+
+                // A single timeout input wakes the peer up for all its timers that are due.
+                if let Some(node) = self.peers.get_mut(&peer) {
+                    if node.timeouts.insert(time) {
+                        self.schedule(peer, Input::Timeout, time);
+                    }
+                }
+            }
+            Out::Event(event) => {
+                if let Some(node) = self.peers.get_mut(&peer) {
+                    node.events.push_back(event);
+
+                    if let Some(limit) = self.event_limit {
+                        while node.events.len() > limit {
+                            node.events.pop_front();
+                        }
+                    }
+                }
+            }
+            Out::Shutdown | Out::Fatal(_) => {}
+        }
+    }
+
+    /// Schedule an input for delivery to a peer, at the given time.
+    fn schedule(&mut self, addr: PeerId, input: Input, time: LocalTime) {
+        self.inbox.insert((time, self.scheduled), (addr, input));
+        self.scheduled += 1;
+    }
+
+    /// Corrupt the encoding of a message, returning the decoded result, if any.
+    fn corrupt(&mut self, msg: &RawNetworkMessage) -> Option<RawNetworkMessage> {
+        // Size of a message header: magic, command, payload length and checksum.
+        const HEADER_SIZE: usize = 24;
+
+        let mut bytes = encode::serialize(msg);
+        let mut payload = bytes.split_off(HEADER_SIZE);
+
+        if !payload.is_empty() && self.rng.bool() {
+            let i = self.rng.usize(..payload.len());
+            payload[i] ^= 1 << self.rng.u8(..8);
+        } else {
+            payload.truncate(self.rng.usize(..=payload.len()));
+        }
+        let checksum = sha256d::Hash::hash(&payload);
+
+        bytes[16..20].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes[20..24].copy_from_slice(&checksum[..4]);
+        bytes.extend(payload);
+
+        encode::deserialize(&bytes).ok()
+    }
+
+    /// Check whether two peers are on either side of a network partition.
+    fn is_partitioned(&self, a: &PeerId, b: &PeerId) -> bool {
+        self.partitions
+            .iter()
+            .any(|(x, y)| (x.contains(a) && y.contains(b)) || (x.contains(b) && y.contains(a)))
+    }
+
+    /// Get a random delay for a message sent over the given link.
+    fn delay(&mut self, link: &LinkConfig) -> LocalDuration {
+        let jitter = link.jitter.as_millis() as u64;
+        let jitter = if jitter > 0 {
+            self.rng.u64(0..=jitter)
+        } else {
+            0
+        };
+
+        link.latency + LocalDuration::from_millis(jitter as u128)
+    }
+}
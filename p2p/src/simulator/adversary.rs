@@ -0,0 +1,116 @@
+//! Adversarial peer behaviors.
+//!
+//! Each behavior is an [`Interceptor`] that distorts the messages a peer sends, so that
+//! a regular protocol instance can be turned into a misbehaving peer by setting it as
+//! the interceptor of that instance's configuration, eg.
+//!
+//! ```ignore
+//! let cfg = Config {
+//!     interceptor: Some(Arc::new(adversary::Silent)),
+//!     ..Config::default()
+//! };
+//! ```
+//!
+//! Behaviors only apply to messages sent after the handshake, unless stated otherwise,
+//! so that honest peers don't simply refuse to talk to the adversary.
+use std::net;
+
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::NetworkMessage;
+
+use nakamoto_common::block::Height;
+
+use crate::protocol::interceptor::{Context, Interceptor, Verdict};
+
+/// Check whether the message is part of the handshake.
+fn is_handshake(msg: &NetworkMessage) -> bool {
+    matches!(msg, NetworkMessage::Version(_) | NetworkMessage::Verack)
+}
+
+/// Completes the handshake, but never sends anything after it, including `pong`s.
+#[derive(Debug, Clone, Copy)]
+pub struct Silent;
+
+impl Interceptor for Silent {
+    fn sending(&self, _peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+        if is_handshake(msg) {
+            Verdict::Accept
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+/// Claims to be at the given height in its `version` message, while only serving the
+/// stale chain it actually has.
+#[derive(Debug, Clone, Copy)]
+pub struct Stale {
+    /// Height claimed.
+    pub height: Height,
+}
+
+impl Interceptor for Stale {
+    fn sending(&self, _peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+        if let NetworkMessage::Version(version) = msg {
+            version.start_height = self.height as i32;
+        }
+        Verdict::Accept
+    }
+}
+
+/// Sends headers with an invalid proof-of-work.
+#[derive(Debug, Clone, Copy)]
+pub struct InvalidPoW;
+
+impl Interceptor for InvalidPoW {
+    fn sending(&self, _peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+        if let NetworkMessage::Headers(headers) = msg {
+            for header in headers.iter_mut() {
+                // Changing the nonce changes the block hash, which is then almost
+                // certainly above the target.
+                header.nonce = header.nonce.wrapping_add(1);
+            }
+        }
+        Verdict::Accept
+    }
+}
+
+/// Never sends compact filters, filter headers or filter checkpoints.
+#[derive(Debug, Clone, Copy)]
+pub struct WithholdFilters;
+
+impl Interceptor for WithholdFilters {
+    fn sending(&self, _peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+        match msg {
+            NetworkMessage::CFilter(_)
+            | NetworkMessage::CFHeaders(_)
+            | NetworkMessage::CFCheckpt(_) => Verdict::Drop,
+            _ => Verdict::Accept,
+        }
+    }
+}
+
+/// Sends an `addr` message with the given number of made-up addresses in place of every
+/// message it sends.
+#[derive(Debug, Clone, Copy)]
+pub struct AddrFlood {
+    /// Number of addresses per message.
+    pub count: usize,
+}
+
+impl Interceptor for AddrFlood {
+    fn sending(&self, _peer: &Context, msg: &mut NetworkMessage) -> Verdict {
+        if !is_handshake(msg) {
+            let addrs = (0..self.count as u32)
+                .map(|i| {
+                    let ip = net::Ipv4Addr::from(0x0b00_0000 + i);
+                    (0, Address::new(&(ip, 8333).into(), ServiceFlags::NETWORK))
+                })
+                .collect();
+
+            *msg = NetworkMessage::Addr(addrs);
+        }
+        Verdict::Accept
+    }
+}
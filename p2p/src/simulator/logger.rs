@@ -0,0 +1,184 @@
+//! Log capture for simulations.
+//!
+//! The `log` crate only supports a single, process-wide logger, which makes it hard to
+//! tell apart the output of tests running in the same process. Instead, the logger
+//! installed by [`install`] forwards records to the [`Sink`] of the simulation that is
+//! stepping on the current thread, if any. Since a simulation steps its peers on a single
+//! thread, each simulation only receives its own records, including those of its peers.
+//!
+//! ```ignore
+//! let logs = Capture::new(Level::Debug);
+//!
+//! sim.set_log_sink(logs.clone());
+//! sim.elapse(LocalDuration::from_secs(60));
+//!
+//! assert!(logs.contains("Received command"));
+//! ```
+//!
+//! Records emitted outside of a simulation are printed, if [`init`] was called on the
+//! current thread.
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use log::*;
+
+use nakamoto_common::block::time::LocalTime;
+
+/// Receives the log records of a simulation.
+pub trait Sink: fmt::Debug {
+    /// Log a record, emitted at the given simulation time.
+    fn log(&mut self, time: LocalTime, record: &Record);
+}
+
+/// A captured log record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Simulation time at which the record was emitted.
+    pub time: LocalTime,
+    /// Log level.
+    pub level: Level,
+    /// Target of the record, ie. its module path by default.
+    pub target: String,
+    /// Formatted message.
+    pub message: String,
+}
+
+/// A sink that keeps records in memory, so that they can be asserted on. Clones share
+/// the same records.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    level: Level,
+    entries: Rc<RefCell<Vec<Entry>>>,
+}
+
+impl Capture {
+    /// Create a new capture, keeping records up to the given level.
+    pub fn new(level: Level) -> Self {
+        Self {
+            level,
+            entries: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /// Get the records captured so far.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.borrow().clone()
+    }
+
+    /// Check whether any captured message contains the given string.
+    pub fn contains(&self, pattern: &str) -> bool {
+        self.entries
+            .borrow()
+            .iter()
+            .any(|e| e.message.contains(pattern))
+    }
+
+    /// Discard the records captured so far.
+    pub fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
+}
+
+impl Sink for Capture {
+    fn log(&mut self, time: LocalTime, record: &Record) {
+        if record.level() <= self.level {
+            self.entries.borrow_mut().push(Entry {
+                time,
+                level: record.level(),
+                target: record.target().to_owned(),
+                message: record.args().to_string(),
+            });
+        }
+    }
+}
+
+/// A sink that prints records to standard output, prefixed with the simulation time.
+#[derive(Debug, Clone)]
+pub struct Print {
+    level: Level,
+}
+
+impl Print {
+    /// Create a new sink, printing records up to the given level.
+    pub fn new(level: Level) -> Self {
+        Self { level }
+    }
+}
+
+impl Sink for Print {
+    fn log(&mut self, time: LocalTime, record: &Record) {
+        if record.level() <= self.level {
+            println!("sim> {} [{}] {}", time, record.target(), record.args());
+        }
+    }
+}
+
+thread_local! {
+    /// Sink of the simulation stepping on this thread, and its current time.
+    static SINK: RefCell<Option<(LocalTime, Box<dyn Sink>)>> = RefCell::new(None);
+    /// Level at which records emitted outside of a simulation are printed.
+    static LEVEL: Cell<Option<Level>> = Cell::new(None);
+}
+
+/// Forwards records to the sink of the current thread.
+struct Dispatch;
+
+impl Log for Dispatch {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        SINK.with(|s| s.borrow().is_some()) || LEVEL.with(|l| l.get() >= Some(metadata.level()))
+    }
+
+    fn log(&self, record: &Record) {
+        let logged = SINK.with(|s| match &mut *s.borrow_mut() {
+            Some((time, sink)) => {
+                sink.log(*time, record);
+                true
+            }
+            None => false,
+        });
+
+        if !logged && LEVEL.with(|l| l.get() >= Some(record.level())) {
+            println!(
+                "test> [{}:{}:{}] {}",
+                record.target(),
+                record.file().unwrap_or_default(),
+                record.line().unwrap_or_default(),
+                record.args()
+            )
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static DISPATCH: Dispatch = Dispatch;
+
+/// Install the simulation logger, if no logger was installed yet. Records are only
+/// captured if this logger is the one installed.
+pub fn install() {
+    if log::set_logger(&DISPATCH).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// Install the simulation logger, and print the records emitted on the current thread
+/// outside of a simulation, up to the given level.
+pub fn init(level: Level) {
+    install();
+    LEVEL.with(|l| l.set(Some(level)));
+}
+
+/// Forward the records emitted on the current thread while running the given function
+/// to the given sink, if any. Returns the sink along with the function's result.
+pub(crate) fn scope<T>(
+    sink: Option<Box<dyn Sink>>,
+    time: LocalTime,
+    f: impl FnOnce() -> T,
+) -> (Option<Box<dyn Sink>>, T) {
+    let prev = SINK.with(|s| s.replace(sink.map(|sink| (time, sink))));
+    let result = f();
+    let sink = SINK.with(|s| s.replace(prev)).map(|(_, sink)| sink);
+
+    (sink, result)
+}
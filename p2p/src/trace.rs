@@ -0,0 +1,27 @@
+//! Optional [`tracing`](https://docs.rs/tracing) instrumentation.
+//!
+//! When the `tracing` feature is enabled, the protocol enters spans for each step, each
+//! peer message and each sub-protocol handling it. Log records can be turned into
+//! `tracing` events that are attached to these spans, eg. with `tracing_log::LogTracer`,
+//! so that a message can be followed from the reactor to the protocol and chain. When
+//! the feature is disabled, spans compile to nothing.
+
+/// Enter a span at the `DEBUG` level, until the returned guard is dropped.
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($($args:tt)*) => {
+        tracing::debug_span!($($args)*).entered()
+    };
+}
+
+/// Enter a span at the `DEBUG` level, until the returned guard is dropped.
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($args:tt)*) => {
+        $crate::trace::Guard
+    };
+}
+
+/// Span guard used when the `tracing` feature is disabled.
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Guard;
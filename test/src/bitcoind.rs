@@ -0,0 +1,234 @@
+//! A local `bitcoind` in regtest mode, for integration tests.
+//!
+//! The node is controlled through `bitcoin-cli`, so no RPC client is needed. The binaries
+//! are looked up in `PATH`, unless the `BITCOIND` and `BITCOIN_CLI` environment variables
+//! are set. Version 0.21 or later is required, for compact block filters.
+//!
+//! ```ignore
+//! let bitcoind = Bitcoind::spawn()?;
+//! let blocks = bitcoind.generate(10)?;
+//!
+//! // Connect a client to `bitcoind.p2p_addr`, and check it syncs to `blocks.last()`.
+//! ```
+use std::env;
+use std::fs;
+use std::io;
+use std::net;
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bitcoin::consensus::encode;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{BlockHash, Transaction, Txid};
+
+use nakamoto_common::block::{BlockHeader, Height};
+
+/// Number of nodes spawned by this process, used to give each its own data directory.
+static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+
+/// Name of the wallet used to mine and fund transactions.
+const WALLET: &str = "nakamoto";
+
+/// A running `bitcoind` in regtest mode. The node is stopped, and its data directory
+/// removed, when this is dropped.
+#[derive(Debug)]
+pub struct Bitcoind {
+    /// Address of the node's peer-to-peer interface.
+    pub p2p_addr: net::SocketAddr,
+    /// Port of the node's RPC interface.
+    rpc_port: u16,
+    /// Data directory of the node.
+    dir: PathBuf,
+    /// The node process.
+    process: process::Child,
+}
+
+impl Bitcoind {
+    /// Spawn a node with an empty chain, and wait until it's ready.
+    pub fn spawn() -> io::Result<Self> {
+        let dir = env::temp_dir().join(format!(
+            "nakamoto-bitcoind-{}-{}",
+            process::id(),
+            SPAWNED.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir)?;
+
+        let p2p_port = self::free_port()?;
+        let rpc_port = self::free_port()?;
+        let process = Command::new(env::var("BITCOIND").unwrap_or_else(|_| "bitcoind".into()))
+            .arg("-regtest")
+            .arg(format!("-datadir={}", dir.display()))
+            .arg(format!("-port={}", p2p_port))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg("-bind=127.0.0.1")
+            .arg("-listen=1")
+            .arg("-server=1")
+            .arg("-blockfilterindex=1")
+            .arg("-peerblockfilters=1")
+            .arg("-fallbackfee=0.0001")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let bitcoind = Self {
+            p2p_addr: ([127, 0, 0, 1], p2p_port).into(),
+            rpc_port,
+            dir,
+            process,
+        };
+        bitcoind.rpc(&["-rpcwait", "getblockchaininfo"])?;
+        bitcoind.rpc(&["createwallet", WALLET])?;
+
+        Ok(bitcoind)
+    }
+
+    /// Call an RPC method with `bitcoin-cli`, returning its output, trimmed.
+    pub fn rpc(&self, args: &[&str]) -> io::Result<String> {
+        let output = Command::new(env::var("BITCOIN_CLI").unwrap_or_else(|_| "bitcoin-cli".into()))
+            .arg("-regtest")
+            .arg(format!("-datadir={}", self.dir.display()))
+            .arg(format!("-rpcport={}", self.rpc_port))
+            .args(args)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "`bitcoin-cli {}` failed: {}",
+                    args.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Call a wallet RPC method, on the node's wallet.
+    fn wallet(&self, args: &[&str]) -> io::Result<String> {
+        let wallet = format!("-rpcwallet={}", WALLET);
+
+        self.rpc(&[&[wallet.as_str()][..], args].concat())
+    }
+
+    /// Mine the given number of blocks, returning their hashes. Each call mines to a new
+    /// address, so that blocks mined after an invalidation don't repeat invalid ones.
+    pub fn generate(&self, count: usize) -> io::Result<Vec<BlockHash>> {
+        let address = self.wallet(&["getnewaddress"])?;
+        let output = self.rpc(&["generatetoaddress", &count.to_string(), &address])?;
+
+        // The output is a JSON array of block hashes, one per line.
+        output
+            .lines()
+            .map(|l| {
+                l.trim()
+                    .trim_matches(|c| matches!(c, '[' | ']' | ',' | '"'))
+            })
+            .filter(|l| !l.is_empty())
+            .map(|l| BlockHash::from_hex(l).map_err(self::invalid_data))
+            .collect()
+    }
+
+    /// Get the height of the node's best chain.
+    pub fn height(&self) -> io::Result<Height> {
+        self.rpc(&["getblockcount"])?
+            .parse()
+            .map_err(self::invalid_data)
+    }
+
+    /// Get the hash of the block at the given height, in the node's best chain.
+    pub fn block_hash(&self, height: Height) -> io::Result<BlockHash> {
+        BlockHash::from_hex(&self.rpc(&["getblockhash", &height.to_string()])?)
+            .map_err(self::invalid_data)
+    }
+
+    /// Submit a block header on its own, as a candidate chain tip. Returns the reason
+    /// given by the node if the header is rejected.
+    pub fn submit_header(&self, header: &BlockHeader) -> io::Result<Result<(), String>> {
+        match self.rpc(&["submitheader", &encode::serialize(header).to_hex()]) {
+            Ok(_) => Ok(Ok(())),
+            // Invalid headers are reported as verification errors.
+            Err(err) if err.to_string().contains("error code: -25") => {
+                let msg = err.to_string();
+                let reason = msg.rsplit("error message:").next().unwrap_or_default();
+
+                Ok(Err(reason.trim().to_owned()))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Mark the given block as invalid, reverting the node's best chain to its parent.
+    pub fn invalidate(&self, hash: &BlockHash) -> io::Result<()> {
+        self.rpc(&["invalidateblock", &hash.to_string()])
+            .map(|_| ())
+    }
+
+    /// Create a transaction sending coins to the wallet, signed but not broadcast.
+    /// Requires mature coinbase outputs, ie. more than 100 blocks.
+    pub fn transaction(&self) -> io::Result<Transaction> {
+        let address = self.wallet(&["getnewaddress"])?;
+        let raw = self.rpc(&[
+            "createrawtransaction",
+            "[]",
+            &format!("{{\"{}\":1.0}}", address),
+        ])?;
+        let funded = self::json_string(&self.wallet(&["fundrawtransaction", &raw])?, "hex")?;
+        let signed = self::json_string(
+            &self.wallet(&["signrawtransactionwithwallet", &funded])?,
+            "hex",
+        )?;
+        let bytes = Vec::<u8>::from_hex(&signed).map_err(self::invalid_data)?;
+
+        encode::deserialize(&bytes).map_err(self::invalid_data)
+    }
+
+    /// Get the transactions in the node's mempool.
+    pub fn mempool(&self) -> io::Result<Vec<Txid>> {
+        let output = self.rpc(&["getrawmempool"])?;
+
+        output
+            .lines()
+            .map(|l| {
+                l.trim()
+                    .trim_matches(|c| matches!(c, '[' | ']' | ',' | '"'))
+            })
+            .filter(|l| !l.is_empty())
+            .map(|l| Txid::from_hex(l).map_err(self::invalid_data))
+            .collect()
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        if self.rpc(&["stop"]).is_err() {
+            self.process.kill().ok();
+        }
+        self.process.wait().ok();
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+/// Get a port that is free to listen on.
+fn free_port() -> io::Result<u16> {
+    let listener = net::TcpListener::bind(("127.0.0.1", 0))?;
+
+    listener.local_addr().map(|a| a.port())
+}
+
+/// Get the value of a string field in a flat JSON object.
+fn json_string(json: &str, field: &str) -> io::Result<String> {
+    let key = format!("\"{}\"", field);
+    let value = json
+        .find(&key)
+        .map(|i| &json[i + key.len()..])
+        .and_then(|rest| rest.splitn(3, '"').nth(1))
+        .ok_or_else(|| invalid_data(format!("field {:?} not found in {}", field, json)))?;
+
+    Ok(value.to_owned())
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
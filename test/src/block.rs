@@ -4,6 +4,10 @@ pub use nakamoto_common::block::*;
 pub mod cache {
     pub mod model;
 }
+pub mod diff;
+pub mod gen;
+pub mod reorg;
+pub mod tree;
 
 /// Solve a block's proof of work puzzle.
 pub fn solve(header: &mut BlockHeader) {
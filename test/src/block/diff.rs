@@ -0,0 +1,186 @@
+//! Differential header-acceptance testing.
+//!
+//! Feeds the same header sequences to a block tree and to a reference implementation,
+//! eg. Bitcoin Core through [`crate::bitcoind`], and reports the headers on which their
+//! accept/reject decisions differ. The sequences generated by [`cases`] target the
+//! difficulty and timestamp rules, which are where consensus divergences in header
+//! validation are most likely.
+//!
+//! Headers are generated on top of the regtest genesis block with [`crate::block::gen`].
+//! Since the reference implementation validates timestamps against its own clock, the
+//! system clock must be used on our side too.
+//!
+//! ```ignore
+//! let bitcoind = Bitcoind::spawn()?;
+//! let cases = diff::cases(16, SystemTime::now(), &mut gen::rng(1));
+//! let divergences = diff::run(&mut tree, &cases, &clock, |h| {
+//!     bitcoind.submit_header(h).unwrap()
+//! });
+//!
+//! assert!(divergences.is_empty(), "{:#?}", divergences);
+//! ```
+use std::iter;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+use nakamoto_common::block::time::{self, Clock};
+use nakamoto_common::block::tree::BlockTree;
+use nakamoto_common::block::{BlockHeader, BlockTime};
+
+use crate::block::{gen, solve};
+
+/// Decision on a header: accepted, or rejected with a reason.
+pub type Decision = Result<(), String>;
+
+/// A header sequence to submit, in order.
+#[derive(Debug, Clone)]
+pub struct Case {
+    /// Name of the case, for error reporting.
+    pub name: &'static str,
+    /// Headers to submit.
+    pub headers: Vec<BlockHeader>,
+}
+
+/// A header on which the tree and the reference implementation disagree.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Name of the case.
+    pub case: &'static str,
+    /// The header.
+    pub header: BlockHeader,
+    /// Decision of the block tree.
+    pub ours: Decision,
+    /// Decision of the reference implementation.
+    pub theirs: Decision,
+}
+
+/// Generate test cases, starting with a valid chain of the given length, on top of which
+/// every other case is built. `now` is the current time of both implementations' clocks.
+///
+/// Generated headers are already at the lowest difficulty allowed on regtest, so headers
+/// with a lower difficulty than required aren't tested.
+///
+/// Panics if the chain is shorter than the median time past span.
+pub fn cases<R: Rng + ?Sized>(length: usize, now: SystemTime, rng: &mut R) -> Vec<Case> {
+    assert!(
+        length >= time::MEDIAN_TIME_SPAN as usize,
+        "the chain must be at least as long as the median time past span"
+    );
+
+    let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs() as BlockTime;
+    let base = gen::chain(&gen::genesis(), length, rng);
+    let tip = *base.last().unwrap();
+    let mtp = self::median_time_past(&base);
+
+    let case = |name, headers| Case { name, headers };
+
+    // Headers that should be rejected all extend the tip of the valid chain, so that
+    // they go through the full validation of the active chain. The headers that should
+    // be accepted come last, and extend each other.
+    let after_mtp = modified(&tip, rng, |h| h.time = mtp + 1);
+    // Leave some leeway for the time it takes to run the cases.
+    let before_limit = modified(&after_mtp, rng, |h| {
+        h.time = now + time::MAX_FUTURE_BLOCK_TIME - 60 * 10
+    });
+
+    vec![
+        case("valid chain", base.clone()),
+        case(
+            "timestamp at median time past",
+            vec![modified(&tip, rng, |h| h.time = mtp)],
+        ),
+        case(
+            "timestamp past the future limit",
+            vec![modified(&tip, rng, |h| {
+                h.time = now + time::MAX_FUTURE_BLOCK_TIME + 60 * 10
+            })],
+        ),
+        case(
+            "higher difficulty than required",
+            vec![modified(&tip, rng, |h| h.bits -= 1)],
+        ),
+        case("invalid proof-of-work", {
+            let mut h = gen::next(&tip, rng);
+            while h.validate_pow(&h.target()).is_ok() {
+                h.nonce += 1;
+            }
+            vec![h]
+        }),
+        case("unknown parent", {
+            let parent = gen::next(&tip, rng);
+            vec![gen::next(&parent, rng)]
+        }),
+        case("timestamp after median time past", vec![after_mtp]),
+        case("timestamp before the future limit", vec![before_limit]),
+    ]
+}
+
+/// Submit the headers of each case to the given block tree, and to the reference
+/// implementation with `submit`, one at a time. Returns the headers on which decisions
+/// differ. The tree must only contain the regtest genesis block.
+///
+/// Block trees hold on to headers with an unknown parent as orphans, while the reference
+/// implementation rejects them, since it can't validate them. Such headers count as
+/// rejected by the tree.
+pub fn run<T, C, F>(tree: &mut T, cases: &[Case], clock: &C, mut submit: F) -> Vec<Divergence>
+where
+    T: BlockTree,
+    C: Clock,
+    F: FnMut(&BlockHeader) -> Decision,
+{
+    let mut divergences = Vec::new();
+
+    for case in cases {
+        for header in &case.headers {
+            let orphan = !tree.is_known(&header.prev_blockhash);
+            let ours = tree
+                .import_blocks(iter::once(*header), clock)
+                .map_err(|e| e.to_string())
+                .and_then(|_| {
+                    if orphan {
+                        Err(format!("parent block {} is missing", header.prev_blockhash))
+                    } else {
+                        Ok(())
+                    }
+                });
+            let theirs = submit(header);
+
+            if ours.is_ok() != theirs.is_ok() {
+                divergences.push(Divergence {
+                    case: case.name,
+                    header: *header,
+                    ours,
+                    theirs,
+                });
+            }
+        }
+    }
+    divergences
+}
+
+/// Generate a valid header on top of the given one, modify it, and solve it again.
+fn modified<R: Rng + ?Sized>(
+    prev: &BlockHeader,
+    rng: &mut R,
+    f: impl Fn(&mut BlockHeader),
+) -> BlockHeader {
+    let mut header = gen::next(prev, rng);
+    f(&mut header);
+    solve(&mut header);
+
+    header
+}
+
+/// Get the median time past of the block following the given chain.
+fn median_time_past(chain: &[BlockHeader]) -> BlockTime {
+    let mut times = chain
+        .iter()
+        .rev()
+        .take(time::MEDIAN_TIME_SPAN as usize)
+        .map(|h| h.time)
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+
+    times[times.len() / 2]
+}
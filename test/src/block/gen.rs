@@ -0,0 +1,95 @@
+//! Generators of valid header chains.
+//!
+//! Unlike [`crate::block::cache::model`], which accepts any header, the headers generated
+//! here pass the real validation path: they have valid proof-of-work for the regtest
+//! difficulty, and timestamps that are above the median time past of their chain. Use
+//! [`clock`] when importing them, so that they aren't too far in the future.
+//!
+//! ```ignore
+//! use nakamoto_test::block::gen;
+//!
+//! let mut rng = gen::rng(42);
+//! let chain = gen::chain(&gen::genesis(), 16, &mut rng);
+//! let fork = gen::fork(&gen::genesis(), &chain, 8, 12, &mut rng);
+//! ```
+use std::net;
+
+use bitcoin::blockdata::constants;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use nakamoto_common::block::time::{AdjustedTime, LocalTime};
+use nakamoto_common::block::{BlockHeader, BlockTime, Height};
+
+use crate::block::solve;
+
+/// Network on which the generated headers are valid.
+pub const NETWORK: bitcoin::Network = bitcoin::Network::Regtest;
+
+/// Target time between generated headers (10 minutes).
+pub const TARGET_SPACING: BlockTime = 60 * 10;
+
+/// Genesis block of the generated headers.
+pub fn genesis() -> BlockHeader {
+    constants::genesis_block(NETWORK).header
+}
+
+/// Clock to import the generated headers with. Set well after all generated headers.
+pub fn clock() -> AdjustedTime<net::SocketAddr> {
+    let time = genesis().time + 60 * 60 * 24 * 365;
+
+    AdjustedTime::new(LocalTime::from_block_time(time))
+}
+
+/// Create a seeded RNG, for reproducible chains.
+pub fn rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+/// Generate a valid header extending the given one. Since timestamps always increase,
+/// they are also above the median time past.
+pub fn next<R: Rng + ?Sized>(prev: &BlockHeader, rng: &mut R) -> BlockHeader {
+    let mut header = BlockHeader {
+        version: 1,
+        prev_blockhash: prev.block_hash(),
+        merkle_root: Default::default(),
+        bits: prev.bits,
+        time: prev.time + rng.gen_range(1, TARGET_SPACING * 2),
+        nonce: rng.gen::<u16>() as u32,
+    };
+    solve(&mut header);
+
+    header
+}
+
+/// Generate a chain of the given length, extending the given header.
+pub fn chain<R: Rng + ?Sized>(base: &BlockHeader, length: usize, rng: &mut R) -> Vec<BlockHeader> {
+    let mut chain: Vec<BlockHeader> = Vec::with_capacity(length);
+
+    for _ in 0..length {
+        let header = next(chain.last().unwrap_or(base), rng);
+        chain.push(header);
+    }
+    chain
+}
+
+/// Generate a fork of the given chain, which extends `base`. The fork shares the first
+/// `height` blocks of the chain, and has the given length past that height. Since all
+/// headers have the same target, the fork has more work than the chain if it is longer.
+///
+/// Panics if `height` is past the end of the chain.
+pub fn fork<R: Rng + ?Sized>(
+    base: &BlockHeader,
+    chain: &[BlockHeader],
+    height: Height,
+    length: usize,
+    rng: &mut R,
+) -> Vec<BlockHeader> {
+    let height = height as usize;
+    let base = if height == 0 {
+        base
+    } else {
+        &chain[height - 1]
+    };
+    self::chain(base, length, rng)
+}
@@ -0,0 +1,204 @@
+//! Re-org scenarios.
+//!
+//! Given a base chain generated with [`crate::block::gen`], [`scenarios`] generates a
+//! family of re-org scenarios: single and multi-block re-orgs, branches that don't have
+//! enough work to trigger a re-org, and repeated flip-flops between two branches. Each
+//! scenario is a list of steps, with the headers to import and the expected state of the
+//! tree after the import. Scenarios are checked against a block tree with [`run`].
+//!
+//! Since generated headers all have the same difficulty, a branch has more work if and
+//! only if it is longer. Re-orgs onto a shorter branch with more work can't be generated.
+//!
+//! ```ignore
+//! let mut rng = gen::rng(1);
+//! let base = gen::chain(&gen::genesis(), 8, &mut rng);
+//!
+//! for scenario in reorg::scenarios(&base, &mut rng) {
+//!     reorg::run(&mut MyTree::new(gen::genesis()), &scenario).unwrap();
+//! }
+//! ```
+use std::iter;
+
+use rand::Rng;
+
+use nakamoto_common::block::tree::{BlockTree, ImportResult};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+
+use crate::block::gen;
+
+/// A step of a re-org scenario.
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// Headers to import, in order.
+    pub headers: Vec<BlockHeader>,
+    /// Active chain after the import, excluding the genesis block.
+    pub chain: Vec<BlockHeader>,
+    /// Blocks that are no longer active after the import, in order of height.
+    pub reverted: Vec<BlockHash>,
+}
+
+/// A re-org scenario, starting from a tree with only the genesis block.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    /// Name of the scenario, for error reporting.
+    pub name: String,
+    /// Steps of the scenario.
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    /// Create a scenario, which starts by importing the given chain.
+    fn new(name: impl Into<String>, base: &[BlockHeader]) -> Self {
+        let mut scenario = Self {
+            name: name.into(),
+            steps: Vec::new(),
+        };
+        scenario.step(base.to_vec(), base.to_vec());
+        scenario
+    }
+
+    /// The active chain after the last step.
+    fn chain(&self) -> &[BlockHeader] {
+        self.steps.last().map(|s| s.chain.as_slice()).unwrap_or(&[])
+    }
+
+    /// Add a step, given the headers to import and the expected active chain.
+    fn step(&mut self, headers: Vec<BlockHeader>, chain: Vec<BlockHeader>) {
+        let reverted = self
+            .chain()
+            .iter()
+            .filter(|h| !chain.contains(h))
+            .map(|h| h.block_hash())
+            .collect();
+
+        self.steps.push(Step {
+            headers,
+            chain,
+            reverted,
+        });
+    }
+
+    /// Add a step that imports a branch forking off the active chain at the given height.
+    /// The branch becomes active if it is longer than the active chain past that height.
+    fn fork<R: Rng + ?Sized>(&mut self, height: usize, length: usize, rng: &mut R) {
+        let active = self.chain().to_vec();
+        let branch = gen::fork(&gen::genesis(), &active, height as Height, length, rng);
+        let chain = if height + length > active.len() {
+            active[..height]
+                .iter()
+                .chain(branch.iter())
+                .cloned()
+                .collect()
+        } else {
+            active
+        };
+        self.step(branch, chain);
+    }
+}
+
+/// Generate re-org scenarios from the given base chain, which must extend the genesis
+/// block of [`gen`]. Panics if the base chain has less than two blocks.
+pub fn scenarios<R: Rng + ?Sized>(base: &[BlockHeader], rng: &mut R) -> Vec<Scenario> {
+    assert!(
+        base.len() >= 2,
+        "the base chain must have at least two blocks"
+    );
+
+    let mut scenarios = Vec::new();
+    let len = base.len();
+
+    // A competing branch that is shorter than the active chain is not activated. Branches
+    // of equal length are avoided, since the tie-breaking rule is up to the tree.
+    let mut scenario = Scenario::new("stale branch", base);
+    scenario.fork(len - 2, 1, rng);
+    scenario.fork(0, len - 1, rng);
+    scenarios.push(scenario);
+
+    // Re-orgs of one or more blocks, up to the whole chain.
+    let mut depths = vec![1, 2, len / 2, len];
+    depths.sort_unstable();
+    depths.dedup();
+
+    for depth in depths {
+        let mut scenario = Scenario::new(format!("{}-block re-org", depth), base);
+        scenario.fork(len - depth, depth + 1, rng);
+        scenarios.push(scenario);
+    }
+
+    // Two branches that keep overtaking each other.
+    let mut scenario = Scenario::new("flip-flop", base);
+    let height = len - 1;
+    let mut branches = [base[height..].to_vec(), Vec::new()];
+
+    for i in 0..6 {
+        let (active, inactive) = (i % 2, (i + 1) % 2);
+        // Extend the inactive branch past the tip of the active one.
+        let length = branches[active].len() - branches[inactive].len() + 1;
+        let tip = branches[inactive].last().copied();
+        let extension = match tip {
+            Some(tip) => gen::chain(&tip, length, rng),
+            None => gen::chain(&base[height - 1], length, rng),
+        };
+        branches[inactive].extend(extension.iter().cloned());
+
+        let chain = base[..height]
+            .iter()
+            .chain(branches[inactive].iter())
+            .cloned()
+            .collect();
+        scenario.step(extension, chain);
+    }
+    scenarios.push(scenario);
+
+    scenarios
+}
+
+/// Run a re-org scenario on a block tree with only the genesis block of [`gen`]. Headers
+/// are imported one at a time, and the state of the tree is checked after each step.
+pub fn run<T: BlockTree>(tree: &mut T, scenario: &Scenario) -> Result<(), String> {
+    let clock = gen::clock();
+
+    for (i, step) in scenario.steps.iter().enumerate() {
+        let err = |msg: String| format!("{}: step {}: {}", scenario.name, i, msg);
+        let mut reverted = Vec::new();
+
+        for header in step.headers.iter() {
+            match tree.import_blocks(iter::once(*header), &clock) {
+                Ok(ImportResult::TipChanged(_, _, r)) => reverted.extend(r),
+                Ok(ImportResult::TipUnchanged) => {}
+                Err(e) => return Err(err(format!("import failed: {}", e))),
+            }
+        }
+        // Blocks may be reverted and activated again within the same step.
+        reverted.retain(|h| !step.chain.iter().any(|b| b.block_hash() == *h));
+
+        let tip = step
+            .chain
+            .last()
+            .map(|h| h.block_hash())
+            .unwrap_or_else(|| gen::genesis().block_hash());
+
+        if tree.height() != step.chain.len() as Height {
+            return Err(err(format!(
+                "expected height {}, got {}",
+                step.chain.len(),
+                tree.height()
+            )));
+        }
+        if tree.tip().0 != tip {
+            return Err(err(format!("expected tip {}, got {}", tip, tree.tip().0)));
+        }
+        for (height, header) in step.chain.iter().enumerate() {
+            if tree.get_block_by_height(height as Height + 1) != Some(header) {
+                return Err(err(format!("unexpected block at height {}", height + 1)));
+            }
+        }
+        if reverted != step.reverted {
+            return Err(err(format!(
+                "expected reverted blocks {:?}, got {:?}",
+                step.reverted, reverted
+            )));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,283 @@
+//! Property-based tests for [`BlockTree`] implementations.
+//!
+//! The generators in this module produce random header trees rooted at the regtest
+//! genesis block, with forks, as well as invalid headers. The `prop_` functions check
+//! invariants that every block tree should uphold, given a fresh tree with [`genesis`]
+//! as its genesis block. Implementors can run them against their own trees, eg.
+//!
+//! ```ignore
+//! use nakamoto_test::block::tree;
+//!
+//! #[quickcheck]
+//! fn prop_tip_selection(headers: tree::Headers) -> bool {
+//!     tree::prop_tip_selection(MyTree::new(tree::genesis()), &headers)
+//! }
+//! ```
+use std::collections::HashMap;
+use std::iter;
+
+use quickcheck::{Arbitrary, Gen};
+use rand::Rng;
+
+use nakamoto_common::block::time::{Clock, MAX_FUTURE_BLOCK_TIME};
+use nakamoto_common::block::tree::{BlockTree, ImportResult};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height, Work};
+
+use crate::block::gen::{chain, next};
+use crate::block::solve;
+
+pub use crate::block::gen::{clock, genesis, NETWORK};
+
+/// A random tree of valid headers, with forks. Parents always come before their children.
+#[derive(Debug, Clone)]
+pub struct Headers(pub Vec<BlockHeader>);
+
+impl Arbitrary for Headers {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let genesis = genesis();
+        let length = g.gen_range(1, g.size() / 5 + 2);
+        let forks = g.gen_range(0, g.size() / 10 + 1);
+        let mut headers = chain(&genesis, length, g);
+
+        for _ in 0..forks {
+            let ix = g.gen_range(0, headers.len() + 1);
+            let base = if ix == 0 { genesis } else { headers[ix - 1] };
+            let length = g.gen_range(1, g.size() / 5 + 2);
+            let fork = chain(&base, length, g);
+
+            headers.extend(fork);
+        }
+        Self(headers)
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let headers = self.0.clone();
+
+        // Only remove headers without children, so that the tree stays connected.
+        Box::new(
+            (0..headers.len())
+                .filter(move |i| {
+                    let hash = headers[*i].block_hash();
+                    !headers.iter().any(|h| h.prev_blockhash == hash)
+                })
+                .map({
+                    let headers = self.0.clone();
+                    move |i| {
+                        let mut headers = headers.clone();
+                        headers.remove(i);
+                        Self(headers)
+                    }
+                }),
+        )
+    }
+}
+
+/// A chain, and a fork of it with more work.
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    /// The initial chain.
+    pub chain: Vec<BlockHeader>,
+    /// The fork, which forks off the chain at `height`.
+    pub fork: Vec<BlockHeader>,
+    /// Height of the last block shared by the chain and the fork.
+    pub height: Height,
+}
+
+impl Arbitrary for Reorg {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let genesis = genesis();
+        let length = g.gen_range(1, g.size() / 5 + 2);
+        let chain = chain(&genesis, length, g);
+        let height = g.gen_range(0, length);
+        let base = if height == 0 {
+            genesis
+        } else {
+            chain[height - 1]
+        };
+        // Since all headers have the same target, the longer fork has more work.
+        let fork = chain(&base, length - height + g.gen_range(1, 3), g);
+
+        Self {
+            chain,
+            fork,
+            height: height as Height,
+        }
+    }
+}
+
+/// Why a header is invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The proof-of-work doesn't meet the target.
+    PoW,
+    /// The difficulty target isn't the expected one.
+    Target,
+    /// The timestamp isn't after the median time past.
+    TimeTooOld,
+    /// The timestamp is too far in the future.
+    TimeTooNew,
+}
+
+/// A chain, and an invalid header extending it.
+#[derive(Debug, Clone)]
+pub struct Invalid {
+    /// A valid chain.
+    pub chain: Vec<BlockHeader>,
+    /// An invalid header extending the chain.
+    pub header: BlockHeader,
+    /// Why the header is invalid.
+    pub reason: Reason,
+}
+
+impl Arbitrary for Invalid {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let genesis = genesis();
+        let length = g.gen_range(0, g.size() / 5 + 1);
+        let chain = chain(&genesis, length, g);
+        let tip = chain.last().unwrap_or(&genesis);
+        let mut header = next(tip, g);
+
+        let reason = match g.gen_range(0, 4) {
+            0 => {
+                while header.validate_pow(&header.target()).is_ok() {
+                    header.nonce += 1;
+                }
+                Reason::PoW
+            }
+            1 => {
+                header.bits = tip.bits - 1;
+                solve(&mut header);
+
+                Reason::Target
+            }
+            2 => {
+                // Timestamps only increase, so the median time past is at least the
+                // genesis time.
+                header.time = genesis.time - 1;
+                solve(&mut header);
+
+                Reason::TimeTooOld
+            }
+            _ => {
+                header.time = clock().block_time() + MAX_FUTURE_BLOCK_TIME + 1;
+                solve(&mut header);
+
+                Reason::TimeTooNew
+            }
+        };
+
+        Self {
+            chain,
+            header,
+            reason,
+        }
+    }
+}
+
+/// The tip is the block with the most cumulative work, and the height is its height.
+pub fn prop_tip_selection<T: BlockTree>(mut tree: T, headers: &Headers) -> bool {
+    let genesis = genesis();
+    let mut chains: HashMap<BlockHash, (Work, Height)> = HashMap::new();
+
+    chains.insert(genesis.block_hash(), (genesis.work(), 0));
+
+    for header in &headers.0 {
+        let (work, height) = chains[&header.prev_blockhash];
+        chains.insert(header.block_hash(), (work + header.work(), height + 1));
+    }
+    if tree
+        .import_blocks(headers.0.iter().cloned(), &clock())
+        .is_err()
+    {
+        return false;
+    }
+    let best = chains.values().map(|(work, _)| *work).max();
+    let (tip, _) = tree.tip();
+
+    match chains.get(&tip) {
+        Some((work, height)) => Some(*work) == best && *height == tree.height(),
+        None => false,
+    }
+}
+
+/// Importing a fork with more work than the active chain makes it the active chain.
+pub fn prop_reorg<T: BlockTree>(mut tree: T, reorg: &Reorg) -> bool {
+    let clock = clock();
+
+    if tree
+        .import_blocks(reorg.chain.iter().cloned(), &clock)
+        .is_err()
+    {
+        return false;
+    }
+    let tip = reorg.fork.last().unwrap().block_hash();
+    let height = reorg.height + reorg.fork.len() as Height;
+
+    match tree.import_blocks(reorg.fork.iter().cloned(), &clock) {
+        Ok(ImportResult::TipChanged(hash, h, _)) if hash == tip && h == height => {}
+        _ => return false,
+    }
+
+    tree.tip().0 == tip
+        && tree.height() == height
+        && reorg.fork.iter().enumerate().all(|(i, header)| {
+            tree.get_block_by_height(reorg.height + i as Height + 1) == Some(header)
+        })
+}
+
+/// Importing the same headers again changes nothing.
+pub fn prop_import_idempotent<T: BlockTree>(mut tree: T, headers: &Headers) -> bool {
+    let clock = clock();
+
+    if tree
+        .import_blocks(headers.0.iter().cloned(), &clock)
+        .is_err()
+    {
+        return false;
+    }
+    let tip = tree.tip();
+    let height = tree.height();
+
+    matches!(
+        tree.import_blocks(headers.0.iter().cloned(), &clock),
+        Ok(ImportResult::TipUnchanged)
+    ) && tree.tip() == tip
+        && tree.height() == height
+}
+
+/// Duplicate headers within an import are ignored.
+pub fn prop_import_duplicates<T: BlockTree + Clone>(tree: T, headers: &Headers) -> bool {
+    let clock = clock();
+    let mut expected = tree.clone();
+    let mut actual = tree;
+
+    let duplicates = headers.0.iter().flat_map(|h| iter::repeat(*h).take(2));
+
+    match (
+        expected.import_blocks(headers.0.iter().cloned(), &clock),
+        actual.import_blocks(duplicates, &clock),
+    ) {
+        (Ok(_), Ok(_)) => actual.tip() == expected.tip() && actual.height() == expected.height(),
+        _ => false,
+    }
+}
+
+/// Invalid headers are rejected, and leave the tree unchanged.
+pub fn prop_invalid_rejected<T: BlockTree>(mut tree: T, invalid: &Invalid) -> bool {
+    let clock = clock();
+
+    if tree
+        .import_blocks(invalid.chain.iter().cloned(), &clock)
+        .is_err()
+    {
+        return false;
+    }
+    let tip = tree.tip();
+    let height = tree.height();
+
+    tree.import_blocks(iter::once(invalid.header), &clock)
+        .is_err()
+        && tree.tip() == tip
+        && tree.height() == height
+        && tree.get_block(&invalid.header.block_hash()).is_none()
+}
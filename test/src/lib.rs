@@ -1,3 +1,4 @@
+pub mod bitcoind;
 pub mod block;
 
 use std::fs::File;
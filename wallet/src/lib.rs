@@ -7,24 +7,26 @@ use std::{fmt, net, thread};
 use crossbeam_channel as chan;
 
 use bitcoin::blockdata::script::Script;
-use bitcoin::blockdata::transaction::{OutPoint, TxOut};
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
 use bitcoin::Address;
 
+use nakamoto_client::builder::ClientBuilder;
 use nakamoto_client::error::Error;
 use nakamoto_client::handle::Handle;
+use nakamoto_client::Config;
 use nakamoto_client::Network;
-use nakamoto_client::{Client, Config};
 use nakamoto_common::block::Height;
 
 /// Re-scan parameters.
 pub struct Rescan {
-    genesis: Height,
+    /// Height from which to start scanning.
+    pub genesis: Height,
 }
 
 /// A Bitcoin wallet.
 pub struct Wallet<H> {
     client: H,
-    addresses: HashSet<Address>,
+    scripts: HashSet<Script>,
     utxos: HashMap<OutPoint, TxOut>,
 }
 
@@ -33,13 +35,27 @@ impl<H: Handle> Wallet<H> {
     pub fn new(client: H, addresses: Vec<Address>) -> Self {
         Self {
             client,
-            addresses: addresses.into_iter().collect(),
+            scripts: addresses.iter().map(|a| a.script_pubkey()).collect(),
             utxos: HashMap::new(),
         }
     }
 
+    /// Watch an output script, eg. one that can't be represented as an address.
+    pub fn watch(&mut self, script: Script) {
+        self.scripts.insert(script);
+    }
+
     /// Rescan the blockchain for matching transactions.
     pub fn rescan(&mut self, options: Rescan) -> Result<(), Error> {
+        self.rescan_with(options, |_, _| {})
+    }
+
+    /// Rescan the blockchain for matching transactions, calling the given function with
+    /// every matching transaction as it is found, along with the height of its block.
+    pub fn rescan_with<F>(&mut self, options: Rescan, mut on_match: F) -> Result<(), Error>
+    where
+        F: FnMut(&Transaction, Height),
+    {
         // 1. Download block filters between `genesis` and `height` Filters can be downloaded in
         //    parallel, but should be processed in-order.
         // 2. As they are downloaded, check if there's a match. If so, add the block hash
@@ -50,12 +66,7 @@ impl<H: Handle> Wallet<H> {
         //    and update the UTXO set.
         // 5. Once there are no more blocks in the queue and filters to check, exit.
         //
-        let addresses: HashSet<Script> = self.addresses.iter().map(|a| a.script_pubkey()).collect();
-        let query = self
-            .addresses
-            .iter()
-            .map(|a| a.script_pubkey())
-            .collect::<Vec<_>>();
+        let query = self.scripts.iter().cloned().collect::<Vec<_>>();
 
         log::info!("Waiting for peers..");
 
@@ -126,15 +137,18 @@ impl<H: Handle> Wallet<H> {
                         );
 
                         for tx in block.txdata.iter() {
+                            let mut matched = false;
+
                             // Look for outputs.
                             for (vout, output) in tx.output.iter().enumerate() {
                                 // Received coin.
-                                if addresses.contains(&output.script_pubkey) {
+                                if self.scripts.contains(&output.script_pubkey) {
                                     let outpoint = OutPoint {
                                         txid: tx.txid(),
                                         vout: vout as u32,
                                     };
                                     self.utxos.insert(outpoint, output.clone());
+                                    matched = true;
                                     log::info!("Unspent output found (balance={})", self.balance());
                                 }
                             }
@@ -142,9 +156,13 @@ impl<H: Handle> Wallet<H> {
                             for input in tx.input.iter() {
                                 // Spent coin.
                                 if self.utxos.remove(&input.previous_output).is_some() {
+                                    matched = true;
                                     log::info!("Spent output found (balance={})", self.balance())
                                 }
                             }
+                            if matched {
+                                on_match(tx, height);
+                            }
                         }
                     }
                 }
@@ -154,7 +172,8 @@ impl<H: Handle> Wallet<H> {
         Ok(())
     }
 
-    fn balance(&self) -> u64 {
+    /// Get the balance of the unspent outputs found.
+    pub fn balance(&self) -> u64 {
         self.utxos.values().map(|u| u.value).sum()
     }
 }
@@ -171,15 +190,13 @@ pub fn run<S: net::ToSocketAddrs + fmt::Debug>(
     let mut cfg = Config {
         listen: vec![], // Don't listen for incoming connections.
         network: Network::Mainnet,
+        connect_only: true,
         ..Config::default()
     };
     cfg.seed(&[seed])?;
-    // TODO: This shouldn't have to be specified manually. We should have a "discovery mode"
-    // that can be static or dynamic.
-    cfg.target_outbound_peers = cfg.connect.len().min(8);
 
     // Create a new client using `Reactor` for networking.
-    let client = Client::<Reactor>::new(cfg)?;
+    let client = ClientBuilder::from(cfg).build::<Reactor>()?;
     let handle = client.handle();
 
     // Start the network client in the background.